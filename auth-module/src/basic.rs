@@ -13,11 +13,11 @@
 // limitations under the License.
 
 use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine as _};
-use http::{header, Method, StatusCode};
+use http::{header, StatusCode};
 use log::{info, trace};
 use maud::{html, DOCTYPE};
 use pandora_module_utils::pingora::{Error, ResponseHeader, SessionWrapper};
-use pandora_module_utils::standard_response::error_response;
+use pandora_module_utils::standard_response::{error_response, send_response};
 use pandora_module_utils::RequestFilterResult;
 
 use crate::{
@@ -59,18 +59,10 @@ async fn unauthorized_response(
     }.into_string();
 
     let mut header = ResponseHeader::build(StatusCode::UNAUTHORIZED, Some(3))?;
-    header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
     header.append_header(header::CONTENT_TYPE, "text/html;charset=utf-8")?;
     header.append_header(header::WWW_AUTHENTICATE, format!("Basic realm=\"{realm}\""))?;
 
-    let send_body = session.req_header().method != Method::HEAD;
-    session
-        .write_response_header(Box::new(header), !send_body)
-        .await?;
-
-    if send_body {
-        session.write_response_body(Some(text.into()), true).await?;
-    }
+    send_response(session, header, text).await?;
 
     Ok(())
 }
@@ -19,7 +19,9 @@ use jwt::{SignWithKey, VerifyWithKey};
 use log::{error, trace, warn};
 use maud::{html, DOCTYPE};
 use pandora_module_utils::pingora::{Error, ErrorType, ResponseHeader, SessionWrapper};
-use pandora_module_utils::standard_response::{error_response, redirect_response_with_cookie};
+use pandora_module_utils::standard_response::{
+    error_response, redirect_response_with_cookie, send_response,
+};
 use pandora_module_utils::RequestFilterResult;
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
@@ -113,18 +115,10 @@ async fn login_response(
     }.into_string();
 
     let mut header = ResponseHeader::build(StatusCode::OK, Some(3))?;
-    header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
     header.append_header(header::CONTENT_TYPE, "text/html;charset=utf-8")?;
     header.append_header(header::CACHE_CONTROL, "no-store")?;
 
-    let send_body = session.req_header().method != Method::HEAD;
-    session
-        .write_response_header(Box::new(header), !send_body)
-        .await?;
-
-    if send_body {
-        session.write_response_body(Some(text.into()), true).await?;
-    }
+    send_response(session, header, text).await?;
 
     Ok(RequestFilterResult::ResponseSent)
 }
@@ -154,20 +148,12 @@ async fn login_response_json(
     text.push('}');
 
     let mut header = ResponseHeader::build(StatusCode::OK, Some(3))?;
-    header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
     header.append_header(header::CONTENT_TYPE, "application/json;charset=utf-8")?;
     if let Some(cookie) = cookie {
         header.append_header(header::SET_COOKIE, cookie)?;
     }
 
-    let send_body = session.req_header().method != Method::HEAD;
-    session
-        .write_response_header(Box::new(header), !send_body)
-        .await?;
-
-    if send_body {
-        session.write_response_body(Some(text.into()), true).await?;
-    }
+    send_response(session, header, text).await?;
 
     Ok(RequestFilterResult::ResponseSent)
 }
@@ -298,12 +284,10 @@ pub(crate) async fn page_auth(
         .sign_with_key(&key)
         .map_err(|err| Error::because(ErrorType::InternalError, "failed signing JTW token", err))?;
 
-    let secure = conf.auth_page_session.secure_cookie.unwrap_or_else(|| {
-        session
-            .digest()
-            .and_then(|digest| digest.ssl_digest.as_ref())
-            .is_some()
-    });
+    let secure = conf
+        .auth_page_session
+        .secure_cookie
+        .unwrap_or_else(|| session.is_https(false));
 
     let cookie = format!(
         "{}={token}; Max-Age={}; HttpOnly{}",
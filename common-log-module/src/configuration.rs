@@ -118,6 +118,10 @@ pub struct CommonLogConf {
     /// [remote_addr, -, -, time_local, request, status, bytes_sent, http_referer, http_user_agent]
     /// ```
     pub log_format: OneOrMany<LogField>,
+    /// Query parameters to be redacted in the logged request line
+    ///
+    /// Values of these parameters are replaced by `REDACTED`, the request itself isn’t affected.
+    pub redact_query_params: OneOrMany<String>,
 }
 
 impl Default for CommonLogConf {
@@ -125,6 +129,7 @@ impl Default for CommonLogConf {
         Self {
             log_file: PathBuf::from("-"),
             log_format: Default::default(),
+            redact_query_params: Default::default(),
         }
     }
 }
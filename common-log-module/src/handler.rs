@@ -20,6 +20,7 @@ use log::error;
 use once_cell::sync::Lazy;
 use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
 use pandora_module_utils::{RequestFilter, RequestFilterResult};
+use std::borrow::Cow;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::SystemTime;
@@ -57,6 +58,31 @@ fn normalize_path(path: PathBuf) -> Result<PathBuf, Box<Error>> {
     }
 }
 
+/// Replaces values of the listed query parameters in a URI by `REDACTED`
+fn redact_query<'a>(uri: &'a str, params: &[String]) -> Cow<'a, str> {
+    let (path, query) = if let Some(parts) = uri.split_once('?') {
+        parts
+    } else {
+        return uri.into();
+    };
+
+    if params.is_empty() {
+        return uri.into();
+    }
+
+    let query = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if params.iter().any(|param| param == name) => {
+                format!("{name}=REDACTED").into()
+            }
+            _ => Cow::Borrowed(pair),
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    format!("{path}?{query}").into()
+}
+
 /// Common Log module handler
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CommonLogHandler {
@@ -146,6 +172,7 @@ impl RequestFilter for CommonLogHandler {
                         .path_and_query()
                         .map(|p| p.as_str())
                         .unwrap_or("");
+                    let uri = redact_query(uri, &self.conf.redact_query_params);
                     let version = &header.version;
                     LogToken::Request(format!("{method} {uri} {version:?}"))
                 }
@@ -253,7 +280,61 @@ impl RequestFilter for CommonLogHandler {
 mod tests {
     use super::*;
 
+    use pandora_module_utils::pingora::{create_test_session, RequestHeader};
+    use startup_module::DefaultApp;
     use std::env::current_dir;
+    use test_log::test;
+
+    #[test]
+    fn query_redaction() {
+        let params = vec!["token".to_owned(), "email".to_owned()];
+        assert_eq!(redact_query("/", &params), "/");
+        assert_eq!(redact_query("/?token=secret", &[]), "/?token=secret");
+        assert_eq!(redact_query("/?token=secret", &params), "/?token=REDACTED");
+        assert_eq!(
+            redact_query("/dir/?a=b&email=me%40example.com&token=&token", &params),
+            "/dir/?a=b&email=REDACTED&token=REDACTED&token"
+        );
+        assert_eq!(
+            redact_query("/?tokens=1&xtoken=2", &params),
+            "/?tokens=1&xtoken=2"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn request_redaction() {
+        let handler = CommonLogHandler::try_from(CommonLogConf {
+            log_format: vec![LogField::Request].into(),
+            redact_query_params: vec!["token".to_owned()].into(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // Use a handler with logging disabled to produce the session
+        let disabled = CommonLogHandler::try_from(CommonLogConf {
+            log_file: "".into(),
+            ..Default::default()
+        })
+        .unwrap();
+        let header =
+            RequestHeader::build("GET", "/path?token=secret&x=y".as_bytes(), None).unwrap();
+        let session = create_test_session(header).await;
+        let mut result = DefaultApp::new(disabled).handle_request(session).await;
+        let mut session = result.session();
+
+        let mut ctx = CommonLogHandler::new_ctx();
+        handler
+            .request_filter(&mut session, &mut ctx)
+            .await
+            .unwrap();
+        assert_eq!(
+            ctx.tokens,
+            vec![LogToken::Request(
+                "GET /path?token=REDACTED&x=y HTTP/1.1".to_owned()
+            )]
+        );
+        assert_eq!(session.uri(), "/path?token=secret&x=y");
+    }
 
     #[test]
     fn path_normalization() {
@@ -0,0 +1,194 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Handles negotiation of the compression algorithm based on the `Accept-Encoding` HTTP header.
+
+use pandora_module_utils::pingora::CompressionAlgorithm;
+use serde::Deserialize;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// A compression algorithm supported for dynamic compression
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Encoding {
+    /// gzip compression, `gzip` in config file
+    Gzip,
+    /// Brotli compression, `br` in config file
+    Brotli,
+    /// Zstandard compression, `zstd` in config file
+    Zstd,
+}
+
+impl Encoding {
+    /// Default priority of the algorithms if the client accepts multiple with the same quality
+    pub(crate) const DEFAULT_PRIORITY: &'static [Encoding] =
+        &[Self::Zstd, Self::Brotli, Self::Gzip];
+
+    /// Returns the algorithm name as used in `Accept-Encoding` HTTP header.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// Returns the corresponding Pingora compression algorithm.
+    pub(crate) fn algorithm(&self) -> CompressionAlgorithm {
+        match self {
+            Self::Gzip => CompressionAlgorithm::Gzip,
+            Self::Brotli => CompressionAlgorithm::Brotli,
+            Self::Zstd => CompressionAlgorithm::Zstd,
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Self::Gzip),
+            "br" => Ok(Self::Brotli),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(format!("Unsupported compression algorithm {s}")),
+        }
+    }
+}
+
+impl TryFrom<String> for Encoding {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Parses an encoding specifier from `Accept-Encoding` HTTP header into an
+/// algorithm/quality pair. Quality values are scaled to the range 0 to 1000.
+fn parse_encoding(encoding: &str) -> Option<(&str, u16)> {
+    let mut params = encoding.split(';');
+    let algorithm = params.next()?.trim();
+    if algorithm.is_empty() {
+        return None;
+    }
+
+    let mut quality = 1000;
+    for param in params {
+        if let Some((name, value)) = param.split_once('=') {
+            if name.trim() == "q" {
+                if let Ok(value) = f64::from_str(value.trim()) {
+                    quality = (value.clamp(0.0, 1.0) * 1000.0) as u16;
+                }
+            }
+        }
+    }
+    Some((algorithm, quality))
+}
+
+/// Selects the algorithm to compress the response with.
+///
+/// `enabled` lists the enabled algorithms in the order of priority. The algorithm with the highest
+/// quality value in the `Accept-Encoding` header wins, priority is only relevant for algorithms
+/// with identical quality values. Algorithms with quality value 0 are never selected.
+pub(crate) fn negotiate<'a, I>(accept_encoding: I, enabled: &[Encoding]) -> Option<Encoding>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let requested = accept_encoding
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .filter_map(parse_encoding)
+        .collect::<Vec<_>>();
+
+    let quality = |name: &str| {
+        requested
+            .iter()
+            .find(|(algorithm, _)| algorithm.eq_ignore_ascii_case(name))
+            .map(|(_, quality)| *quality)
+    };
+    let wildcard_quality = quality("*");
+
+    let mut result = None;
+    let mut result_quality = 0;
+    for encoding in enabled {
+        let quality = quality(encoding.name()).or(wildcard_quality).unwrap_or(0);
+        if quality > result_quality {
+            result = Some(*encoding);
+            result_quality = quality;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding_negotiation() {
+        fn negotiate(accept_encoding: &str, enabled: &[Encoding]) -> Option<Encoding> {
+            super::negotiate([accept_encoding], enabled)
+        }
+
+        let all = Encoding::DEFAULT_PRIORITY;
+        assert_eq!(negotiate("", all), None);
+        assert_eq!(negotiate("identity", all), None);
+        assert_eq!(negotiate("deflate", all), None);
+        assert_eq!(negotiate("gzip", all), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip, br", all), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip, br, zstd", all), Some(Encoding::Zstd));
+        assert_eq!(negotiate("GZIP, Br", all), Some(Encoding::Brotli));
+        assert_eq!(negotiate("*", all), Some(Encoding::Zstd));
+        assert_eq!(negotiate("zstd;q=0, *", all), Some(Encoding::Brotli));
+        assert_eq!(negotiate("zstd;q=0, gzip", all), Some(Encoding::Gzip));
+        assert_eq!(
+            negotiate("zstd;q=0, br;q=0, *;q=0.1", all),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate("*;q=0", all), None);
+        assert_eq!(
+            negotiate("gzip;q=1.0, br;q=0.9, zstd;q=0.8", all),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            negotiate("gzip, zstd", &[Encoding::Gzip]),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate("zstd", &[Encoding::Gzip]), None);
+        assert_eq!(
+            negotiate("zstd, br, gzip", &[Encoding::Gzip, Encoding::Brotli]),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            super::negotiate(["zstd;q=0", "gzip"], all),
+            Some(Encoding::Gzip)
+        );
+    }
+
+    #[test]
+    fn encoding_parsing() {
+        assert_eq!("gzip".parse(), Ok(Encoding::Gzip));
+        assert_eq!("br".parse(), Ok(Encoding::Brotli));
+        assert_eq!("zstd".parse(), Ok(Encoding::Zstd));
+        assert!("deflate".parse::<Encoding>().is_err());
+    }
+}
@@ -14,14 +14,17 @@
 
 #![doc = include_str!("../README.md")]
 
+mod encoding;
+
 use async_trait::async_trait;
 use clap::Parser;
 use log::trace;
 use pandora_module_utils::pingora::{
-    CompressionAlgorithm, Error, HttpModules, ResponseCompression, ResponseCompressionBuilder,
-    SessionWrapper,
+    Error, HttpModules, ResponseCompression, ResponseCompressionBuilder, SessionWrapper,
 };
-use pandora_module_utils::{DeserializeMap, RequestFilter};
+use pandora_module_utils::{DeserializeMap, OneOrMany, RequestFilter};
+
+pub use encoding::Encoding;
 
 /// Command line options of the compression module
 #[derive(Debug, Default, Parser)]
@@ -39,6 +42,12 @@ pub struct CompressionOpt {
     #[clap(long)]
     pub compression_level_zstd: Option<u32>,
 
+    /// Compression algorithm to prefer if the client accepts multiple algorithms with the same
+    /// quality. This command line flag can be specified multiple times, supported algorithms are
+    /// zstd, br and gzip.
+    #[clap(long)]
+    pub compression_priority: Option<Vec<Encoding>>,
+
     /// Decompress upstream responses before passing them on
     #[clap(long)]
     pub decompress_upstream: bool,
@@ -56,6 +65,11 @@ pub struct CompressionConf {
     /// Compression level to be used for dynamic Zstandard compression (omit to disable compression).
     pub compression_level_zstd: Option<u32>,
 
+    /// Priority of compression algorithms if the client accepts multiple algorithms with the same
+    /// quality. Enabled algorithms missing from this list have the lowest priority. The default
+    /// priority is `[zstd, br, gzip]`.
+    pub compression_priority: OneOrMany<Encoding>,
+
     /// If `true`, upstream responses will be decompressed
     pub decompress_upstream: bool,
 }
@@ -76,6 +90,10 @@ impl CompressionConf {
             self.compression_level_zstd = opt.compression_level_zstd;
         }
 
+        if let Some(compression_priority) = opt.compression_priority {
+            self.compression_priority = compression_priority.into();
+        }
+
         if opt.decompress_upstream {
             self.decompress_upstream = opt.decompress_upstream;
         }
@@ -86,13 +104,43 @@ impl CompressionConf {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CompressionHandler {
     conf: CompressionConf,
+    priority: Vec<Encoding>,
+}
+
+impl CompressionHandler {
+    /// Returns the compression level configured for an algorithm if it is enabled.
+    fn level(&self, encoding: Encoding) -> Option<u32> {
+        match encoding {
+            Encoding::Gzip => self.conf.compression_level_gzip,
+            Encoding::Brotli => self.conf.compression_level_brotli,
+            Encoding::Zstd => self.conf.compression_level_zstd,
+        }
+    }
 }
 
 impl TryFrom<CompressionConf> for CompressionHandler {
     type Error = Box<Error>;
 
     fn try_from(conf: CompressionConf) -> Result<Self, Self::Error> {
-        Ok(Self { conf })
+        let mut handler = Self {
+            conf,
+            priority: Vec::new(),
+        };
+
+        let priority = handler
+            .conf
+            .compression_priority
+            .iter()
+            .chain(Encoding::DEFAULT_PRIORITY)
+            .copied()
+            .collect::<Vec<_>>();
+        for encoding in priority {
+            if handler.level(encoding).is_some() && !handler.priority.contains(&encoding) {
+                handler.priority.push(encoding);
+            }
+        }
+
+        Ok(handler)
     }
 }
 
@@ -111,32 +159,43 @@ impl RequestFilter for CompressionHandler {
         session: &mut impl SessionWrapper,
         _ctx: &mut Self::CTX,
     ) -> Result<(), Box<Error>> {
-        macro_rules! enable_compression {
-            ($pref:ident => $algorithm:ident) => {
-                if let Some(level) = self.conf.$pref {
-                    trace!(
-                        concat!(
-                            "Enabled ",
-                            stringify!($algorithm),
-                            " compression with compression level {}"
-                        ),
-                        level
-                    );
-                    session
-                        .downstream_modules_ctx
-                        .get_mut::<ResponseCompression>()
-                        .unwrap()
-                        .adjust_algorithm_level(CompressionAlgorithm::$algorithm, level);
-                }
-            };
+        if self.conf.decompress_upstream {
+            session.upstream_compression.adjust_decompression(true);
         }
 
-        enable_compression!(compression_level_gzip => Gzip);
-        enable_compression!(compression_level_brotli => Brotli);
-        enable_compression!(compression_level_zstd => Zstd);
+        if session.compression_disabled() {
+            trace!("Compression disabled for this request, skipping negotiation");
+            return Ok(());
+        }
 
-        if self.conf.decompress_upstream {
-            session.upstream_compression.adjust_decompression(true);
+        let accept_encoding = session
+            .req_header()
+            .headers
+            .get_all("Accept-Encoding")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>();
+
+        if let Some(encoding) = encoding::negotiate(accept_encoding.iter().copied(), &self.priority)
+        {
+            // Pingora only considers the first algorithm listed in Accept-Encoding header, so
+            // make sure that the negotiated algorithm is listed first.
+            let accept_encoding = std::iter::once(encoding.name())
+                .chain(accept_encoding)
+                .collect::<Vec<_>>()
+                .join(", ");
+            session
+                .req_header_mut()
+                .insert_header("Accept-Encoding", accept_encoding)?;
+
+            if let Some(level) = self.level(encoding) {
+                trace!("Enabled {encoding} compression with compression level {level}");
+                session
+                    .downstream_modules_ctx
+                    .get_mut::<ResponseCompression>()
+                    .unwrap()
+                    .adjust_algorithm_level(encoding.algorithm(), level);
+            }
         }
 
         Ok(())
@@ -147,8 +206,10 @@ impl RequestFilter for CompressionHandler {
 mod tests {
     use super::*;
 
-    use pandora_module_utils::pingora::{create_test_session, RequestHeader, Session};
-    use pandora_module_utils::FromYaml;
+    use pandora_module_utils::pingora::{
+        create_test_session, RequestHeader, ResponseHeader, Session,
+    };
+    use pandora_module_utils::{FromYaml, RequestFilterResult};
     use startup_module::{AppResult, DefaultApp};
     use test_log::test;
 
@@ -157,6 +218,8 @@ mod tests {
             <CompressionHandler as RequestFilter>::Conf::from_yaml(
                 r#"
                     compression_level_gzip: 6
+                    compression_level_brotli: 4
+                    compression_level_zstd: 3
                     decompress_upstream: true
                 "#,
             )
@@ -168,10 +231,29 @@ mod tests {
     }
 
     async fn make_session() -> Session {
-        let header = RequestHeader::build("GET", b"/", None).unwrap();
+        make_session_with_encoding("gzip").await
+    }
+
+    async fn make_session_with_encoding(accept_encoding: &str) -> Session {
+        let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+        header
+            .insert_header("Accept-Encoding", accept_encoding)
+            .unwrap();
         create_test_session(header).await
     }
 
+    fn accept_encoding(result: &mut AppResult) -> String {
+        result
+            .session()
+            .req_header()
+            .headers
+            .get("Accept-Encoding")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
     fn assert_compression(result: &mut AppResult, downstream: bool, upstream: bool) {
         let session = result.session();
         assert_eq!(
@@ -199,4 +281,135 @@ mod tests {
         let mut result = app.handle_request(session).await;
         assert_compression(&mut result, true, true);
     }
+
+    #[test(tokio::test)]
+    async fn unconfigured_header() {
+        let mut app = make_app(false);
+        let session = make_session_with_encoding("gzip, zstd").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(accept_encoding(&mut result), "gzip, zstd");
+    }
+
+    #[test(tokio::test)]
+    async fn negotiation() {
+        let mut app = make_app(true);
+        let session = make_session_with_encoding("gzip, br, zstd").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(accept_encoding(&mut result), "zstd, gzip, br, zstd");
+
+        let session = make_session_with_encoding("zstd;q=0, gzip").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(accept_encoding(&mut result), "gzip, zstd;q=0, gzip");
+
+        let session = make_session_with_encoding("zstd;q=0, deflate").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(accept_encoding(&mut result), "zstd;q=0, deflate");
+        assert_compression(&mut result, false, true);
+    }
+
+    #[test(tokio::test)]
+    async fn configured_priority() {
+        let conf = <CompressionHandler as RequestFilter>::Conf::from_yaml(
+            r#"
+                compression_level_gzip: 6
+                compression_level_zstd: 3
+                compression_priority: [gzip, br]
+            "#,
+        )
+        .unwrap();
+        let mut app = DefaultApp::<CompressionHandler>::new(conf.try_into().unwrap());
+
+        let session = make_session_with_encoding("zstd, br, gzip").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(accept_encoding(&mut result), "gzip, zstd, br, gzip");
+
+        let session = make_session_with_encoding("zstd, br").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(accept_encoding(&mut result), "zstd, zstd, br");
+
+        let session = make_session_with_encoding("zstd, gzip;q=0.5").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(accept_encoding(&mut result), "zstd, zstd, gzip;q=0.5");
+    }
+
+    #[test(tokio::test)]
+    async fn disabled_compression() {
+        #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+        struct TestConf {
+            disable_compression: bool,
+        }
+
+        #[derive(Debug)]
+        struct TestHandler {
+            conf: TestConf,
+        }
+
+        impl TryFrom<TestConf> for TestHandler {
+            type Error = Box<Error>;
+
+            fn try_from(conf: TestConf) -> Result<Self, Self::Error> {
+                Ok(Self { conf })
+            }
+        }
+
+        #[async_trait]
+        impl RequestFilter for TestHandler {
+            type Conf = TestConf;
+            type CTX = ();
+            fn new_ctx() -> Self::CTX {}
+
+            async fn request_filter(
+                &self,
+                session: &mut impl SessionWrapper,
+                _ctx: &mut Self::CTX,
+            ) -> Result<RequestFilterResult, Box<Error>> {
+                if self.conf.disable_compression {
+                    session.disable_compression();
+                }
+
+                let body = "Hello world!".repeat(100);
+                let mut header = ResponseHeader::build(200, None)?;
+                header.insert_header("Content-Type", "text/plain")?;
+                header.insert_header("Content-Length", body.len())?;
+                session
+                    .write_response_header(Box::new(header), false)
+                    .await?;
+                session.write_response_body(Some(body.into()), true).await?;
+                Ok(RequestFilterResult::ResponseSent)
+            }
+        }
+
+        #[derive(Debug, RequestFilter)]
+        struct Handler {
+            compression: CompressionHandler,
+            test: TestHandler,
+        }
+
+        fn content_encoding(result: &mut AppResult) -> Option<String> {
+            result
+                .session()
+                .response_written()
+                .unwrap()
+                .headers
+                .get("Content-Encoding")
+                .map(|value| value.to_str().unwrap().to_owned())
+        }
+
+        for (disable_compression, expected) in [(false, Some("gzip".to_owned())), (true, None)] {
+            let conf = <Handler as RequestFilter>::Conf::from_yaml(format!(
+                r#"
+                    compression_level_gzip: 6
+                    disable_compression: {disable_compression}
+                "#,
+            ))
+            .unwrap();
+            let mut app = DefaultApp::<Handler>::new(conf.try_into().unwrap());
+
+            let session = make_session().await;
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none());
+            assert_eq!(content_encoding(&mut result), expected);
+            assert_eq!(result.session().compression_disabled(), disable_compression);
+        }
+    }
 }
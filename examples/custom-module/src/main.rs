@@ -61,8 +61,9 @@ fn main() {
 
     let opt = Opt::parse();
 
+    let files = opt.startup.conf.as_deref().unwrap_or(&[]);
     #[allow(unused_mut)]
-    let mut conf = match Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[])) {
+    let mut conf = match Conf::load_from_files_with_limit(files, opt.startup.max_config_size) {
         Ok(conf) => conf,
         Err(err) => {
             error!("{err}");
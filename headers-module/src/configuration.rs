@@ -20,13 +20,214 @@
 use http::{
     header,
     header::{HeaderName, HeaderValue},
+    HeaderMap, Method,
 };
-use pandora_module_utils::merger::{HostPathMatcher, PathMatch, PathMatchResult};
+use log::warn;
+use pandora_module_utils::merger::{closest_match, HostPathMatcher, PathMatch, PathMatchResult};
+use pandora_module_utils::pingora::{Error, ErrorType};
 use pandora_module_utils::router::{Path, EMPTY_PATH};
 use pandora_module_utils::{DeserializeMap, OneOrMany};
+use regex::Regex;
+use serde::Deserialize;
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::fmt::Debug;
+use std::fmt::{Debug, Display};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::deserialize::{
+    deserialize_header_conditions, deserialize_header_names, deserialize_http_date,
+    deserialize_methods, deserialize_status_matchers,
+};
+
+/// A matcher applying a regular expression to the request path like `~\.map$`
+///
+/// The regular expression is applied to the normalized path which always starts with `/`. It
+/// isn’t anchored implicitly, `^` and `$` have to be used to match the complete path.
+#[derive(Debug, Clone)]
+pub struct RegexMatcher {
+    /// Regular expression to apply to the path
+    pub regex: Regex,
+    /// If `true`, this is a negated rule, see [`HostPathMatcher::negated`]
+    pub negated: bool,
+}
+
+impl PartialEq for RegexMatcher {
+    fn eq(&self, other: &Self) -> bool {
+        self.regex.as_str() == other.regex.as_str() && self.negated == other.negated
+    }
+}
+
+impl Eq for RegexMatcher {}
+
+/// A single rule in the `include` or `exclude` list
+///
+/// In configuration files, a rule is usually given as a string, see [`RuleMatcher::from_str`].
+/// Host/path rules can also be given as a map like `{path: "/static/*", priority: 10}` to set
+/// the matcher's priority.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleMatcher {
+    /// A host/path matcher like `example.com/dir/*`
+    Path(HostPathMatcher),
+    /// A regular expression matcher like `~\.map$`
+    Regex(RegexMatcher),
+}
+
+impl RuleMatcher {
+    fn negated(&self) -> bool {
+        match self {
+            Self::Path(matcher) => matcher.negated,
+            Self::Regex(matcher) => matcher.negated,
+        }
+    }
+}
+
+impl Display for RuleMatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Path(matcher) => Debug::fmt(matcher, f),
+            Self::Regex(matcher) => {
+                if matcher.negated {
+                    f.write_str("!")?;
+                }
+                f.write_str("~")?;
+                match matcher.regex.as_str().strip_prefix("(?i)") {
+                    Some(pattern) => write!(f, "*{pattern}"),
+                    None => f.write_str(matcher.regex.as_str()),
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for RuleMatcher {
+    type Err = Box<Error>;
+
+    /// Converts a string into a rule. Strings starting with `~` are regular expressions, `~*`
+    /// makes the regular expression case-insensitive. Like with host/path matchers, a leading `!`
+    /// produces a negated rule, e.g. `!~\.map$`. Anything else is parsed via
+    /// [`HostPathMatcher::from_str`].
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (rest, negated) = if let Some(rest) = value.strip_prefix('!') {
+            (rest, true)
+        } else {
+            (value, false)
+        };
+
+        let Some(pattern) = rest.strip_prefix('~') else {
+            return Ok(Self::Path(value.parse()?));
+        };
+        let regex = if let Some(pattern) = pattern.strip_prefix('*') {
+            Regex::new(&format!("(?i){pattern}"))
+        } else {
+            Regex::new(pattern)
+        }
+        .map_err(|err| {
+            Error::explain(
+                ErrorType::InternalError,
+                format!("invalid regular expression `{pattern}`: {err}"),
+            )
+        })?;
+        Ok(Self::Regex(RegexMatcher { regex, negated }))
+    }
+}
+
+impl From<&str> for RuleMatcher {
+    /// Converts a string into a rule, see [`RuleMatcher::from_str`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string is an invalid rule, use `str::parse` to handle this case.
+    fn from(value: &str) -> Self {
+        value.parse().unwrap_or_else(|err| panic!("{err}"))
+    }
+}
+
+impl TryFrom<String> for RuleMatcher {
+    type Error = Box<Error>;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// A range of response status codes like `200`, `200-299` or `2xx`, both ends inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusMatcher {
+    /// First status code in the range
+    pub from: u16,
+    /// Last status code in the range
+    pub to: u16,
+}
+
+impl StatusMatcher {
+    /// Checks whether a status code is within the range.
+    pub fn matches(&self, status: u16) -> bool {
+        (self.from..=self.to).contains(&status)
+    }
+}
+
+impl FromStr for StatusMatcher {
+    type Err = Box<Error>;
+
+    /// Parses a single status code like `200`, a range like `200-299` or a status class like
+    /// `2xx`. Only status codes between 100 and 599 are accepted.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            Error::explain(
+                ErrorType::InternalError,
+                format!("invalid status code matcher `{value}`, expected e.g. 200, 200-299 or 2xx"),
+            )
+        };
+        let parse = |code: &str| {
+            code.trim()
+                .parse::<u16>()
+                .ok()
+                .filter(|code| (100..=599).contains(code))
+                .ok_or_else(invalid)
+        };
+
+        let (from, to) = if let Some(class) = value.strip_suffix("xx") {
+            let class = parse(&format!("{class}00"))?;
+            (class, class + 99)
+        } else if let Some((from, to)) = value.split_once('-') {
+            (parse(from)?, parse(to)?)
+        } else {
+            let code = parse(value)?;
+            (code, code)
+        };
+
+        if from > to {
+            return Err(invalid());
+        }
+        Ok(Self { from, to })
+    }
+}
+
+/// A condition on a request header, see [`MatchRules::if_header`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderCondition {
+    /// The header has to be present, `true` in config file
+    Present,
+    /// The header has to be absent, `false` in config file
+    Absent,
+    /// One of the header’s values has to be identical to the given value
+    Equals(String),
+}
+
+impl HeaderCondition {
+    /// Checks whether the condition is satisfied by the given request headers.
+    pub fn matches(&self, name: &HeaderName, headers: &HeaderMap) -> bool {
+        match self {
+            Self::Present => headers.contains_key(name),
+            Self::Absent => !headers.contains_key(name),
+            Self::Equals(expected) => headers
+                .get_all(name)
+                .iter()
+                .any(|value| value == expected.as_str()),
+        }
+    }
+}
 
 /// Include and exclude rules applying to a configuration entry
 ///
@@ -36,18 +237,164 @@ use std::fmt::Debug;
 ///   selected.
 /// * If a prefix rule like `example.com/dir/*` applies to this host/path combination, it applies
 ///   if all similar rules match a shorter path.
+/// * Wildcard host rules like `*.example.com/dir/*` apply only if no rule for the exact host
+///   name matches the host/path combination. Longer wildcard hosts like `*.www.example.com` are
+///   preferred over shorter ones.
 /// * Fallback rules like `/dir/*` apply only if no host-specific rule matches the host/path
 ///   combination. When multiple matching fallback rules exist, one is selected using the criteria
 ///   above.
+/// * Regular expression rules like `~\.map$` are only considered if none of the rules above
+///   match. If both include and exclude rules match, the exclude rule wins.
 ///
 /// The configuration entry is only applied to a host/path configuration if there is a matching
-/// rule and that rule is an include rule.
+/// rule and that rule is an include rule. Negated rules like `!example.com/dir/*` are exclude
+/// rules in the `include` list and include rules in the `exclude` list. If an include and an
+/// exclude rule are equally close, the exclude rule wins.
+///
+/// If `exclude_wins` is `true`, a matching exclude rule is honored even if an include rule is
+/// closer, unless some matching include rule has a higher priority or the same kind of host
+/// (fallback, wildcard or exact host name) and a more specific path, see
+/// [`HostPathMatcher::overrides_exclude`]. So with `include: example.com/*` and
+/// `exclude: /private/*`, `example.com/private/file.txt` is excluded whereas it would be included
+/// otherwise. An additional include rule `/private/public/*` still applies to
+/// `example.com/private/public/file.txt` however. Matching regular expression exclude rules
+/// always win in this mode.
+///
+/// If `methods` is non-empty, the configuration entry only applies to requests using one of the
+/// listed methods. This isn’t considered by the [`PathMatch`] implementation, the request method
+/// has to be checked separately via [`MatchRules::matches_method`].
+///
+/// Similarly, if `status` is non-empty, the configuration entry only applies to responses with
+/// one of the listed status codes, see [`MatchRules::matches_status`]. If `if_header` is
+/// non-empty, the configuration entry only applies to requests satisfying all header conditions,
+/// see [`MatchRules::matches_headers`].
 #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct MatchRules {
     /// Rules determining the locations where the configuration entry should apply
-    pub include: OneOrMany<HostPathMatcher>,
+    pub include: OneOrMany<RuleMatcher>,
     /// Rules determining the locations where the configuration entry should not apply
-    pub exclude: OneOrMany<HostPathMatcher>,
+    pub exclude: OneOrMany<RuleMatcher>,
+    /// If `true`, matching exclude rules win over closer include rules unless these are more
+    /// specific for the same kind of host
+    pub exclude_wins: bool,
+    /// Request methods the configuration entry should apply to, all methods if empty
+    #[pandora(deserialize_with = "deserialize_methods")]
+    pub methods: Vec<Method>,
+    /// Response status codes the configuration entry should apply to, all if empty
+    #[pandora(deserialize_with = "deserialize_status_matchers")]
+    pub status: Vec<StatusMatcher>,
+    /// Request headers the configuration entry depends on, no conditions if empty
+    #[pandora(deserialize_with = "deserialize_header_conditions")]
+    pub if_header: Vec<(HeaderName, HeaderCondition)>,
+}
+
+impl MatchRules {
+    /// Checks whether the configuration entry applies to requests with the given method.
+    pub fn matches_method(&self, method: &Method) -> bool {
+        self.methods.is_empty() || self.methods.contains(method)
+    }
+
+    /// Checks whether the configuration entry applies to responses with the given status code.
+    pub fn matches_status(&self, status: u16) -> bool {
+        self.status.is_empty() || self.status.iter().any(|matcher| matcher.matches(status))
+    }
+
+    /// Checks whether the configuration entry applies to requests with the given headers.
+    pub fn matches_headers(&self, headers: &HeaderMap) -> bool {
+        self.if_header
+            .iter()
+            .all(|(name, condition)| condition.matches(name, headers))
+    }
+
+    /// Checks whether any of the rules is a regular expression. Such rules cannot be resolved
+    /// ahead of time and have to be checked for each request.
+    pub fn has_regex(&self) -> bool {
+        self.include
+            .iter()
+            .chain(self.exclude.iter())
+            .any(|rule| matches!(rule, RuleMatcher::Regex(_)))
+    }
+
+    /// Checks whether any of the rules is a file name suffix rule like `/static/*.css`. Such
+    /// rules only apply to some paths within a directory and have to be checked for each request.
+    pub fn has_suffix(&self) -> bool {
+        self.include
+            .iter()
+            .chain(self.exclude.iter())
+            .any(|rule| matches!(rule, RuleMatcher::Path(matcher) if matcher.suffix.is_some()))
+    }
+
+    /// Describes the rule deciding whether the configuration entry applies to the given host/path
+    /// combination, for diagnostic purposes. Returns `None` if no rule matches.
+    pub fn explain(&self, host: &[u8], path: &Path) -> Option<String> {
+        if self.include.is_empty() && self.exclude.is_empty() {
+            return Some("included by default".to_owned());
+        }
+
+        let (result, index) = self.matches_explain(host, path, false);
+        let index = index?;
+        let list = if index < self.include.len() {
+            "include"
+        } else {
+            "exclude"
+        };
+        let outcome = if result.any() { "included" } else { "excluded" };
+        Some(format!("{outcome} by {list} rule `{}`", self.rule(index)?))
+    }
+
+    /// Retrieves a rule by its index, include rules are followed by exclude rules.
+    fn rule(&self, index: usize) -> Option<&RuleMatcher> {
+        if let Some(rule) = self.include.get(index) {
+            Some(rule)
+        } else {
+            self.exclude.get(index - self.include.len())
+        }
+    }
+
+    /// Finds a matching exclude rule that isn’t overridden by any matching include rule, as
+    /// relevant if `exclude_wins` is set.
+    fn honored_exclude(&self, host: &[u8], path: &Path, force_prefix: bool) -> Option<usize> {
+        let regex_path = format!("/{}", String::from_utf8_lossy(path));
+        let (includes, excludes): (Vec<_>, Vec<_>) = self
+            .rules()
+            .filter(|(_, rule, _)| match rule {
+                RuleMatcher::Path(matcher) => {
+                    let result = matcher.matches(host, path, force_prefix);
+                    if force_prefix {
+                        result.prefix()
+                    } else {
+                        result.any()
+                    }
+                }
+                RuleMatcher::Regex(matcher) => !force_prefix && matcher.regex.is_match(&regex_path),
+            })
+            .partition(|(_, _, include)| *include);
+
+        let overridden = |exclude: &HostPathMatcher| {
+            includes.iter().any(|(_, rule, _)| match rule {
+                RuleMatcher::Path(include) => include.overrides_exclude(exclude),
+                RuleMatcher::Regex(_) => false,
+            })
+        };
+        excludes
+            .into_iter()
+            .find(|(_, rule, _)| match rule {
+                RuleMatcher::Path(exclude) => !overridden(exclude),
+                RuleMatcher::Regex(_) => true,
+            })
+            .map(|(index, _, _)| index)
+    }
+
+    /// Iterates over all rules along with their index and whether they are include rules
+    fn rules(&self) -> impl Iterator<Item = (usize, &RuleMatcher, bool)> {
+        // Negated rules flip the meaning of the list they are in
+        self.include
+            .iter()
+            .map(|rule| (rule, !rule.negated()))
+            .chain(self.exclude.iter().map(|rule| (rule, rule.negated())))
+            .enumerate()
+            .map(|(index, (rule, include))| (index, rule, include))
+    }
 }
 
 impl PathMatch for MatchRules {
@@ -62,7 +409,11 @@ impl PathMatch for MatchRules {
                 self.include
                     .iter()
                     .chain(self.exclude.iter())
-                    .flat_map(|matcher| matcher.iter()),
+                    .flat_map(|rule| match rule {
+                        RuleMatcher::Path(matcher) => matcher.iter(),
+                        // Regular expressions cannot enumerate paths, treat them as fallback
+                        RuleMatcher::Regex(_) => HostPathMatcher::FALLBACK.iter(),
+                    }),
             )
         }
     }
@@ -73,80 +424,117 @@ impl PathMatch for MatchRules {
         path: &Path,
         force_prefix: bool,
     ) -> PathMatchResult<Self::SorterIndex> {
-        fn find_match<'a>(
-            rules: &'a [HostPathMatcher],
-            host: &[u8],
-            path: &Path,
-            force_prefix: bool,
-        ) -> (
-            PathMatchResult<<HostPathMatcher as PathMatch>::SorterIndex>,
-            usize,
-            Option<&'a HostPathMatcher>,
-        ) {
-            rules.iter().enumerate().fold(
-                (PathMatchResult::EMPTY, 0, None),
-                |(previous_result, previous_index, previous), (index, current)| {
-                    let result = current.matches(host, path, force_prefix);
-                    if result.any() {
-                        if previous.is_some_and(|previous| previous > current) {
-                            (previous_result, previous_index, previous)
-                        } else {
-                            (result, index, Some(current))
-                        }
-                    } else {
-                        (previous_result, previous_index, previous)
-                    }
-                },
-            )
+        self.matches_explain(host, path, force_prefix).0
+    }
+
+    fn sorter(&self, index: Self::SorterIndex) -> &Self::Sorter {
+        match self.rule(index) {
+            Some(RuleMatcher::Path(matcher)) => matcher,
+            Some(RuleMatcher::Regex(_)) | None => HostPathMatcher::FALLBACK,
         }
+    }
 
+    fn matches_explain(
+        &self,
+        host: &[u8],
+        path: &Path,
+        force_prefix: bool,
+    ) -> (
+        PathMatchResult<Self::SorterIndex>,
+        Option<Self::SorterIndex>,
+    ) {
         if self.include.is_empty() && self.exclude.is_empty() {
             // By default, this is a fallback rule matching everything
             let result = PathMatchResult::EMPTY.set_sorter(0);
 
-            return if path.is_empty() {
-                result.set_exact().set_prefix()
+            return if path.is_root() {
+                (result.set_exact().set_prefix(), None)
             } else {
-                result.set_prefix()
+                (result.set_prefix(), None)
             };
         }
 
-        let (_, _, exclude) = find_match(&self.exclude, host, path, force_prefix);
-        let (include_result, include_index, include) =
-            find_match(&self.include, host, path, force_prefix);
-
-        let mut result = PathMatchResult::EMPTY.set_sorter(include_index);
-        if include_result.exact() {
-            result = result.set_exact();
-        }
-        if include_result.prefix() {
-            result = result.set_prefix();
+        if self.exclude_wins {
+            if let Some(index) = self.honored_exclude(host, path, force_prefix) {
+                return (PathMatchResult::EMPTY, Some(index));
+            }
         }
 
-        if let Some(exclude) = exclude {
-            if include.is_some_and(|include| include > exclude) {
-                result
-            } else {
-                PathMatchResult::EMPTY
+        // If exclude rules win, any matching exclude rules are overridden at this point
+        let path_rules = self
+            .rules()
+            .filter(|(_, _, include)| *include || !self.exclude_wins)
+            .filter_map(|(index, rule, include)| match rule {
+                RuleMatcher::Path(matcher) => Some((index, matcher, include)),
+                RuleMatcher::Regex(_) => None,
+            })
+            .collect::<Vec<_>>();
+        let closest = closest_match(
+            path_rules
+                .iter()
+                .map(|(_, matcher, include)| (*matcher, *include)),
+            host,
+            path,
+            force_prefix,
+        );
+        match closest {
+            Some((index, true, include_result)) => {
+                let index = path_rules[index].0;
+                let mut result = PathMatchResult::EMPTY.set_sorter(index);
+                if include_result.exact() {
+                    result = result.set_exact();
+                }
+                if include_result.prefix() {
+                    result = result.set_prefix();
+                }
+                return (result, Some(index));
             }
-        } else if include_result.any() {
-            result
-        } else {
-            PathMatchResult::EMPTY
+            Some((index, false, _)) => {
+                return (PathMatchResult::EMPTY, Some(path_rules[index].0));
+            }
+            None if force_prefix => return (PathMatchResult::EMPTY, None),
+            None => {}
         }
-    }
 
-    fn sorter(&self, index: Self::SorterIndex) -> &Self::Sorter {
-        if self.include.is_empty() {
-            HostPathMatcher::FALLBACK
-        } else {
-            &self.include[index]
+        let path = format!("/{}", String::from_utf8_lossy(path));
+        let mut included = None;
+        for (index, rule, include) in self.rules() {
+            if let RuleMatcher::Regex(matcher) = rule {
+                if matcher.regex.is_match(&path) {
+                    if !include {
+                        return (PathMatchResult::EMPTY, Some(index));
+                    }
+                    included.get_or_insert(index);
+                }
+            }
+        }
+        match included {
+            Some(index) => (
+                PathMatchResult::EMPTY.set_sorter(index).set_exact(),
+                Some(index),
+            ),
+            None => (PathMatchResult::EMPTY, None),
         }
     }
 }
 
 pub(crate) type Header = (HeaderName, HeaderValue);
 
+/// Determines how the configured `Cache-Control` header interacts with a `Cache-Control` header
+/// already present in the response, e.g. one set by the upstream server
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheControlPrecedence {
+    /// The existing header is replaced by the configured one, `replace` in config file
+    #[default]
+    Replace,
+    /// Configured directives are added to the existing header, `merge` in config file
+    Merge,
+    /// The existing header is kept, configured header is only used if there is none, `defer` in
+    /// config file
+    Defer,
+}
+
 pub(crate) trait IntoHeaders {
     /// Merges two configurations, with conflicting settings from `other` being prioritized.
     fn merge_with(&mut self, other: &Self);
@@ -218,6 +606,11 @@ macro_rules! impl_conf {
             $into = $from.clone();
         }
     };
+    (merge($into:expr, $from:expr, Option<OneOrMany<$type:ty>>)) => {
+        if $from.is_some() {
+            $into = $from.clone();
+        }
+    };
     (merge($into:expr, $from:expr, Option<$type:ty>)) => {
         if $from.is_some() {
             $into = $from;
@@ -244,6 +637,12 @@ macro_rules! impl_conf {
             $list.push($header_name.into());
         }
     };
+    (doc($header_name:literal, cache_control Option<CacheControlPrecedence>)) => {
+        "Determines how the header interacts with an existing `Cache-Control` header"
+    };
+    (push($list:expr, $header_name:literal, $value:expr, cache_control Option<CacheControlPrecedence>)) => {
+        // Not a directive, handled separately
+    };
     (finalize($list:expr, cache_control)) => {
         vec![(
             header::CACHE_CONTROL,
@@ -276,6 +675,96 @@ macro_rules! impl_conf {
             HeaderValue::from_str(&$list.join("; ")).unwrap(),
         )]
     };
+
+    // Permissions-Policy types
+    (doc($header_name:literal, permissions Option<OneOrMany<String>>)) => {
+        concat!(
+            "If set, ", $header_name, " feature will be restricted to the listed origins, nothing \
+            allowed if the list is empty"
+        )
+    };
+    (push($list:expr, $header_name:literal, $value:expr, permissions Option<OneOrMany<String>>)) => {
+        if let Some(value) = $value {
+            $list.push(format!(concat!($header_name, "={}"), format_allowlist(&value)).into());
+        }
+    };
+    (finalize($list:expr, permissions)) => {
+        vec![(
+            HeaderName::from_static("permissions-policy"),
+            HeaderValue::from_str(&$list.join(", ")).unwrap(),
+        )]
+    };
+
+    // Strict-Transport-Security types
+    (doc($header_name:literal, hsts Option<usize>)) => {
+        concat!(
+            "If set, ", $header_name, " directive will be sent. The header is only sent if this is set."
+        )
+    };
+    (doc($header_name:literal, hsts bool)) => {
+        concat!("If `true`, ", $header_name, " directive will be sent")
+    };
+    (push($list:expr, $header_name:literal, $value:expr, hsts Option<usize>)) => {
+        if let Some(value) = $value {
+            $list.push(format!(concat!($header_name, "={}"), value).into());
+        }
+    };
+    (push($list:expr, $header_name:literal, $value:expr, hsts bool)) => {
+        if $value {
+            $list.push($header_name.into());
+        }
+    };
+    // Cross-Origin-*-Policy types
+    (doc($header_name:literal, cross_origin Option<CrossOriginPolicy>)) => {
+        concat!("If set, `Cross-Origin-", $header_name, "` header will be sent")
+    };
+    (push($list:expr, $header_name:literal, $value:expr, cross_origin Option<CrossOriginPolicy>)) => {
+        if let Some(value) = $value {
+            $list.push(format!(concat!("cross-origin-", $header_name, ": {}"), value.as_str()).into());
+        }
+    };
+    (finalize($list:expr, cross_origin)) => {
+        // Each entry is a separate header
+        $list
+            .iter()
+            .filter_map(|entry| entry.split_once(": "))
+            .filter(|(name, value)| {
+                let valid = CrossOriginPolicy::is_valid_for(name, value);
+                if !valid {
+                    warn!("`{value}` is not a valid value for the {name} header, not sending it");
+                }
+                valid
+            })
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    HeaderValue::from_str(value).unwrap(),
+                )
+            })
+            .collect()
+    };
+
+    (finalize($list:expr, hsts)) => {
+        if !$list.iter().any(|entry| entry.starts_with("max-age=")) {
+            warn!("Strict-Transport-Security header without max-age, not sending it");
+            Vec::new()
+        } else {
+            if $list.iter().any(|entry| *entry == "preload")
+                && !$list.iter().any(|entry| *entry == "includeSubDomains")
+            {
+                // https://hstspreload.org/#deployment-recommendations
+                warn!(
+                    "Strict-Transport-Security preload directive requires includeSubDomains, \
+                    ignoring it"
+                );
+                $list.retain(|entry| *entry != "preload");
+            }
+            vec![(
+                header::STRICT_TRANSPORT_SECURITY,
+                HeaderValue::from_str(&$list.join("; ")).unwrap(),
+            )]
+        }
+    };
 }
 
 impl_conf! {cache_control:
@@ -294,6 +783,7 @@ impl_conf! {cache_control:
         immutable("immutable", bool),
         stale_while_revalidate("stale-while-revalidate", Option<usize>),
         stale_if_error("stale-if-error", Option<usize>),
+        precedence("precedence", Option<CacheControlPrecedence>),
     }
 }
 
@@ -329,6 +819,139 @@ impl_conf! {csp:
     }
 }
 
+impl_conf! {hsts:
+    /// Configuration for the Strict-Transport-Security header
+    pub struct StrictTransportSecurityConf {
+        max_age("max-age", Option<usize>),
+        include_subdomains("includeSubDomains", bool),
+        preload("preload", bool),
+    }
+}
+
+/// A keyword for the Cross-Origin-Opener-Policy, Cross-Origin-Embedder-Policy and
+/// Cross-Origin-Resource-Policy headers like `same-origin`
+///
+/// Only known keywords are accepted when deserializing. Keywords not valid for a particular
+/// header are rejected when the header is produced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrossOriginPolicy(pub(crate) &'static str);
+
+impl CrossOriginPolicy {
+    /// Keywords valid for the Cross-Origin-Opener-Policy header
+    pub const OPENER: &'static [&'static str] = &[
+        "unsafe-none",
+        "same-origin-allow-popups",
+        "same-origin",
+        "noopener-allow-popups",
+    ];
+
+    /// Keywords valid for the Cross-Origin-Embedder-Policy header
+    pub const EMBEDDER: &'static [&'static str] =
+        &["unsafe-none", "require-corp", "credentialless"];
+
+    /// Keywords valid for the Cross-Origin-Resource-Policy header
+    pub const RESOURCE: &'static [&'static str] = &["same-site", "same-origin", "cross-origin"];
+
+    /// Returns the keyword
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+
+    /// Checks whether a keyword is valid for the given header
+    fn is_valid_for(header: &str, value: &str) -> bool {
+        let known = match header {
+            "cross-origin-opener-policy" => Self::OPENER,
+            "cross-origin-embedder-policy" => Self::EMBEDDER,
+            "cross-origin-resource-policy" => Self::RESOURCE,
+            _ => &[],
+        };
+        known.contains(&value)
+    }
+}
+
+impl TryFrom<&str> for CrossOriginPolicy {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::OPENER
+            .iter()
+            .chain(Self::EMBEDDER)
+            .chain(Self::RESOURCE)
+            .find(|known| known.eq_ignore_ascii_case(value))
+            .map(|known| Self(*known))
+            .ok_or(())
+    }
+}
+
+impl_conf! {cross_origin:
+    /// Configuration for the Cross-Origin-Opener-Policy, Cross-Origin-Embedder-Policy and
+    /// Cross-Origin-Resource-Policy headers
+    pub struct CrossOriginConf {
+        opener_policy("opener-policy", Option<CrossOriginPolicy>),
+        embedder_policy("embedder-policy", Option<CrossOriginPolicy>),
+        resource_policy("resource-policy", Option<CrossOriginPolicy>),
+    }
+}
+
+/// Formats a Permissions-Policy allowlist like `(self "https://example.com")`, keywords stay
+/// unquoted. A single `*` entry is kept as is.
+fn format_allowlist(origins: &[String]) -> String {
+    if let [origin] = origins {
+        if origin == "*" {
+            return origin.clone();
+        }
+    }
+
+    let origins = origins
+        .iter()
+        .map(|origin| {
+            if matches!(origin.as_str(), "self" | "src" | "*") {
+                origin.clone()
+            } else {
+                format!("\"{}\"", origin.replace(['"', '\\'], ""))
+            }
+        })
+        .collect::<Vec<_>>();
+    format!("({})", origins.join(" "))
+}
+
+impl_conf! {permissions:
+    /// Configuration for the Permissions-Policy header
+    pub struct PermissionsPolicyConf {
+        accelerometer("accelerometer", Option<OneOrMany<String>>),
+        ambient_light_sensor("ambient-light-sensor", Option<OneOrMany<String>>),
+        autoplay("autoplay", Option<OneOrMany<String>>),
+        battery("battery", Option<OneOrMany<String>>),
+        bluetooth("bluetooth", Option<OneOrMany<String>>),
+        camera("camera", Option<OneOrMany<String>>),
+        display_capture("display-capture", Option<OneOrMany<String>>),
+        encrypted_media("encrypted-media", Option<OneOrMany<String>>),
+        fullscreen("fullscreen", Option<OneOrMany<String>>),
+        gamepad("gamepad", Option<OneOrMany<String>>),
+        geolocation("geolocation", Option<OneOrMany<String>>),
+        gyroscope("gyroscope", Option<OneOrMany<String>>),
+        hid("hid", Option<OneOrMany<String>>),
+        identity_credentials_get("identity-credentials-get", Option<OneOrMany<String>>),
+        idle_detection("idle-detection", Option<OneOrMany<String>>),
+        local_fonts("local-fonts", Option<OneOrMany<String>>),
+        magnetometer("magnetometer", Option<OneOrMany<String>>),
+        microphone("microphone", Option<OneOrMany<String>>),
+        midi("midi", Option<OneOrMany<String>>),
+        otp_credentials("otp-credentials", Option<OneOrMany<String>>),
+        payment("payment", Option<OneOrMany<String>>),
+        picture_in_picture("picture-in-picture", Option<OneOrMany<String>>),
+        publickey_credentials_create("publickey-credentials-create", Option<OneOrMany<String>>),
+        publickey_credentials_get("publickey-credentials-get", Option<OneOrMany<String>>),
+        screen_wake_lock("screen-wake-lock", Option<OneOrMany<String>>),
+        serial("serial", Option<OneOrMany<String>>),
+        storage_access("storage-access", Option<OneOrMany<String>>),
+        usb("usb", Option<OneOrMany<String>>),
+        web_share("web-share", Option<OneOrMany<String>>),
+        window_management("window-management", Option<OneOrMany<String>>),
+        xr_spatial_tracking("xr-spatial-tracking", Option<OneOrMany<String>>),
+    }
+}
+
 /// Custom headers configuration
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct CustomHeadersConf {
@@ -351,6 +974,173 @@ impl IntoHeaders for CustomHeadersConf {
     }
 }
 
+/// Configuration for headers to be removed from the response
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct RemoveHeadersConf {
+    /// Names of the headers to be removed
+    #[pandora(deserialize_with = "deserialize_header_names")]
+    pub headers: Vec<HeaderName>,
+}
+
+/// A client hint token like `Sec-CH-UA-Mobile`, see
+/// <https://developer.mozilla.org/en-US/docs/Web/HTTP/Client_hints>
+///
+/// Only known client hints are accepted when deserializing, the token is normalized to its
+/// canonical spelling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientHint(pub(crate) &'static str);
+
+impl ClientHint {
+    /// List of all client hints recognized
+    pub const KNOWN: &'static [&'static str] = &[
+        "Sec-CH-UA",
+        "Sec-CH-UA-Arch",
+        "Sec-CH-UA-Bitness",
+        "Sec-CH-UA-Form-Factors",
+        "Sec-CH-UA-Full-Version",
+        "Sec-CH-UA-Full-Version-List",
+        "Sec-CH-UA-Mobile",
+        "Sec-CH-UA-Model",
+        "Sec-CH-UA-Platform",
+        "Sec-CH-UA-Platform-Version",
+        "Sec-CH-UA-WoW64",
+        "Sec-CH-Prefers-Color-Scheme",
+        "Sec-CH-Prefers-Reduced-Motion",
+        "Sec-CH-Prefers-Reduced-Transparency",
+        "Sec-CH-DPR",
+        "Sec-CH-Width",
+        "Sec-CH-Viewport-Width",
+        "Sec-CH-Viewport-Height",
+        "Sec-CH-Device-Memory",
+        "Device-Memory",
+        "DPR",
+        "Width",
+        "Viewport-Width",
+        "Downlink",
+        "ECT",
+        "RTT",
+        "Save-Data",
+    ];
+
+    /// Returns the canonical name of the client hint
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
+impl TryFrom<&str> for ClientHint {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::KNOWN
+            .iter()
+            .find(|known| known.eq_ignore_ascii_case(value))
+            .map(|known| Self(*known))
+            .ok_or(())
+    }
+}
+
+/// Configuration for the Deprecation and Sunset headers marking deprecated resources
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct DeprecationConf {
+    /// Date when the resource was or will be deprecated, sent as the Deprecation header
+    /// (RFC 9745)
+    #[pandora(deserialize_with = "deserialize_http_date")]
+    pub deprecation: Option<SystemTime>,
+
+    /// Date when the resource is expected to become unavailable, sent as the Sunset header
+    /// (RFC 8594)
+    #[pandora(deserialize_with = "deserialize_http_date")]
+    pub sunset: Option<SystemTime>,
+}
+
+impl IntoHeaders for DeprecationConf {
+    fn merge_with(&mut self, other: &Self) {
+        if other.deprecation.is_some() {
+            self.deprecation = other.deprecation;
+        }
+        if other.sunset.is_some() {
+            self.sunset = other.sunset;
+        }
+    }
+
+    fn into_headers(self) -> Vec<Header> {
+        let mut headers = Vec::new();
+        if let Some(deprecation) = self.deprecation {
+            // Deprecation header uses the structured field date format
+            let seconds = deprecation
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs());
+            headers.push((
+                HeaderName::from_static("deprecation"),
+                HeaderValue::from_str(&format!("@{seconds}")).unwrap(),
+            ));
+        }
+        if let Some(sunset) = self.sunset {
+            headers.push((
+                HeaderName::from_static("sunset"),
+                HeaderValue::from_str(&httpdate::fmt_http_date(sunset)).unwrap(),
+            ));
+        }
+        headers
+    }
+}
+
+/// Configuration for the Accept-CH and Critical-CH headers
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct ClientHintsConf {
+    /// Client hints to be requested via the Accept-CH header
+    #[pandora(rename = "accept-ch")]
+    pub accept_ch: OneOrMany<ClientHint>,
+
+    /// Client hints to be requested via the Critical-CH header, these are automatically added to
+    /// the Accept-CH header as well
+    #[pandora(rename = "critical-ch")]
+    pub critical_ch: OneOrMany<ClientHint>,
+}
+
+impl IntoHeaders for ClientHintsConf {
+    fn merge_with(&mut self, other: &Self) {
+        for (list, other_list) in [
+            (&mut self.accept_ch, &other.accept_ch),
+            (&mut self.critical_ch, &other.critical_ch),
+        ] {
+            for hint in other_list.iter() {
+                if !list.contains(hint) {
+                    list.push(*hint);
+                }
+            }
+        }
+    }
+
+    fn into_headers(mut self) -> Vec<Header> {
+        for hint in self.critical_ch.iter() {
+            if !self.accept_ch.contains(hint) {
+                self.accept_ch.push(*hint);
+            }
+        }
+
+        let mut headers = Vec::new();
+        for (name, list) in [
+            ("accept-ch", self.accept_ch),
+            ("critical-ch", self.critical_ch),
+        ] {
+            if !list.is_empty() {
+                let value = list
+                    .iter()
+                    .map(ClientHint::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                headers.push((
+                    HeaderName::from_static(name),
+                    HeaderValue::from_str(&value).unwrap(),
+                ));
+            }
+        }
+        headers
+    }
+}
+
 /// Various settings to configure HTTP response headers
 #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct HeadersInnerConf {
@@ -360,8 +1150,30 @@ pub struct HeadersInnerConf {
     /// Content-Security-Policy header
     pub content_security_policy: OneOrMany<WithMatchRules<ContentSecurityPolicyConf>>,
 
+    /// Permissions-Policy header
+    pub permissions_policy: OneOrMany<WithMatchRules<PermissionsPolicyConf>>,
+
+    /// Strict-Transport-Security header
+    pub strict_transport_security: OneOrMany<WithMatchRules<StrictTransportSecurityConf>>,
+
+    /// Accept-CH and Critical-CH headers
+    pub client_hints: OneOrMany<WithMatchRules<ClientHintsConf>>,
+
+    /// Cross-Origin-Opener-Policy, Cross-Origin-Embedder-Policy and Cross-Origin-Resource-Policy
+    /// headers
+    pub cross_origin: OneOrMany<WithMatchRules<CrossOriginConf>>,
+
+    /// Deprecation and Sunset headers
+    pub deprecation: OneOrMany<WithMatchRules<DeprecationConf>>,
+
     /// Custom headers, headers configures as name => value map here
     pub custom: OneOrMany<WithMatchRules<CustomHeadersConf>>,
+
+    /// Custom headers to be sent only with the first response on each connection
+    pub first_response: CustomHeadersConf,
+
+    /// Headers to be removed from the response, applied after all other rules
+    pub remove: OneOrMany<WithMatchRules<RemoveHeadersConf>>,
 }
 
 /// Configuration file settings of the headers module
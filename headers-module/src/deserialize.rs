@@ -15,13 +15,321 @@
 //! Custom deserialization code for the configuration
 
 use http::header::{HeaderName, HeaderValue};
-use pandora_module_utils::{DeserializeMap, MapVisitor};
+use http::Method;
+use pandora_module_utils::{DeserializeMap, MapVisitor, OneOrMany};
 use serde::de::{
-    Deserialize, DeserializeSeed, Deserializer, Error as _, MapAccess, Unexpected, Visitor,
+    Deserialize, DeserializeSeed, Deserializer, Error as _, MapAccess, SeqAccess, Unexpected,
+    Visitor,
 };
 use std::collections::HashMap;
+use std::time::SystemTime;
 
-use crate::configuration::CustomHeadersConf;
+use crate::configuration::{
+    ClientHint, CrossOriginPolicy, CustomHeadersConf, HeaderCondition, RuleMatcher, StatusMatcher,
+};
+use crate::template;
+
+impl<'de> Deserialize<'de> for ClientHint {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        ClientHint::try_from(value.as_str())
+            .map_err(|_| D::Error::invalid_value(Unexpected::Str(&value), &"known client hint"))
+    }
+}
+
+impl<'de> Deserialize<'de> for HeaderCondition {
+    /// Deserializes a header condition: `true` if the header has to be present, `false` if it has
+    /// to be absent, anything else is the expected header value.
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct VisitorImpl;
+
+        impl<'de> Visitor<'de> for VisitorImpl {
+            type Value = HeaderCondition;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                formatter.write_str("boolean or header value")
+            }
+
+            fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(if v {
+                    HeaderCondition::Present
+                } else {
+                    HeaderCondition::Absent
+                })
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(HeaderCondition::Equals(v.to_owned()))
+            }
+        }
+
+        deserializer.deserialize_any(VisitorImpl)
+    }
+}
+
+impl<'de> Deserialize<'de> for RuleMatcher {
+    /// Accepts either a rule string or a map like `{path: "/static/*", priority: 10}`. Priority
+    /// can only be set for host/path rules, not for regular expressions.
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct VisitorImpl;
+
+        impl<'de> Visitor<'de> for VisitorImpl {
+            type Value = RuleMatcher;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                formatter.write_str("rule string or map with `path` and `priority` keys")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                let mut path = None;
+                let mut priority = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "path" => path = Some(map.next_value::<String>()?),
+                        "priority" => priority = Some(map.next_value()?),
+                        _ => {
+                            return Err(A::Error::unknown_field(&key, &["path", "priority"]));
+                        }
+                    }
+                }
+
+                let path = path.ok_or_else(|| A::Error::missing_field("path"))?;
+                match path.parse().map_err(A::Error::custom)? {
+                    RuleMatcher::Path(mut matcher) => {
+                        matcher.priority = priority.unwrap_or(0);
+                        Ok(RuleMatcher::Path(matcher))
+                    }
+                    RuleMatcher::Regex(_) if priority.is_some() => Err(A::Error::custom(format!(
+                        "priority cannot be set for regular expression rule `{path}`"
+                    ))),
+                    rule => Ok(rule),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(VisitorImpl)
+    }
+}
+
+impl<'de> Deserialize<'de> for CrossOriginPolicy {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        CrossOriginPolicy::try_from(value.as_str()).map_err(|_| {
+            D::Error::invalid_value(Unexpected::Str(&value), &"known cross-origin policy")
+        })
+    }
+}
+
+/// Deserializes a header name or a list of header names, rejecting invalid names.
+pub(crate) fn deserialize_header_names<'de, D>(deserializer: D) -> Result<Vec<HeaderName>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    OneOrMany::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|name| {
+            HeaderName::try_from(name.as_str())
+                .map_err(|_| D::Error::invalid_value(Unexpected::Str(name), &"header name"))
+        })
+        .collect()
+}
+
+/// Deserializes a request method or a list of request methods. Method names are
+/// case-insensitive, only standard methods like `GET` are accepted.
+pub(crate) fn deserialize_methods<'de, D>(deserializer: D) -> Result<Vec<Method>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    const KNOWN: &[Method] = &[
+        Method::GET,
+        Method::HEAD,
+        Method::POST,
+        Method::PUT,
+        Method::DELETE,
+        Method::CONNECT,
+        Method::OPTIONS,
+        Method::TRACE,
+        Method::PATCH,
+    ];
+
+    OneOrMany::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|name| {
+            KNOWN
+                .iter()
+                .find(|method| method.as_str().eq_ignore_ascii_case(name))
+                .cloned()
+                .ok_or_else(|| D::Error::invalid_value(Unexpected::Str(name), &"request method"))
+        })
+        .collect()
+}
+
+/// Deserializes a status code matcher or a list of matchers. Matchers can be numbers like `200` or
+/// strings like `200-299` or `2xx`.
+pub(crate) fn deserialize_status_matchers<'de, D>(
+    deserializer: D,
+) -> Result<Vec<StatusMatcher>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct VisitorImpl {
+        // List entries cannot be lists themselves
+        list_allowed: bool,
+    }
+
+    impl<'de> DeserializeSeed<'de> for VisitorImpl {
+        type Value = Vec<StatusMatcher>;
+
+        fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_any(self)
+        }
+    }
+
+    impl<'de> Visitor<'de> for VisitorImpl {
+        type Value = Vec<StatusMatcher>;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            formatter.write_str("status code matcher like 200, 200-299 or 2xx")
+        }
+
+        fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            self.visit_str(&v.to_string())
+        }
+
+        fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            self.visit_str(&v.to_string())
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Ok(vec![v.parse().map_err(E::custom)?])
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            if !self.list_allowed {
+                return Err(A::Error::invalid_type(Unexpected::Seq, &self));
+            }
+
+            let mut result = Vec::new();
+            while let Some(matchers) = seq.next_element_seed(VisitorImpl {
+                list_allowed: false,
+            })? {
+                result.extend(matchers);
+            }
+            Ok(result)
+        }
+    }
+
+    VisitorImpl { list_allowed: true }.deserialize(deserializer)
+}
+
+/// Deserializes a map of request header names to conditions.
+pub(crate) fn deserialize_header_conditions<'de, D>(
+    deserializer: D,
+) -> Result<Vec<(HeaderName, HeaderCondition)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct VisitorImpl;
+
+    impl<'de> Visitor<'de> for VisitorImpl {
+        type Value = Vec<(HeaderName, HeaderCondition)>;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            formatter.write_str("map of header names to conditions")
+        }
+
+        fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+        where
+            A: MapAccess<'de>,
+        {
+            let mut result = Vec::new();
+            while let Some(name) = map.next_key::<String>()? {
+                let name = HeaderName::try_from(name.as_str())
+                    .map_err(|_| A::Error::invalid_value(Unexpected::Str(&name), &"header name"))?;
+                result.push((name, map.next_value()?));
+            }
+            Ok(result)
+        }
+    }
+
+    deserializer.deserialize_map(VisitorImpl)
+}
+
+/// Deserializes a date in the IMF-fixdate format like `Sun, 06 Nov 1994 08:49:37 GMT`, other
+/// HTTP date formats are rejected.
+pub(crate) fn deserialize_http_date<'de, D>(deserializer: D) -> Result<Option<SystemTime>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    httpdate::parse_http_date(&value)
+        .ok()
+        .filter(|date| httpdate::fmt_http_date(*date) == value)
+        .map(Some)
+        .ok_or_else(|| {
+            D::Error::invalid_value(
+                Unexpected::Str(&value),
+                &"date like `Sun, 06 Nov 1994 08:49:37 GMT`",
+            )
+        })
+}
 
 impl<'de> DeserializeSeed<'de> for CustomHeadersConf {
     type Value = Self;
@@ -116,6 +424,7 @@ impl<'de> MapVisitor<'de> for CustomHeadersVisitor {
         let name = HeaderName::try_from(field)
             .map_err(|_| D::Error::invalid_value(Unexpected::Str(field), &"header name"))?;
         let value = String::deserialize(deserializer)?;
+        let value = template::prepare(&value).map_err(D::Error::custom)?;
         let value = HeaderValue::try_from(&value)
             .map_err(|_| D::Error::invalid_value(Unexpected::Str(&value), &"header value"))?;
         self.headers.insert(name, value);
@@ -134,11 +443,46 @@ impl<'de> MapVisitor<'de> for CustomHeadersVisitor {
 
 #[cfg(test)]
 mod tests {
-    use crate::configuration::{MatchRules, WithMatchRules};
+    use crate::configuration::{MatchRules, RuleMatcher, WithMatchRules};
 
     use super::*;
 
-    use pandora_module_utils::{merger::HostPathMatcher, FromYaml, OneOrMany};
+    use pandora_module_utils::{FromYaml, OneOrMany};
+
+    #[test]
+    fn client_hints_deserialization() {
+        use crate::configuration::{ClientHint, ClientHintsConf};
+
+        #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+        struct DummyConf {
+            inner: ClientHintsConf,
+        }
+
+        assert_eq!(
+            DummyConf::from_yaml(
+                r#"
+                    inner:
+                        accept-ch: [sec-ch-ua-mobile, DPR]
+                        critical-ch: save-data
+                "#
+            )
+            .unwrap(),
+            DummyConf {
+                inner: ClientHintsConf {
+                    accept_ch: vec![ClientHint("Sec-CH-UA-Mobile"), ClientHint("DPR")].into(),
+                    critical_ch: vec![ClientHint("Save-Data")].into(),
+                }
+            }
+        );
+
+        DummyConf::from_yaml(
+            r#"
+                inner:
+                    accept-ch: [Sec-CH-UA-Mobile, Sec-CH-Unknown]
+            "#,
+        )
+        .expect_err("unknown client hint should be rejected");
+    }
 
     #[test]
     fn custom_headers_deserialization() {
@@ -183,7 +527,7 @@ mod tests {
             DummyConf {
                 inner: vec![WithMatchRules {
                     match_rules: MatchRules {
-                        include: vec![HostPathMatcher::from("/*")].into(),
+                        include: vec![RuleMatcher::from("/*")].into(),
                         ..Default::default()
                     },
                     conf: CustomHeadersConf {
@@ -211,7 +555,7 @@ mod tests {
             DummyConf {
                 inner: vec![WithMatchRules {
                     match_rules: MatchRules {
-                        include: vec![HostPathMatcher::from("/*")].into(),
+                        include: vec![RuleMatcher::from("/*")].into(),
                         ..Default::default()
                     },
                     conf: CustomHeadersConf {
@@ -252,7 +596,7 @@ mod tests {
                     },
                     WithMatchRules {
                         match_rules: MatchRules {
-                            include: vec![HostPathMatcher::from("/*")].into(),
+                            include: vec![RuleMatcher::from("/*")].into(),
                             ..Default::default()
                         },
                         conf: CustomHeadersConf {
@@ -267,4 +611,54 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn cache_control_single_or_list() {
+        use crate::configuration::CacheControlConf;
+
+        #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+        struct DummyConf {
+            inner: OneOrMany<WithMatchRules<CacheControlConf>>,
+        }
+
+        let expected = DummyConf {
+            inner: vec![WithMatchRules {
+                match_rules: MatchRules {
+                    include: vec![RuleMatcher::from("example.com")].into(),
+                    ..Default::default()
+                },
+                conf: CacheControlConf {
+                    max_age: Some(3600),
+                    public: true,
+                    ..Default::default()
+                },
+            }]
+            .into(),
+        };
+
+        assert_eq!(
+            DummyConf::from_yaml(
+                r#"
+                    inner:
+                        include: example.com
+                        max-age: 3600
+                        public: true
+                "#
+            )
+            .unwrap(),
+            expected
+        );
+        assert_eq!(
+            DummyConf::from_yaml(
+                r#"
+                    inner:
+                    -   include: [example.com]
+                        max-age: 3600
+                        public: true
+                "#
+            )
+            .unwrap(),
+            expected
+        );
+    }
 }
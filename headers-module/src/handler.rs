@@ -13,26 +13,150 @@
 // limitations under the License.
 
 use async_trait::async_trait;
-use http::{HeaderName, HeaderValue};
-use log::trace;
-use pandora_module_utils::merger::{Merger, StrictHostPathMatcher};
+use http::{header, HeaderMap, HeaderName, HeaderValue, Method};
+use log::{log_enabled, trace, Level};
+use pandora_module_utils::merger::{HostPathMatcher, Merger, PathMatch, StrictHostPathMatcher};
 use pandora_module_utils::pingora::{
-    Error, HttpModule, HttpModuleBuilder, HttpModules, ResponseHeader, SessionWrapper,
+    Error, HttpModule, HttpModuleBuilder, HttpModules, ResponseHeader, SessionWrapper, SocketDigest,
 };
-use pandora_module_utils::router::Router;
-use pandora_module_utils::{OneOrMany, RequestFilter, RequestFilterResult};
+use pandora_module_utils::router::{Path, Router};
+use pandora_module_utils::{RequestFilter, RequestFilterResult};
 use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 
-use crate::configuration::{Header, HeadersConf, IntoHeaders, WithMatchRules};
+use crate::configuration::{
+    CacheControlConf, CacheControlPrecedence, Header, HeadersConf, HeadersInnerConf, IntoHeaders,
+    MatchRules, RemoveHeadersConf, WithMatchRules,
+};
+use crate::template::{has_placeholders, substitute, TemplateContext};
+
+/// A rule that cannot be merged ahead of time: rules with regular expressions or request header
+/// conditions in their match rules are checked for each request, rules restricted to particular
+/// status codes for each response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ConditionalRule {
+    match_rules: MatchRules,
+    headers: Vec<Header>,
+    remove: Vec<HeaderName>,
+    cache_control_precedence: Option<CacheControlPrecedence>,
+}
+
+impl ConditionalRule {
+    fn new(match_rules: MatchRules) -> Self {
+        Self {
+            match_rules,
+            headers: Vec::new(),
+            remove: Vec::new(),
+            cache_control_precedence: None,
+        }
+    }
+
+    /// Checks whether the match rules cannot be merged ahead of time.
+    fn is_conditional(match_rules: &MatchRules) -> bool {
+        match_rules.has_regex()
+            || match_rules.has_suffix()
+            || !match_rules.status.is_empty()
+            || !match_rules.if_header.is_empty()
+    }
+
+    fn matches(&self, host: &[u8], path: &Path, headers: &HeaderMap) -> bool {
+        self.match_rules.matches_headers(headers)
+            && self.match_rules.matches(host, path, false).any()
+    }
+
+    /// Applies the rule on top of the merged configuration.
+    fn apply(
+        &self,
+        headers: &mut Option<Vec<Header>>,
+        remove: &mut Vec<HeaderName>,
+        cache_control_precedence: &mut Option<CacheControlPrecedence>,
+    ) {
+        if !self.headers.is_empty() {
+            // Headers set by conditional rules replace any previous values
+            let list = headers.get_or_insert_with(Vec::new);
+            list.retain(|(name, _)| !self.headers.iter().any(|(n, _)| n == name));
+            list.extend_from_slice(&self.headers);
+        }
+        for name in &self.remove {
+            if !remove.contains(name) {
+                remove.push(name.clone());
+            }
+        }
+        if self.cache_control_precedence.is_some() {
+            *cache_control_precedence = self.cache_control_precedence;
+        }
+    }
+}
+
+/// A rule merged ahead of time, kept to explain the merged headers in diagnostic output
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RuleSource {
+    name: &'static str,
+    match_rules: MatchRules,
+    headers: Vec<Header>,
+}
+
+/// Selects the rules applying to the given request method. If `method` is `None`, only rules
+/// without a method restriction are selected.
+fn for_method<'a, C>(
+    rules: &'a [WithMatchRules<C>],
+    method: Option<&'a Method>,
+) -> impl Iterator<Item = &'a WithMatchRules<C>>
+where
+    C: Default + Clone + Eq,
+{
+    rules.iter().filter(move |rule| match method {
+        Some(method) => rule.match_rules.matches_method(method),
+        None => rule.match_rules.methods.is_empty(),
+    })
+}
 
-fn merge_rules<C>(rules: OneOrMany<WithMatchRules<C>>) -> Merger<StrictHostPathMatcher, Vec<Header>>
+fn merge_rules<C>(
+    name: &'static str,
+    rules: &[WithMatchRules<C>],
+    method: Option<&Method>,
+    conditional_rules: &mut Vec<ConditionalRule>,
+    sources: &mut Vec<RuleSource>,
+) -> Merger<StrictHostPathMatcher, Vec<Header>>
 where
     C: Default + Clone + Eq + IntoHeaders,
 {
     let mut merger = Merger::new();
-    for rule in rules {
-        merger.push(rule.match_rules, rule.conf);
+    for rule in for_method(rules, method) {
+        if ConditionalRule::is_conditional(&rule.match_rules) {
+            conditional_rules.push(ConditionalRule {
+                headers: rule.conf.clone().into_headers(),
+                ..ConditionalRule::new(rule.match_rules.clone())
+            });
+        } else {
+            sources.push(RuleSource {
+                name,
+                match_rules: rule.match_rules.clone(),
+                headers: rule.conf.clone().into_headers(),
+            });
+            merger.push(rule.match_rules.clone(), rule.conf.clone());
+        }
     }
+
+    if log_enabled!(Level::Trace) {
+        let describe = |list: Vec<(&HostPathMatcher, &C)>| {
+            list.into_iter()
+                .map(|(rule, conf)| (rule, conf.clone().into_headers()))
+                .collect::<Vec<_>>()
+        };
+        for entry in merger.explain() {
+            trace!(
+                "{name} headers for {}/{:?} by rule: {:?}, for paths within: {:?}",
+                String::from_utf8_lossy(entry.host),
+                entry.path,
+                describe(entry.exact),
+                describe(entry.prefix)
+            );
+        }
+    }
+
     merger.merge_into_merger(|values| {
         let mut result = C::default();
         for conf in values {
@@ -42,6 +166,56 @@ where
     })
 }
 
+fn merge_precedence(
+    rules: &[WithMatchRules<CacheControlConf>],
+    method: Option<&Method>,
+    conditional_rules: &mut Vec<ConditionalRule>,
+) -> Result<Router<Option<CacheControlPrecedence>>, Box<Error>> {
+    let mut merger = Merger::new();
+    for rule in for_method(rules, method) {
+        if ConditionalRule::is_conditional(&rule.match_rules) {
+            if rule.conf.precedence.is_some() {
+                conditional_rules.push(ConditionalRule {
+                    cache_control_precedence: rule.conf.precedence,
+                    ..ConditionalRule::new(rule.match_rules.clone())
+                });
+            }
+        } else {
+            merger.push(rule.match_rules.clone(), rule.conf.precedence);
+        }
+    }
+    merger.merge(|values| values.fold(None, |result, value| value.or(result)))
+}
+
+fn merge_removals(
+    rules: &[WithMatchRules<RemoveHeadersConf>],
+    method: Option<&Method>,
+    conditional_rules: &mut Vec<ConditionalRule>,
+) -> Result<Router<Vec<HeaderName>>, Box<Error>> {
+    let mut merger = Merger::new();
+    for rule in for_method(rules, method) {
+        if ConditionalRule::is_conditional(&rule.match_rules) {
+            conditional_rules.push(ConditionalRule {
+                remove: rule.conf.headers.clone(),
+                ..ConditionalRule::new(rule.match_rules.clone())
+            });
+        } else {
+            merger.push(rule.match_rules.clone(), rule.conf.headers.clone());
+        }
+    }
+    merger.merge(|values| {
+        let mut result = Vec::new();
+        for names in values {
+            for name in names {
+                if !result.contains(name) {
+                    result.push(name.clone());
+                }
+            }
+        }
+        result
+    })
+}
+
 struct HeadersHttpModuleBuilder {}
 
 impl HttpModuleBuilder for HeadersHttpModuleBuilder {
@@ -52,11 +226,19 @@ impl HttpModuleBuilder for HeadersHttpModuleBuilder {
 
 struct HeadersHttpModule {
     headers: Option<Vec<Header>>,
+    cache_control_precedence: Option<CacheControlPrecedence>,
+    remove: Vec<HeaderName>,
+    status_rules: Vec<ConditionalRule>,
 }
 
 impl HeadersHttpModule {
     fn new() -> Self {
-        Self { headers: None }
+        Self {
+            headers: None,
+            cache_control_precedence: None,
+            remove: Vec::new(),
+            status_rules: Vec::new(),
+        }
     }
 }
 
@@ -75,32 +257,196 @@ impl HttpModule for HeadersHttpModule {
         resp: &mut ResponseHeader,
         _end_of_stream: bool,
     ) -> Result<(), Box<Error>> {
+        let status = resp.status.as_u16();
+        for rule in std::mem::take(&mut self.status_rules) {
+            if rule.match_rules.matches_status(status) {
+                trace!("Applying rule for response status {status}: {rule:?}");
+                rule.apply(
+                    &mut self.headers,
+                    &mut self.remove,
+                    &mut self.cache_control_precedence,
+                );
+            }
+        }
+
         if let Some(list) = &self.headers {
             for (name, value) in list.iter() {
+                if name == header::CACHE_CONTROL && resp.headers.contains_key(name) {
+                    match self.cache_control_precedence.unwrap_or_default() {
+                        CacheControlPrecedence::Replace => {}
+                        CacheControlPrecedence::Merge => {
+                            let mut new_value = Vec::new();
+                            for existing in resp.headers.get_all(name) {
+                                new_value.extend_from_slice(existing.as_bytes());
+                                new_value.extend_from_slice(b", ");
+                            }
+                            new_value.extend_from_slice(value.as_bytes());
+                            resp.insert_header(name, HeaderValue::from_bytes(&new_value).unwrap())?;
+                            continue;
+                        }
+                        CacheControlPrecedence::Defer => continue,
+                    }
+                }
                 resp.insert_header(name, value)?;
             }
             trace!("Added headers to response: {list:?}");
         }
+
+        for name in &self.remove {
+            resp.remove_header(name);
+        }
+        if !self.remove.is_empty() {
+            trace!("Removed headers from response: {:?}", self.remove);
+        }
         Ok(())
     }
 }
 
-/// Headers module handler
+/// Number of independently locked shards of [`ConnectionTracker`]
+const CONNECTION_SHARDS: usize = 16;
+
+/// Minimal number of known connections in a shard before closed connections are pruned
+const MIN_PRUNE_CONNECTIONS: usize = 16;
+
+/// Maximal time that closed connections are kept in a shard
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Keeps track of the connections that responses have been sent on
+#[derive(Debug, Default)]
+struct ConnectionTracker {
+    shards: [Mutex<KnownConnections>; CONNECTION_SHARDS],
+}
+
+/// Connections seen by [`ConnectionTracker`] within a shard
+#[derive(Debug, Default)]
+struct KnownConnections {
+    /// Socket digests of the connections keyed by their address
+    digests: HashMap<usize, Weak<SocketDigest>>,
+    /// Number of connections left after closed connections were last pruned
+    pruned_len: usize,
+    /// Time when closed connections were last pruned
+    pruned_at: Option<Instant>,
+}
+
+impl KnownConnections {
+    /// Removes closed connections if the number of known connections doubled or if closed
+    /// connections might have been kept for too long.
+    fn prune(&mut self) {
+        let now = Instant::now();
+        let expired = self.pruned_at.map_or(true, |pruned_at| {
+            now.duration_since(pruned_at) >= PRUNE_INTERVAL
+        });
+        if expired || self.digests.len() >= (self.pruned_len * 2).max(MIN_PRUNE_CONNECTIONS) {
+            self.digests.retain(|_, known| known.strong_count() > 0);
+            self.pruned_len = self.digests.len();
+            self.pruned_at = Some(now);
+        }
+    }
+}
+
+impl ConnectionTracker {
+    /// Registers the connection, returns `true` if it hasn’t been seen before.
+    ///
+    /// Connections are recognized by the address of their socket digest which is shared by all
+    /// requests received on the same connection. The weak reference stored keeps the allocation
+    /// alive, so the address cannot be reused by another connection while it is listed. The
+    /// connections are distributed across several shards to reduce lock contention. Closed
+    /// connections are pruned from a shard whenever its number of known connections doubles or
+    /// [`PRUNE_INTERVAL`] passed since the last pruning.
+    fn register(&self, connection: &Arc<SocketDigest>) -> bool {
+        let key = Arc::as_ptr(connection) as usize;
+
+        // Lower bits of the address are always zero due to alignment
+        let shard = (key >> 4) % CONNECTION_SHARDS;
+        let mut connections = self.shards[shard].lock().unwrap();
+        connections.prune();
+
+        if connections.digests.contains_key(&key) {
+            return false;
+        }
+        connections.digests.insert(key, Arc::downgrade(connection));
+        true
+    }
+}
+
+/// Lists the request methods that any of the rules are restricted to.
+fn rule_methods(conf: &HeadersInnerConf) -> Vec<Method> {
+    fn add_methods<C>(methods: &mut Vec<Method>, rules: &[WithMatchRules<C>])
+    where
+        C: Default + Clone + Eq,
+    {
+        for rule in rules {
+            for method in &rule.match_rules.methods {
+                if !methods.contains(method) {
+                    methods.push(method.clone());
+                }
+            }
+        }
+    }
+
+    let mut methods = Vec::new();
+    add_methods(&mut methods, &conf.cache_control);
+    add_methods(&mut methods, &conf.content_security_policy);
+    add_methods(&mut methods, &conf.permissions_policy);
+    add_methods(&mut methods, &conf.strict_transport_security);
+    add_methods(&mut methods, &conf.client_hints);
+    add_methods(&mut methods, &conf.cross_origin);
+    add_methods(&mut methods, &conf.deprecation);
+    add_methods(&mut methods, &conf.custom);
+    add_methods(&mut methods, &conf.remove);
+    methods
+}
+
+/// Header rules merged for a particular request method
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct HeadersHandler {
+struct MergedRules {
     router: Router<Vec<Header>>,
+    cache_control_precedence: Router<Option<CacheControlPrecedence>>,
+    remove: Router<Vec<HeaderName>>,
+    conditional_rules: Vec<ConditionalRule>,
+    sources: Vec<RuleSource>,
 }
 
-impl TryFrom<HeadersConf> for HeadersHandler {
-    type Error = Box<Error>;
-
-    fn try_from(value: HeadersConf) -> Result<Self, Self::Error> {
-        let cache_control = merge_rules(value.response_headers.cache_control);
-        let content_security_policy = merge_rules(value.response_headers.content_security_policy);
-        let custom = merge_rules(value.response_headers.custom);
+impl MergedRules {
+    /// Merges the rules applying to the given request method. If `method` is `None`, only rules
+    /// without a method restriction are considered.
+    fn new(conf: &HeadersInnerConf, method: Option<&Method>) -> Result<Self, Box<Error>> {
+        let mut conditional = Vec::new();
+        let mut sources = Vec::new();
+        let cache_control_precedence =
+            merge_precedence(&conf.cache_control, method, &mut conditional)?;
+
+        macro_rules! merge {
+            ($field:ident) => {
+                merge_rules(
+                    stringify!($field),
+                    &conf.$field,
+                    method,
+                    &mut conditional,
+                    &mut sources,
+                )
+            };
+        }
+        let cache_control = merge!(cache_control);
+        let content_security_policy = merge!(content_security_policy);
+        let permissions_policy = merge!(permissions_policy);
+        let strict_transport_security = merge!(strict_transport_security);
+        let client_hints = merge!(client_hints);
+        let cross_origin = merge!(cross_origin);
+        let deprecation = merge!(deprecation);
+        let custom = merge!(custom);
+        let remove = merge_removals(&conf.remove, method, &mut conditional)?;
 
         let mut merged = cache_control;
-        merged.extend([content_security_policy, custom]);
+        merged.extend([
+            content_security_policy,
+            permissions_policy,
+            strict_transport_security,
+            client_hints,
+            cross_origin,
+            deprecation,
+            custom,
+        ]);
 
         let router = merged.merge(|values| {
             let mut result = Vec::<(HeaderName, HeaderValue)>::new();
@@ -119,10 +465,86 @@ impl TryFrom<HeadersConf> for HeadersHandler {
                 }
             }
             result
-        });
-        trace!("Merged headers configuration into: {router:#?}");
+        })?;
+        trace!("Merged headers configuration for method {method:?} into: {router:#?}");
+
+        Ok(Self {
+            router,
+            cache_control_precedence,
+            remove,
+            conditional_rules: conditional,
+            sources,
+        })
+    }
+
+    /// Logs which of the rules merged ahead of time apply to the host/path combination and the
+    /// headers each of them contributes.
+    fn explain(&self, host: &[u8], path: &Path) {
+        for source in &self.sources {
+            if let Some(explanation) = source.match_rules.explain(host, path) {
+                trace!(
+                    "{} rule with headers {:?}: {explanation}",
+                    source.name,
+                    source.headers
+                );
+            }
+        }
+    }
+}
+
+/// Headers module handler
+#[derive(Debug, Clone)]
+pub struct HeadersHandler {
+    rules: MergedRules,
+    method_rules: Vec<(Method, MergedRules)>,
+    first_response: Vec<Header>,
+    templates: bool,
+    connections: Arc<ConnectionTracker>,
+}
+
+impl PartialEq for HeadersHandler {
+    fn eq(&self, other: &Self) -> bool {
+        // Connection tracker is runtime state, not configuration
+        self.rules == other.rules
+            && self.method_rules == other.method_rules
+            && self.first_response == other.first_response
+            && self.templates == other.templates
+    }
+}
+
+impl Eq for HeadersHandler {}
+
+impl TryFrom<HeadersConf> for HeadersHandler {
+    type Error = Box<Error>;
+
+    fn try_from(value: HeadersConf) -> Result<Self, Self::Error> {
+        let conf = value.response_headers;
 
-        Ok(Self { router })
+        // Rules restricted to particular methods are merged separately for each of these methods
+        let rules = MergedRules::new(&conf, None)?;
+        let method_rules = rule_methods(&conf)
+            .into_iter()
+            .map(|method| {
+                let rules = MergedRules::new(&conf, Some(&method))?;
+                Ok((method, rules))
+            })
+            .collect::<Result<_, Box<Error>>>()?;
+
+        // Only custom headers can contain placeholders
+        let templates = conf
+            .custom
+            .iter()
+            .map(|rule| &rule.conf)
+            .chain(std::iter::once(&conf.first_response))
+            .any(|custom| custom.headers.values().any(has_placeholders));
+
+        Ok(Self {
+            rules,
+            method_rules,
+            first_response: conf.first_response.into_headers(),
+            templates,
+            connections: Default::default(),
+        })
     }
 }
 
@@ -151,14 +573,118 @@ impl RequestFilter for HeadersHandler {
             session.host()
         );
 
+        let method = &session.req_header().method;
+        let rules = self
+            .method_rules
+            .iter()
+            .find(|(rules_method, _)| rules_method == method)
+            .map_or(&self.rules, |(_, rules)| rules);
+
         let host = session.host().unwrap_or_default();
-        if let Some(list) = self.router.lookup(host.as_ref(), path) {
+        let mut headers = rules
+            .router
+            .lookup(host.as_ref(), path)
+            .map(|list| list.as_value().clone());
+        let mut remove = rules
+            .remove
+            .lookup(host.as_ref(), path)
+            .map(|list| list.as_value().clone())
+            .unwrap_or_default();
+        let mut cache_control_precedence = rules
+            .cache_control_precedence
+            .lookup(host.as_ref(), path)
+            .and_then(|precedence| *precedence.as_value());
+
+        if log_enabled!(Level::Trace) {
+            rules.explain(host.to_ascii_lowercase().as_bytes(), &Path::new(path));
+        }
+
+        let mut status_rules = Vec::new();
+        if !rules.conditional_rules.is_empty() {
+            let host = host.to_ascii_lowercase();
+            let path = Path::new(path);
+            let request_headers = &session.req_header().headers;
+            for rule in &rules.conditional_rules {
+                if !rule.matches(host.as_bytes(), &path, request_headers) {
+                    continue;
+                }
+
+                trace!("Conditional rule applies to this request: {rule:?}");
+                if rule.match_rules.status.is_empty() {
+                    rule.apply(&mut headers, &mut remove, &mut cache_control_precedence);
+                } else {
+                    // Status code is only known in the response phase
+                    status_rules.push(rule.clone());
+                }
+            }
+        }
+
+        if !self.first_response.is_empty() {
+            let first = if let Some(connection) = session.connection() {
+                self.connections.register(&connection)
+            } else {
+                true
+            };
+            if first {
+                trace!("First response on this connection, adding connection headers");
+                headers
+                    .get_or_insert_with(Vec::new)
+                    .extend_from_slice(&self.first_response);
+            }
+        }
+
+        if self.templates {
+            let context = TemplateContext {
+                host: &host,
+                path,
+                scheme: if session.is_https(false) {
+                    "https"
+                } else {
+                    "http"
+                },
+                method: session.req_header().method.as_str(),
+            };
+            let lists = headers
+                .iter_mut()
+                .chain(status_rules.iter_mut().map(|rule| &mut rule.headers));
+            for list in lists {
+                for (_, value) in list.iter_mut() {
+                    if has_placeholders(value) {
+                        if let Some(result) = substitute(value, &context) {
+                            *value = result;
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(list) = headers {
+            trace!("Prepared headers for response: {list:?}");
+            let module = session
+                .downstream_modules_ctx
+                .get_mut::<HeadersHttpModule>()
+                .unwrap();
+            module.headers = Some(list);
+            module.cache_control_precedence = cache_control_precedence;
+        }
+
+        if !remove.is_empty() {
+            trace!("Prepared headers to be removed from response: {remove:?}");
             session
                 .downstream_modules_ctx
                 .get_mut::<HeadersHttpModule>()
                 .unwrap()
-                .headers = Some(list.as_value().clone());
-            trace!("Prepared headers for response: {list:?}");
+                .remove = remove;
+        }
+
+        if !status_rules.is_empty() {
+            trace!("Prepared rules depending on response status: {status_rules:?}");
+            let module = session
+                .downstream_modules_ctx
+                .get_mut::<HeadersHttpModule>()
+                .unwrap();
+            module.cache_control_precedence = cache_control_precedence;
+            module.status_rules = status_rules;
         }
 
         Ok(RequestFilterResult::Unhandled)
@@ -169,6 +695,7 @@ impl RequestFilter for HeadersHandler {
 mod tests {
     use super::*;
 
+    use crate::configuration::{PermissionsPolicyConf, RuleMatcher};
     use http::header;
     use pandora_module_utils::pingora::{create_test_session, HttpPeer, RequestHeader, Session};
     use pandora_module_utils::{DeserializeMap, FromYaml};
@@ -674,4 +1201,1455 @@ mod tests {
             ],
         );
     }
+
+    #[test(tokio::test)]
+    async fn first_response() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                response_headers:
+                    custom:
+                        Server: My very own web server
+                    first_response:
+                        X-Connection: new
+            "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        async fn make_session_on_connection(connection: &Arc<SocketDigest>) -> Session {
+            let mut session = make_session("https://localhost/").await;
+            session.digest_mut().unwrap().socket_digest = Some(connection.clone());
+            session
+        }
+
+        let connection1 = Arc::new(SocketDigest::from_raw_fd(0));
+        let connection2 = Arc::new(SocketDigest::from_raw_fd(0));
+
+        let session = make_session_on_connection(&connection1).await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, _| make_response_header())
+            .await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("Server", "My very own web server"),
+                ("X-Connection", "new"),
+            ],
+        );
+
+        let session = make_session_on_connection(&connection1).await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, _| make_response_header())
+            .await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("Server", "My very own web server"),
+            ],
+        );
+
+        let session = make_session_on_connection(&connection2).await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, _| make_response_header())
+            .await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("Server", "My very own web server"),
+                ("X-Connection", "new"),
+            ],
+        );
+
+        // Connection closed and reopened, this counts as a new connection
+        drop(connection1);
+        let connection1 = Arc::new(SocketDigest::from_raw_fd(0));
+        let session = make_session_on_connection(&connection1).await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, _| make_response_header())
+            .await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("Server", "My very own web server"),
+                ("X-Connection", "new"),
+            ],
+        );
+    }
+
+    #[test]
+    fn connection_tracker_pruning() {
+        let tracker = ConnectionTracker::default();
+        let open = Arc::new(SocketDigest::from_raw_fd(0));
+        assert!(tracker.register(&open));
+
+        for _ in 0..1000 {
+            let closed = Arc::new(SocketDigest::from_raw_fd(0));
+            assert!(tracker.register(&closed));
+            assert!(!tracker.register(&closed));
+        }
+
+        assert!(!tracker.register(&open));
+        for shard in &tracker.shards {
+            assert!(shard.lock().unwrap().digests.len() <= MIN_PRUNE_CONNECTIONS);
+        }
+
+        // Closed connections are pruned after a while even if the shard doesn’t grow
+        let tracker = ConnectionTracker::default();
+        let closed = Arc::new(SocketDigest::from_raw_fd(0));
+        assert!(tracker.register(&closed));
+        let key = Arc::as_ptr(&closed) as usize;
+        let mut shard = tracker.shards[(key >> 4) % CONNECTION_SHARDS]
+            .lock()
+            .unwrap();
+        drop(closed);
+
+        shard.prune();
+        assert!(shard.digests.contains_key(&key));
+
+        shard.pruned_at = Some(Instant::now() - PRUNE_INTERVAL);
+        shard.prune();
+        assert!(!shard.digests.contains_key(&key));
+    }
+
+    #[test(tokio::test)]
+    async fn cache_control_precedence() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                response_headers:
+                    cache_control:
+                    -
+                        max-age: 300
+                        include: localhost/replace/*
+                    -
+                        max-age: 300
+                        precedence: merge
+                        include: localhost/merge/*
+                    -
+                        max-age: 300
+                        precedence: defer
+                        include: localhost/defer/*
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        let make_upstream_header = || -> Result<ResponseHeader, Box<Error>> {
+            let mut header = ResponseHeader::build(200, None)?;
+            header.insert_header("Cache-Control", "no-cache")?;
+            Ok(header)
+        };
+
+        for (path, expected) in [
+            ("https://localhost/replace/", "max-age=300"),
+            ("https://localhost/merge/", "no-cache, max-age=300"),
+            ("https://localhost/defer/", "no-cache"),
+        ] {
+            let session = make_session(path).await;
+            let mut result = app
+                .handle_request_with_upstream(session, |_, _| make_upstream_header())
+                .await;
+            assert!(result.err().is_none());
+            assert_headers(
+                result.session().response_written().unwrap(),
+                vec![("Cache-Control", expected)],
+            );
+        }
+
+        // Configured header is still used if upstream didn't send any
+        let session = make_session("https://localhost/defer/").await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, _| make_response_header())
+            .await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("Cache-Control", "max-age=300"),
+            ],
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn negated_rules() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    custom:
+                    -
+                        include:
+                        - localhost/static/*
+                        - "!localhost/static/private/*"
+                        - "!localhost/static/index.html"
+                        X-Static: static
+                    -
+                        include: ["/*", "!example.com"]
+                        exclude: ["example.net/*", "!example.net/public/*"]
+                        X-Other: other
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        for (uri, expected) in [
+            ("https://localhost/", vec![("X-Other", "other")]),
+            (
+                "https://localhost/static/file.txt",
+                vec![("X-Static", "static"), ("X-Other", "other")],
+            ),
+            (
+                "https://localhost/static/private/",
+                vec![("X-Other", "other")],
+            ),
+            (
+                "https://localhost/static/private/file.txt",
+                vec![("X-Other", "other")],
+            ),
+            (
+                "https://localhost/static/index.html",
+                vec![("X-Other", "other")],
+            ),
+            ("https://example.com/", vec![]),
+            ("https://example.com/static/file.txt", vec![]),
+            ("https://example.net/", vec![]),
+            (
+                "https://example.net/public/file.txt",
+                vec![("X-Other", "other")],
+            ),
+        ] {
+            let session = make_session(uri).await;
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none());
+
+            let mut headers = vec![("X-Me", "none"), ("X-Test", "unchanged")];
+            headers.extend(expected);
+            assert_headers(result.session().response_written().unwrap(), headers);
+        }
+
+        // A lone `!` is rejected
+        assert!(<Handler as RequestFilter>::Conf::from_yaml(
+            r#"
+            response_headers:
+                custom:
+                    include: "!"
+                    X-Static: static
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn explain() {
+        let rules = MatchRules::from_yaml(
+            r#"
+                include:
+                - localhost/static/*
+                - "!localhost/static/private/*"
+                - '~\.map$'
+                exclude:
+                - localhost/static/private/*
+                - "!localhost/static/private/shared/*"
+            "#,
+        )
+        .unwrap();
+
+        let explain = |host: &str, path: &str| rules.explain(host.as_bytes(), &Path::new(path));
+        assert_eq!(
+            explain("localhost", "/static/file.txt").as_deref(),
+            Some("included by include rule `localhost/static/*`")
+        );
+        assert_eq!(
+            explain("localhost", "/static/private/file.txt").as_deref(),
+            Some("excluded by exclude rule `localhost/static/private/*`")
+        );
+        assert_eq!(
+            explain("localhost", "/static/private/shared/file.txt").as_deref(),
+            Some("included by exclude rule `!localhost/static/private/shared/*`")
+        );
+        assert_eq!(
+            explain("example.com", "/file.js.map").as_deref(),
+            Some("included by include rule `~\\.map$`")
+        );
+        assert_eq!(explain("example.com", "/file.js"), None);
+
+        assert_eq!(
+            MatchRules::default()
+                .explain(b"localhost", &Path::new("/"))
+                .as_deref(),
+            Some("included by default")
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn priority() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    custom:
+                    -
+                        include:
+                        - path: "/*"
+                          priority: 10
+                        exclude: localhost/static/private/*
+                        X-Priority: high
+                    -
+                        include: /static/*
+                        exclude: localhost/static/*
+                        X-Static: static
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        for (uri, expected) in [
+            ("https://localhost/", vec![("X-Priority", "high")]),
+            (
+                "https://localhost/static/private/file.txt",
+                vec![("X-Priority", "high")],
+            ),
+            (
+                "https://example.com/static/file.txt",
+                vec![("X-Priority", "high"), ("X-Static", "static")],
+            ),
+        ] {
+            let session = make_session(uri).await;
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none());
+
+            let mut headers = vec![("X-Me", "none"), ("X-Test", "unchanged")];
+            headers.extend(expected);
+            assert_headers(result.session().response_written().unwrap(), headers);
+        }
+
+        let rules = MatchRules::from_yaml(
+            r#"
+                include:
+                - localhost/*
+                - {path: "!localhost/dir/*", priority: -1}
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            rules.include,
+            vec![
+                RuleMatcher::from("localhost/*"),
+                RuleMatcher::Path(HostPathMatcher {
+                    priority: -1,
+                    ..HostPathMatcher::from("!localhost/dir/*")
+                }),
+            ]
+            .into()
+        );
+        assert_eq!(
+            rules
+                .explain(b"localhost", &Path::new("/dir/file.txt"))
+                .as_deref(),
+            Some("included by include rule `localhost/*`")
+        );
+
+        // Priority cannot be set for regular expressions, unknown keys are rejected
+        for yaml in [
+            "include: {path: '~\\.map$', priority: 1}",
+            "include: {path: /*, weight: 1}",
+            "include: {priority: 1}",
+        ] {
+            assert!(MatchRules::from_yaml(yaml).is_err(), "{yaml}");
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn exclude_wins() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    custom:
+                    -
+                        include: localhost/*
+                        exclude: /private/*
+                        exclude_wins: true
+                        X-Exclude-Wins: present
+                    -
+                        include: localhost/*
+                        exclude: /private/*
+                        X-Default: present
+                    -
+                        include: [localhost/*, /private/public/*]
+                        exclude: /private/*
+                        exclude_wins: true
+                        X-Public: present
+                    -
+                        include: localhost/*
+                        exclude: '~\.bak$'
+                        exclude_wins: true
+                        X-No-Backup: present
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        for (uri, expected) in [
+            (
+                "https://localhost/",
+                vec![
+                    ("X-Exclude-Wins", "present"),
+                    ("X-Default", "present"),
+                    ("X-Public", "present"),
+                    ("X-No-Backup", "present"),
+                ],
+            ),
+            // Fallback exclude rule wins over host-specific include rule
+            (
+                "https://localhost/private/file.txt",
+                vec![("X-Default", "present"), ("X-No-Backup", "present")],
+            ),
+            // Fallback include rule with a longer path wins over fallback exclude rule
+            (
+                "https://localhost/private/public/file.txt",
+                vec![
+                    ("X-Default", "present"),
+                    ("X-Public", "present"),
+                    ("X-No-Backup", "present"),
+                ],
+            ),
+            ("https://example.com/private/file.txt", vec![]),
+            (
+                "https://example.com/private/public/file.txt",
+                vec![("X-Public", "present")],
+            ),
+            // Regular expression exclude rule wins over host/path include rule
+            (
+                "https://localhost/file.bak",
+                vec![
+                    ("X-Exclude-Wins", "present"),
+                    ("X-Default", "present"),
+                    ("X-Public", "present"),
+                ],
+            ),
+        ] {
+            let session = make_session(uri).await;
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none());
+
+            let mut headers = vec![("X-Me", "none"), ("X-Test", "unchanged")];
+            headers.extend(expected);
+            assert_headers(result.session().response_written().unwrap(), headers);
+        }
+
+        let rules = MatchRules::from_yaml(
+            r#"
+                include: example.com/*
+                exclude: /private/*
+                exclude_wins: true
+            "#,
+        )
+        .unwrap();
+        assert!(rules.exclude_wins);
+        assert!(rules
+            .matches(b"example.com", &Path::new("/file.txt"), false)
+            .any());
+        assert!(!rules
+            .matches(b"example.com", &Path::new("/private/file.txt"), false)
+            .any());
+        assert_eq!(
+            rules
+                .explain(b"example.com", &Path::new("/private/file.txt"))
+                .as_deref(),
+            Some("excluded by exclude rule `/private/*`")
+        );
+
+        // Higher priority include rule still wins
+        let rules = MatchRules::from_yaml(
+            r#"
+                include: {path: example.com/*, priority: 1}
+                exclude: /private/*
+                exclude_wins: true
+            "#,
+        )
+        .unwrap();
+        assert!(rules
+            .matches(b"example.com", &Path::new("/private/file.txt"), false)
+            .any());
+    }
+
+    #[test(tokio::test)]
+    async fn remove_headers() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    custom:
+                        X-Added: added
+                    remove:
+                    -
+                        headers: X-Test
+                    -
+                        include: localhost/private/*
+                        headers: [X-Me, X-Added]
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        for (uri, expected) in [
+            (
+                "https://localhost/",
+                vec![("X-Me", "none"), ("X-Added", "added")],
+            ),
+            ("https://localhost/private/file.txt", vec![]),
+            (
+                "https://example.com/private/file.txt",
+                vec![("X-Me", "none"), ("X-Added", "added")],
+            ),
+        ] {
+            let session = make_session(uri).await;
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none());
+            assert_headers(result.session().response_written().unwrap(), expected);
+        }
+
+        // Invalid header names are rejected
+        assert!(<Handler as RequestFilter>::Conf::from_yaml(
+            r#"
+            response_headers:
+                remove:
+                    headers: "X Invalid"
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn wildcard_hosts() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    custom:
+                    -
+                        X-Level: fallback
+                    -
+                        include: "*.example.com/admin/*"
+                        X-Level: wildcard
+                    -
+                        include: "*.b.example.com/admin/*"
+                        X-Level: deep
+                    -
+                        include: www.example.com/admin/*
+                        X-Level: exact
+                    -
+                        include: "*.example.com"
+                        exclude: www.example.com
+                        X-Wildcard: present
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        for (uri, expected) in [
+            (
+                "https://www.example.com/admin/x",
+                vec![("X-Level", "exact")],
+            ),
+            (
+                "https://a.b.example.com/admin/x",
+                vec![("X-Level", "deep"), ("X-Wildcard", "present")],
+            ),
+            (
+                "https://c.example.com/admin/x",
+                vec![("X-Level", "wildcard"), ("X-Wildcard", "present")],
+            ),
+            (
+                "https://c.example.com/",
+                vec![("X-Level", "fallback"), ("X-Wildcard", "present")],
+            ),
+            ("https://example.com/admin/x", vec![("X-Level", "fallback")]),
+            ("https://www.example.com/", vec![("X-Level", "fallback")]),
+        ] {
+            let session = make_session(uri).await;
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none());
+
+            let mut headers = vec![("X-Me", "none"), ("X-Test", "unchanged")];
+            headers.extend(expected);
+            assert_headers(result.session().response_written().unwrap(), headers);
+        }
+
+        // Wildcards are only allowed as host name prefix
+        assert!(<Handler as RequestFilter>::Conf::from_yaml(
+            r#"
+            response_headers:
+                custom:
+                    include: "www.*.com"
+                    X-Level: invalid
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn regex_rules() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    cache_control:
+                        include: '~^/assets/.*\.[0-9a-f]{8}\.js$'
+                        immutable: true
+                    custom:
+                    -
+                        include: '~*\.map$'
+                        exclude: '~^/public/'
+                        X-Source-Map: present
+                    -
+                        include: [localhost/docs/*, '~\.txt$']
+                        X-Text: present
+                    -
+                        include: localhost/*
+                        exclude: '~\.txt$'
+                        X-Prefix: present
+                    remove:
+                        include: '~^/private/'
+                        headers: X-Test
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        for (uri, expected) in [
+            (
+                "https://localhost/",
+                vec![("X-Test", "unchanged"), ("X-Prefix", "present")],
+            ),
+            (
+                "https://localhost/assets/app.0123abcd.js",
+                vec![
+                    ("X-Test", "unchanged"),
+                    ("X-Prefix", "present"),
+                    ("Cache-Control", "immutable"),
+                ],
+            ),
+            // Regular expressions are only anchored where requested
+            (
+                "https://localhost/static/assets/app.0123abcd.js",
+                vec![("X-Test", "unchanged"), ("X-Prefix", "present")],
+            ),
+            (
+                "https://localhost/assets/app.0123abcd.js.map",
+                vec![
+                    ("X-Test", "unchanged"),
+                    ("X-Prefix", "present"),
+                    ("X-Source-Map", "present"),
+                ],
+            ),
+            // Case-insensitive matching with `~*`
+            (
+                "https://example.com/app.JS.MAP",
+                vec![("X-Test", "unchanged"), ("X-Source-Map", "present")],
+            ),
+            // Exclude regular expression wins over include
+            (
+                "https://example.com/public/app.js.map",
+                vec![("X-Test", "unchanged")],
+            ),
+            // Prefix rules take precedence over regular expressions
+            (
+                "https://localhost/docs/file.html",
+                vec![
+                    ("X-Test", "unchanged"),
+                    ("X-Text", "present"),
+                    ("X-Prefix", "present"),
+                ],
+            ),
+            (
+                "https://localhost/file.txt",
+                vec![
+                    ("X-Test", "unchanged"),
+                    ("X-Text", "present"),
+                    ("X-Prefix", "present"),
+                ],
+            ),
+            (
+                "https://example.com/file.txt",
+                vec![("X-Test", "unchanged"), ("X-Text", "present")],
+            ),
+            (
+                "https://example.com/private/file.txt",
+                vec![("X-Text", "present")],
+            ),
+        ] {
+            let session = make_session(uri).await;
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none());
+
+            let mut headers = vec![("X-Me", "none")];
+            headers.extend(expected);
+            assert_headers(result.session().response_written().unwrap(), headers);
+        }
+
+        // Invalid regular expressions are rejected when loading configuration
+        assert!(<Handler as RequestFilter>::Conf::from_yaml(
+            r#"
+            response_headers:
+                custom:
+                    include: "~^/(unclosed"
+                    X-Invalid: invalid
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn suffix_rules() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    cache_control:
+                        include: localhost/static/*.css
+                        exclude: localhost/static/vendor/*
+                        max-age: 3600
+                    custom:
+                    -
+                        include: /*.tar.gz
+                        X-Archive: present
+                    -
+                        include: localhost/*
+                        exclude: localhost/*.min.js
+                        X-Prefix: present
+                    -
+                        include: /*.min.js
+                        exclude: [/*.js, localhost/static/legacy.min.js]
+                        X-Minified: present
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        for (uri, expected) in [
+            ("https://localhost/static", vec![("X-Prefix", "present")]),
+            (
+                "https://localhost/static/style.css",
+                vec![("X-Prefix", "present"), ("Cache-Control", "max-age=3600")],
+            ),
+            (
+                "https://localhost/static/dir/app.min.css",
+                vec![("X-Prefix", "present"), ("Cache-Control", "max-age=3600")],
+            ),
+            // Exclude rule for the directory wins over the suffix rule
+            (
+                "https://localhost/static/vendor/lib.css",
+                vec![("X-Prefix", "present")],
+            ),
+            (
+                "https://localhost/static/style.css.map",
+                vec![("X-Prefix", "present")],
+            ),
+            // Multiple dots in the file name and the suffix
+            (
+                "https://localhost/dist/pkg-1.2.3.tar.gz",
+                vec![("X-Prefix", "present"), ("X-Archive", "present")],
+            ),
+            ("https://example.com/dist/pkg-1.2.3.gz", vec![]),
+            // Suffix rule wins over prefix rule for the same directory, longer suffix wins over
+            // shorter one
+            (
+                "https://localhost/lib.min.js",
+                vec![("X-Minified", "present")],
+            ),
+            (
+                "https://example.com/lib.min.js",
+                vec![("X-Minified", "present")],
+            ),
+            ("https://example.com/lib.js", vec![]),
+            // Exact path wins over the suffix rules
+            ("https://localhost/static/legacy.min.js", vec![]),
+        ] {
+            let session = make_session(uri).await;
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none());
+
+            let mut headers = vec![("X-Me", "none"), ("X-Test", "unchanged")];
+            headers.extend(expected);
+            assert_headers(result.session().response_written().unwrap(), headers);
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn methods() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    cache_control:
+                    -
+                        methods: [GET, head]
+                        max-age: 300
+                    -
+                        methods: POST
+                        no-storage: true
+                    custom:
+                    -
+                        methods: OPTIONS
+                        X-Allowed-Methods: GET, HEAD, POST, OPTIONS
+                    -
+                        X-Server: server
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        for (method, expected) in [
+            ("GET", vec![("Cache-Control", "max-age=300")]),
+            ("HEAD", vec![("Cache-Control", "max-age=300")]),
+            ("POST", vec![("Cache-Control", "no-storage")]),
+            (
+                "OPTIONS",
+                vec![("X-Allowed-Methods", "GET, HEAD, POST, OPTIONS")],
+            ),
+            ("PUT", vec![]),
+        ] {
+            let mut header = RequestHeader::build(method, b"/", None).unwrap();
+            header.set_uri("https://localhost/".try_into().unwrap());
+            let session = create_test_session(header).await;
+
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none());
+
+            let mut headers = vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("X-Server", "server"),
+            ];
+            headers.extend(expected);
+            assert_headers(result.session().response_written().unwrap(), headers);
+        }
+
+        // Unknown methods are rejected
+        assert!(<Handler as RequestFilter>::Conf::from_yaml(
+            r#"
+            response_headers:
+                custom:
+                    methods: FETCH
+                    X-Invalid: invalid
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn status() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: false
+                response_headers:
+                    cache_control:
+                    -
+                        status: 200
+                        max-age: 300
+                    -
+                        status: [3xx, 404-410]
+                        no-storage: true
+                    content_security_policy:
+                        status: 2xx
+                        script-src: ["'self'"]
+                    custom:
+                    -
+                        status: 302
+                        include: /subdir/*
+                        X-Redirect: subdir
+                    -
+                        X-Server: server
+                    remove:
+                        status: 5xx
+                        headers: X-Test
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        for (path, status, expected) in [
+            (
+                "https://localhost/",
+                200,
+                vec![
+                    ("X-Test", "unchanged"),
+                    ("Cache-Control", "max-age=300"),
+                    ("Content-Security-Policy", "script-src 'self'"),
+                ],
+            ),
+            (
+                "https://localhost/subdir/file",
+                200,
+                vec![
+                    ("X-Test", "unchanged"),
+                    ("Cache-Control", "max-age=300"),
+                    ("Content-Security-Policy", "script-src 'self'"),
+                ],
+            ),
+            (
+                "https://localhost/",
+                302,
+                vec![("X-Test", "unchanged"), ("Cache-Control", "no-storage")],
+            ),
+            (
+                "https://localhost/subdir/file",
+                302,
+                vec![
+                    ("X-Test", "unchanged"),
+                    ("Cache-Control", "no-storage"),
+                    ("X-Redirect", "subdir"),
+                ],
+            ),
+            (
+                "https://localhost/",
+                404,
+                vec![("X-Test", "unchanged"), ("Cache-Control", "no-storage")],
+            ),
+            ("https://localhost/", 500, vec![]),
+        ] {
+            let session = make_session(path).await;
+            let mut result = app
+                .handle_request_with_upstream(session, |_, _| {
+                    let mut header = make_response_header()?;
+                    header.set_status(status)?;
+                    Ok(header)
+                })
+                .await;
+            assert!(result.err().is_none());
+
+            let mut headers = vec![("X-Me", "none"), ("X-Server", "server")];
+            headers.extend(expected);
+            assert_headers(result.session().response_written().unwrap(), headers);
+        }
+
+        // Invalid status code matchers are rejected
+        for status in ["6xx", "299-200", "abc", "[[200]]"] {
+            assert!(<Handler as RequestFilter>::Conf::from_yaml(format!(
+                r#"
+                response_headers:
+                    custom:
+                        status: {status}
+                        X-Invalid: invalid
+                "#,
+            ))
+            .is_err());
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn if_header() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    custom:
+                    -
+                        if_header:
+                            X-Requested-With: true
+                        Vary: X-Requested-With
+                    -
+                        if_header:
+                            sec-fetch-dest: iframe
+                            Sec-Fetch-Mode: navigate
+                        X-Embedded: iframe
+                    -
+                        if_header:
+                            Save-Data: false
+                        X-Full: full
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        for (request_headers, expected) in [
+            (vec![], vec![("X-Full", "full")]),
+            (
+                vec![("X-Requested-With", "XMLHttpRequest")],
+                vec![("Vary", "X-Requested-With"), ("X-Full", "full")],
+            ),
+            (
+                vec![("X-Requested-With", "")],
+                vec![("Vary", "X-Requested-With"), ("X-Full", "full")],
+            ),
+            (vec![("Save-Data", "on")], vec![]),
+            (
+                vec![("Sec-Fetch-Dest", "iframe"), ("Sec-Fetch-Mode", "navigate")],
+                vec![("X-Embedded", "iframe"), ("X-Full", "full")],
+            ),
+            (
+                vec![
+                    ("Sec-Fetch-Dest", "document"),
+                    ("Sec-Fetch-Dest", "iframe"),
+                    ("Sec-Fetch-Mode", "navigate"),
+                ],
+                vec![("X-Embedded", "iframe"), ("X-Full", "full")],
+            ),
+            (
+                vec![("Sec-Fetch-Dest", "IFRAME"), ("Sec-Fetch-Mode", "navigate")],
+                vec![("X-Full", "full")],
+            ),
+            (vec![("Sec-Fetch-Dest", "iframe")], vec![("X-Full", "full")]),
+        ] {
+            let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+            header.set_uri("https://localhost/".try_into().unwrap());
+            for (name, value) in request_headers {
+                header.append_header(name, value).unwrap();
+            }
+            let session = create_test_session(header).await;
+
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none());
+
+            let mut headers = vec![("X-Me", "none"), ("X-Test", "unchanged")];
+            headers.extend(expected);
+            assert_headers(result.session().response_written().unwrap(), headers);
+        }
+
+        // Invalid header names are rejected
+        assert!(<Handler as RequestFilter>::Conf::from_yaml(
+            r#"
+            response_headers:
+                custom:
+                    if_header:
+                        "X Invalid": true
+                    X-Invalid: invalid
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn deprecation() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    deprecation:
+                    -
+                        include: localhost/api/v1/*
+                        deprecation: "Sun, 01 Jan 2023 00:00:00 GMT"
+                        sunset: "Mon, 01 Jan 2024 00:00:00 GMT"
+                    -
+                        include: localhost/api/v1/legacy/*
+                        sunset: "Fri, 01 Dec 2023 00:00:00 GMT"
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        for (uri, expected) in [
+            (
+                "https://localhost/api/v1/users",
+                vec![
+                    ("Deprecation", "@1672531200"),
+                    ("Sunset", "Mon, 01 Jan 2024 00:00:00 GMT"),
+                ],
+            ),
+            (
+                "https://localhost/api/v1/legacy/users",
+                vec![
+                    ("Deprecation", "@1672531200"),
+                    ("Sunset", "Fri, 01 Dec 2023 00:00:00 GMT"),
+                ],
+            ),
+            ("https://localhost/api/v2/users", vec![]),
+            ("https://example.com/api/v1/users", vec![]),
+        ] {
+            let session = make_session(uri).await;
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none());
+
+            let mut headers = vec![("X-Me", "none"), ("X-Test", "unchanged")];
+            headers.extend(expected);
+            assert_headers(result.session().response_written().unwrap(), headers);
+        }
+
+        // Only IMF-fixdate is accepted
+        for date in ["Sunday, 06-Nov-94 08:49:37 GMT", "2024-01-01"] {
+            assert!(<Handler as RequestFilter>::Conf::from_yaml(format!(
+                r#"
+                response_headers:
+                    deprecation:
+                        sunset: "{date}"
+                "#
+            ))
+            .is_err());
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn templates() {
+        std::env::set_var("HEADERS_MODULE_TEST_VAR", "from environment");
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    custom:
+                        X-Request-Host: "${host}"
+                        X-Request: "${method} ${path}"
+                        X-Literal: literal value
+                        X-Escaped: "$${host}"
+                        X-Environment: "${env.HEADERS_MODULE_TEST_VAR}"
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        for (uri, host, request) in [
+            ("https://localhost/", "localhost", "GET /"),
+            (
+                "https://example.com/dir/file.txt",
+                "example.com",
+                "GET /dir/file.txt",
+            ),
+        ] {
+            let session = make_session(uri).await;
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none());
+            assert_headers(
+                result.session().response_written().unwrap(),
+                vec![
+                    ("X-Me", "none"),
+                    ("X-Test", "unchanged"),
+                    ("X-Request-Host", host),
+                    ("X-Request", request),
+                    ("X-Literal", "literal value"),
+                    ("X-Escaped", "${host}"),
+                    ("X-Environment", "from environment"),
+                ],
+            );
+        }
+
+        // Unknown placeholders and missing environment variables are rejected
+        for value in ["${unknown}", "${env.HEADERS_MODULE_MISSING_VAR}"] {
+            assert!(<Handler as RequestFilter>::Conf::from_yaml(format!(
+                r#"
+                response_headers:
+                    custom:
+                        X-Invalid: "{value}"
+                "#
+            ))
+            .is_err());
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn permissions_policy() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    permissions_policy:
+                    -
+                        geolocation: []
+                        camera: [self, "https://example.com"]
+                        include: localhost
+                    -
+                        camera: "*"
+                        usb: []
+                        include: localhost/media/*
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        for (uri, expected) in [
+            (
+                "https://localhost/",
+                Some(r#"camera=(self "https://example.com"), geolocation=()"#),
+            ),
+            (
+                "https://localhost/media/",
+                Some("camera=*, geolocation=(), usb=()"),
+            ),
+            ("https://example.com/", None),
+        ] {
+            let session = make_session(uri).await;
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none());
+
+            let mut headers = vec![("X-Me", "none"), ("X-Test", "unchanged")];
+            if let Some(expected) = expected {
+                headers.push(("Permissions-Policy", expected));
+            }
+            assert_headers(result.session().response_written().unwrap(), headers);
+        }
+    }
+
+    #[test]
+    fn permissions_policy_merge() {
+        let mut conf = PermissionsPolicyConf::from_yaml(
+            r#"
+                geolocation: self
+                microphone: []
+            "#,
+        )
+        .unwrap();
+        let other = PermissionsPolicyConf::from_yaml(
+            r#"
+                geolocation: ["https://a.example.com", "https://b.example.com"]
+                fullscreen: self
+            "#,
+        )
+        .unwrap();
+        conf.merge_with(&other);
+
+        assert_eq!(
+            conf.into_headers(),
+            vec![(
+                HeaderName::from_static("permissions-policy"),
+                HeaderValue::from_static(
+                    r#"fullscreen=(self), geolocation=("https://a.example.com" "https://b.example.com"), microphone=()"#
+                ),
+            )]
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn strict_transport_security() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    strict_transport_security:
+                    -
+                        max-age: 31536000
+                        include: localhost
+                    -
+                        includeSubDomains: true
+                        include: [localhost/sub/*, localhost/preload/*, example.com]
+                    -
+                        preload: true
+                        include: [localhost/preload/*, localhost/nosub/*]
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        for (uri, expected) in [
+            ("https://localhost/", Some("max-age=31536000")),
+            (
+                "https://localhost/sub/",
+                Some("max-age=31536000; includeSubDomains"),
+            ),
+            (
+                "https://localhost/preload/",
+                Some("max-age=31536000; includeSubDomains; preload"),
+            ),
+            // preload is ignored without includeSubDomains
+            ("https://localhost/nosub/", Some("max-age=31536000")),
+            // No header without max-age
+            ("https://example.com/", None),
+        ] {
+            let session = make_session(uri).await;
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none());
+
+            let mut headers = vec![("X-Me", "none"), ("X-Test", "unchanged")];
+            if let Some(expected) = expected {
+                headers.push(("Strict-Transport-Security", expected));
+            }
+            assert_headers(result.session().response_written().unwrap(), headers);
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn client_hints() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    client_hints:
+                    -
+                        accept-ch: [sec-ch-ua-mobile, Sec-CH-Viewport-Width]
+                        critical-ch: Sec-CH-Prefers-Color-Scheme
+                        include: localhost
+                    -
+                        accept-ch: [dpr, Sec-CH-UA-Mobile]
+                        include: localhost/images/*
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        let session = make_session("https://localhost/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                (
+                    "Accept-CH",
+                    "Sec-CH-UA-Mobile, Sec-CH-Viewport-Width, Sec-CH-Prefers-Color-Scheme",
+                ),
+                ("Critical-CH", "Sec-CH-Prefers-Color-Scheme"),
+            ],
+        );
+
+        let session = make_session("https://localhost/images/image.png").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                (
+                    "Accept-CH",
+                    "Sec-CH-UA-Mobile, Sec-CH-Viewport-Width, DPR, Sec-CH-Prefers-Color-Scheme",
+                ),
+                ("Critical-CH", "Sec-CH-Prefers-Color-Scheme"),
+            ],
+        );
+
+        let session = make_session("https://example.com/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![("X-Me", "none"), ("X-Test", "unchanged")],
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn cross_origin() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    cross_origin:
+                    -
+                        opener-policy: same-origin
+                        embedder-policy: require-corp
+                        resource-policy: same-site
+                        include: localhost
+                    -
+                        resource-policy: Cross-Origin
+                        include: localhost/public/*
+                    -
+                        embedder-policy: credentialless
+                        include: example.com
+                    -
+                        opener-policy: same-site
+                        include: example.net
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        for (path, expected) in [
+            (
+                "https://localhost/",
+                vec![
+                    ("Cross-Origin-Opener-Policy", "same-origin"),
+                    ("Cross-Origin-Embedder-Policy", "require-corp"),
+                    ("Cross-Origin-Resource-Policy", "same-site"),
+                ],
+            ),
+            (
+                "https://localhost/public/file.js",
+                vec![
+                    ("Cross-Origin-Opener-Policy", "same-origin"),
+                    ("Cross-Origin-Embedder-Policy", "require-corp"),
+                    ("Cross-Origin-Resource-Policy", "cross-origin"),
+                ],
+            ),
+            (
+                "https://example.com/",
+                vec![("Cross-Origin-Embedder-Policy", "credentialless")],
+            ),
+            // same-site isn’t valid for Cross-Origin-Opener-Policy
+            ("https://example.net/", vec![]),
+        ] {
+            let session = make_session(path).await;
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none());
+
+            let mut headers = vec![("X-Me", "none"), ("X-Test", "unchanged")];
+            headers.extend(expected);
+            assert_headers(result.session().response_written().unwrap(), headers);
+        }
+
+        // Unknown keywords are rejected
+        assert!(<Handler as RequestFilter>::Conf::from_yaml(
+            r#"
+            response_headers:
+                cross_origin:
+                    opener-policy: same-origin-allow-everything
+            "#,
+        )
+        .is_err());
+    }
 }
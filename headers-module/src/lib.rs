@@ -17,5 +17,6 @@
 pub mod configuration;
 mod deserialize;
 mod handler;
+mod template;
 
 pub use handler::HeadersHandler;
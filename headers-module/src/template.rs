@@ -0,0 +1,110 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Placeholder substitution in custom header values like `X-Request-Host: ${host}`
+
+use http::HeaderValue;
+
+/// Placeholders that are substituted for each request
+const REQUEST_PLACEHOLDERS: &[&str] = &["host", "path", "scheme", "method"];
+
+/// Request-specific values that placeholders are replaced with
+#[derive(Debug)]
+pub(crate) struct TemplateContext<'a> {
+    pub(crate) host: &'a str,
+    pub(crate) path: &'a str,
+    pub(crate) scheme: &'a str,
+    pub(crate) method: &'a str,
+}
+
+impl TemplateContext<'_> {
+    fn resolve(&self, name: &str) -> Option<&str> {
+        match name {
+            "host" => Some(self.host),
+            "path" => Some(self.path),
+            "scheme" => Some(self.scheme),
+            "method" => Some(self.method),
+            _ => None,
+        }
+    }
+}
+
+/// Processes the placeholders in a value. `resolve` determines the replacement for a placeholder
+/// name, `None` keeps the placeholder unchanged. Escaped placeholders like `$${host}` are only
+/// unescaped if `unescape` is `true`.
+fn process(
+    value: &str,
+    unescape: bool,
+    mut resolve: impl FnMut(&str) -> Result<Option<String>, String>,
+) -> Result<String, String> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            result.push_str(&rest[..if unescape { start - 1 } else { start }]);
+            result.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+            return Err(format!("unterminated placeholder in `{value}`"));
+        };
+        result.push_str(&rest[..start]);
+        match resolve(&rest[start + 2..end])? {
+            Some(replacement) => result.push_str(&replacement),
+            None => result.push_str(&rest[start..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Prepares a configured header value: environment variable placeholders like `${env.HOME}` are
+/// resolved, request placeholders are validated and kept for [`substitute`].
+pub(crate) fn prepare(value: &str) -> Result<String, String> {
+    process(value, false, |name| {
+        if let Some(variable) = name.strip_prefix("env.") {
+            std::env::var(variable)
+                // Make sure the variable value isn’t interpreted as placeholder later
+                .map(|value| Some(value.replace("${", "$${")))
+                .map_err(|_| format!("environment variable `{variable}` is not set"))
+        } else if REQUEST_PLACEHOLDERS.contains(&name) {
+            Ok(None)
+        } else {
+            Err(format!("unknown placeholder `${{{name}}}`"))
+        }
+    })
+}
+
+/// Checks whether a header value contains placeholders (or escaped placeholders) to be processed
+/// for each request.
+pub(crate) fn has_placeholders(value: &HeaderValue) -> bool {
+    value.as_bytes().windows(2).any(|window| window == b"${")
+}
+
+/// Replaces request placeholders in a header value. Returns `None` if the result isn’t a valid
+/// header value.
+pub(crate) fn substitute(
+    value: &HeaderValue,
+    context: &TemplateContext<'_>,
+) -> Option<HeaderValue> {
+    let value = value.to_str().ok()?;
+    let result = process(value, true, |name| {
+        Ok(context.resolve(name).map(str::to_owned))
+    })
+    .ok()?;
+    HeaderValue::from_str(&result).ok()
+}
@@ -0,0 +1,191 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![doc = include_str!("../README.md")]
+
+use async_trait::async_trait;
+use http::status::StatusCode;
+use log::debug;
+use pandora_module_utils::pingora::{Error, SessionWrapper};
+use pandora_module_utils::standard_response::redirect_response;
+use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
+
+/// Configuration file settings of the lowercase module
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct LowercaseConf {
+    /// If `true`, requests to paths containing uppercase letters will be redirected to the
+    /// lowercase path.
+    pub lowercase_paths: bool,
+}
+
+/// Lowercase module handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LowercaseHandler {
+    conf: LowercaseConf,
+}
+
+impl TryFrom<LowercaseConf> for LowercaseHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: LowercaseConf) -> Result<Self, Self::Error> {
+        Ok(Self { conf })
+    }
+}
+
+/// Converts ASCII letters in a path to lowercase, leaving percent-encoded octets unchanged.
+/// Returns `None` if the path is lowercase already.
+fn to_lowercase(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut changed = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && bytes.get(i + 1).is_some_and(u8::is_ascii_hexdigit)
+            && bytes.get(i + 2).is_some_and(u8::is_ascii_hexdigit)
+        {
+            result.extend_from_slice(&bytes[i..i + 3]);
+            i += 3;
+        } else {
+            changed |= bytes[i].is_ascii_uppercase();
+            result.push(bytes[i].to_ascii_lowercase());
+            i += 1;
+        }
+    }
+
+    if changed {
+        // Only ASCII characters have been changed, so this is still valid UTF-8
+        String::from_utf8(result).ok()
+    } else {
+        None
+    }
+}
+
+#[async_trait]
+impl RequestFilter for LowercaseHandler {
+    type Conf = LowercaseConf;
+
+    type CTX = ();
+
+    fn new_ctx() -> Self::CTX {}
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        if !self.conf.lowercase_paths {
+            return Ok(RequestFilterResult::Unhandled);
+        }
+
+        let uri = session.uri();
+        let mut location = if let Some(path) = to_lowercase(uri.path()) {
+            path
+        } else {
+            return Ok(RequestFilterResult::Unhandled);
+        };
+
+        if let Some(query) = uri.query() {
+            location.push('?');
+            location.push_str(query);
+        }
+
+        if let Some(prefix) = session
+            .original_uri()
+            .path()
+            .strip_suffix(uri.path())
+            .filter(|p| !p.is_empty())
+        {
+            // A prefix has been removed from the original URI, insert it for the redirect.
+            location.insert_str(0, prefix);
+        }
+
+        debug!("redirecting to lowercase URI: {location}");
+        redirect_response(session, StatusCode::MOVED_PERMANENTLY, &location).await?;
+        Ok(RequestFilterResult::ResponseSent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http::header;
+    use pandora_module_utils::pingora::{create_test_session, ErrorType, RequestHeader, Session};
+    use pandora_module_utils::FromYaml;
+    use startup_module::DefaultApp;
+    use test_log::test;
+
+    fn make_app(conf: &str) -> DefaultApp<LowercaseHandler> {
+        DefaultApp::new(
+            <LowercaseHandler as RequestFilter>::Conf::from_yaml(conf)
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    async fn make_session(path: &str) -> Session {
+        let header = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+        create_test_session(header).await
+    }
+
+    #[test]
+    fn lowercasing() {
+        assert_eq!(to_lowercase("/about/team.html"), None);
+        assert_eq!(to_lowercase("/%C3%84/%2F"), None);
+        assert_eq!(
+            to_lowercase("/About/Team.HTML").as_deref(),
+            Some("/about/team.html")
+        );
+        assert_eq!(to_lowercase("/A%C3%84%4").as_deref(), Some("/a%C3%84%4"));
+        assert_eq!(to_lowercase("/%4G%zz").as_deref(), Some("/%4g%zz"));
+    }
+
+    #[test(tokio::test)]
+    async fn redirect() {
+        let mut app = make_app("lowercase_paths: true");
+
+        let session = make_session("/About/%C3%84Team.html?Lang=EN").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        let session = result.session();
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, 301);
+        assert_eq!(
+            response.headers.get(header::LOCATION).unwrap(),
+            "/about/%C3%84team.html?Lang=EN"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn no_redirect() {
+        let mut app = make_app("lowercase_paths: true");
+        let session = make_session("/about/%C3%84team.html?Lang=EN").await;
+        let result = app.handle_request(session).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(404))
+        );
+
+        // Module is disabled by default
+        let mut app = make_app("{}");
+        let session = make_session("/About/Team.html").await;
+        let result = app.handle_request(session).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(404))
+        );
+    }
+}
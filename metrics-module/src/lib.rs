@@ -0,0 +1,222 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![doc = include_str!("../README.md")]
+
+use async_trait::async_trait;
+use http::{header, status::StatusCode};
+use pandora_module_utils::metrics::{registry, Counter};
+use pandora_module_utils::pingora::{Error, ResponseHeader, SessionWrapper};
+use pandora_module_utils::standard_response::send_response;
+use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
+
+/// Configuration file settings of the metrics module
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct MetricsConf {
+    /// The URI path where metrics are exposed
+    pub metrics_path: Option<String>,
+
+    /// If `true`, the request counter is split up into shards updated by different threads
+    /// independently, avoiding contention under high load
+    pub metrics_sharded_counters: bool,
+}
+
+/// Metrics module handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsHandler {
+    metrics_path: Option<String>,
+    requests: Counter,
+}
+
+impl TryFrom<MetricsConf> for MetricsHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: MetricsConf) -> Result<Self, Self::Error> {
+        let shards = if conf.metrics_sharded_counters {
+            std::thread::available_parallelism().map_or(1, |threads| threads.get())
+        } else {
+            1
+        };
+        Ok(Self {
+            metrics_path: conf.metrics_path,
+            requests: registry().sharded_counter(
+                "pandora_requests_total",
+                "Number of requests received",
+                shards,
+            )?,
+        })
+    }
+}
+
+#[async_trait]
+impl RequestFilter for MetricsHandler {
+    type Conf = MetricsConf;
+
+    type CTX = ();
+
+    fn new_ctx() -> Self::CTX {}
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        self.requests.inc();
+
+        if self.metrics_path.as_deref() != Some(session.req_header().uri.path()) {
+            return Ok(RequestFilterResult::Unhandled);
+        }
+
+        let text = registry().render();
+
+        let mut header = ResponseHeader::build(StatusCode::OK, Some(2))?;
+        header.append_header(
+            header::CONTENT_TYPE,
+            "text/plain;version=0.0.4;charset=utf-8",
+        )?;
+
+        send_response(session, header, text).await?;
+
+        Ok(RequestFilterResult::ResponseSent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{create_test_session, ErrorType, RequestHeader, Session};
+    use pandora_module_utils::FromYaml;
+    use startup_module::DefaultApp;
+    use test_log::test;
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    struct TestConf {
+        test_counter: String,
+    }
+
+    #[derive(Debug)]
+    struct TestHandler {
+        counter: Counter,
+    }
+
+    impl TryFrom<TestConf> for TestHandler {
+        type Error = Box<Error>;
+
+        fn try_from(conf: TestConf) -> Result<Self, Self::Error> {
+            Ok(Self {
+                counter: registry().counter(&conf.test_counter, "Test requests")?,
+            })
+        }
+    }
+
+    #[async_trait]
+    impl RequestFilter for TestHandler {
+        type Conf = TestConf;
+
+        type CTX = ();
+
+        fn new_ctx() -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            _session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<RequestFilterResult, Box<Error>> {
+            self.counter.inc();
+            Ok(RequestFilterResult::Unhandled)
+        }
+    }
+
+    #[derive(Debug, RequestFilter)]
+    struct Handler {
+        metrics: MetricsHandler,
+        test: TestHandler,
+    }
+
+    fn make_app(conf: &str) -> DefaultApp<Handler> {
+        DefaultApp::new(
+            <Handler as RequestFilter>::Conf::from_yaml(conf)
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    async fn make_session(method: &str, path: &str) -> Session {
+        let header = RequestHeader::build(method, path.as_bytes(), None).unwrap();
+        create_test_session(header).await
+    }
+
+    #[test(tokio::test)]
+    async fn unconfigured() {
+        let mut app = make_app("test_counter: unconfigured_requests_total");
+
+        let session = make_session("GET", "/metrics").await;
+        let result = app.handle_request(session).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(404))
+        );
+        assert_eq!(
+            registry()
+                .counter("unconfigured_requests_total", "")
+                .unwrap()
+                .get(),
+            1
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn custom_counter() {
+        let mut app = make_app(
+            r#"
+                metrics_path: /metrics
+                test_counter: custom_requests_total
+            "#,
+        );
+
+        let session = make_session("GET", "/file.txt").await;
+        let result = app.handle_request(session).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(404))
+        );
+
+        let session = make_session("GET", "/metrics").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        let body = result.body_str().into_owned();
+        assert!(body.contains(
+            "# HELP custom_requests_total Test requests\n\
+             # TYPE custom_requests_total counter\n\
+             custom_requests_total 1\n"
+        ));
+        assert!(body.contains("# TYPE pandora_requests_total counter\n"));
+
+        let session = result.session();
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.headers.get(header::CONTENT_LENGTH).unwrap(),
+            &body.len().to_string()
+        );
+
+        // HEAD requests get no body
+        let session = make_session("HEAD", "/metrics").await;
+        let result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(result.body_str(), "");
+    }
+}
@@ -14,8 +14,9 @@
 
 use proc_macro::TokenStream;
 use proc_macro2::{Span, TokenStream as TokenStream2};
-use quote::quote;
+use quote::{format_ident, quote};
 use serde_derive_internals::attr::RenameRule;
+use syn::ext::IdentExt;
 use syn::{spanned::Spanned, DeriveInput, Error, Field, FieldsNamed, Ident, LitStr, Path, Type};
 
 use crate::utils::{generics_with_de, get_fields, type_name_short, where_clause};
@@ -59,6 +60,9 @@ impl TryFrom<&DeriveInput> for ContainerAttributes {
                     rename_all = RenameRule::from_str(&lit.value())
                         .map_err(|_| Error::new_spanned(lit, "invalid rename_all value"))?;
                     Ok(())
+                } else if meta.path.is_ident("deny_unknown_fields") {
+                    // Unknown fields are always rejected, accept this for Serde compatibility
+                    Ok(())
                 } else if meta.path.is_ident("crate") {
                     if crate_path.is_some() {
                         return Err(Error::new_spanned(meta.path, "duplicate crate"));
@@ -93,6 +97,7 @@ struct FieldAttributes {
     deserialize_name: Vec<LitStr>,
     deserialize: TokenStream2,
     flatten: bool,
+    default: Option<Path>,
 }
 
 impl FieldAttributes {
@@ -102,6 +107,7 @@ impl FieldAttributes {
         let mut skip = false;
         let mut deserialize_with = None;
         let mut flatten = false;
+        let mut default = None;
 
         let name = if let Some(name) = &field.ident {
             name.clone()
@@ -145,6 +151,13 @@ impl FieldAttributes {
                 } else if meta.path.is_ident("flatten") {
                     flatten = true;
                     Ok(())
+                } else if meta.path.is_ident("default") {
+                    if default.is_some() {
+                        return Err(Error::new_spanned(meta.path, "duplicate default"));
+                    }
+                    let s: LitStr = meta.value()?.parse()?;
+                    default = Some(s.parse_with(Path::parse_mod_style)?);
+                    Ok(())
                 } else if meta.path.is_ident("deserialize_with")
                     || meta.path.is_ident("deserialize_with_seed")
                     || meta.path.is_ident("with")
@@ -191,6 +204,12 @@ impl FieldAttributes {
                     "deserialize_with is incompatible with flatten",
                 ));
             }
+            if let Some(default) = default {
+                return Err(Error::new_spanned(
+                    default,
+                    "default is incompatible with flatten",
+                ));
+            }
         }
 
         let ty = field.ty.clone();
@@ -219,8 +238,16 @@ impl FieldAttributes {
             deserialize_name,
             deserialize,
             flatten,
+            default,
         })
     }
+
+    /// Name of the visitor field recording whether a field with a custom default was present.
+    fn present_name(&self) -> Option<Ident> {
+        self.default
+            .as_ref()
+            .map(|_| format_ident!("__{}_present", self.name.unraw()))
+    }
 }
 
 fn collect_deserialize_names<'a>(attrs: &[&'a FieldAttributes]) -> Result<Vec<&'a LitStr>, Error> {
@@ -251,6 +278,12 @@ fn generate_deserialize_map_impl(
             None
         } else if attrs.flatten {
             Some(quote! {#crate_path::DeserializeMap<#de>})
+        } else if attrs.default.is_some() {
+            Some(quote! {
+                #crate_path::serde::Deserialize<#de>
+                    + ::std::default::Default
+                    + ::std::cmp::PartialEq
+            })
         } else {
             Some(quote! {#crate_path::serde::Deserialize<#de>})
         }
@@ -286,12 +319,27 @@ fn generate_deserialize_map_impl(
     });
     let finalize = field_attrs.iter().map(|attr| {
         let field_name = &attr.name;
+        let ty = &attr.ty;
         if attr.flatten {
             quote! {self.#field_name.finalize()?}
+        } else if let (Some(default), Some(present_name)) = (&attr.default, attr.present_name()) {
+            quote! {
+                if !self.#present_name
+                    && self.#field_name == <#ty as ::std::default::Default>::default()
+                {
+                    #default()
+                } else {
+                    self.#field_name
+                }
+            }
         } else {
             quote! {self.#field_name}
         }
     });
+    let present_name = field_attrs
+        .iter()
+        .filter_map(|attr| attr.present_name())
+        .collect::<Vec<_>>();
 
     let flattened_name = field_attrs
         .iter()
@@ -313,6 +361,10 @@ fn generate_deserialize_map_impl(
         .collect::<Vec<_>>();
     let regular_deserialize_name = regular_fields.iter().map(|attr| &attr.deserialize_name);
     let regular_deserialize = regular_fields.iter().map(|attr| &attr.deserialize);
+    let regular_mark_present = regular_fields.iter().map(|attr| {
+        attr.present_name()
+            .map(|present_name| quote! {self.#present_name = true;})
+    });
     let deserialize_name = collect_deserialize_names(&regular_fields)?;
 
     Ok(quote! {
@@ -327,6 +379,9 @@ fn generate_deserialize_map_impl(
                 #(
                     #field_name: #inner_type,
                 )*
+                #(
+                    #present_name: ::std::primitive::bool,
+                )*
                 __marker: ::std::marker::PhantomData<&#de ()>,
             }
 
@@ -363,6 +418,7 @@ fn generate_deserialize_map_impl(
                         #(
                             #(#regular_deserialize_name)|* => {
                                 self.#regular_name = #regular_deserialize?;
+                                #regular_mark_present
                                 ::std::result::Result::Ok(self)
                             }
                         )*
@@ -414,6 +470,9 @@ fn generate_deserialize_map_impl(
                         #(
                             #field_name: #init,
                         )*
+                        #(
+                            #present_name: false,
+                        )*
                         __marker: ::std::marker::PhantomData,
                     }
                 }
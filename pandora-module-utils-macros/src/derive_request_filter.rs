@@ -54,6 +54,10 @@ fn generate_request_filter_impl(
         .iter()
         .map(|field| field.ident.as_ref())
         .collect::<Vec<_>>();
+    let field_name_str = field_name
+        .iter()
+        .map(|name| name.map(ToString::to_string))
+        .collect::<Vec<_>>();
     let field_type = fields
         .named
         .iter()
@@ -141,6 +145,35 @@ fn generate_request_filter_impl(
                     ::std::result::Result::Ok(pandora_module_utils::RequestFilterResult::Unhandled)
                 }
 
+                async fn request_filter_with_trace(
+                    &self,
+                    _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
+                    _ctx: &mut Self::CTX,
+                ) -> ::std::result::Result<
+                    (
+                        ::pandora_module_utils::RequestFilterResult,
+                        ::std::option::Option<&'static str>,
+                    ),
+                    ::std::boxed::Box<::pandora_module_utils::pingora::Error>
+                >
+                where
+                    Self::CTX: ::std::marker::Send,
+                {
+                    #(
+                        let result = self.#field_name.request_filter(_session, &mut _ctx.#field_name).await?;
+                        if result != ::pandora_module_utils::RequestFilterResult::Unhandled {
+                            return ::std::result::Result::Ok((
+                                result,
+                                ::std::option::Option::Some(#field_name_str),
+                            ));
+                        }
+                    )*
+                    ::std::result::Result::Ok((
+                        pandora_module_utils::RequestFilterResult::Unhandled,
+                        ::std::option::Option::None,
+                    ))
+                }
+
                 async fn upstream_peer(
                     &self,
                     _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
@@ -160,6 +193,22 @@ fn generate_request_filter_impl(
                     ::std::result::Result::Ok(::std::option::Option::None)
                 }
 
+                async fn response_filter(
+                    &self,
+                    _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
+                    _upstream_response: &mut ::pandora_module_utils::pingora::ResponseHeader,
+                    _ctx: &mut Self::CTX,
+                ) -> ::std::result::Result<
+                    (),
+                    ::std::boxed::Box<::pandora_module_utils::pingora::Error>
+                >
+                {
+                    #(
+                        self.#field_name.response_filter(_session, _upstream_response, &mut _ctx.#field_name).await?;
+                    )*
+                    ::std::result::Result::Ok(())
+                }
+
                 async fn logging(
                     &self,
                     _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
@@ -224,6 +224,15 @@ pub fn derive_request_filter(input: TokenStream) -> TokenStream {
 ///
 ///   Same as `deserialize_with` but `$module::deserialize` will be used as the `deserialize_with`
 ///   function.
+/// * `#[pandora(default = "path")]`
+///
+///   Call the given function to produce the value of this field if it is missing, rather than
+///   leaving it at the value produced by the structure’s `Default` implementation. The function
+///   must be callable as `fn() -> T`, and the field type has to implement `Default` and
+///   `PartialEq`. As configurations can be merged, the function is only called if the field still
+///   has its `Default` value after deserialization. A configured value replaces the default
+///   value rather than being merged into it. Note that a value identical to the `Default` value
+///   is considered missing when merging a configuration that doesn’t set the field.
 ///
 /// In addition, the following analogs of [Serde’s container
 /// attributes](https://serde.rs/container-attrs.html) are currently supported:
@@ -235,6 +244,10 @@ pub fn derive_request_filter(input: TokenStream) -> TokenStream {
 ///   `"lowercase"`, `"UPPERCASE"`, `"PascalCase"`, `"camelCase"`, `"snake_case"`,
 ///   `"SCREAMING_SNAKE_CASE"`, `"kebab-case"`, `"SCREAMING-KEBAB-CASE"`. The field’s individual
 ///   `rename` attribute takes precedence.
+/// * `#[pandora(deny_unknown_fields)]`
+///
+///   Reject unknown fields during deserialization. This is always the default behavior, the
+///   attribute is merely accepted for compatibility with Serde.
 /// * `#[pandora(crate = "path")]`
 ///
 ///   Specify a path to the `pandora_module_utils` crate instance to use when referring to APIs
@@ -14,7 +14,7 @@
 
 use async_trait::async_trait;
 use pandora_module_utils::pingora::{
-    create_test_session, Error, ErrorType, RequestHeader, SessionWrapper,
+    create_test_session, Error, ErrorType, HttpPeer, RequestHeader, ResponseHeader, SessionWrapper,
 };
 use pandora_module_utils::serde::{Deserialize, Deserializer};
 use pandora_module_utils::{
@@ -25,14 +25,20 @@ use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
 use test_log::test;
 
+/// Records the handlers that ran during the logging phase in session extensions
+#[derive(Debug, Default, Clone)]
+struct Logged(Vec<String>);
+
 #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
 struct Handler1Conf {
     handle_request: bool,
+    use_upstream: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct Handler1 {
     handle_request: bool,
+    use_upstream: bool,
 }
 
 impl TryFrom<Handler1Conf> for Handler1 {
@@ -41,6 +47,7 @@ impl TryFrom<Handler1Conf> for Handler1 {
     fn try_from(conf: Handler1Conf) -> Result<Self, Self::Error> {
         Ok(Self {
             handle_request: conf.handle_request,
+            use_upstream: conf.use_upstream,
         })
     }
 }
@@ -63,6 +70,45 @@ impl RequestFilter for Handler1 {
             RequestFilterResult::Unhandled
         })
     }
+
+    async fn upstream_peer(
+        &self,
+        _session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<Option<Box<HttpPeer>>, Box<Error>> {
+        Ok(if self.use_upstream {
+            Some(Box::new(HttpPeer::new(
+                "127.0.0.1:80",
+                false,
+                String::new(),
+            )))
+        } else {
+            None
+        })
+    }
+
+    async fn response_filter(
+        &self,
+        _session: &mut impl SessionWrapper,
+        upstream_response: &mut ResponseHeader,
+        _ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        upstream_response.append_header("X-Handler", "handler1")?;
+        Ok(())
+    }
+
+    async fn logging(
+        &self,
+        session: &mut impl SessionWrapper,
+        e: Option<&Error>,
+        _ctx: &mut Self::CTX,
+    ) {
+        session
+            .extensions_mut()
+            .get_or_insert_default::<Logged>()
+            .0
+            .push(format!("handler1 error:{}", e.is_some()));
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
@@ -123,6 +169,33 @@ impl<T: Default + Sync, U: Default + Sync> RequestFilter for Handler2<T, U> {
         ctx.value1 = self.conf.value3;
         Ok(RequestFilterResult::Unhandled)
     }
+
+    async fn response_filter(
+        &self,
+        _session: &mut impl SessionWrapper,
+        upstream_response: &mut ResponseHeader,
+        _ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        upstream_response.append_header("X-Handler", "handler2")?;
+        Ok(())
+    }
+
+    async fn logging(
+        &self,
+        session: &mut impl SessionWrapper,
+        e: Option<&Error>,
+        ctx: &mut Self::CTX,
+    ) {
+        session
+            .extensions_mut()
+            .get_or_insert_default::<Logged>()
+            .0
+            .push(format!(
+                "handler2 error:{} value1:{}",
+                e.is_some(),
+                ctx.value1
+            ));
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
@@ -197,6 +270,165 @@ async fn handler() -> Result<(), Box<Error>> {
     Ok(())
 }
 
+#[test(tokio::test)]
+async fn scheme_filter() -> Result<(), Box<Error>> {
+    use pandora_module_utils::scheme::SchemeFilter;
+
+    #[derive(Debug, RequestFilter)]
+    struct Handler {
+        https_only: SchemeFilter<Handler1>,
+    }
+
+    async fn handled(app: &mut DefaultApp<Handler>, forwarded_proto: Option<&str>) -> bool {
+        let mut header = RequestHeader::build("GET", "/".as_bytes(), None).unwrap();
+        if let Some(forwarded_proto) = forwarded_proto {
+            header
+                .insert_header("X-Forwarded-Proto", forwarded_proto)
+                .unwrap();
+        }
+        let session = create_test_session(header).await;
+        app.handle_request(session).await.err().is_none()
+    }
+
+    let conf = <Handler as RequestFilter>::Conf::from_yaml(
+        r#"
+            handle_request: true
+            only_scheme: https
+            trust_forwarded_proto: true
+        "#,
+    )?;
+    let mut app = DefaultApp::new(Handler::try_from(conf)?);
+    assert!(handled(&mut app, Some("https")).await);
+    assert!(handled(&mut app, Some("HTTPS, http")).await);
+    assert!(!handled(&mut app, Some("http")).await);
+    assert!(!handled(&mut app, None).await);
+
+    // Forwarded scheme is ignored unless explicitly trusted
+    let conf = <Handler as RequestFilter>::Conf::from_yaml(
+        r#"
+            handle_request: true
+            only_scheme: https
+        "#,
+    )?;
+    let mut app = DefaultApp::new(Handler::try_from(conf)?);
+    assert!(!handled(&mut app, Some("https")).await);
+
+    let conf = <Handler as RequestFilter>::Conf::from_yaml(
+        r#"
+            handle_request: true
+            only_scheme: http
+        "#,
+    )?;
+    let mut app = DefaultApp::new(Handler::try_from(conf)?);
+    assert!(handled(&mut app, Some("https")).await);
+    assert!(handled(&mut app, None).await);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn request_filter_with_trace() -> Result<(), Box<Error>> {
+    let header = RequestHeader::build("GET", "/".as_bytes(), None)?;
+    let session = create_test_session(header).await;
+
+    let conf = <Handler<String, u32> as RequestFilter>::Conf::default();
+    let mut handler = Handler::<String, u32>::try_from(conf).unwrap();
+    let mut result = DefaultApp::new(handler.clone())
+        .handle_request(session)
+        .await;
+    let mut session = result.session();
+    let mut ctx = <Handler<String, u32> as RequestFilter>::new_ctx();
+
+    assert_eq!(
+        handler
+            .request_filter_with_trace(&mut session, &mut ctx)
+            .await?,
+        (RequestFilterResult::Unhandled, None)
+    );
+
+    handler.handler1.handle_request = true;
+    assert_eq!(
+        handler
+            .request_filter_with_trace(&mut session, &mut ctx)
+            .await?,
+        (RequestFilterResult::ResponseSent, Some("handler1"))
+    );
+
+    // Handlers that aren't composed of other handlers don't report a name
+    assert_eq!(
+        handler
+            .handler1
+            .request_filter_with_trace(&mut session, &mut ())
+            .await?,
+        (RequestFilterResult::ResponseSent, None)
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn logging() -> Result<(), Box<Error>> {
+    let header = RequestHeader::build("GET", "/".as_bytes(), None)?;
+    let session = create_test_session(header).await;
+
+    let conf = <Handler<String, u32> as RequestFilter>::Conf::default();
+    let handler = Handler::<String, u32>::try_from(conf).unwrap();
+    let mut app = DefaultApp::new(handler);
+
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_some());
+    assert_eq!(
+        result.session().extensions().get::<Logged>().unwrap().0,
+        vec!["handler2 error:true value1:1234", "handler1 error:true"]
+    );
+
+    let header = RequestHeader::build("GET", "/".as_bytes(), None)?;
+    let session = create_test_session(header).await;
+
+    let conf = <Handler<String, u32> as RequestFilter>::Conf::default();
+    let mut handler = Handler::<String, u32>::try_from(conf).unwrap();
+    handler.handler1.handle_request = true;
+    let mut app = DefaultApp::new(handler);
+
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_eq!(
+        result.session().extensions().get::<Logged>().unwrap().0,
+        vec!["handler2 error:false value1:1234", "handler1 error:false"]
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn response_filter() -> Result<(), Box<Error>> {
+    let header = RequestHeader::build("GET", "/".as_bytes(), None)?;
+    let session = create_test_session(header).await;
+
+    let conf = <Handler<String, u32> as RequestFilter>::Conf::default();
+    let mut handler = Handler::<String, u32>::try_from(conf).unwrap();
+    handler.handler1.use_upstream = true;
+    let mut app = DefaultApp::new(handler);
+
+    let mut result = app
+        .handle_request_with_upstream(session, |_, _| ResponseHeader::build(200, None))
+        .await;
+    assert!(result.err().is_none());
+
+    let session = result.session();
+    let values = session
+        .response_written()
+        .unwrap()
+        .headers
+        .get_all("X-Handler")
+        .iter()
+        .map(|value| value.to_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(values, vec!["handler2", "handler1"]);
+
+    Ok(())
+}
+
 #[test]
 fn container_attributes() {
     #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
@@ -242,6 +474,42 @@ fn container_attributes() {
     assert_eq!(&conf.string_value2, "3");
 }
 
+#[test]
+fn unknown_fields() {
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    #[pandora(deny_unknown_fields, rename_all = "kebab-case")]
+    struct Conf {
+        max_age: u32,
+        #[pandora(rename = "Public")]
+        public: bool,
+    }
+
+    let conf = Conf::from_yaml(
+        r#"
+            max-age: 300
+            Public: true
+        "#,
+    )
+    .unwrap();
+    assert_eq!(conf.max_age, 300);
+    assert!(conf.public);
+
+    let err = Conf::from_yaml(
+        r#"
+            max_agee: 300
+        "#,
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(
+        err.contains("unknown field `max_agee`, expected one of `Public`, `max-age`"),
+        "{err}"
+    );
+
+    // Renamed fields are only accepted under their new name
+    assert!(Conf::from_yaml("public: true").is_err());
+}
+
 #[test]
 fn field_attributes() {
     use pandora_module_utils::serde::{de::Deserializer, Deserialize};
@@ -336,6 +604,51 @@ fn field_attributes() {
     assert_eq!(conf.value6.value, String::new());
 }
 
+#[test]
+fn field_defaults() {
+    fn default_index_files() -> Vec<String> {
+        vec!["index.html".to_owned()]
+    }
+
+    fn default_level() -> u32 {
+        6
+    }
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    struct Conf {
+        #[pandora(default = "default_index_files")]
+        index_files: Vec<String>,
+        #[pandora(default = "default_level", alias = "level")]
+        compression_level: u32,
+        enabled: bool,
+    }
+
+    let conf = Conf::from_yaml("enabled: true").unwrap();
+    assert_eq!(conf.index_files, vec!["index.html".to_owned()]);
+    assert_eq!(conf.compression_level, 6);
+    assert!(conf.enabled);
+
+    // The structure’s own `Default` implementation doesn’t use the default functions
+    assert_eq!(Conf::default().index_files, Vec::<String>::new());
+    assert_eq!(Conf::default().compression_level, 0);
+
+    let conf = Conf::from_yaml(
+        r#"
+            index_files: [index.htm]
+            level: 0
+        "#,
+    )
+    .unwrap();
+    assert_eq!(conf.index_files, vec!["index.htm".to_owned()]);
+    assert_eq!(conf.compression_level, 0);
+    assert!(!conf.enabled);
+
+    // Non-default values set by an earlier configuration are kept
+    let conf = conf.merge_from_yaml("enabled: true").unwrap();
+    assert_eq!(conf.index_files, vec!["index.htm".to_owned()]);
+    assert!(conf.enabled);
+}
+
 #[test]
 fn from_yaml_seed() {
     fn assert_hash_eq<V: Debug + Eq>(left: &HashMap<String, V>, right: Vec<(&str, V)>) {
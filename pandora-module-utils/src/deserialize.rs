@@ -16,7 +16,10 @@
 //! #[serde(flatten)]
 
 use pingora::server::configuration::ServerConf;
-use serde::de::value::{MapAccessDeserializer, StrDeserializer, StringDeserializer};
+use serde::de::value::{
+    BoolDeserializer, BorrowedStrDeserializer, CharDeserializer, F64Deserializer, I64Deserializer,
+    MapAccessDeserializer, StrDeserializer, StringDeserializer, U64Deserializer,
+};
 use serde::de::{Deserialize, DeserializeSeed, Deserializer, Error, SeqAccess, Visitor};
 use std::fmt::Debug;
 use std::ops::{Deref, DerefMut};
@@ -133,7 +136,8 @@ impl_deserialize_map!(ServerConf {
 /// A wrapper around the `Vec` type allowing more comfortable deserialization.
 ///
 /// If a list is encountered in the configuration file, it is deserialized into `Vec` directly.
-/// String or map values are deserialized as a `Vec` instance with one element instead.
+/// Any other value (string, number, boolean or map) is deserialized as a `Vec` instance with one
+/// element instead, so that `value: a` and `value: [a]` produce the same result.
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct OneOrMany<T> {
     inner: Vec<T>,
@@ -144,6 +148,16 @@ impl<T> OneOrMany<T> {
     pub fn into_inner(self) -> Vec<T> {
         self.inner
     }
+
+    /// Returns the number of values
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns `true` if there are no values
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
 }
 
 impl<T> Debug for OneOrMany<T>
@@ -224,6 +238,17 @@ impl<'de, T: Deserialize<'de>> DeserializeSeed<'de> for OneOrMany<T> {
         struct ListVisitor<T> {
             seed: OneOrMany<T>,
         }
+        impl<'de, T: Deserialize<'de>> ListVisitor<T> {
+            /// Adds a single value to the list, used for anything that isn’t a sequence.
+            fn push<D>(self, deserializer: D) -> Result<OneOrMany<T>, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                let mut list = self.seed;
+                list.push(T::deserialize(deserializer)?);
+                Ok(list)
+            }
+        }
         impl<'de, T: Deserialize<'de>> Visitor<'de> for ListVisitor<T> {
             type Value = OneOrMany<T>;
 
@@ -242,40 +267,67 @@ impl<'de, T: Deserialize<'de>> DeserializeSeed<'de> for OneOrMany<T> {
                 Ok(list)
             }
 
+            fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                self.push(BoolDeserializer::new(v))
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                self.push(I64Deserializer::new(v))
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                self.push(U64Deserializer::new(v))
+            }
+
+            fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                self.push(F64Deserializer::new(v))
+            }
+
+            fn visit_char<E>(self, v: char) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                self.push(CharDeserializer::new(v))
+            }
+
             fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
             where
                 E: Error,
             {
-                let mut list = self.seed;
-                list.push(T::deserialize(StringDeserializer::new(v))?);
-                Ok(list)
+                self.push(StringDeserializer::new(v))
             }
 
             fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
             where
                 E: Error,
             {
-                let mut list = self.seed;
-                list.push(T::deserialize(StrDeserializer::new(v))?);
-                Ok(list)
+                self.push(StrDeserializer::new(v))
             }
 
             fn visit_borrowed_str<E>(self, v: &'de str) -> Result<Self::Value, E>
             where
                 E: Error,
             {
-                let mut list = self.seed;
-                list.push(T::deserialize(StrDeserializer::new(v))?);
-                Ok(list)
+                self.push(BorrowedStrDeserializer::new(v))
             }
 
             fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
             where
                 A: serde::de::MapAccess<'de>,
             {
-                let mut list = self.seed;
-                list.push(T::deserialize(MapAccessDeserializer::new(map))?);
-                Ok(list)
+                self.push(MapAccessDeserializer::new(map))
             }
         }
 
@@ -521,6 +573,37 @@ mod tests {
         assert_eq!(&*conf.value, &vec!["hi".to_owned(), "another".to_owned()]);
     }
 
+    #[test]
+    fn one_or_many_iteration() {
+        #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+        #[pandora(crate = "crate")]
+        struct Conf {
+            value: OneOrMany<u32>,
+        }
+
+        for (yaml, expected) in [
+            ("{}", vec![]),
+            ("value: []", vec![]),
+            ("value: 1", vec![1]),
+            ("value: [3, 1, 2]", vec![3, 1, 2]),
+        ] {
+            let conf = Conf::from_yaml(yaml).unwrap();
+            assert_eq!(conf.value.len(), expected.len(), "{yaml}");
+            assert_eq!(conf.value.is_empty(), expected.is_empty(), "{yaml}");
+            assert_eq!(
+                conf.value.iter().copied().collect::<Vec<_>>(),
+                expected,
+                "{yaml}"
+            );
+
+            let mut values = Vec::with_capacity(conf.value.len());
+            for value in conf.value {
+                values.push(value);
+            }
+            assert_eq!(values, expected, "{yaml}");
+        }
+    }
+
     #[test]
     fn one_or_many_maps() {
         #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
@@ -579,4 +662,141 @@ mod tests {
             &vec![InnerConf { value: 1 }, InnerConf { value: 2 }]
         );
     }
+
+    #[test]
+    fn one_or_many_scalars() {
+        #[derive(Debug, Default, Clone, PartialEq, DeserializeMap)]
+        #[pandora(crate = "crate")]
+        struct Conf {
+            numbers: OneOrMany<i32>,
+            floats: OneOrMany<f64>,
+            flags: OneOrMany<bool>,
+        }
+
+        let single = Conf::from_yaml(
+            r#"
+                numbers: -12
+                floats: 1.5
+                flags: true
+            "#,
+        )
+        .unwrap();
+        let list = Conf::from_yaml(
+            r#"
+                numbers: [-12]
+                floats: [1.5]
+                flags: [true]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(single, list);
+        assert_eq!(&*single.numbers, &vec![-12]);
+        assert_eq!(&*single.floats, &vec![1.5]);
+        assert_eq!(&*single.flags, &vec![true]);
+
+        let conf = single
+            .merge_from_yaml(
+                r#"
+                    numbers: [3, 4]
+                    flags: false
+                "#,
+            )
+            .unwrap();
+        assert_eq!(&*conf.numbers, &vec![-12, 3, 4]);
+        assert_eq!(&*conf.flags, &vec![true, false]);
+    }
+
+    #[test]
+    fn one_or_many_host_path_matchers() {
+        use crate::merger::HostPathMatcher;
+
+        #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+        #[pandora(crate = "crate")]
+        struct Conf {
+            include: OneOrMany<HostPathMatcher>,
+        }
+
+        let single = Conf::from_yaml("include: localhost/dir/*").unwrap();
+        let list = Conf::from_yaml("include: [localhost/dir/*]").unwrap();
+        assert_eq!(single, list);
+        assert_eq!(
+            &*single.include,
+            &vec![HostPathMatcher::from("localhost/dir/*")]
+        );
+
+        let list = Conf::from_yaml(
+            r#"
+                include:
+                - localhost/dir/*
+                - "!example.com"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            &*list.include,
+            &vec![
+                HostPathMatcher::from("localhost/dir/*"),
+                HostPathMatcher::from("!example.com")
+            ]
+        );
+
+        assert!(Conf::from_yaml("include: \"!\"").is_err());
+    }
+
+    #[test]
+    fn host_path_matcher_priority() {
+        use crate::merger::HostPathMatcher;
+
+        #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+        #[pandora(crate = "crate")]
+        struct Conf {
+            include: OneOrMany<HostPathMatcher>,
+        }
+
+        let conf = Conf::from_yaml(
+            r#"
+                include:
+                - localhost/dir/*
+                - {path: "!localhost/dir/private/*", priority: -1}
+                - path: /static/*
+                  priority: 10
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            &*conf.include,
+            &vec![
+                HostPathMatcher::from("localhost/dir/*"),
+                HostPathMatcher {
+                    priority: -1,
+                    ..HostPathMatcher::from("!localhost/dir/private/*")
+                },
+                HostPathMatcher {
+                    priority: 10,
+                    ..HostPathMatcher::from("/static/*")
+                },
+            ]
+        );
+
+        // Single map entry and map without priority
+        let conf = Conf::from_yaml("include: {path: localhost, priority: 5}").unwrap();
+        assert_eq!(
+            &*conf.include,
+            &vec![HostPathMatcher {
+                priority: 5,
+                ..HostPathMatcher::from("localhost")
+            }]
+        );
+        let conf = Conf::from_yaml("include: [{path: localhost}]").unwrap();
+        assert_eq!(&*conf.include, &vec![HostPathMatcher::from("localhost")]);
+
+        for invalid in [
+            "include: {priority: 5}",
+            "include: {path: localhost, priority: high}",
+            "include: {path: localhost, unknown: 1}",
+            "include: {path: \"!\", priority: 1}",
+        ] {
+            assert!(Conf::from_yaml(invalid).is_err(), "{invalid}");
+        }
+    }
 }
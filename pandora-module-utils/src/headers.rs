@@ -0,0 +1,209 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Helpers for working with HTTP headers
+
+use http::header::{self, HeaderMap, HeaderName};
+
+/// Hop-by-hop headers, these only apply to a single connection and shouldn’t be forwarded, see
+/// <https://datatracker.ietf.org/doc/html/rfc9110#section-7.6.1>
+const HOP_BY_HOP: &[HeaderName] = &[
+    header::CONNECTION,
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// Hop-by-hop headers not defined in the `http` crate
+const HOP_BY_HOP_NAMES: &[&str] = &["keep-alive", "proxy-connection"];
+
+/// Headers which are commonly sent multiple times, with each value being meaningful
+const MULTI_VALUED: &[HeaderName] = &[
+    header::ALLOW,
+    header::LINK,
+    header::SET_COOKIE,
+    header::VARY,
+    header::VIA,
+    header::WARNING,
+    header::WWW_AUTHENTICATE,
+];
+
+/// Checks whether a header is a hop-by-hop header which shouldn’t be passed on, e.g.
+/// `Connection` or `Transfer-Encoding`.
+///
+/// Headers listed in the `Connection` header are hop-by-hop headers as well, this function
+/// cannot detect these.
+pub fn is_hop_by_hop(name: &HeaderName) -> bool {
+    HOP_BY_HOP.contains(name) || HOP_BY_HOP_NAMES.contains(&name.as_str())
+}
+
+/// Determines how [`merge_headers`] handles headers present in both maps
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// Values are appended for headers that are commonly sent multiple times such as
+    /// `Set-Cookie` or `Vary`, for other headers the existing values are replaced.
+    #[default]
+    Auto,
+    /// Existing values are always replaced.
+    Replace,
+    /// Values are always appended to the existing values.
+    Append,
+    /// Existing values are kept, headers are only added if not present yet.
+    Defer,
+}
+
+/// Merges the headers from `from` into `into` according to the policy.
+///
+/// Hop-by-hop headers (see [`is_hop_by_hop`]) as well as any headers listed in the `Connection`
+/// header of `from` are skipped. Existing hop-by-hop headers in `into` are left unchanged.
+pub fn merge_headers(into: &mut HeaderMap, from: &HeaderMap, policy: MergePolicy) {
+    let connection_headers = from
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+
+    for name in from.keys() {
+        if is_hop_by_hop(name) || connection_headers.contains(name) {
+            continue;
+        }
+
+        let append = match policy {
+            MergePolicy::Auto => MULTI_VALUED.contains(name),
+            MergePolicy::Replace => false,
+            MergePolicy::Append => true,
+            MergePolicy::Defer => {
+                if into.contains_key(name) {
+                    continue;
+                }
+                true
+            }
+        };
+
+        if !append {
+            into.remove(name);
+        }
+        for value in from.get_all(name) {
+            into.append(name.clone(), value.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http::HeaderValue;
+
+    fn make_map(headers: &[(&str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        map
+    }
+
+    fn values(map: &HeaderMap, name: &str) -> Vec<String> {
+        map.get_all(name)
+            .iter()
+            .map(|value| value.to_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn hop_by_hop() {
+        let mut into = make_map(&[("Connection", "close"), ("X-Existing", "1")]);
+        let from = make_map(&[
+            ("Connection", "keep-alive, X-Private"),
+            ("Keep-Alive", "timeout=5"),
+            ("Transfer-Encoding", "chunked"),
+            ("Upgrade", "websocket"),
+            ("TE", "trailers"),
+            ("Proxy-Connection", "keep-alive"),
+            ("X-Private", "secret"),
+            ("X-Public", "visible"),
+        ]);
+        merge_headers(&mut into, &from, MergePolicy::Append);
+
+        assert_eq!(values(&into, "Connection"), ["close"]);
+        assert_eq!(values(&into, "X-Existing"), ["1"]);
+        assert_eq!(values(&into, "X-Public"), ["visible"]);
+        for name in [
+            "Keep-Alive",
+            "Transfer-Encoding",
+            "Upgrade",
+            "TE",
+            "Proxy-Connection",
+            "X-Private",
+        ] {
+            assert!(!into.contains_key(name), "{name}");
+        }
+
+        assert!(is_hop_by_hop(&header::TRANSFER_ENCODING));
+        assert!(is_hop_by_hop(&HeaderName::from_static("keep-alive")));
+        assert!(!is_hop_by_hop(&header::CONTENT_TYPE));
+    }
+
+    #[test]
+    fn policies() {
+        let existing = make_map(&[
+            ("Set-Cookie", "a=1"),
+            ("Content-Type", "text/plain"),
+            ("X-Custom", "old"),
+        ]);
+        let from = make_map(&[
+            ("Set-Cookie", "b=2"),
+            ("Set-Cookie", "c=3"),
+            ("Content-Type", "text/html"),
+            ("X-Custom", "new1"),
+            ("X-Custom", "new2"),
+            ("X-Added", "added"),
+        ]);
+
+        let mut into = existing.clone();
+        merge_headers(&mut into, &from, MergePolicy::Auto);
+        assert_eq!(values(&into, "Set-Cookie"), ["a=1", "b=2", "c=3"]);
+        assert_eq!(values(&into, "Content-Type"), ["text/html"]);
+        assert_eq!(values(&into, "X-Custom"), ["new1", "new2"]);
+        assert_eq!(values(&into, "X-Added"), ["added"]);
+
+        let mut into = existing.clone();
+        merge_headers(&mut into, &from, MergePolicy::Replace);
+        assert_eq!(values(&into, "Set-Cookie"), ["b=2", "c=3"]);
+        assert_eq!(values(&into, "Content-Type"), ["text/html"]);
+        assert_eq!(values(&into, "X-Custom"), ["new1", "new2"]);
+        assert_eq!(values(&into, "X-Added"), ["added"]);
+
+        let mut into = existing.clone();
+        merge_headers(&mut into, &from, MergePolicy::Append);
+        assert_eq!(values(&into, "Set-Cookie"), ["a=1", "b=2", "c=3"]);
+        assert_eq!(values(&into, "Content-Type"), ["text/plain", "text/html"]);
+        assert_eq!(values(&into, "X-Custom"), ["old", "new1", "new2"]);
+        assert_eq!(values(&into, "X-Added"), ["added"]);
+
+        let mut into = existing.clone();
+        merge_headers(&mut into, &from, MergePolicy::Defer);
+        assert_eq!(values(&into, "Set-Cookie"), ["a=1"]);
+        assert_eq!(values(&into, "Content-Type"), ["text/plain"]);
+        assert_eq!(values(&into, "X-Custom"), ["old"]);
+        assert_eq!(values(&into, "X-Added"), ["added"]);
+    }
+}
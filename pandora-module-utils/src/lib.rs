@@ -16,21 +16,26 @@
 #![allow(non_ascii_idents)]
 
 mod deserialize;
+pub mod headers;
 #[doc(hidden)]
 pub mod jar;
 pub mod merger;
+pub mod metrics;
 pub mod pingora;
+pub mod registry;
 pub mod router;
+pub mod scheme;
 pub mod standard_response;
 mod trie;
 
 use log::{error, info, trace};
-use pingora::{Error, ErrorType, HttpModules, HttpPeer, SessionWrapper};
-use serde::{de::DeserializeSeed, Deserialize};
+use pingora::{Error, ErrorType, HttpModules, HttpPeer, ResponseHeader, SessionWrapper};
+use serde::de::{DeserializeSeed, Error as _};
+use serde::Deserialize;
 use std::fmt::Debug;
 use std::fs::File;
-use std::io::BufReader;
-use std::path::Path;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
 pub use deserialize::{DeserializeMap, MapVisitor, OneOrMany, _private};
 pub use pandora_module_utils_macros::{merge_conf, merge_opt, DeserializeMap, RequestFilter};
@@ -115,6 +120,23 @@ pub trait RequestFilter: Sized {
         Ok(RequestFilterResult::Unhandled)
     }
 
+    /// Runs [`RequestFilter::request_filter`] and additionally reports which handler terminated
+    /// the handler chain.
+    ///
+    /// For handlers produced via `#[derive(RequestFilter)]` the name is the identifier of the
+    /// struct field that returned a result other than [`RequestFilterResult::Unhandled`]. It is
+    /// `None` if the request wasn’t handled or the handler isn’t composed of other handlers.
+    async fn request_filter_with_trace(
+        &self,
+        session: &mut impl SessionWrapper,
+        ctx: &mut Self::CTX,
+    ) -> Result<(RequestFilterResult, Option<&'static str>), Box<Error>>
+    where
+        Self::CTX: Send,
+    {
+        Ok((self.request_filter(session, ctx).await?, None))
+    }
+
     /// Handler to run during Pingora’s `upstream_peer` phase, see
     /// [`pingora::ProxyHttp::upstream_peer`]. Unlike Pingora’s method, here returning a result is
     /// optional. If `None` is returned, other handlers in the chain will be called. If all of them
@@ -127,6 +149,18 @@ pub trait RequestFilter: Sized {
         Ok(None)
     }
 
+    /// Handler to run during Pingora’s `response_filter` phase, see
+    /// [`pingora::ProxyHttp::response_filter`]. Note that this phase only runs for responses
+    /// received from an upstream server, not for responses produced by `request_filter`.
+    async fn response_filter(
+        &self,
+        _session: &mut impl SessionWrapper,
+        _upstream_response: &mut ResponseHeader,
+        _ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        Ok(())
+    }
+
     /// Handler to run during Pingora’s `logging` phase, see [`pingora::ProxyHttp::logging`].
     async fn logging(
         &self,
@@ -148,7 +182,21 @@ pub trait FromYaml {
         I: IntoIterator,
         I::Item: AsRef<str>;
 
+    /// Loads and merges configuration from a number of YAML files like
+    /// [`FromYaml::load_from_files`]. If `max_size` is given, configuration files (including any
+    /// included files) larger than `max_size` bytes are rejected before parsing.
+    fn load_from_files_with_limit<I>(files: I, max_size: Option<u64>) -> Result<Self, Box<Error>>
+    where
+        Self: Sized,
+        I: IntoIterator,
+        I::Item: AsRef<str>;
+
     /// Loads configuration from a YAML file.
+    ///
+    /// A mapping can contain the key `$include` with a file path as value. The file will be
+    /// loaded (path is relative to the including file’s directory) and its mapping entries will be
+    /// added to the including mapping, with entries of the including mapping taking precedence. If
+    /// `$include` is the only key, the mapping will be replaced by the file contents.
     fn load_from_yaml(path: impl AsRef<Path>) -> Result<Self, Box<Error>>
     where
         Self: Sized;
@@ -158,6 +206,17 @@ pub trait FromYaml {
     where
         Self: Sized;
 
+    /// Loads configuration from a YAML file, using existing data for missing fields. If
+    /// `max_size` is given, configuration files larger than `max_size` bytes are rejected before
+    /// parsing.
+    fn merge_load_from_yaml_with_limit(
+        self,
+        path: impl AsRef<Path>,
+        max_size: Option<u64>,
+    ) -> Result<Self, Box<Error>>
+    where
+        Self: Sized;
+
     /// Loads configuration from a YAML string.
     fn from_yaml(yaml_conf: impl AsRef<str>) -> Result<Self, Box<Error>>
     where
@@ -175,6 +234,14 @@ where
     for<'de> D: DeserializeSeed<'de, Value = D>,
 {
     fn load_from_files<I>(files: I) -> Result<Self, Box<Error>>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        Self::load_from_files_with_limit(files, None)
+    }
+
+    fn load_from_files_with_limit<I>(files: I, max_size: Option<u64>) -> Result<Self, Box<Error>>
     where
         I: IntoIterator,
         I::Item: AsRef<str>,
@@ -210,7 +277,7 @@ where
 
         let result = files.into_iter().try_fold(Self::default(), |conf, path| {
             info!("Loading configuration file `{}`", path.display());
-            conf.merge_load_from_yaml(path)
+            conf.merge_load_from_yaml_with_limit(path, max_size)
         });
 
         if let Ok(conf) = &result {
@@ -225,25 +292,37 @@ where
     }
 
     fn merge_load_from_yaml(self, path: impl AsRef<Path>) -> Result<Self, Box<Error>> {
+        self.merge_load_from_yaml_with_limit(path, None)
+    }
+
+    fn merge_load_from_yaml_with_limit(
+        self,
+        path: impl AsRef<Path>,
+        max_size: Option<u64>,
+    ) -> Result<Self, Box<Error>> {
         let path = path.as_ref();
-        let file = File::open(path).map_err(|err| {
+        let yaml = read_yaml_file(path, max_size)?;
+
+        // Parsing errors will be reported when deserializing below
+        let mut value = serde_yaml::from_str(&yaml).unwrap_or(serde_yaml::Value::Null);
+        let mut stack = vec![canonicalize_yaml_path(path)?];
+        let has_includes = resolve_includes(&mut value, parent_dir(path), &mut stack, max_size)?;
+
+        // Only deserialize from the parsed value if necessary, deserializing from string directly
+        // produces better error messages.
+        let conf = if has_includes {
+            self.deserialize(value.clone())
+                .map_err(|err| locate_merged_error::<Self>(&value, err))
+        } else {
+            self.deserialize(serde_yaml::Deserializer::from_str(&yaml))
+        }
+        .map_err(|err| {
             Error::because(
-                ErrorType::FileOpenError,
-                format!("failed opening configuration file `{}`", path.display()),
+                ErrorType::FileReadError,
+                format!("failed reading configuration file `{}`", path.display()),
                 err,
             )
         })?;
-        let reader = BufReader::new(file);
-
-        let conf = self
-            .deserialize(serde_yaml::Deserializer::from_reader(reader))
-            .map_err(|err| {
-                Error::because(
-                    ErrorType::FileReadError,
-                    format!("failed reading configuration file `{}`", path.display()),
-                    err,
-                )
-            })?;
 
         Ok(conf)
     }
@@ -262,3 +341,413 @@ where
         Ok(conf)
     }
 }
+
+/// Key used to include another configuration file
+const INCLUDE_KEY: &str = "$include";
+
+/// Reads a configuration file into a string.
+///
+/// If `max_size` is given, at most `max_size + 1` bytes are read and files exceeding the limit
+/// result in an error.
+fn read_yaml_file(path: &Path, max_size: Option<u64>) -> Result<String, Box<Error>> {
+    let read_error = |err: std::io::Error| {
+        Error::because(
+            ErrorType::FileOpenError,
+            format!("failed opening configuration file `{}`", path.display()),
+            err,
+        )
+    };
+
+    let max_size = if let Some(max_size) = max_size {
+        max_size
+    } else {
+        return std::fs::read_to_string(path).map_err(read_error);
+    };
+
+    let mut yaml = Vec::new();
+    File::open(path)
+        .and_then(|file| file.take(max_size.saturating_add(1)).read_to_end(&mut yaml))
+        .map_err(read_error)?;
+
+    if yaml.len() as u64 > max_size {
+        return Err(Error::explain(
+            ErrorType::FileReadError,
+            format!(
+                "configuration file `{}` exceeds the size limit of {max_size} bytes",
+                path.display()
+            ),
+        ));
+    }
+
+    String::from_utf8(yaml).map_err(|err| {
+        Error::because(
+            ErrorType::FileReadError,
+            format!("failed reading configuration file `{}`", path.display()),
+            err,
+        )
+    })
+}
+
+/// Improves an error produced when deserializing a merged configuration. Unlike errors produced
+/// when deserializing from a string, these don’t indicate the path to the offending setting. So
+/// the merged configuration is serialized and deserialized again to determine the path. The
+/// location within the serialized configuration is meaningless to the user and is removed.
+fn locate_merged_error<D>(value: &serde_yaml::Value, err: serde_yaml::Error) -> serde_yaml::Error
+where
+    D: Default,
+    for<'de> D: DeserializeSeed<'de, Value = D>,
+{
+    let Some(located) = serde_yaml::to_string(value).ok().and_then(|yaml| {
+        D::default()
+            .deserialize(serde_yaml::Deserializer::from_str(&yaml))
+            .err()
+    }) else {
+        return err;
+    };
+
+    let message = located.to_string();
+    let message = match located.location() {
+        Some(location) => message
+            .strip_suffix(&format!(
+                " at line {} column {}",
+                location.line(),
+                location.column()
+            ))
+            .unwrap_or(&message),
+        None => &message,
+    };
+    serde_yaml::Error::custom(message)
+}
+
+/// Canonicalizes a configuration file path, so that it can be used to detect recursive includes.
+fn canonicalize_yaml_path(path: &Path) -> Result<PathBuf, Box<Error>> {
+    path.canonicalize().map_err(|err| {
+        Error::because(
+            ErrorType::FileOpenError,
+            format!("failed opening configuration file `{}`", path.display()),
+            err,
+        )
+    })
+}
+
+/// Returns the directory that paths within a configuration file are relative to.
+fn parent_dir(path: &Path) -> &Path {
+    path.parent().unwrap_or(Path::new(""))
+}
+
+/// Loads an included configuration file, resolving any includes within it.
+///
+/// `stack` contains the canonical paths of all files currently being loaded, used to detect
+/// recursive includes.
+fn load_include(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    max_size: Option<u64>,
+) -> Result<serde_yaml::Value, Box<Error>> {
+    let canonical = canonicalize_yaml_path(path)?;
+    if stack.contains(&canonical) {
+        return Err(Error::explain(
+            ErrorType::FileReadError,
+            format!(
+                "recursive include of configuration file `{}`",
+                path.display()
+            ),
+        ));
+    }
+
+    info!("Including configuration file `{}`", path.display());
+    let yaml = read_yaml_file(path, max_size)?;
+    let mut value = serde_yaml::from_str(&yaml).map_err(|err| {
+        Error::because(
+            ErrorType::FileReadError,
+            format!("failed reading configuration file `{}`", path.display()),
+            err,
+        )
+    })?;
+
+    stack.push(canonical);
+    resolve_includes(&mut value, parent_dir(path), stack, max_size)?;
+    stack.pop();
+
+    Ok(value)
+}
+
+/// Recursively replaces `$include` keys in mappings by the contents of the respective files.
+/// Returns `true` if any includes were found.
+fn resolve_includes(
+    value: &mut serde_yaml::Value,
+    dir: &Path,
+    stack: &mut Vec<PathBuf>,
+    max_size: Option<u64>,
+) -> Result<bool, Box<Error>> {
+    match value {
+        serde_yaml::Value::Mapping(mapping) => {
+            let mut has_includes = false;
+            let include = mapping.remove(&serde_yaml::Value::String(INCLUDE_KEY.to_owned()));
+            if let Some(include) = include {
+                has_includes = true;
+
+                let path = if let serde_yaml::Value::String(path) = include {
+                    dir.join(path)
+                } else {
+                    return Err(Error::explain(
+                        ErrorType::FileReadError,
+                        format!("`{INCLUDE_KEY}` value has to be a file path"),
+                    ));
+                };
+
+                let included = load_include(&path, stack, max_size)?;
+                if mapping.is_empty() {
+                    *value = included;
+                    return Ok(true);
+                } else if let serde_yaml::Value::Mapping(included) = included {
+                    for (key, entry) in included {
+                        if !mapping.contains_key(&key) {
+                            mapping.insert(key, entry);
+                        }
+                    }
+                } else {
+                    return Err(Error::explain(
+                        ErrorType::FileReadError,
+                        format!(
+                            "configuration file `{}` has to contain a mapping to be merged with other keys",
+                            path.display()
+                        ),
+                    ));
+                }
+            }
+
+            for (_, entry) in mapping.iter_mut() {
+                has_includes |= resolve_includes(entry, dir, stack, max_size)?;
+            }
+            Ok(has_includes)
+        }
+        serde_yaml::Value::Sequence(sequence) => {
+            let mut has_includes = false;
+            for entry in sequence {
+                has_includes |= resolve_includes(entry, dir, stack, max_size)?;
+            }
+            Ok(has_includes)
+        }
+        _ => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    #[pandora(crate = "crate")]
+    struct Conf {
+        value: Option<String>,
+        hosts: HashMap<String, HostConf>,
+    }
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    #[pandora(crate = "crate")]
+    struct HostConf {
+        root: Option<String>,
+        headers: OneOrMany<String>,
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pandora-module-utils-{name}"));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("hosts").join("headers")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn include() {
+        let dir = test_dir("include");
+        std::fs::write(
+            dir.join("config.yaml"),
+            r#"
+                value: main
+                hosts:
+                    localhost:
+                        $include: hosts/localhost.yaml
+                        root: /overridden
+                    example.com:
+                        $include: hosts/example.com.yaml
+            "#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("hosts").join("localhost.yaml"),
+            r#"
+                root: /localhost
+                headers:
+                    $include: headers/common.yaml
+            "#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("hosts").join("example.com.yaml"),
+            r#"
+                root: /example.com
+            "#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("hosts").join("headers").join("common.yaml"),
+            r#"
+                [X-Header1, X-Header2]
+            "#,
+        )
+        .unwrap();
+
+        let conf = Conf::load_from_yaml(dir.join("config.yaml")).unwrap();
+        assert_eq!(conf.value.as_deref(), Some("main"));
+        assert_eq!(
+            conf.hosts.get("localhost"),
+            Some(&HostConf {
+                root: Some("/overridden".to_owned()),
+                headers: vec!["X-Header1".to_owned(), "X-Header2".to_owned()].into(),
+            })
+        );
+        assert_eq!(
+            conf.hosts.get("example.com"),
+            Some(&HostConf {
+                root: Some("/example.com".to_owned()),
+                headers: Default::default(),
+            })
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recursive_include() {
+        let dir = test_dir("recursive-include");
+        std::fs::write(
+            dir.join("config.yaml"),
+            r#"
+                hosts:
+                    $include: hosts/hosts.yaml
+            "#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("hosts").join("hosts.yaml"),
+            r#"
+                localhost:
+                    $include: ../config.yaml
+            "#,
+        )
+        .unwrap();
+
+        let err = Conf::load_from_yaml(dir.join("config.yaml")).unwrap_err();
+        assert_eq!(err.etype, ErrorType::FileReadError);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn error_location() {
+        #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+        #[pandora(crate = "crate")]
+        struct RulesConf {
+            rules: OneOrMany<RuleConf>,
+        }
+
+        #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+        #[pandora(crate = "crate")]
+        struct RuleConf {
+            include: OneOrMany<merger::HostPathMatcher>,
+        }
+
+        let err = RulesConf::from_yaml(
+            r#"
+                rules:
+                - include: /static/*
+                - include:
+                  - /dir/*
+                  - /dir/*x
+            "#,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("rules[1].include[1]: "), "{err}");
+        assert!(
+            err.contains("invalid host/path matcher `/dir/*x` at position 6"),
+            "{err}"
+        );
+        assert!(err.contains(" at line 6 column "), "{err}");
+
+        // Locations within the merged configuration are meaningless, only the path is reported
+        let dir = test_dir("error-location");
+        std::fs::write(
+            dir.join("config.yaml"),
+            r#"
+                rules:
+                    $include: hosts/rules.yaml
+            "#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("hosts").join("rules.yaml"),
+            r#"
+                - include: /static/*
+                - include: [/dir/*, /dir/*x]
+            "#,
+        )
+        .unwrap();
+
+        let err = RulesConf::load_from_yaml(dir.join("config.yaml"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("rules[1].include[1]: "), "{err}");
+        assert!(
+            err.contains("invalid host/path matcher `/dir/*x` at position 6"),
+            "{err}"
+        );
+        assert!(!err.contains(" at line "), "{err}");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn size_limit() {
+        let dir = test_dir("size-limit");
+        std::fs::write(
+            dir.join("config.yaml"),
+            r#"
+                value: main
+                hosts:
+                    localhost:
+                        $include: hosts/localhost.yaml
+            "#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("hosts").join("localhost.yaml"),
+            format!("root: /{}", "x".repeat(1000)),
+        )
+        .unwrap();
+
+        let path = dir.join("config.yaml").to_string_lossy().into_owned();
+        let conf = Conf::load_from_files_with_limit([&path], Some(2000)).unwrap();
+        assert_eq!(conf.value.as_deref(), Some("main"));
+
+        // Included file exceeds the limit
+        let err = Conf::load_from_files_with_limit([&path], Some(500)).unwrap_err();
+        assert_eq!(err.etype, ErrorType::FileReadError);
+        assert!(err.to_string().contains("localhost.yaml"));
+        assert!(err
+            .to_string()
+            .contains("exceeds the size limit of 500 bytes"));
+
+        // Main file exceeds the limit
+        let err = Conf::load_from_files_with_limit([&path], Some(10)).unwrap_err();
+        assert!(err.to_string().contains("config.yaml"));
+        assert!(err
+            .to_string()
+            .contains("exceeds the size limit of 10 bytes"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -14,11 +14,17 @@
 
 //! Rule/configuration merging to be performed prior to creating a router.
 
-use serde::Deserialize;
+use serde::de::{Error as _, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::borrow::Borrow;
+use std::cmp::Ordering;
 use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
 use std::{collections::HashMap, fmt::Debug};
 
+use crate::pingora::{Error, ErrorType};
 use crate::router::{Path, Router};
+use crate::OneOrMany;
 
 /// Combination of various flags to be returned from `PathMatch::matches`
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -103,13 +109,65 @@ pub trait PathMatch {
 
     /// Retrieves the sorter associated with a previous match by its index
     fn sorter(&self, index: Self::SorterIndex) -> &Self::Sorter;
+
+    /// Checks whether the configuration applies to the given path like [`PathMatch::matches`]
+    /// but also returns the index of the rule that decided the outcome, for diagnostic purposes.
+    /// Unlike the sorter index of the result, this index is also set when the closest matching
+    /// rule is an exclude rule.
+    fn matches_explain(
+        &self,
+        host: &[u8],
+        path: &Path,
+        force_prefix: bool,
+    ) -> (
+        PathMatchResult<Self::SorterIndex>,
+        Option<Self::SorterIndex>,
+    ) {
+        let result = self.matches(host, path, force_prefix);
+        (result, result.sorter())
+    }
+}
+
+/// Prefix of wildcard host names
+const WILDCARD_PREFIX: &[u8] = b"*.";
+
+/// Checks whether rules for the host name `pattern` apply to `host`. Rules for the empty host name
+/// apply to all hosts, rules for a wildcard host like `*.example.com` apply to its subdomains as
+/// well as to more specific wildcard hosts like `*.www.example.com`.
+fn host_applies(pattern: &[u8], host: &[u8]) -> bool {
+    if pattern.is_empty() {
+        true
+    } else if pattern.starts_with(WILDCARD_PREFIX) {
+        let suffix = &pattern[1..];
+        host.len() > suffix.len() && host.ends_with(suffix)
+    } else {
+        pattern == host
+    }
+}
+
+/// Determines the specificity of a host name: fallback host before wildcard hosts before exact
+/// host names, shorter wildcard hosts before longer ones.
+fn host_specificity(host: &[u8]) -> (u8, usize) {
+    if host.is_empty() {
+        (0, 0)
+    } else if host.starts_with(WILDCARD_PREFIX) {
+        (1, host.len())
+    } else {
+        (2, host.len())
+    }
 }
 
 /// A basic path matcher, applying to a single host/path combination
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
-#[serde(from = "String")]
+///
+/// Matchers are ordered by priority first, then by specificity: fallback host before wildcard
+/// hosts before exact host names, shorter wildcard hosts before longer ones, then by path.
+///
+/// In configuration files, a matcher is usually given as a string like `localhost/dir/*`, see
+/// [`HostPathMatcher::from_str`]. A map like `{path: "localhost/dir/*", priority: 10}` sets the
+/// priority as well.
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct HostPathMatcher {
-    /// Host name that the matcher applies to
+    /// Host name that the matcher applies to, can be a wildcard host like `*.example.com`
     pub host: Vec<u8>,
 
     /// Path that the matcher applies to
@@ -117,6 +175,18 @@ pub struct HostPathMatcher {
 
     /// If `true`, only exact path matches are accepted, otherwise both exact and prefix matches.
     pub exact: bool,
+
+    /// File name suffix like `.css`. If set, the matcher applies to paths within `path` only if
+    /// their final segment ends with this suffix.
+    pub suffix: Option<Vec<u8>>,
+
+    /// If `true`, this is a negative rule excluding the host/path combination from a list of
+    /// rules, see [`closest_match`]. The matcher itself matches the same way regardless.
+    pub negated: bool,
+
+    /// Explicit priority of the matcher, `0` by default. If multiple matchers apply, the one with
+    /// the higher priority is preferred regardless of specificity.
+    pub priority: i32,
 }
 
 impl HostPathMatcher {
@@ -125,24 +195,127 @@ impl HostPathMatcher {
         host: Vec::new(),
         path: Path { path: Vec::new() },
         exact: false,
+        suffix: None,
+        negated: false,
+        priority: 0,
     };
+
+    /// Compares the priority and specificity of two matchers, a greater matcher is preferred when
+    /// both match. Unlike the `Ord` implementation, this ignores the `negated` flag.
+    fn cmp_specificity(&self, other: &Self) -> Ordering {
+        (self.priority, host_specificity(&self.host), &self.host)
+            .cmp(&(other.priority, host_specificity(&other.host), &other.host))
+            .then_with(|| self.cmp_path_specificity(other))
+    }
+
+    /// Compares the specificity of the paths of two matchers, ignoring their hosts.
+    fn cmp_path_specificity(&self, other: &Self) -> Ordering {
+        (
+            &self.path,
+            self.exact,
+            self.suffix.as_ref().map(|suffix| (suffix.len(), suffix)),
+        )
+            .cmp(&(
+                &other.path,
+                other.exact,
+                other.suffix.as_ref().map(|suffix| (suffix.len(), suffix)),
+            ))
+    }
+
+    /// Checks whether this matcher takes precedence over the given matcher if the latter is an
+    /// exclude rule meant to win over include rules. This is the case if this matcher has a
+    /// higher priority, or if it has the same priority and the same kind of host (fallback,
+    /// wildcard or exact host name) but a more specific path.
+    pub fn overrides_exclude(&self, exclude: &Self) -> bool {
+        match self.priority.cmp(&exclude.priority) {
+            Ordering::Greater => true,
+            Ordering::Less => false,
+            Ordering::Equal => {
+                host_specificity(&self.host).0 == host_specificity(&exclude.host).0
+                    && self.cmp_path_specificity(exclude).is_gt()
+            }
+        }
+    }
+}
+
+impl PartialOrd for HostPathMatcher {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HostPathMatcher {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.cmp_specificity(other)
+            .then(self.negated.cmp(&other.negated))
+    }
+}
+
+/// Finds the closest rule applying to a host/path combination, considering rule specificity
+/// the same way [`Merger`] does: higher priority before lower priority, then exact host before
+/// wildcard host before fallback host, longer wildcard host before shorter one, longer path before
+/// shorter path, exact match before suffix match before prefix match, longer suffix before shorter
+/// suffix.
+///
+/// Each rule is accompanied by a flag indicating whether it is an including rule. If an
+/// including and an excluding rule are equally specific, the excluding rule wins. The index of
+/// the closest rule and its match result are returned, the caller should consider the
+/// host/path combination matched only if it is an including rule.
+///
+/// If `force_prefix` is `true`, only rules applying to paths *within* `path` are considered.
+pub fn closest_match<M: Borrow<HostPathMatcher>>(
+    rules: impl IntoIterator<Item = (M, bool)>,
+    host: &[u8],
+    path: &Path,
+    force_prefix: bool,
+) -> Option<(usize, bool, PathMatchResult<()>)> {
+    rules
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, (matcher, include))| {
+            let result = matcher.borrow().matches(host, path, force_prefix);
+            let applies = if force_prefix {
+                result.prefix()
+            } else {
+                result.any()
+            };
+            applies.then_some((index, matcher, include, result))
+        })
+        .max_by(|(_, a, a_include, _), (_, b, b_include, _)| {
+            a.borrow()
+                .cmp_specificity(b.borrow())
+                .then(b_include.cmp(a_include))
+        })
+        .map(|(index, _, include, result)| (index, include, result))
 }
 
 impl Debug for HostPathMatcher {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.negated {
+            f.write_str("!")?;
+        } else if self.host.starts_with(b"!") {
+            f.write_str("\\")?;
+        }
         f.write_str(&String::from_utf8_lossy(&self.host))?;
-        if !self.path.is_empty() || self.exact {
+        if !self.path.is_root() || self.exact {
             f.write_str("/")?;
         }
         self.path.fmt(f)?;
-        if !self.exact {
+        if let Some(suffix) = &self.suffix {
+            write!(f, "/*{}", String::from_utf8_lossy(suffix))?;
+        } else if !self.exact {
             f.write_str("/*")?;
         }
+        if self.priority != 0 {
+            write!(f, " (priority {})", self.priority)?;
+        }
         Ok(())
     }
 }
 
-impl From<&str> for HostPathMatcher {
+impl FromStr for HostPathMatcher {
+    type Err = Box<Error>;
+
     /// Converts a string like `localhost/subdir/*` into a path matcher. The following input types
     /// are supported:
     ///
@@ -150,36 +323,191 @@ impl From<&str> for HostPathMatcher {
     /// * `host/path`: Applies to only the given path within the given host
     /// * `host/path/*`: Applies to the given path within the given host and any paths within this
     ///   directory.
+    /// * `host/path/*.ext`: Applies to any paths within the given directory of the given host
+    ///   with the final segment ending in `.ext`, e.g. `/path/file.ext` or `/path/dir/file.ext`.
     ///
     /// Both `host` and `path` can be empty, the former indicating the fallback host, the latter
-    /// the root directory of the host.
-    fn from(path: &str) -> Self {
-        if path.contains('/') {
-            let (path, exact) = if let Some(path) = path.strip_suffix("/*") {
-                (path, false)
+    /// the root directory of the host. A host like `*.example.com` is a wildcard host applying
+    /// to all subdomains of `example.com`, wildcards are rejected anywhere else in the host name.
+    ///
+    /// A leading `!` produces a negated matcher, e.g. `!localhost/subdir/*`. A `!` without
+    /// anything following it is rejected. A host name actually starting with `!` can be escaped
+    /// as `\!`.
+    ///
+    /// Whitespace and wildcards in unexpected places are rejected, the error message indicates
+    /// the position (counting characters from 1) of the offending character.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(offset) = value.find(char::is_whitespace) {
+            return Err(invalid_matcher(value, offset, "whitespace is not allowed"));
+        }
+
+        let (matcher, negated) = if let Some(matcher) = value.strip_prefix('!') {
+            if matcher.is_empty() {
+                return Err(invalid_matcher(
+                    value,
+                    0,
+                    "`!` has to be followed by a host or path",
+                ));
+            }
+            (matcher, true)
+        } else if let Some(matcher) = value.strip_prefix('\\').filter(|m| m.starts_with('!')) {
+            (matcher, false)
+        } else {
+            (value, false)
+        };
+        let start = value.len() - matcher.len();
+
+        if let Some((dir, segment)) = matcher.rsplit_once('/') {
+            let (matcher, exact, suffix) = if segment == "*" {
+                (dir, false, None)
+            } else if let Some(suffix) = segment
+                .strip_prefix('*')
+                .filter(|suffix| suffix.len() > 1 && suffix.starts_with('.'))
+            {
+                if let Some(offset) = suffix.find('*') {
+                    return Err(invalid_matcher(
+                        value,
+                        start + dir.len() + 2 + offset,
+                        "`*` is only allowed at the start of a file name suffix",
+                    ));
+                }
+                (dir, false, Some(suffix.as_bytes().to_owned()))
             } else {
-                (path, true)
+                (matcher, true, None)
             };
 
-            let (host, path) = path.split_once('/').unwrap_or((path, ""));
-            Self {
+            let (host, path) = matcher.split_once('/').unwrap_or((matcher, ""));
+            validate_host(value, start, host)?;
+            if let Some(offset) = path.find('*') {
+                return Err(invalid_matcher(
+                    value,
+                    start + host.len() + 1 + offset,
+                    "`*` is only allowed as the final path segment",
+                ));
+            }
+            Ok(Self {
                 host: host.as_bytes().to_owned(),
                 path: Path::new(path),
                 exact,
-            }
+                suffix,
+                negated,
+                priority: 0,
+            })
         } else {
-            Self {
-                host: path.as_bytes().to_owned(),
+            validate_host(value, start, matcher)?;
+            Ok(Self {
+                host: matcher.as_bytes().to_owned(),
                 path: Path::new(""),
                 exact: false,
-            }
+                suffix: None,
+                negated,
+                priority: 0,
+            })
         }
     }
 }
 
-impl From<String> for HostPathMatcher {
-    fn from(value: String) -> Self {
-        value.as_str().into()
+/// Produces the error for an invalid host/path matcher, `offset` being the byte offset of the
+/// offending character within `value`.
+fn invalid_matcher(value: &str, offset: usize, hint: &str) -> Box<Error> {
+    let position = value[..offset].chars().count() + 1;
+    Error::explain(
+        ErrorType::InternalError,
+        format!("invalid host/path matcher `{value}` at position {position}: {hint}"),
+    )
+}
+
+/// Makes sure that a host name contains a wildcard only as the `*.` prefix. `start` is the byte
+/// offset of the host name within `value`.
+fn validate_host(value: &str, start: usize, host: &str) -> Result<(), Box<Error>> {
+    let (suffix, start) = match host.strip_prefix("*.") {
+        Some("") => {
+            return Err(invalid_matcher(
+                value,
+                start + 1,
+                "`*.` has to be followed by a domain name",
+            ))
+        }
+        Some(suffix) => (suffix, start + 2),
+        None => (host, start),
+    };
+    match suffix.find('*') {
+        Some(offset) => Err(invalid_matcher(
+            value,
+            start + offset,
+            "`*` is only allowed as `*.` prefix of the host name",
+        )),
+        None => Ok(()),
+    }
+}
+
+impl From<&str> for HostPathMatcher {
+    /// Converts a string into a path matcher, see [`HostPathMatcher::from_str`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string is an invalid matcher (a lone `!`, whitespace or a misplaced
+    /// wildcard), use `str::parse` to handle this case.
+    fn from(path: &str) -> Self {
+        path.parse().unwrap_or_else(|err| panic!("{err}"))
+    }
+}
+
+impl TryFrom<String> for HostPathMatcher {
+    type Error = Box<Error>;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl<'de> Deserialize<'de> for HostPathMatcher {
+    /// Accepts either a string like `localhost/dir/*` or a map like
+    /// `{path: "localhost/dir/*", priority: 10}`.
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct MatcherVisitor;
+
+        impl<'de> Visitor<'de> for MatcherVisitor {
+            type Value = HostPathMatcher;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                formatter.write_str("host/path string or map with `path` and `priority` keys")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                let mut path = None;
+                let mut priority = 0;
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "path" => path = Some(map.next_value::<String>()?),
+                        "priority" => priority = map.next_value()?,
+                        _ => {
+                            return Err(A::Error::unknown_field(&key, &["path", "priority"]));
+                        }
+                    }
+                }
+
+                let path = path.ok_or_else(|| A::Error::missing_field("path"))?;
+                let mut matcher: HostPathMatcher = path.parse().map_err(A::Error::custom)?;
+                matcher.priority = priority;
+                Ok(matcher)
+            }
+        }
+
+        deserializer.deserialize_any(MatcherVisitor)
     }
 }
 
@@ -197,12 +525,27 @@ impl PathMatch for HostPathMatcher {
         path: &Path,
         _force_prefix: bool,
     ) -> PathMatchResult<Self::SorterIndex> {
-        let result = if self.host.is_empty() || self.host == host {
+        let result = if host_applies(&self.host, host) {
             PathMatchResult::EMPTY.set_sorter(())
         } else {
             return PathMatchResult::EMPTY;
         };
 
+        if let Some(suffix) = &self.suffix {
+            // Suffix matchers only ever apply to individual paths, not to entire directories
+            return if &self.path != path
+                && self.path.is_prefix_of(path)
+                && path
+                    .segments()
+                    .last()
+                    .is_some_and(|segment| segment.ends_with(suffix))
+            {
+                result.set_exact()
+            } else {
+                PathMatchResult::EMPTY
+            };
+        }
+
         if &self.path == path {
             if self.exact {
                 result.set_exact()
@@ -221,6 +564,61 @@ impl PathMatch for HostPathMatcher {
     }
 }
 
+/// A list of path matchers, the closest matching rule determines whether the list matches.
+///
+/// Negated rules like `!localhost/dir/*` can be used to exclude locations that a broader rule
+/// includes, see [`closest_match`].
+impl PathMatch for OneOrMany<HostPathMatcher> {
+    type Sorter = HostPathMatcher;
+    type SorterIndex = usize;
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&[u8], &Path)> + '_> {
+        Box::new(self.deref().iter().flat_map(|matcher| matcher.iter()))
+    }
+
+    fn matches(
+        &self,
+        host: &[u8],
+        path: &Path,
+        force_prefix: bool,
+    ) -> PathMatchResult<Self::SorterIndex> {
+        self.matches_explain(host, path, force_prefix).0
+    }
+
+    fn sorter(&self, index: Self::SorterIndex) -> &Self::Sorter {
+        &self[index]
+    }
+
+    fn matches_explain(
+        &self,
+        host: &[u8],
+        path: &Path,
+        force_prefix: bool,
+    ) -> (
+        PathMatchResult<Self::SorterIndex>,
+        Option<Self::SorterIndex>,
+    ) {
+        let rules = self
+            .deref()
+            .iter()
+            .map(|matcher| (matcher, !matcher.negated));
+        match closest_match(rules, host, path, force_prefix) {
+            Some((index, true, result)) => {
+                let mut converted = PathMatchResult::EMPTY.set_sorter(index);
+                if result.exact() {
+                    converted = converted.set_exact();
+                }
+                if result.prefix() {
+                    converted = converted.set_prefix();
+                }
+                (converted, Some(index))
+            }
+            Some((index, false, _)) => (PathMatchResult::EMPTY, Some(index)),
+            None => (PathMatchResult::EMPTY, None),
+        }
+    }
+}
+
 /// A basic path matcher, applying to a single path on the empty host
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
 #[serde(from = "String")]
@@ -312,13 +710,36 @@ impl PathMatch for PathMatcher {
 
 /// This is almost identical to `HostPathMatcher` but won’t allow prefix rules to match on exact
 /// path.
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct StrictHostPathMatcher {
     host: Vec<u8>,
     path: Path,
     exact: bool,
 }
 
+impl PartialOrd for StrictHostPathMatcher {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for StrictHostPathMatcher {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (
+            host_specificity(&self.host),
+            &self.host,
+            &self.path,
+            self.exact,
+        )
+            .cmp(&(
+                host_specificity(&other.host),
+                &other.host,
+                &other.path,
+                other.exact,
+            ))
+    }
+}
+
 impl PathMatch for StrictHostPathMatcher {
     type Sorter = Self;
     type SorterIndex = ();
@@ -333,7 +754,7 @@ impl PathMatch for StrictHostPathMatcher {
         path: &Path,
         force_prefix: bool,
     ) -> PathMatchResult<Self::SorterIndex> {
-        let result = if self.host.is_empty() || self.host == host {
+        let result = if host_applies(&self.host, host) {
             PathMatchResult::EMPTY.set_sorter(())
         } else {
             return PathMatchResult::EMPTY;
@@ -363,7 +784,7 @@ impl Debug for StrictHostPathMatcher {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str("!")?;
         f.write_str(&String::from_utf8_lossy(&self.host))?;
-        if !self.path.is_empty() || self.exact {
+        if !self.path.is_root() || self.exact {
             f.write_str("/")?;
         }
         self.path.fmt(f)?;
@@ -409,6 +830,21 @@ impl<Matcher: PathMatch, Conf> Default for MergerEntries<Matcher, Conf> {
     }
 }
 
+/// Configurations applying to a host/path combination, produced by [`Merger::explain`]
+#[derive(Debug)]
+pub struct MergerExplanation<'a, Matcher: PathMatch, Conf> {
+    /// Host name of the entry, empty for the fallback host
+    pub host: &'a [u8],
+    /// Path of the entry
+    pub path: &'a Path,
+    /// Configurations applying to the exact path along with the rule responsible for each, in
+    /// order of increasing preference
+    pub exact: Vec<(&'a Matcher::Sorter, &'a Conf)>,
+    /// Configurations applying to the paths within this path along with the rule responsible for
+    /// each, in order of increasing preference
+    pub prefix: Vec<(&'a Matcher::Sorter, &'a Conf)>,
+}
+
 /// A type allowing a number of configurations with their specific path-based restrictions to be
 /// merged, producing a single configuration for each relevant path.
 ///
@@ -422,7 +858,7 @@ impl<Matcher: PathMatch, Conf> Default for MergerEntries<Matcher, Conf> {
 /// merger.push(HostPathMatcher::from(""), "d"); // fallback
 ///
 /// // Merge configurations by joining them
-/// let router = merger.merge(|values| values.copied().collect::<String>());
+/// let router = merger.merge(|values| values.copied().collect::<String>()).unwrap();
 /// assert_eq!(*router.lookup("localhost", "/").unwrap(), "da");
 /// assert_eq!(*router.lookup("localhost", "/abc").unwrap(), "dab");
 /// assert_eq!(*router.lookup("localhost", "/abc/def").unwrap(), "da");
@@ -454,25 +890,50 @@ where
 
     fn ensure_host(&mut self, host: &[u8]) -> &mut MergerEntries<Matcher, Conf> {
         if !self.hosts.contains_key(host) {
-            // Copy fallback host if it exists
-            self.hosts.insert(
-                host.to_owned(),
-                self.hosts
-                    .get(&Vec::new())
-                    .map(|entries| {
-                        let mut new_entries = Vec::new();
-                        for (path, list) in entries.iter() {
-                            new_entries.push((path.clone(), list.clone()));
-                        }
-                        MergerEntries { inner: new_entries }
-                    })
-                    .unwrap_or_default(),
-            );
+            // Copy the most specific wildcard or fallback host applying to this host if it exists,
+            // keeping only the configurations that still apply.
+            let entries = self
+                .hosts
+                .iter()
+                .filter(|(source, _)| host_applies(source, host))
+                .max_by_key(|(source, _)| host_specificity(source))
+                .map(|(_, entries)| {
+                    let mut new_entries = Vec::new();
+                    for (path, list) in entries.iter() {
+                        let list = list
+                            .iter()
+                            .filter_map(|entry| {
+                                Some(MergerEntry {
+                                    matcher: entry.matcher.clone(),
+                                    sorter: Self::applicable_sorter(&entry.matcher, host, path)?,
+                                    conf: entry.conf.clone(),
+                                })
+                            })
+                            .collect();
+                        new_entries.push((path.clone(), list));
+                    }
+                    MergerEntries { inner: new_entries }
+                })
+                .unwrap_or_default();
+            self.hosts.insert(host.to_owned(), entries);
         }
 
         self.hosts.get_mut(host).unwrap()
     }
 
+    /// Checks whether a matcher applies to a path or to paths within it, returns the sorter index
+    /// for the entry if it does.
+    fn applicable_sorter(
+        matcher: &Matcher,
+        host: &[u8],
+        path: &Path,
+    ) -> Option<Matcher::SorterIndex> {
+        matcher
+            .matches(host, path, false)
+            .sorter()
+            .or_else(|| matcher.matches(host, path, true).sorter())
+    }
+
     fn ensure_entry(entries: &mut MergerEntries<Matcher, Conf>, host: &[u8], path: &Path) {
         let index = match entries.binary_search_by_key(&path, |(path, _)| path) {
             Ok(_) => return,
@@ -486,8 +947,7 @@ where
             if parent_path.is_prefix_of(path) {
                 // Copy any configurations from parent that apply
                 for entry in parent_list {
-                    let result = entry.matcher.matches(host, path, false);
-                    if let Some(sorter) = result.sorter() {
+                    if let Some(sorter) = Self::applicable_sorter(&entry.matcher, host, path) {
                         list.push(MergerEntry {
                             matcher: entry.matcher.clone(),
                             sorter,
@@ -509,12 +969,11 @@ where
         for (host, path) in matcher.iter() {
             Self::ensure_entry(self.ensure_host(host), host, path);
 
-            if host.is_empty() {
-                // Fallback entry applies to all hosts, make sure to add entries there.
-                for (host, entries) in self.hosts.iter_mut() {
-                    if !host.is_empty() {
-                        Self::ensure_entry(entries, host, path);
-                    }
+            // Fallback and wildcard entries apply to other hosts as well, make sure to add
+            // entries there.
+            for (other, entries) in self.hosts.iter_mut() {
+                if other.as_slice() != host && host_applies(host, other) {
+                    Self::ensure_entry(entries, other, path);
                 }
             }
         }
@@ -522,8 +981,7 @@ where
         // Add this conf to any entries it applies to
         for (host, entries) in self.hosts.iter_mut() {
             for (path, list) in entries.iter_mut() {
-                let result = matcher.matches(host, path, false);
-                if let Some(sorter) = result.sorter() {
+                if let Some(sorter) = Self::applicable_sorter(&matcher, host, path) {
                     let new_matcher = matcher.clone();
                     list.push(MergerEntry {
                         matcher,
@@ -563,8 +1021,44 @@ where
         (value_exact, value_prefix)
     }
 
+    /// Lists the configurations that will be merged for each host/path combination along with
+    /// the rules that made them apply. This is meant for diagnostics, the entries are sorted by
+    /// host and path.
+    pub fn explain(&self) -> Vec<MergerExplanation<'_, Matcher, Conf>> {
+        fn select<Matcher: PathMatch, Conf>(
+            list: &[MergerEntry<Matcher, Conf>],
+            filter: impl Fn(&Matcher) -> bool,
+        ) -> Vec<(&Matcher::Sorter, &Conf)> {
+            let mut result = list
+                .iter()
+                .filter(|entry| filter(&entry.matcher))
+                .map(|entry| (entry.matcher.sorter(entry.sorter), &entry.conf))
+                .collect::<Vec<_>>();
+            result.sort_by_key(|(sorter, _)| *sorter);
+            result
+        }
+
+        let mut hosts = self.hosts.iter().collect::<Vec<_>>();
+        hosts.sort_by_key(|(host, _)| *host);
+
+        let mut result = Vec::new();
+        for (host, entries) in hosts {
+            for (path, list) in entries.iter() {
+                result.push(MergerExplanation {
+                    host,
+                    path,
+                    exact: select(list, |matcher| matcher.matches(host, path, false).any()),
+                    prefix: select(list, |matcher| matcher.matches(host, path, true).prefix()),
+                });
+            }
+        }
+        result
+    }
+
     /// Merges the configurations using the given merging callback, producing a router.
-    pub fn merge<C, M>(self, callback: C) -> Router<M>
+    ///
+    /// This will fail if any of the host names produced by the path matchers is invalid.
+    pub fn merge<C, M>(self, callback: C) -> Result<Router<M>, Box<Error>>
     where
         C: for<'a> Fn(Box<dyn Iterator<Item = &'a Conf> + 'a>) -> M,
         M: Clone + Eq,
@@ -600,7 +1094,7 @@ where
             }
 
             for (path, value_exact, value_prefix) in values {
-                builder.push(&host, path.deref(), value_exact, Some(value_prefix));
+                builder.push(&host, path.deref(), value_exact, Some(value_prefix))?;
             }
         }
         builder.build()
@@ -716,7 +1210,9 @@ mod tests {
         merger.push("example.com/abc/def/".into(), "e".to_owned());
         merger.push("example.com/x/*".into(), "f".to_owned());
         merger.push("/abc/*".into(), "g".to_owned());
-        let router = merger.merge(|values| values.map(String::as_str).collect::<String>());
+        let router = merger
+            .merge(|values| values.map(String::as_str).collect::<String>())
+            .unwrap();
 
         assert_eq!(lookup(&router, "localhost", "/"), Some("a".to_owned()));
 
@@ -743,6 +1239,444 @@ mod tests {
         );
     }
 
+    #[test]
+    fn negated_rules() {
+        fn rules(list: &[&str]) -> OneOrMany<HostPathMatcher> {
+            list.iter()
+                .map(|rule| HostPathMatcher::from(*rule))
+                .collect::<Vec<_>>()
+                .into()
+        }
+
+        let mut merger = Merger::<OneOrMany<HostPathMatcher>, String>::new();
+        merger.push(rules(&["localhost"]), "a".to_owned());
+        merger.push(
+            rules(&[
+                "localhost/static/*",
+                "!localhost/static/private/*",
+                "!localhost/static/index.html",
+            ]),
+            "b".to_owned(),
+        );
+        merger.push(rules(&["/*", "!example.com"]), "c".to_owned());
+        let router = merger
+            .merge(|values| values.map(String::as_str).collect::<String>())
+            .unwrap();
+
+        assert_eq!(lookup(&router, "localhost", "/"), Some("ca".to_owned()));
+        assert_eq!(
+            lookup(&router, "localhost", "/static/file.txt"),
+            Some("cab".to_owned())
+        );
+
+        // Deeper negated rules carve holes out of the broader rule
+        assert_eq!(
+            lookup(&router, "localhost", "/static/private"),
+            Some("ca".to_owned())
+        );
+        assert_eq!(
+            lookup(&router, "localhost", "/static/private/file.txt"),
+            Some("ca".to_owned())
+        );
+        assert_eq!(
+            lookup(&router, "localhost", "/static/index.html"),
+            Some("ca".to_owned())
+        );
+
+        // Negated exact rule doesn’t apply to paths within
+        assert_eq!(
+            lookup(&router, "localhost", "/static/index.html/file.txt"),
+            Some("cab".to_owned())
+        );
+
+        // Negated host
+        assert_eq!(lookup(&router, "example.com", "/"), Some("".to_owned()));
+        assert_eq!(lookup(&router, "example.net", "/"), Some("c".to_owned()));
+
+        // Excluding rule wins over an equally specific including rule
+        let mut merger = Merger::<OneOrMany<HostPathMatcher>, String>::new();
+        merger.push(
+            rules(&["!localhost/abc/*", "localhost/abc/*"]),
+            "a".to_owned(),
+        );
+        merger.push(rules(&["localhost/abc/*"]), "b".to_owned());
+        let router = merger
+            .merge(|values| values.map(String::as_str).collect::<String>())
+            .unwrap();
+        assert_eq!(lookup(&router, "localhost", "/abc"), Some("b".to_owned()));
+    }
+
+    #[test]
+    fn priority() {
+        let high_priority = |rule: &str| HostPathMatcher {
+            priority: 10,
+            ..HostPathMatcher::from(rule)
+        };
+
+        // A fallback include with high priority wins over a more specific exclude
+        let rules = OneOrMany::from(vec![
+            HostPathMatcher::from("localhost/dir/*"),
+            HostPathMatcher::from("!localhost/dir/private/*"),
+            high_priority("/*"),
+        ]);
+        for path in ["/dir/file.txt", "/dir/private/file.txt", "/other"] {
+            let result = rules.matches(b"localhost", &Path::new(path), false);
+            assert_eq!(result.sorter(), Some(2), "{path}");
+        }
+
+        // A high-priority exclude wins over a more specific include
+        let rules = OneOrMany::from(vec![
+            HostPathMatcher::from("localhost/dir/public/*"),
+            high_priority("!localhost/dir/*"),
+        ]);
+        assert!(!rules
+            .matches(b"localhost", &Path::new("/dir/public/file.txt"), false)
+            .any());
+        assert!(!rules
+            .matches(b"localhost", &Path::new("/dir/file.txt"), false)
+            .any());
+
+        // Merger applies the configuration of higher priority matchers last
+        let mut merger = Merger::<HostPathMatcher, String>::new();
+        merger.push(high_priority(""), "a".to_owned());
+        merger.push("localhost/dir/*".into(), "b".to_owned());
+        let router = merger
+            .merge(|values| values.map(String::as_str).collect::<String>())
+            .unwrap();
+        assert_eq!(
+            lookup(&router, "localhost", "/dir/file"),
+            Some("ba".to_owned())
+        );
+        assert_eq!(lookup(&router, "localhost", "/"), Some("a".to_owned()));
+
+        assert_eq!(
+            format!("{:?}", high_priority("localhost/dir/*")),
+            "localhost/dir/* (priority 10)"
+        );
+    }
+
+    #[test]
+    fn overrides_exclude() {
+        let overrides = |include: &str, exclude: &str| {
+            HostPathMatcher::from(include).overrides_exclude(&HostPathMatcher::from(exclude))
+        };
+
+        assert!(!overrides("example.com/*", "/private/*"));
+        assert!(!overrides("example.com/private/public/*", "/private/*"));
+        assert!(overrides("/private/public/*", "/private/*"));
+        assert!(overrides("/private", "/private/*"));
+        assert!(!overrides("/private/*", "/private/*"));
+        assert!(overrides("*.example.com/dir/*", "*.www.example.com/*"));
+        assert!(!overrides("example.com/dir/*", "*.example.com/*"));
+
+        let high_priority = HostPathMatcher {
+            priority: 1,
+            ..HostPathMatcher::from("example.com/*")
+        };
+        assert!(high_priority.overrides_exclude(&HostPathMatcher::from("/private/*")));
+    }
+
+    #[test]
+    fn suffix_rules() {
+        let matches = |rules: &[&str], host: &str, path: &str| {
+            let rules = OneOrMany::from(
+                rules
+                    .iter()
+                    .map(|rule| HostPathMatcher::from(*rule))
+                    .collect::<Vec<_>>(),
+            );
+            rules
+                .matches(host.as_bytes(), &Path::new(path), false)
+                .any()
+        };
+
+        let matcher = HostPathMatcher::from("example.com/static/*.css");
+        assert_eq!(matcher.host, b"example.com");
+        assert_eq!(matcher.path, Path::new("/static"));
+        assert_eq!(matcher.suffix.as_deref(), Some(b".css".as_slice()));
+        assert!(!matcher.exact);
+        assert_eq!(format!("{matcher:?}"), "example.com/static/*.css");
+        assert_eq!(format!("{:?}", HostPathMatcher::from("/*.css")), "/*.css");
+
+        // A bare `*.css` is still a wildcard host
+        assert!(HostPathMatcher::from("*.css").suffix.is_none());
+
+        let rules = ["localhost/static/*.css"];
+        assert!(matches(&rules, "localhost", "/static/style.css"));
+        assert!(matches(&rules, "localhost", "/static/dir/style.css"));
+        assert!(!matches(&rules, "localhost", "/static"));
+        assert!(!matches(&rules, "localhost", "/static/style.css/file"));
+        assert!(!matches(&rules, "localhost", "/static/style.scss.map"));
+        assert!(!matches(&rules, "localhost", "/style.css"));
+        assert!(!matches(&rules, "example.com", "/static/style.css"));
+
+        // Multiple dots in file names and suffixes
+        assert!(matches(&["/*.css"], "localhost", "/app.min.css"));
+        assert!(matches(&["/*.min.css"], "localhost", "/app.min.css"));
+        assert!(!matches(&["/*.min.css"], "localhost", "/app.css"));
+        assert!(matches(
+            &["/*.tar.gz"],
+            "localhost",
+            "/dist/pkg-1.2.3.tar.gz"
+        ));
+        assert!(!matches(&["/*.tar.gz"], "localhost", "/dist/pkg-1.2.3.gz"));
+
+        // Suffix rules combined with excludes
+        let rules = ["/static/*.js", "!/static/vendor/*"];
+        assert!(matches(&rules, "localhost", "/static/app.js"));
+        assert!(!matches(&rules, "localhost", "/static/vendor/lib.js"));
+
+        let rules = ["/static/*", "!/static/*.map"];
+        assert!(matches(&rules, "localhost", "/static/app.js"));
+        assert!(!matches(&rules, "localhost", "/static/app.js.map"));
+        assert!(!matches(&rules, "localhost", "/static/dir/app.js.map"));
+
+        // Exact path beats suffix rule beats prefix rule at the same depth
+        let rules = ["/static/*", "!/static/*.css", "/static/main.css"];
+        assert!(matches(&rules, "localhost", "/static/main.css"));
+        assert!(!matches(&rules, "localhost", "/static/other.css"));
+        assert!(matches(&rules, "localhost", "/static/other.txt"));
+
+        // Longer suffix is more specific
+        let rules = ["/*.min.js", "!/*.js"];
+        assert!(matches(&rules, "localhost", "/app.min.js"));
+        assert!(!matches(&rules, "localhost", "/app.js"));
+        let rules = ["!/*.min.js", "/*.js"];
+        assert!(!matches(&rules, "localhost", "/app.min.js"));
+
+        // A deeper prefix rule is still more specific
+        let rules = ["/*.css", "!/static/*"];
+        assert!(!matches(&rules, "localhost", "/static/style.css"));
+        assert!(matches(&rules, "localhost", "/style.css"));
+    }
+
+    #[test]
+    fn explain() {
+        let rules = OneOrMany::from(vec![
+            HostPathMatcher::from("localhost/static/*"),
+            HostPathMatcher::from("!localhost/static/private/*"),
+            HostPathMatcher::from("localhost/static/private/shared/*"),
+        ]);
+        let explain = |path: &str| {
+            let (result, index) = rules.matches_explain(b"localhost", &Path::new(path), false);
+            (
+                result.any(),
+                index.map(|index| format!("{:?}", rules[index])),
+            )
+        };
+        assert_eq!(
+            explain("/static/file.txt"),
+            (true, Some("localhost/static/*".to_owned()))
+        );
+        assert_eq!(
+            explain("/static/private/file.txt"),
+            (false, Some("!localhost/static/private/*".to_owned()))
+        );
+        assert_eq!(
+            explain("/static/private/shared"),
+            (true, Some("localhost/static/private/shared/*".to_owned()))
+        );
+        assert_eq!(explain("/file.txt"), (false, None));
+
+        let mut merger = Merger::<OneOrMany<HostPathMatcher>, &str>::new();
+        merger.push(vec![HostPathMatcher::from("localhost")].into(), "a");
+        merger.push(rules.clone(), "b");
+        let explanation = merger
+            .explain()
+            .into_iter()
+            .map(|entry| {
+                let describe = |list: Vec<(&HostPathMatcher, &&str)>| {
+                    list.into_iter()
+                        .map(|(rule, conf)| format!("{conf} by {rule:?}"))
+                        .collect::<Vec<_>>()
+                };
+                (
+                    format!("{}/{:?}", String::from_utf8_lossy(entry.host), entry.path),
+                    describe(entry.exact),
+                    describe(entry.prefix),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            explanation,
+            vec![
+                (
+                    "localhost/".to_owned(),
+                    vec!["a by localhost/*".to_owned()],
+                    vec!["a by localhost/*".to_owned()],
+                ),
+                (
+                    "localhost/static".to_owned(),
+                    vec![
+                        "a by localhost/*".to_owned(),
+                        "b by localhost/static/*".to_owned()
+                    ],
+                    vec![
+                        "a by localhost/*".to_owned(),
+                        "b by localhost/static/*".to_owned()
+                    ],
+                ),
+                (
+                    "localhost/static/private".to_owned(),
+                    vec!["a by localhost/*".to_owned()],
+                    vec!["a by localhost/*".to_owned()],
+                ),
+                (
+                    "localhost/static/private/shared".to_owned(),
+                    vec![
+                        "a by localhost/*".to_owned(),
+                        "b by localhost/static/private/shared/*".to_owned()
+                    ],
+                    vec![
+                        "a by localhost/*".to_owned(),
+                        "b by localhost/static/private/shared/*".to_owned()
+                    ],
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn wildcard_hosts() {
+        let mut merger = Merger::<HostPathMatcher, String>::new();
+        merger.push("*.example.com/admin/*".into(), "w".to_owned());
+        merger.push("www.example.com/admin/*".into(), "e".to_owned());
+        merger.push("/admin/*".into(), "f".to_owned());
+        merger.push("*.b.example.com/admin/*".into(), "v".to_owned());
+        let router = merger
+            .merge(|values| values.map(String::as_str).collect::<String>())
+            .unwrap();
+
+        // Exact host before wildcard host before fallback, longer wildcard before shorter one
+        assert_eq!(
+            lookup(&router, "www.example.com", "/admin/x"),
+            Some("fwe".to_owned())
+        );
+        assert_eq!(
+            lookup(&router, "a.b.example.com", "/admin"),
+            Some("fwv".to_owned())
+        );
+        assert_eq!(
+            lookup(&router, "c.example.com", "/admin"),
+            Some("fw".to_owned())
+        );
+        assert_eq!(
+            lookup(&router, "example.com", "/admin"),
+            Some("f".to_owned())
+        );
+        assert_eq!(lookup(&router, "www.example.com", "/other"), None);
+
+        // Negated wildcard rules
+        let mut merger = Merger::<OneOrMany<HostPathMatcher>, String>::new();
+        merger.push(
+            vec![
+                HostPathMatcher::from("/*"),
+                HostPathMatcher::from("!*.example.com/*"),
+                HostPathMatcher::from("www.example.com/*"),
+            ]
+            .into(),
+            "a".to_owned(),
+        );
+        let router = merger
+            .merge(|values| values.map(String::as_str).collect::<String>())
+            .unwrap();
+        assert_eq!(lookup(&router, "example.com", "/"), Some("a".to_owned()));
+        assert_eq!(lookup(&router, "a.example.com", "/"), Some("".to_owned()));
+        assert_eq!(
+            lookup(&router, "www.example.com", "/"),
+            Some("a".to_owned())
+        );
+        assert_eq!(lookup(&router, "example.net", "/"), Some("a".to_owned()));
+
+        // Specificity ordering
+        let mut matchers = [
+            "www.example.com",
+            "*.b.example.com/admin/*",
+            "/admin/*",
+            "*.example.com/admin/*",
+            "*.example.com/admin/x",
+        ]
+        .map(HostPathMatcher::from);
+        matchers.sort();
+        assert_eq!(
+            matchers.map(|matcher| format!("{matcher:?}")),
+            [
+                "/admin/*",
+                "*.example.com/admin/*",
+                "*.example.com/admin/x",
+                "*.b.example.com/admin/*",
+                "www.example.com/*",
+            ]
+        );
+
+        // Misplaced wildcards
+        for matcher in [
+            "*",
+            "*.",
+            "*/admin/*",
+            "a.*.com",
+            "**.example.com",
+            "*.*.com/",
+        ] {
+            assert!(matcher.parse::<HostPathMatcher>().is_err(), "{matcher}");
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        for (matcher, expected) in [
+            (
+                "example.com//admin/*x",
+                "`example.com//admin/*x` at position 20: `*` is only allowed as the final path segment",
+            ),
+            (
+                "/static*",
+                "`/static*` at position 8: `*` is only allowed as the final path segment",
+            ),
+            (
+                "!/*/private/*",
+                "`!/*/private/*` at position 3: `*` is only allowed as the final path segment",
+            ),
+            (
+                "example.com /admin",
+                "`example.com /admin` at position 12: whitespace is not allowed",
+            ),
+            (
+                "ex\u{e4}mple.com/a b",
+                "`ex\u{e4}mple.com/a b` at position 14: whitespace is not allowed",
+            ),
+            ("!", "`!` at position 1: `!` has to be followed by a host or path"),
+            (
+                "*./dir",
+                "`*./dir` at position 2: `*.` has to be followed by a domain name",
+            ),
+            (
+                "!www.*.com/*",
+                "`!www.*.com/*` at position 6: `*` is only allowed as `*.` prefix of the host name",
+            ),
+            (
+                "/static/*.c*ss",
+                "`/static/*.c*ss` at position 12: `*` is only allowed at the start of a file name suffix",
+            ),
+            (
+                "*.*.com",
+                "`*.*.com` at position 3: `*` is only allowed as `*.` prefix of the host name",
+            ),
+        ] {
+            let err = matcher.parse::<HostPathMatcher>().unwrap_err().to_string();
+            assert!(
+                err.contains(&format!("invalid host/path matcher {expected}")),
+                "{err}"
+            );
+        }
+
+        // Duplicate slashes are merely normalized
+        assert_eq!(
+            HostPathMatcher::from("example.com//admin/*"),
+            HostPathMatcher::from("example.com/admin/*")
+        );
+    }
+
     #[test]
     fn merge_into_merger() {
         let mut merger = Merger::<HostPathMatcher, String>::new();
@@ -756,7 +1690,8 @@ mod tests {
 
         let router = merger
             .merge_into_merger(|values| values.map(String::as_str).collect::<String>())
-            .merge(|values| values.map(String::as_str).collect::<String>());
+            .merge(|values| values.map(String::as_str).collect::<String>())
+            .unwrap();
 
         assert_eq!(lookup(&router, "localhost", "/"), Some("a".to_owned()));
 
@@ -807,7 +1742,9 @@ mod tests {
         let merger2 =
             merger2.merge_into_merger(|values| values.map(String::as_str).collect::<String>());
         merger1.extend([merger2]);
-        let router = merger1.merge(|values| values.map(String::as_str).collect::<String>());
+        let router = merger1
+            .merge(|values| values.map(String::as_str).collect::<String>())
+            .unwrap();
 
         assert_eq!(lookup(&router, "localhost", "/"), Some("ak".to_owned()));
 
@@ -915,7 +1852,9 @@ mod tests {
 
         let mut merger = Merger::new();
         merger.push(CustomMatcher::new(), "a".to_owned());
-        let router = merger.merge(|values| values.map(String::as_str).collect::<String>());
+        let router = merger
+            .merge(|values| values.map(String::as_str).collect::<String>())
+            .unwrap();
 
         assert_eq!(lookup(&router, "localhost", "/"), Some("".to_owned()));
         assert_eq!(lookup(&router, "localhost", "/abc"), Some("".to_owned()));
@@ -975,7 +1914,9 @@ mod tests {
 
         let mut merger = Merger::new();
         merger.push(CustomMatcher::new(), "match");
-        let router = merger.merge(|values| *values.last().unwrap_or(&""));
+        let router = merger
+            .merge(|values| *values.last().unwrap_or(&""))
+            .unwrap();
 
         assert_eq!(router.lookup("", "").as_deref().copied(), Some("match"));
         assert_eq!(
@@ -1039,7 +1980,9 @@ mod tests {
         merger.push(CustomMatcher::new(4), "e");
         merger.push(CustomMatcher::new(2), "f");
         merger.push(CustomMatcher::new(9), "g");
-        let router = merger.merge(|values| values.copied().collect::<String>());
+        let router = merger
+            .merge(|values| values.copied().collect::<String>())
+            .unwrap();
 
         assert_eq!(lookup(&router, "", ""), Some("bfdeagc".to_owned()));
     }
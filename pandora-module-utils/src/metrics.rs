@@ -0,0 +1,403 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Named counters and gauges shared between modules
+//!
+//! Modules register their metrics with the global [`registry`], typically when the handler is
+//! created from its configuration. Registering a metric that already exists returns the existing
+//! instance, so that multiple handler instances (e.g. one per virtual host) will update the same
+//! value. The Metrics module renders all registered metrics in the Prometheus text format.
+//!
+//! ```rust
+//! use pandora_module_utils::metrics::registry;
+//!
+//! let hits = registry()
+//!     .counter("example_cache_hits_total", "Number of cache hits")
+//!     .unwrap();
+//! hits.inc();
+//! assert!(registry().render().contains("example_cache_hits_total 1\n"));
+//! ```
+
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::pingora::{Error, ErrorType};
+
+/// A single counter value, aligned so that different shards don’t share a cache line
+#[derive(Debug, Default)]
+#[repr(align(128))]
+struct Shard(AtomicU64);
+
+/// Returns the index of the current thread, used to choose a counter shard. Threads are numbered
+/// in the order they first update a sharded counter.
+fn thread_index() -> usize {
+    static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static INDEX: usize = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
+    }
+    INDEX.with(|index| *index)
+}
+
+/// A counter, a value that can only go up
+///
+/// A counter can be split up into multiple shards, see [`Counter::sharded`]. Each thread then
+/// updates its own shard, the shards are only added up when the value is retrieved.
+#[derive(Debug, Clone)]
+pub struct Counter(Arc<[Shard]>);
+
+impl Counter {
+    /// Creates a counter with the given number of shards. Sharding avoids contention if the
+    /// counter is updated by many threads simultaneously, ideally there should be a shard for
+    /// each worker thread. A counter with a single shard is a regular counter.
+    pub fn sharded(shards: usize) -> Self {
+        Self((0..shards.max(1)).map(|_| Shard::default()).collect())
+    }
+
+    /// Returns the number of shards of this counter.
+    pub fn shards(&self) -> usize {
+        self.0.len()
+    }
+
+    fn shard(&self) -> &AtomicU64 {
+        let index = if self.0.len() > 1 {
+            thread_index() % self.0.len()
+        } else {
+            0
+        };
+        &self.0[index].0
+    }
+
+    /// Increases the counter by one.
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Increases the counter by the given amount.
+    pub fn add(&self, value: u64) {
+        self.shard().fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Retrieves the current value of the counter, adding up the values of all shards.
+    pub fn get(&self) -> u64 {
+        self.0.iter().fold(0, |sum, shard| {
+            sum.wrapping_add(shard.0.load(Ordering::Relaxed))
+        })
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::sharded(1)
+    }
+}
+
+impl PartialEq for Counter {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Counter {}
+
+/// A gauge, a value that can go up and down
+#[derive(Debug, Clone, Default)]
+pub struct Gauge(Arc<AtomicI64>);
+
+impl Gauge {
+    /// Sets the gauge to the given value.
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    /// Increases the gauge by one.
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Decreases the gauge by one.
+    pub fn dec(&self) {
+        self.add(-1);
+    }
+
+    /// Changes the gauge by the given amount.
+    pub fn add(&self, value: i64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Retrieves the current value of the gauge.
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl PartialEq for Gauge {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Gauge {}
+
+#[derive(Debug)]
+enum Metric {
+    Counter(Counter),
+    Gauge(Gauge),
+}
+
+impl Metric {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Counter(_) => "counter",
+            Self::Gauge(_) => "gauge",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    help: String,
+    metric: Metric,
+}
+
+/// A collection of named metrics
+#[derive(Debug, Default)]
+pub struct Registry {
+    entries: Mutex<BTreeMap<String, Entry>>,
+}
+
+/// Checks whether the name is a valid Prometheus metric name.
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+impl Registry {
+    /// Creates a new empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn register<T>(
+        &self,
+        name: &str,
+        help: &str,
+        make_metric: impl FnOnce() -> Metric,
+        extract: impl FnOnce(&Metric) -> Option<T>,
+    ) -> Result<T, Box<Error>> {
+        if !is_valid_name(name) {
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                format!("invalid metric name {name:?}"),
+            ));
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(name.to_owned()).or_insert_with(|| Entry {
+            help: help.to_owned(),
+            metric: make_metric(),
+        });
+        extract(&entry.metric).ok_or_else(|| {
+            Error::explain(
+                ErrorType::InternalError,
+                format!(
+                    "metric {name} is already registered as a {}",
+                    entry.metric.kind()
+                ),
+            )
+        })
+    }
+
+    /// Registers a counter with the given name and description. If a counter with this name
+    /// already exists, it is returned. An error is returned if the name is invalid or is already
+    /// used by a metric of a different type.
+    pub fn counter(&self, name: &str, help: &str) -> Result<Counter, Box<Error>> {
+        self.register(
+            name,
+            help,
+            || Metric::Counter(Counter::default()),
+            |metric| match metric {
+                Metric::Counter(counter) => Some(counter.clone()),
+                Metric::Gauge(_) => None,
+            },
+        )
+    }
+
+    /// Registers a counter split up into the given number of shards, see [`Counter::sharded`].
+    /// If a counter with this name already exists, it is returned regardless of its number of
+    /// shards. An error is returned if the name is invalid or is already used by a metric of a
+    /// different type.
+    pub fn sharded_counter(
+        &self,
+        name: &str,
+        help: &str,
+        shards: usize,
+    ) -> Result<Counter, Box<Error>> {
+        self.register(
+            name,
+            help,
+            || Metric::Counter(Counter::sharded(shards)),
+            |metric| match metric {
+                Metric::Counter(counter) => Some(counter.clone()),
+                Metric::Gauge(_) => None,
+            },
+        )
+    }
+
+    /// Registers a gauge with the given name and description. If a gauge with this name already
+    /// exists, it is returned. An error is returned if the name is invalid or is already used by
+    /// a metric of a different type.
+    pub fn gauge(&self, name: &str, help: &str) -> Result<Gauge, Box<Error>> {
+        self.register(
+            name,
+            help,
+            || Metric::Gauge(Gauge::default()),
+            |metric| match metric {
+                Metric::Gauge(gauge) => Some(gauge.clone()),
+                Metric::Counter(_) => None,
+            },
+        )
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format, sorted by name.
+    pub fn render(&self) -> String {
+        let mut result = String::new();
+        for (name, entry) in self.entries.lock().unwrap().iter() {
+            if !entry.help.is_empty() {
+                let help = entry.help.replace('\\', "\\\\").replace('\n', "\\n");
+                let _ = writeln!(result, "# HELP {name} {help}");
+            }
+            let _ = writeln!(result, "# TYPE {name} {}", entry.metric.kind());
+            let value = match &entry.metric {
+                Metric::Counter(counter) => counter.get().to_string(),
+                Metric::Gauge(gauge) => gauge.get().to_string(),
+            };
+            let _ = writeln!(result, "{name} {value}");
+        }
+        result
+    }
+}
+
+/// Retrieves the global registry that the Metrics module exposes.
+pub fn registry() -> &'static Registry {
+    static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+    &REGISTRY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_and_render() {
+        let registry = Registry::new();
+
+        let hits = registry.counter("cache_hits_total", "Cache hits").unwrap();
+        let connections = registry
+            .gauge("open_connections", "Open connections\nright now")
+            .unwrap();
+        hits.add(3);
+        connections.inc();
+        connections.inc();
+        connections.dec();
+
+        // Registering again produces the same counter
+        let hits2 = registry.counter("cache_hits_total", "Ignored").unwrap();
+        assert_eq!(hits, hits2);
+        hits2.inc();
+        assert_eq!(hits.get(), 4);
+
+        assert_eq!(
+            registry.render(),
+            "# HELP cache_hits_total Cache hits\n\
+             # TYPE cache_hits_total counter\n\
+             cache_hits_total 4\n\
+             # HELP open_connections Open connections\\nright now\n\
+             # TYPE open_connections gauge\n\
+             open_connections 1\n"
+        );
+    }
+
+    #[test]
+    fn sharded_counter() {
+        let registry = Registry::new();
+
+        let requests = registry
+            .sharded_counter("requests_total", "Requests", 4)
+            .unwrap();
+        assert_eq!(requests.shards(), 4);
+
+        // Each thread updates the shard belonging to it
+        let threads = 10;
+        for _ in 0..threads {
+            let requests = requests.clone();
+            std::thread::spawn(move || {
+                let shard = &requests.0[thread_index() % requests.shards()].0;
+                let before = shard.load(Ordering::Relaxed);
+                requests.add(2);
+                requests.inc();
+                assert_eq!(shard.load(Ordering::Relaxed), before + 3);
+            })
+            .join()
+            .unwrap();
+        }
+        assert!(requests
+            .0
+            .iter()
+            .all(|shard| shard.0.load(Ordering::Relaxed) > 0));
+        assert_eq!(requests.get(), threads * 3);
+
+        // Registering again produces the same counter, regardless of the number of shards
+        let requests2 = registry.counter("requests_total", "Ignored").unwrap();
+        assert_eq!(requests, requests2);
+        assert_eq!(requests2.shards(), 4);
+        assert_eq!(
+            registry.render(),
+            "# HELP requests_total Requests\n\
+             # TYPE requests_total counter\n\
+             requests_total 30\n"
+        );
+
+        assert_eq!(Counter::sharded(0).shards(), 1);
+        assert_eq!(Counter::default().shards(), 1);
+    }
+
+    #[test]
+    fn registration_errors() {
+        let registry = Registry::new();
+
+        assert!(registry.counter("", "").is_err());
+        assert!(registry.counter("1st", "").is_err());
+        assert!(registry.gauge("with space", "").is_err());
+        assert!(registry.gauge("with-dash", "").is_err());
+
+        registry.counter("requests_total", "").unwrap();
+        assert!(registry.gauge("requests_total", "").is_err());
+        registry.gauge(":valid_gauge", "").unwrap();
+        assert!(registry.counter(":valid_gauge", "").is_err());
+
+        assert_eq!(
+            registry.render(),
+            "# TYPE :valid_gauge gauge\n\
+             :valid_gauge 0\n\
+             # TYPE requests_total counter\n\
+             requests_total 0\n"
+        );
+    }
+}
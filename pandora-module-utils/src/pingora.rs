@@ -17,13 +17,14 @@
 
 use async_trait::async_trait;
 use bytes::Bytes;
-use http::{header, Extensions, Uri};
+use http::{header, Extensions, Uri, Version};
 use once_cell::sync::OnceCell;
 pub use pingora::http::{IntoCaseHeaderName, RequestHeader, ResponseHeader};
 pub use pingora::modules::http::compression::{ResponseCompression, ResponseCompressionBuilder};
 pub use pingora::modules::http::{HttpModule, HttpModuleBuilder, HttpModules};
 pub use pingora::protocols::http::compression::Algorithm as CompressionAlgorithm;
 pub use pingora::protocols::l4::socket::SocketAddr;
+pub use pingora::protocols::SocketDigest;
 pub use pingora::proxy::{http_proxy_service, ProxyHttp, Session};
 pub use pingora::server::configuration::{Opt as ServerOpt, ServerConf};
 pub use pingora::server::Server;
@@ -40,6 +41,10 @@ use std::sync::Arc;
 #[async_trait]
 pub trait SessionWrapper: Send + Deref<Target = Session> + DerefMut {
     /// Attempts to determine the request host if one was specified.
+    ///
+    /// For HTTP/1 requests the `Host` header takes precedence over the host name in the request
+    /// URI. HTTP/2 requests specify the host via the `:authority` pseudo-header which ends up in
+    /// the request URI, so there the `Host` header is only used if `:authority` is missing.
     fn host(&self) -> Option<Cow<'_, str>>
     where
         Self: Sized,
@@ -49,28 +54,39 @@ pub trait SessionWrapper: Send + Deref<Target = Session> + DerefMut {
             host.to_str().ok().map(|h| h.into())
         }
 
-        fn host_from_uri(session: &impl SessionWrapper) -> Option<Cow<'_, str>> {
-            let uri = session.uri();
-            let host = uri.host()?;
-            if let Some(port) = uri.port() {
-                let mut host = host.to_owned();
-                host.push(':');
-                host.push_str(port.as_str());
-                Some(host.into())
-            } else {
-                Some(host.into())
-            }
+        if matches!(self.req_header().version, Version::HTTP_2 | Version::HTTP_3) {
+            self.uri_host().or_else(|| host_from_header(self))
+        } else {
+            host_from_header(self).or_else(|| self.uri_host())
         }
+    }
 
-        host_from_header(self).or_else(|| host_from_uri(self))
+    /// Determines the request host from the request URI if it is present there, e.g. for
+    /// absolute-form request targets like `GET http://example.com/ HTTP/1.1`.
+    fn uri_host(&self) -> Option<Cow<'_, str>> {
+        let uri = self.uri();
+        let host = uri.host()?;
+        if let Some(port) = uri.port() {
+            let mut host = host.to_owned();
+            host.push(':');
+            host.push_str(port.as_str());
+            Some(host.into())
+        } else {
+            Some(host.into())
+        }
     }
 
     /// Overwrites the client address for this connection.
     fn set_client_addr(&mut self, addr: SocketAddr) {
+        if let Some(socket_digest) = self.connection() {
+            self.extensions_mut()
+                .get_or_insert(OriginalSocketDigest(socket_digest));
+        }
+
         if let Some(digest) = self.digest_mut() {
             // Existing SocketDigest is behind an Arc reference and cannot be changed, create a new
             // one.
-            let mut socket_digest = pingora::protocols::SocketDigest::from_raw_fd(0);
+            let mut socket_digest = SocketDigest::from_raw_fd(0);
             socket_digest.peer_addr = OnceCell::new();
             let _ = socket_digest.peer_addr.set(Some(addr));
             socket_digest.local_addr = OnceCell::new();
@@ -84,6 +100,45 @@ pub trait SessionWrapper: Send + Deref<Target = Session> + DerefMut {
         }
     }
 
+    /// Returns the socket digest of the connection the request was received on.
+    ///
+    /// Requests received on the same connection share the same socket digest, so this can be used
+    /// to recognize connections. Unlike the socket digest in [`Session::digest`] this isn’t
+    /// affected by calling `set_client_addr`.
+    fn connection(&self) -> Option<Arc<SocketDigest>> {
+        if let Some(OriginalSocketDigest(socket_digest)) = self.extensions().get() {
+            Some(socket_digest.clone())
+        } else {
+            self.digest()?.socket_digest.clone()
+        }
+    }
+
+    /// Determines whether the request was received via HTTPS.
+    ///
+    /// By default, this is the case if the request came in on a TLS listener. If
+    /// `trust_forwarded_proto` is `true`, the `X-Forwarded-Proto` header takes precedence if
+    /// present. Clients can set this header as well, so it should only be trusted if a reverse
+    /// proxy in front of the server is guaranteed to overwrite it.
+    fn is_https(&self, trust_forwarded_proto: bool) -> bool {
+        if trust_forwarded_proto {
+            if let Some(proto) = self
+                .req_header()
+                .headers
+                .get("X-Forwarded-Proto")
+                .and_then(|value| value.to_str().ok())
+            {
+                // With multiple proxies this can be a list, the first entry is the client-facing
+                // proxy.
+                let proto = proto.split(',').next().unwrap_or_default().trim();
+                return proto.eq_ignore_ascii_case("https");
+            }
+        }
+
+        self.digest()
+            .and_then(|digest| digest.ssl_digest.as_ref())
+            .is_some()
+    }
+
     /// Returns a reference to the associated extensions.
     fn extensions(&self) -> &Extensions;
 
@@ -130,6 +185,23 @@ pub trait SessionWrapper: Send + Deref<Target = Session> + DerefMut {
         self.extensions_mut().insert(RemoteUser(remote_user));
     }
 
+    /// Disables dynamic compression of the response to the current request, regardless of the
+    /// client’s `Accept-Encoding` header.
+    ///
+    /// This is meant for responses that are already compressed, e.g. pre-compressed static files.
+    fn disable_compression(&mut self) {
+        self.extensions_mut().insert(CompressionDisabled);
+        if let Some(compression) = self.downstream_modules_ctx.get_mut::<ResponseCompression>() {
+            compression.adjust_level(0);
+        }
+    }
+
+    /// Returns `true` if dynamic compression has been disabled for the current request via
+    /// [`SessionWrapper::disable_compression`].
+    fn compression_disabled(&self) -> bool {
+        self.extensions().get::<CompressionDisabled>().is_some()
+    }
+
     /// See [`Session::response_written`](pingora::protocols::http::server::Session::response_written)
     fn response_written(&self) -> Option<&ResponseHeader> {
         self.deref().response_written()
@@ -147,6 +219,24 @@ pub trait SessionWrapper: Send + Deref<Target = Session> + DerefMut {
     }
 }
 
+/// Parses an absolute-form request target (`GET http://example.com/ HTTP/1.1`).
+///
+/// Pingora treats the request target as a path, so that for absolute-form request targets scheme
+/// and authority end up in the URI path. If this is the case, this function will return the
+/// properly parsed URI. `None` is returned for any other URIs.
+pub fn parse_absolute_form(uri: &Uri) -> Option<Uri> {
+    if uri.scheme().is_some() || uri.authority().is_some() || uri.path().starts_with('/') {
+        return None;
+    }
+
+    let uri: Uri = uri.path_and_query()?.as_str().parse().ok()?;
+    if uri.scheme().is_some() && uri.authority().is_some() {
+        Some(uri)
+    } else {
+        None
+    }
+}
+
 /// Type used to store remote user’s name in `SessionWrapper::extensions`
 #[derive(Debug, Clone)]
 struct RemoteUser(String);
@@ -155,6 +245,14 @@ struct RemoteUser(String);
 #[derive(Debug, Clone)]
 struct OriginalUri(Uri);
 
+/// Marker type stored in `SessionWrapper::extensions` if compression is disabled
+#[derive(Debug, Clone)]
+struct CompressionDisabled;
+
+/// Type used to store the original socket digest in `SessionWrapper::extensions`
+#[derive(Debug, Clone)]
+struct OriginalSocketDigest(Arc<SocketDigest>);
+
 /// Creates a new Pingora session for tests with given request header
 pub async fn create_test_session(header: RequestHeader) -> Session {
     create_test_session_with_body(header, "").await
@@ -180,3 +278,29 @@ pub async fn create_test_session_with_body(
 
     session
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn absolute_form() {
+        fn parse(path: &str) -> Option<Uri> {
+            let uri = Uri::builder().path_and_query(path).build().unwrap();
+            parse_absolute_form(&uri)
+        }
+
+        assert_eq!(parse("/"), None);
+        assert_eq!(parse("/http://example.com/"), None);
+        assert_eq!(parse("*"), None);
+        assert_eq!(parse("http://example.com").unwrap(), "http://example.com/");
+        assert_eq!(
+            parse("https://example.com:8443/dir/file.txt?abc").unwrap(),
+            "https://example.com:8443/dir/file.txt?abc"
+        );
+        assert_eq!(
+            parse_absolute_form(&"https://example.com/".parse().unwrap()),
+            None
+        );
+    }
+}
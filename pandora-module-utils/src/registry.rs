@@ -0,0 +1,675 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Allows selecting handlers by name in the configuration file.
+//!
+//! Handler types are usually combined at compile time via `#[derive(RequestFilter)]`. A
+//! [`HandlerRegistry`] makes it possible to choose the handler at run time instead: handler
+//! types are registered under a name, and a configuration like the following will instantiate the
+//! handler registered as `my_custom_handler`:
+//!
+//! ```yaml
+//! type: my_custom_handler
+//! message: Hi there!
+//! ```
+//!
+//! All settings other than `type` are passed on to the handler’s configuration type.
+//!
+//! The resulting [`DynamicHandler`] implements [`RequestFilter`] like any other handler. Note
+//! that [`RequestFilter::init_downstream_modules`] cannot be forwarded to dynamically selected
+//! handlers, so handlers relying on downstream modules such as compression need to be combined
+//! with the respective module statically.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{Extensions, Uri};
+use serde::de::DeserializeOwned;
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+use crate::pingora::{
+    Error, ErrorType, HttpPeer, ResponseHeader, Session, SessionWrapper, SocketAddr, SocketDigest,
+};
+use crate::{RequestFilter, RequestFilterResult};
+
+/// Name of the configuration setting selecting the handler type
+const TYPE_KEY: &str = "type";
+
+/// Per-request state of a handler with its type erased
+pub type DynCtx = Box<dyn Any + Send + Sync>;
+
+/// A function producing a handler from its deserialized configuration
+pub type HandlerFactory =
+    Box<dyn Fn(serde_yaml::Value) -> Result<Box<dyn DynRequestFilter>, Box<Error>> + Send + Sync>;
+
+/// Object-safe counterpart of [`RequestFilter`]
+///
+/// This trait is implemented automatically for all request filters with a per-request state
+/// that can be sent between threads. Handlers are invoked via `&mut dyn SessionWrapper` and a
+/// type-erased context produced by [`DynRequestFilter::new_dyn_ctx`].
+#[async_trait]
+pub trait DynRequestFilter: Debug + Send + Sync {
+    /// Creates a new per-request state object, see [`RequestFilter::new_ctx`]
+    fn new_dyn_ctx(&self) -> DynCtx;
+
+    /// See [`RequestFilter::early_request_filter`]
+    async fn dyn_early_request_filter(
+        &self,
+        session: &mut dyn SessionWrapper,
+        ctx: &mut DynCtx,
+    ) -> Result<(), Box<Error>>;
+
+    /// See [`RequestFilter::request_filter`]
+    async fn dyn_request_filter(
+        &self,
+        session: &mut dyn SessionWrapper,
+        ctx: &mut DynCtx,
+    ) -> Result<RequestFilterResult, Box<Error>>;
+
+    /// See [`RequestFilter::upstream_peer`]
+    async fn dyn_upstream_peer(
+        &self,
+        session: &mut dyn SessionWrapper,
+        ctx: &mut DynCtx,
+    ) -> Result<Option<Box<HttpPeer>>, Box<Error>>;
+
+    /// See [`RequestFilter::response_filter`]
+    async fn dyn_response_filter(
+        &self,
+        session: &mut dyn SessionWrapper,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut DynCtx,
+    ) -> Result<(), Box<Error>>;
+
+    /// See [`RequestFilter::logging`]
+    async fn dyn_logging(
+        &self,
+        session: &mut dyn SessionWrapper,
+        e: Option<&Error>,
+        ctx: &mut DynCtx,
+    );
+}
+
+/// Retrieves the handler’s context from the type-erased context.
+fn downcast_ctx<C: 'static>(ctx: &mut DynCtx) -> Result<&mut C, Box<Error>> {
+    (**ctx).downcast_mut().ok_or_else(|| {
+        Error::explain(
+            ErrorType::InternalError,
+            "context passed to dynamic handler has unexpected type",
+        )
+    })
+}
+
+#[async_trait]
+impl<H> DynRequestFilter for H
+where
+    H: RequestFilter + Debug + Send + Sync,
+    H::CTX: Send + Sync + 'static,
+{
+    fn new_dyn_ctx(&self) -> DynCtx {
+        Box::new(H::new_ctx())
+    }
+
+    async fn dyn_early_request_filter(
+        &self,
+        session: &mut dyn SessionWrapper,
+        ctx: &mut DynCtx,
+    ) -> Result<(), Box<Error>> {
+        let ctx = downcast_ctx(ctx)?;
+        self.early_request_filter(&mut DynSession(session), ctx)
+            .await
+    }
+
+    async fn dyn_request_filter(
+        &self,
+        session: &mut dyn SessionWrapper,
+        ctx: &mut DynCtx,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        let ctx = downcast_ctx(ctx)?;
+        self.request_filter(&mut DynSession(session), ctx).await
+    }
+
+    async fn dyn_upstream_peer(
+        &self,
+        session: &mut dyn SessionWrapper,
+        ctx: &mut DynCtx,
+    ) -> Result<Option<Box<HttpPeer>>, Box<Error>> {
+        let ctx = downcast_ctx(ctx)?;
+        self.upstream_peer(&mut DynSession(session), ctx).await
+    }
+
+    async fn dyn_response_filter(
+        &self,
+        session: &mut dyn SessionWrapper,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut DynCtx,
+    ) -> Result<(), Box<Error>> {
+        let ctx = downcast_ctx(ctx)?;
+        self.response_filter(&mut DynSession(session), upstream_response, ctx)
+            .await
+    }
+
+    async fn dyn_logging(
+        &self,
+        session: &mut dyn SessionWrapper,
+        e: Option<&Error>,
+        ctx: &mut DynCtx,
+    ) {
+        if let Ok(ctx) = downcast_ctx(ctx) {
+            self.logging(&mut DynSession(session), e, ctx).await
+        }
+    }
+}
+
+/// Wraps a session trait object so that it can be passed to [`RequestFilter`] methods
+///
+/// All methods are forwarded to the wrapped session, so that any customizations of the original
+/// session wrapper are preserved.
+struct DynSession<'a>(&'a mut dyn SessionWrapper);
+
+impl Deref for DynSession<'_> {
+    type Target = Session;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.deref()
+    }
+}
+
+impl DerefMut for DynSession<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.deref_mut()
+    }
+}
+
+#[async_trait]
+impl SessionWrapper for DynSession<'_> {
+    fn uri_host(&self) -> Option<Cow<'_, str>> {
+        self.0.uri_host()
+    }
+
+    fn set_client_addr(&mut self, addr: SocketAddr) {
+        self.0.set_client_addr(addr)
+    }
+
+    fn connection(&self) -> Option<Arc<SocketDigest>> {
+        self.0.connection()
+    }
+
+    fn is_https(&self, trust_forwarded_proto: bool) -> bool {
+        self.0.is_https(trust_forwarded_proto)
+    }
+
+    fn extensions(&self) -> &Extensions {
+        self.0.extensions()
+    }
+
+    fn extensions_mut(&mut self) -> &mut Extensions {
+        self.0.extensions_mut()
+    }
+
+    fn uri(&self) -> &Uri {
+        self.0.uri()
+    }
+
+    fn set_uri(&mut self, uri: Uri) {
+        self.0.set_uri(uri)
+    }
+
+    fn original_uri(&self) -> &Uri {
+        self.0.original_uri()
+    }
+
+    fn remote_user(&self) -> Option<&str> {
+        self.0.remote_user()
+    }
+
+    fn set_remote_user(&mut self, remote_user: String) {
+        self.0.set_remote_user(remote_user)
+    }
+
+    fn disable_compression(&mut self) {
+        self.0.disable_compression()
+    }
+
+    fn compression_disabled(&self) -> bool {
+        self.0.compression_disabled()
+    }
+
+    fn response_written(&self) -> Option<&ResponseHeader> {
+        self.0.response_written()
+    }
+
+    async fn write_response_body(
+        &mut self,
+        data: Option<Bytes>,
+        end_of_stream: bool,
+    ) -> Result<(), Box<Error>> {
+        self.0.write_response_body(data, end_of_stream).await
+    }
+}
+
+/// Maps handler type names to functions producing the respective handlers
+///
+/// ```rust
+/// use pandora_module_utils::registry::HandlerRegistry;
+/// use pandora_module_utils::serde_yaml::Value;
+/// use pandora_module_utils::{DeserializeMap, FromYaml, RequestFilter};
+///
+/// #[derive(Debug, Default, DeserializeMap)]
+/// struct MyConf {
+///     message: String,
+/// }
+///
+/// #[derive(Debug)]
+/// struct MyHandler {
+///     message: String,
+/// }
+///
+/// impl TryFrom<MyConf> for MyHandler {
+///     type Error = Box<pandora_module_utils::pingora::Error>;
+///
+///     fn try_from(conf: MyConf) -> Result<Self, Self::Error> {
+///         Ok(Self { message: conf.message })
+///     }
+/// }
+///
+/// impl RequestFilter for MyHandler {
+///     type Conf = MyConf;
+///     type CTX = ();
+///     fn new_ctx() -> Self::CTX {}
+/// }
+///
+/// let mut registry = HandlerRegistry::new();
+/// registry.register::<MyHandler>("my_custom_handler").unwrap();
+///
+/// let conf = Value::from_yaml("{type: my_custom_handler, message: Hi!}").unwrap();
+/// let handler = registry.create(conf).unwrap();
+/// assert_eq!(handler.handler_type(), "my_custom_handler");
+/// ```
+#[derive(Default)]
+pub struct HandlerRegistry {
+    factories: HashMap<String, HandlerFactory>,
+}
+
+impl Debug for HandlerRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names = self.factories.keys().collect::<Vec<_>>();
+        names.sort();
+        f.debug_struct("HandlerRegistry")
+            .field("factories", &names)
+            .finish()
+    }
+}
+
+impl HandlerRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a handler type under the given name.
+    ///
+    /// The configuration will be deserialized into the handler’s configuration type and converted
+    /// into the handler. An error is returned if the name is already taken.
+    pub fn register<H>(&mut self, name: impl Into<String>) -> Result<(), Box<Error>>
+    where
+        H: RequestFilter + Debug + Send + Sync + 'static,
+        H::Conf: DeserializeOwned + TryInto<H, Error = Box<Error>>,
+        H::CTX: Send + Sync + 'static,
+    {
+        self.register_factory(name, |conf| {
+            let conf: H::Conf = serde_yaml::from_value(conf).map_err(|err| {
+                Error::because(
+                    ErrorType::InternalError,
+                    "failed deserializing handler configuration",
+                    err,
+                )
+            })?;
+            Ok(Box::new(conf.try_into()?))
+        })
+    }
+
+    /// Registers a function producing handlers under the given name.
+    ///
+    /// The function receives the configuration without the `type` setting. An error is returned
+    /// if the name is already taken.
+    pub fn register_factory<F>(
+        &mut self,
+        name: impl Into<String>,
+        factory: F,
+    ) -> Result<(), Box<Error>>
+    where
+        F: Fn(serde_yaml::Value) -> Result<Box<dyn DynRequestFilter>, Box<Error>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let name = name.into();
+        if self.factories.contains_key(&name) {
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                format!("handler type {name} is already registered"),
+            ));
+        }
+        self.factories.insert(name, Box::new(factory));
+        Ok(())
+    }
+
+    /// Checks whether a handler type has been registered under the given name.
+    pub fn contains(&self, name: &str) -> bool {
+        self.factories.contains_key(name)
+    }
+
+    /// Creates a handler from its configuration, the `type` setting determines the handler type.
+    pub fn create(&self, conf: serde_yaml::Value) -> Result<DynamicHandler, Box<Error>> {
+        let mut conf = match conf {
+            serde_yaml::Value::Mapping(conf) => conf,
+            _ => {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    "dynamic handler configuration has to be a map",
+                ))
+            }
+        };
+
+        let handler_type = match conf.remove(&TYPE_KEY.into()) {
+            Some(serde_yaml::Value::String(handler_type)) => handler_type,
+            _ => {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    "dynamic handler configuration is missing the `type` setting",
+                ))
+            }
+        };
+
+        let factory = self.factories.get(&handler_type).ok_or_else(|| {
+            let mut names = self.factories.keys().cloned().collect::<Vec<_>>();
+            names.sort();
+            Error::explain(
+                ErrorType::InternalError,
+                format!(
+                    "unknown handler type {handler_type}, registered types: {}",
+                    names.join(", ")
+                ),
+            )
+        })?;
+
+        Ok(DynamicHandler {
+            handler_type,
+            handler: factory(serde_yaml::Value::Mapping(conf))?,
+        })
+    }
+}
+
+/// A handler selected via [`HandlerRegistry::create`]
+///
+/// Its configuration is only known at run time, so it cannot be combined with other handlers via
+/// `#[derive(RequestFilter)]`. It can be passed to an app directly however.
+#[derive(Debug)]
+pub struct DynamicHandler {
+    handler_type: String,
+    handler: Box<dyn DynRequestFilter>,
+}
+
+impl DynamicHandler {
+    /// Returns the name the handler type was registered under.
+    pub fn handler_type(&self) -> &str {
+        &self.handler_type
+    }
+
+    /// Returns the handler’s context, creating it if necessary.
+    ///
+    /// [`RequestFilter::new_ctx`] is static, so the context can only be created once the handler
+    /// is known.
+    fn ctx<'a>(&self, ctx: &'a mut DynamicCtx) -> &'a mut DynCtx {
+        ctx.0.get_or_insert_with(|| self.handler.new_dyn_ctx())
+    }
+}
+
+/// Per-request state of a [`DynamicHandler`]
+#[derive(Debug, Default)]
+pub struct DynamicCtx(Option<DynCtx>);
+
+#[async_trait]
+impl RequestFilter for DynamicHandler {
+    type Conf = serde_yaml::Value;
+
+    type CTX = DynamicCtx;
+
+    fn new_ctx() -> Self::CTX {
+        DynamicCtx::default()
+    }
+
+    async fn early_request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        self.handler
+            .dyn_early_request_filter(session, self.ctx(ctx))
+            .await
+    }
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        self.handler
+            .dyn_request_filter(session, self.ctx(ctx))
+            .await
+    }
+
+    async fn upstream_peer(
+        &self,
+        session: &mut impl SessionWrapper,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Box<HttpPeer>>, Box<Error>> {
+        self.handler.dyn_upstream_peer(session, self.ctx(ctx)).await
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        self.handler
+            .dyn_response_filter(session, upstream_response, self.ctx(ctx))
+            .await
+    }
+
+    async fn logging(
+        &self,
+        session: &mut impl SessionWrapper,
+        e: Option<&Error>,
+        ctx: &mut Self::CTX,
+    ) {
+        self.handler.dyn_logging(session, e, self.ctx(ctx)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::pingora::{create_test_session, RequestHeader};
+    use crate::{DeserializeMap, FromYaml};
+    use test_log::test;
+
+    #[derive(Debug, Default, DeserializeMap)]
+    #[pandora(crate = "crate")]
+    struct GreetingConf {
+        greeting: String,
+    }
+
+    #[derive(Debug)]
+    struct GreetingHandler {
+        greeting: String,
+    }
+
+    impl TryFrom<GreetingConf> for GreetingHandler {
+        type Error = Box<Error>;
+
+        fn try_from(conf: GreetingConf) -> Result<Self, Self::Error> {
+            if conf.greeting.is_empty() {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    "greeting required",
+                ));
+            }
+            Ok(Self {
+                greeting: conf.greeting,
+            })
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Greeted(String);
+
+    #[async_trait]
+    impl RequestFilter for GreetingHandler {
+        type Conf = GreetingConf;
+        type CTX = u32;
+
+        fn new_ctx() -> Self::CTX {
+            0
+        }
+
+        async fn request_filter(
+            &self,
+            session: &mut impl SessionWrapper,
+            ctx: &mut Self::CTX,
+        ) -> Result<RequestFilterResult, Box<Error>> {
+            *ctx += 1;
+            session
+                .extensions_mut()
+                .insert(Greeted(format!("{} {}", self.greeting, ctx)));
+            Ok(RequestFilterResult::Handled)
+        }
+    }
+
+    struct TestSession {
+        session: Session,
+        extensions: Extensions,
+    }
+
+    impl Deref for TestSession {
+        type Target = Session;
+
+        fn deref(&self) -> &Self::Target {
+            &self.session
+        }
+    }
+
+    impl DerefMut for TestSession {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.session
+        }
+    }
+
+    impl SessionWrapper for TestSession {
+        fn extensions(&self) -> &Extensions {
+            &self.extensions
+        }
+
+        fn extensions_mut(&mut self) -> &mut Extensions {
+            &mut self.extensions
+        }
+    }
+
+    async fn make_session() -> TestSession {
+        let header = RequestHeader::build("GET", b"/", None).unwrap();
+        TestSession {
+            session: create_test_session(header).await,
+            extensions: Extensions::new(),
+        }
+    }
+
+    fn make_registry() -> HandlerRegistry {
+        let mut registry = HandlerRegistry::new();
+        registry.register::<GreetingHandler>("greeting").unwrap();
+        registry
+    }
+
+    #[test]
+    fn registration() {
+        let mut registry = make_registry();
+        assert!(registry.contains("greeting"));
+        assert!(!registry.contains("other"));
+
+        assert!(registry.register::<GreetingHandler>("greeting").is_err());
+        assert!(registry
+            .register_factory("other", |_| Ok(Box::new(GreetingHandler {
+                greeting: "Hi".into()
+            })))
+            .is_ok());
+        assert!(registry.contains("other"));
+    }
+
+    #[test]
+    fn invalid_configuration() {
+        let registry = make_registry();
+        let create = |conf: &str| registry.create(serde_yaml::Value::from_yaml(conf).unwrap());
+
+        assert!(create("greeting").is_err());
+        assert!(create("{greeting: Hello}").is_err());
+        assert!(create("{type: [greeting], greeting: Hello}").is_err());
+        assert!(create("{type: unknown, greeting: Hello}").is_err());
+        assert!(create("{type: greeting, greeting: Hello, unknown: 1}").is_err());
+        assert!(create("{type: greeting}").is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn invocation() -> Result<(), Box<Error>> {
+        let registry = make_registry();
+        let handler = registry.create(serde_yaml::Value::from_yaml(
+            r#"
+                type: greeting
+                greeting: Hello
+            "#,
+        )?)?;
+        assert_eq!(handler.handler_type(), "greeting");
+
+        let mut session = make_session().await;
+        let mut ctx = DynamicHandler::new_ctx();
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::Handled
+        );
+        assert_eq!(
+            session.extensions().get::<Greeted>(),
+            Some(&Greeted("Hello 1".into()))
+        );
+
+        // Context is preserved between phases of the same request
+        handler.request_filter(&mut session, &mut ctx).await?;
+        assert_eq!(
+            session.extensions().get::<Greeted>(),
+            Some(&Greeted("Hello 2".into()))
+        );
+
+        // Phases not implemented by the handler fall back to the defaults
+        assert!(handler
+            .upstream_peer(&mut session, &mut ctx)
+            .await?
+            .is_none());
+
+        Ok(())
+    }
+}
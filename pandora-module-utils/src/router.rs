@@ -26,15 +26,172 @@
 //! Empty host name is considered the fallback host, its values apply to all hosts but with a lower
 //! priority than values designated to the host.
 //!
-//! Only the best match is returned. If rules exist for `/`, `/dir/` and `/dir/subdir/` for
-//! example, the path `/dir/subdir/file` will match `/dir/subdir/`.
+//! Host names like `*.example.com` are wildcard hosts, their values apply to all subdomains such as
+//! `www.example.com` or `a.b.example.com` but not `example.com` itself. If multiple wildcard hosts
+//! match, the longest one wins. Wildcard hosts have a lower priority than exact host name matches
+//! but a higher priority than the fallback host.
+//!
+//! [`Router::lookup_hosts`] allows trying a list of host names in order instead, e.g. the literal
+//! host name followed by an alias. The first host name with a matching rule wins then.
+//!
+//! Normally, only the best match is returned. If rules exist for `/`, `/dir/` and `/dir/subdir/`
+//! for example, the path `/dir/subdir/file` will match `/dir/subdir/`. `Router::lookup_all` can be
+//! used to retrieve all matching values instead.
+//!
+//! Rules can also be restricted to a set of request methods via
+//! [`RouterBuilder::push_with_methods`]. These are only considered by
+//! [`Router::lookup_with_method`], all other lookup functions ignore them.
+//!
+//! Where longest match isn’t the desired behavior, rules can be given an explicit priority via
+//! [`RouterBuilder::push_with_priority`]. [`Router::lookup`] will then prefer matches with higher
+//! priority regardless of their length.
+//!
+//! Path patterns like `/users/{id}/avatar` can be added via [`RouterBuilder::push_pattern`]. A
+//! parameter like `{id}` matches any single path segment, the captured segments are returned by
+//! [`Router::lookup_pattern`]. Other lookup functions ignore path patterns.
+//!
+//! Request paths are percent-encoded, so `/sub%64ir` doesn’t match a rule for `/subdir` with the
+//! regular lookup functions. [`Router::lookup_decoded`] and [`Router::lookup_hosts_decoded`]
+//! percent-decode the path first, see [`decode_path`] for the restrictions applied.
+//!
+//! For troubleshooting, [`Router::routes`] lists all rules of a routing table and
+//! [`Router::describe_lookup`] explains which rule a lookup selects and why.
 
+use http::Method;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt::Debug;
-use std::ops::Deref;
+use std::ops::{BitOr, BitOrAssign, Deref};
+use std::sync::{Arc, PoisonError, RwLock};
 
+use crate::pingora::{Error, ErrorType};
+use crate::scheme::RequestScheme;
 pub use crate::trie::LookupResult;
-use crate::trie::{common_prefix_length, Trie, SEPARATOR};
+use crate::trie::{common_prefix_length, compare_labels, normalize, Trie, TrieBuilder, SEPARATOR};
+
+/// Prefix of wildcard host names
+const WILDCARD_PREFIX: &[u8] = b"*.";
+
+/// Character separating host name labels
+const HOST_SEPARATOR: u8 = b'.';
+
+/// Host names up to this length are lowercased without allocating memory
+const HOST_BUFFER_SIZE: usize = 128;
+
+/// Host name converted to lowercase for lookups
+struct LowercaseHost<'a> {
+    /// Buffer holding short host names that had to be converted
+    buffer: [u8; HOST_BUFFER_SIZE],
+    /// Host name if it isn’t stored in the buffer
+    host: Option<Cow<'a, [u8]>>,
+    len: usize,
+}
+
+impl<'a> LowercaseHost<'a> {
+    fn new(host: &'a [u8]) -> Self {
+        let len = host.len();
+        let mut buffer = [0; HOST_BUFFER_SIZE];
+        let host = if !host.iter().any(u8::is_ascii_uppercase) {
+            Some(Cow::Borrowed(host))
+        } else if len <= HOST_BUFFER_SIZE {
+            buffer[..len].copy_from_slice(host);
+            buffer[..len].make_ascii_lowercase();
+            None
+        } else {
+            Some(Cow::Owned(host.to_ascii_lowercase()))
+        };
+        Self { buffer, host, len }
+    }
+}
+
+impl Deref for LowercaseHost<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        match &self.host {
+            Some(host) => host,
+            None => &self.buffer[..self.len],
+        }
+    }
+}
+
+/// Prefix of percent-encoded escape sequences
+const ESCAPE: u8 = b'%';
+
+/// Converts a hexadecimal digit into its value.
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Percent-decodes a single path segment, see [`decode_path`].
+fn decode_segment(segment: &[u8]) -> Option<Cow<'_, [u8]>> {
+    if !segment.contains(&ESCAPE) {
+        return Some(Cow::Borrowed(segment));
+    }
+
+    let mut result = Vec::with_capacity(segment.len());
+    let mut bytes = segment.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == ESCAPE {
+            let high = hex_value(*bytes.next()?)?;
+            let low = hex_value(*bytes.next()?)?;
+            let decoded = (high << 4) | low;
+            if decoded == SEPARATOR || decoded == 0 {
+                return None;
+            }
+            result.push(decoded);
+        } else {
+            result.push(byte);
+        }
+    }
+
+    if result == b"." || result == b".." || std::str::from_utf8(&result).is_err() {
+        return None;
+    }
+    Some(Cow::Owned(result))
+}
+
+/// Percent-decodes a request path so that it can be matched against router rules.
+///
+/// Each segment is decoded separately, segments without escape sequences are kept as is. If the
+/// path contains no escape sequences at all, it is returned without allocating memory.
+///
+/// `None` is returned if the path cannot be decoded safely:
+///
+/// * Invalid escape sequences like `%zz` or a truncated `%2`
+/// * Escaped path separators (`%2F`), these would introduce new segment boundaries
+/// * Escaped NUL bytes (`%00`)
+/// * Segments like `%2e%2e` which only become `.` or `..` after decoding, these are commonly used
+///   to bypass path-based restrictions
+/// * Escape sequences decoding to invalid UTF-8, including overlong encodings like `%c0%ae`
+///
+/// ```rust
+/// use pandora_module_utils::router::decode_path;
+///
+/// assert_eq!(decode_path(b"/sub%64ir/file").as_deref(), Some(&b"/subdir/file"[..]));
+/// assert_eq!(decode_path(b"/dir%2Ffile"), None);
+/// ```
+pub fn decode_path(path: &[u8]) -> Option<Cow<'_, [u8]>> {
+    if !path.contains(&ESCAPE) {
+        return Some(Cow::Borrowed(path));
+    }
+
+    let mut result = Vec::with_capacity(path.len());
+    for (index, segment) in path.split(|b| *b == SEPARATOR).enumerate() {
+        if index > 0 {
+            result.push(SEPARATOR);
+        }
+        result.extend_from_slice(&decode_segment(segment)?);
+    }
+    Some(Cow::Owned(result))
+}
 
 /// Empty path
 pub const EMPTY_PATH: &Path = &Path { path: Vec::new() };
@@ -48,43 +205,63 @@ pub struct Path {
 impl Path {
     /// Creates a new router path for given host and path
     pub fn new(path: impl AsRef<[u8]>) -> Self {
+        Self::from_bytes(path.as_ref())
+    }
+
+    /// Creates a router path from a request path, normalizing it the same way [`Router::lookup`]
+    /// does: empty segments are dropped, so leading, trailing and duplicate slashes are
+    /// irrelevant.
+    ///
+    /// ```rust
+    /// use pandora_module_utils::router::Path;
+    ///
+    /// assert_eq!(Path::from_bytes(b"//abc//def/"), Path::from_bytes(b"abc/def"));
+    /// assert!(Path::from_bytes(b"/").is_root());
+    /// ```
+    pub fn from_bytes(path: &[u8]) -> Self {
         Self {
-            path: Self::normalize(path),
+            path: normalize(path, SEPARATOR),
         }
     }
 
-    /// Normalizes the path by removing unnecessary separators
-    fn normalize(path: impl AsRef<[u8]>) -> Vec<u8> {
-        let mut had_separator = true;
-        let mut path: Vec<u8> = path
-            .as_ref()
-            .iter()
-            .copied()
-            .filter(|b| {
-                if *b == SEPARATOR {
-                    if had_separator {
-                        false
-                    } else {
-                        had_separator = true;
-                        true
-                    }
-                } else {
-                    had_separator = false;
-                    true
-                }
-            })
-            .collect();
+    /// Returns the normalized path without leading or trailing slashes, e.g. `abc/def`.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.path
+    }
 
-        if path.ends_with(&[SEPARATOR]) {
-            path.pop();
-        }
+    /// Iterates over the segments of the path. The root path has no segments.
+    ///
+    /// ```rust
+    /// use pandora_module_utils::router::Path;
+    ///
+    /// let path = Path::from_bytes(b"/abc//def/");
+    /// assert_eq!(path.segments().collect::<Vec<_>>(), [b"abc", b"def"]);
+    /// assert_eq!(Path::from_bytes(b"/").segments().count(), 0);
+    /// ```
+    pub fn segments(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        self.path
+            .split(|b| *b == SEPARATOR)
+            .filter(|segment| !segment.is_empty())
+    }
+
+    /// Checks whether this is the root path, same as [`EMPTY_PATH`].
+    pub fn is_root(&self) -> bool {
+        self.path.is_empty()
+    }
 
-        path
+    /// Checks whether a request path is equivalent to this path after normalization. Unlike
+    /// comparing to the result of [`Path::from_bytes`], this doesn’t allocate memory.
+    pub fn matches(&self, path: &[u8]) -> bool {
+        self.segments().eq(path
+            .split(|b| *b == SEPARATOR)
+            .filter(|segment| !segment.is_empty()))
     }
 
-    /// Checks whether this path is a parent of the other path
+    /// Checks whether this path is a parent of the other path, considering complete segments
+    /// only: `/abc` is a prefix of `/abc/def` but not of `/abcdef`. The root path is a prefix of
+    /// any path, any path is a prefix of itself.
     pub fn is_prefix_of(&self, other: &Path) -> bool {
-        common_prefix_length(&self.path, &other.path) == self.path.len()
+        common_prefix_length(&self.path, &other.path, SEPARATOR) == self.path.len()
     }
 
     /// If this path is a non-empty prefix of the given path, removes the prefix. Otherwise returns
@@ -115,6 +292,38 @@ impl Path {
             Some(path)
         }
     }
+
+    /// Like [`Path::remove_prefix_from`] but for a percent-encoded path as passed to
+    /// [`Router::lookup_decoded`]. Segments are decoded for comparison, the remainder is returned
+    /// in its original encoded form.
+    pub fn remove_prefix_from_encoded<'a>(&self, path: &'a impl AsRef<[u8]>) -> Option<&'a [u8]> {
+        if self.path.is_empty() {
+            return None;
+        }
+
+        let mut path = path.as_ref();
+        for segment in self.path.split(|b| *b == SEPARATOR) {
+            while let [SEPARATOR, rest @ ..] = path {
+                path = rest;
+            }
+
+            let end = path
+                .iter()
+                .position(|b| *b == SEPARATOR)
+                .unwrap_or(path.len());
+            if *decode_segment(&path[..end])? != *segment {
+                return None;
+            }
+
+            path = &path[end..];
+        }
+
+        if path.is_empty() {
+            Some(b"/")
+        } else {
+            Some(path)
+        }
+    }
 }
 
 impl Debug for Path {
@@ -131,6 +340,391 @@ impl Deref for Path {
     }
 }
 
+/// A set of HTTP request methods that a routing rule applies to
+///
+/// Sets can be combined via the `|` operator, e.g. `Methods::GET | Methods::HEAD`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Methods(u16);
+
+impl Methods {
+    /// `GET` requests
+    pub const GET: Self = Self(1 << 0);
+    /// `HEAD` requests
+    pub const HEAD: Self = Self(1 << 1);
+    /// `POST` requests
+    pub const POST: Self = Self(1 << 2);
+    /// `PUT` requests
+    pub const PUT: Self = Self(1 << 3);
+    /// `DELETE` requests
+    pub const DELETE: Self = Self(1 << 4);
+    /// `CONNECT` requests
+    pub const CONNECT: Self = Self(1 << 5);
+    /// `OPTIONS` requests
+    pub const OPTIONS: Self = Self(1 << 6);
+    /// `TRACE` requests
+    pub const TRACE: Self = Self(1 << 7);
+    /// `PATCH` requests
+    pub const PATCH: Self = Self(1 << 8);
+    /// Requests with a non-standard method, only matched by [`Methods::ANY`]
+    const OTHER: Self = Self(1 << 15);
+    /// Any request method, including non-standard ones
+    pub const ANY: Self = Self(u16::MAX);
+
+    /// Checks whether the set contains the given method.
+    pub fn contains(self, method: &Method) -> bool {
+        self.0 & Self::from(method).0 != 0
+    }
+}
+
+impl From<&Method> for Methods {
+    fn from(method: &Method) -> Self {
+        match *method {
+            Method::GET => Self::GET,
+            Method::HEAD => Self::HEAD,
+            Method::POST => Self::POST,
+            Method::PUT => Self::PUT,
+            Method::DELETE => Self::DELETE,
+            Method::CONNECT => Self::CONNECT,
+            Method::OPTIONS => Self::OPTIONS,
+            Method::TRACE => Self::TRACE,
+            Method::PATCH => Self::PATCH,
+            _ => Self::OTHER,
+        }
+    }
+}
+
+impl BitOr for Methods {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Methods {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// Parsed segment of a path pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PatternSegment {
+    Literal(Vec<u8>),
+    Parameter(String),
+}
+
+/// Parses a path pattern like `/users/{id}/avatar` into its segments.
+fn parse_pattern(pattern: &[u8]) -> Result<Vec<PatternSegment>, Box<Error>> {
+    let invalid = |reason: &str| {
+        Error::explain(
+            ErrorType::InternalError,
+            format!(
+                "invalid path pattern {}: {reason}",
+                String::from_utf8_lossy(pattern)
+            ),
+        )
+    };
+
+    let mut segments = Vec::new();
+    for segment in pattern.split(|c| *c == SEPARATOR).filter(|s| !s.is_empty()) {
+        if let Some(name) = segment
+            .strip_prefix(b"{")
+            .and_then(|segment| segment.strip_suffix(b"}"))
+        {
+            let name = std::str::from_utf8(name)
+                .map_err(|_| invalid("parameter names have to be valid UTF-8"))?;
+            if name.is_empty() || name.contains(['{', '}']) {
+                return Err(invalid("invalid parameter name"));
+            }
+            if segments.contains(&PatternSegment::Parameter(name.to_owned())) {
+                return Err(invalid("duplicate parameter name"));
+            }
+            segments.push(PatternSegment::Parameter(name.to_owned()));
+        } else if segment.contains(&b'{') || segment.contains(&b'}') {
+            return Err(invalid("parameters have to span entire path segments"));
+        } else {
+            segments.push(PatternSegment::Literal(segment.to_vec()));
+        }
+    }
+    Ok(segments)
+}
+
+/// Node of the tree storing the path patterns for a host
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct PatternNode<Value> {
+    /// Child nodes for literal segments, sorted by segment
+    literals: Vec<(Vec<u8>, PatternNode<Value>)>,
+    /// Child node for a parameter segment
+    parameter: Option<Box<PatternNode<Value>>>,
+    /// Parameter names and value of the pattern ending in this node
+    value: Option<(Vec<String>, Value)>,
+}
+
+impl<Value> Default for PatternNode<Value> {
+    fn default() -> Self {
+        Self {
+            literals: Vec::new(),
+            parameter: None,
+            value: None,
+        }
+    }
+}
+
+impl<Value> PatternNode<Value> {
+    /// Adds a pattern to the tree, returns the previous value if the pattern was already present.
+    fn insert(&mut self, segments: Vec<PatternSegment>, value: Value) -> Option<Value> {
+        let mut node = self;
+        let mut names = Vec::new();
+        for segment in segments {
+            node = match segment {
+                PatternSegment::Literal(literal) => {
+                    let index = match node
+                        .literals
+                        .binary_search_by(|(existing, _)| existing.cmp(&literal))
+                    {
+                        Ok(index) => index,
+                        Err(index) => {
+                            node.literals.insert(index, (literal, Self::default()));
+                            index
+                        }
+                    };
+                    &mut node.literals[index].1
+                }
+                PatternSegment::Parameter(name) => {
+                    names.push(name);
+                    node.parameter.get_or_insert_with(Default::default)
+                }
+            };
+        }
+        node.value
+            .replace((names, value))
+            .map(|(_, previous)| previous)
+    }
+
+    /// Merges another tree into this one, values of the other tree take precedence.
+    fn merge(&mut self, other: Self) {
+        for (literal, child) in other.literals {
+            match self
+                .literals
+                .binary_search_by(|(existing, _)| existing.cmp(&literal))
+            {
+                Ok(index) => self.literals[index].1.merge(child),
+                Err(index) => self.literals.insert(index, (literal, child)),
+            }
+        }
+
+        if let Some(child) = other.parameter {
+            match &mut self.parameter {
+                Some(existing) => existing.merge(*child),
+                None => self.parameter = Some(child),
+            }
+        }
+
+        if other.value.is_some() {
+            self.value = other.value;
+        }
+    }
+
+    /// Finds the pattern matching the given path segments exactly, collecting the segments
+    /// matched by parameters. Literal segments are preferred over parameters.
+    fn lookup<'a, 'p>(
+        &'a self,
+        segments: &[&'p [u8]],
+        captures: &mut Vec<&'p [u8]>,
+    ) -> Option<&'a (Vec<String>, Value)> {
+        let (segment, rest) = if let Some(split) = segments.split_first() {
+            split
+        } else {
+            return self.value.as_ref();
+        };
+
+        if let Ok(index) = self
+            .literals
+            .binary_search_by(|(literal, _)| literal.as_slice().cmp(segment))
+        {
+            if let Some(result) = self.literals[index].1.lookup(rest, captures) {
+                return Some(result);
+            }
+        }
+
+        if let Some(parameter) = &self.parameter {
+            captures.push(segment);
+            if let Some(result) = parameter.lookup(rest, captures) {
+                return Some(result);
+            }
+            captures.pop();
+        }
+        None
+    }
+}
+
+/// Result of a [`Router::lookup_pattern`] call
+///
+/// Dereferences to the value of the matching rule. Parameter values are slices of the path passed
+/// to the lookup, these aren’t decoded or copied.
+#[derive(Debug)]
+pub struct PatternMatch<'a, 'p, Value> {
+    value: &'a Value,
+    names: &'a [String],
+    captures: Vec<&'p [u8]>,
+}
+
+impl<'a, 'p, Value> PatternMatch<'a, 'p, Value> {
+    /// Returns the value of the matching rule.
+    pub fn value(&self) -> &'a Value {
+        self.value
+    }
+
+    /// Returns the path segment captured by the parameter with the given name.
+    pub fn get(&self, name: &str) -> Option<&'p [u8]> {
+        self.names
+            .iter()
+            .position(|existing| existing == name)
+            .map(|index| self.captures[index])
+    }
+
+    /// Iterates over parameter names and their captured path segments in the order of appearance
+    /// in the pattern. This is empty if a literal rule matched.
+    pub fn params(&self) -> impl Iterator<Item = (&'a str, &'p [u8])> + '_ {
+        self.names
+            .iter()
+            .map(String::as_str)
+            .zip(self.captures.iter().copied())
+    }
+}
+
+impl<Value> Deref for PatternMatch<'_, '_, Value> {
+    type Target = Value;
+
+    fn deref(&self) -> &Self::Target {
+        self.value
+    }
+}
+
+/// The kind of host name a rule considered by [`Router::describe_lookup`] was defined for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostMatch {
+    /// Rule defined for the exact host name
+    Exact,
+    /// Rule defined for a wildcard host name like `*.example.com`
+    Wildcard,
+    /// Fallback rule applying to all host names
+    Fallback,
+}
+
+/// A matching rule considered by [`Router::describe_lookup`]
+#[derive(Debug, Clone)]
+pub struct LookupCandidate<'a, Value> {
+    /// Priority of the rule, see [`RouterBuilder::push_with_priority`]
+    pub priority: i32,
+    /// Kind of host name the rule was defined for
+    pub host_match: HostMatch,
+    /// Host name, normalized path and value of the rule
+    pub result: LookupResult<'a, Value>,
+    /// Number of segments matched, the host name counts as a segment
+    pub segments: usize,
+}
+
+/// Trace of a routing decision produced by [`Router::describe_lookup`]
+#[derive(Debug, Clone)]
+pub struct LookupTrace<'a, Value> {
+    /// All rules matching the host/path combination. These are ordered by priority (descending),
+    /// then exact host before wildcard host before fallback, then from the shortest to the longest
+    /// path.
+    pub candidates: Vec<LookupCandidate<'a, Value>>,
+    /// Index of the rule that [`Router::lookup`] selects within `candidates`, if any
+    pub winner: Option<usize>,
+}
+
+impl<Value> LookupTrace<'_, Value> {
+    /// Explains why the winning rule has been selected.
+    pub fn reason(&self) -> String {
+        let winner = if let Some(winner) = self.winner.and_then(|index| self.candidates.get(index))
+        {
+            winner
+        } else {
+            return "no matching rule".to_owned();
+        };
+
+        let mut reason = match winner.host_match {
+            HostMatch::Exact => "longest matching path for the exact host name",
+            HostMatch::Wildcard => {
+                "longest matching path for the wildcard host name, no rules for the exact host name"
+            }
+            HostMatch::Fallback => "longest matching fallback path, no host-specific rules",
+        }
+        .to_owned();
+        if self
+            .candidates
+            .iter()
+            .any(|candidate| candidate.priority != winner.priority)
+        {
+            reason.push_str(&format!(
+                ", priority {} is the highest priority with matching rules",
+                winner.priority
+            ));
+        }
+        reason
+    }
+}
+
+impl<Value: Debug> std::fmt::Display for LookupTrace<'_, Value> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, candidate) in self.candidates.iter().enumerate() {
+            writeln!(
+                f,
+                "{} {}/{} (priority {}, {:?} host, {} segments): {:?}",
+                if self.winner == Some(index) { "*" } else { " " },
+                String::from_utf8_lossy(candidate.result.host()),
+                String::from_utf8_lossy(candidate.result.path()),
+                candidate.priority,
+                candidate.host_match,
+                candidate.segments,
+                candidate.result.as_value(),
+            )?;
+        }
+        write!(f, "result: {}", self.reason())
+    }
+}
+
+/// Splits a key of the host trie like `example.com/dir` into host name and path.
+fn split_host_key(key: &[u8]) -> (&[u8], &[u8]) {
+    if let Some(pos) = key.iter().position(|c| *c == SEPARATOR) {
+        (&key[..pos], &key[pos + 1..])
+    } else {
+        (key, &[])
+    }
+}
+
+/// A rule listed by [`Router::routes_sorted`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteEntry<'a, Value> {
+    /// Host name of the rule in lowercase, empty for fallback rules
+    pub host: &'a [u8],
+    /// Path of the rule, normalized like [`LookupResult::path`]
+    pub path: &'a [u8],
+    /// Value applying to the exact path if any
+    pub value_exact: Option<&'a Value>,
+    /// Value applying to the paths within this path if any
+    pub value_prefix: Option<&'a Value>,
+}
+
+impl<Value: Debug> std::fmt::Display for RouteEntry<'_, Value> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{}: exact {:?}, prefix {:?}",
+            String::from_utf8_lossy(self.host),
+            String::from_utf8_lossy(self.path),
+            self.value_exact,
+            self.value_prefix,
+        )
+    }
+}
+
 /// The router implementation.
 ///
 /// A new instance can be created by calling [`Router::builder`]. You add the rules and call
@@ -140,22 +734,37 @@ impl Deref for Path {
 /// use pandora_module_utils::router::Router;
 ///
 /// let mut builder = Router::builder();
-/// builder.push("localhost", "/", "Localhost root", Some("Within localhost"));
-/// builder.push("localhost", "/dir/", "Localhost subdirectory", None);
-/// builder.push("example.com", "/", "Website root", Some("Within website"));
-/// builder.push("example.com", "/dir/", "Website subdirectory", Some("Within website subdirectory"));
+/// builder.push("localhost", "/", "Localhost root", Some("Within localhost")).unwrap();
+/// builder.push("localhost", "/dir/", "Localhost subdirectory", None).unwrap();
+/// builder.push("example.com", "/", "Website root", Some("Within website")).unwrap();
+/// builder.push("example.com", "/dir/", "Website subdirectory", Some("Within website subdirectory")).unwrap();
 ///
-/// let router = builder.build();
+/// let router = builder.build().unwrap();
 /// assert_eq!(*router.lookup("localhost", "/").unwrap(), "Localhost root");
 /// assert_eq!(*router.lookup("localhost", "/dir/file").unwrap(), "Within localhost");
 /// assert_eq!(*router.lookup("example.com", "/dir/file").unwrap(), "Within website subdirectory");
 /// ```
+///
+/// With the `serde` feature enabled, a router can be serialized via [`Router::save`] and restored
+/// via [`Router::load`], e.g. to precompute large routing tables.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Router<Value> {
     trie: Trie<Value>,
     fallback: Trie<Value>,
+    wildcards: Trie<Vec<u8>>,
+    method_routes: Vec<(Methods, Router<Value>)>,
+    #[cfg_attr(feature = "serde", serde(default = "Vec::new"))]
+    scheme_routes: Vec<(RequestScheme, Router<Value>)>,
+    #[cfg_attr(feature = "serde", serde(default = "Vec::new"))]
+    priority_routes: Vec<(i32, Router<Value>)>,
+    #[cfg_attr(feature = "serde", serde(default = "Vec::new"))]
+    patterns: Vec<(Vec<u8>, PatternNode<Value>)>,
 }
 
+/// Routers for rules with explicit priorities, sorted by priority in descending order
+type PriorityRoutes<Value> = [(i32, Router<Value>)];
+
 impl<Value> Router<Value> {
     /// Returns a builder instance that can be used to set up a router.
     ///
@@ -168,331 +777,3105 @@ impl<Value> Router<Value> {
         RouterBuilder {
             entries: Default::default(),
             fallbacks: Default::default(),
+            method_builders: Default::default(),
+            scheme_builders: Default::default(),
+            priority_builders: Default::default(),
+            patterns: Default::default(),
         }
     }
 
     /// Looks up a host/path combination in the routing table, returns the matching value if any.
+    ///
+    /// The result also reports the host name and path of the rule that matched, see
+    /// [`LookupResult::host`] and [`LookupResult::path`]. Host names are compared
+    /// case-insensitively, the lookup doesn’t allocate memory for typical host name lengths.
+    ///
+    /// If rules with explicit priorities have been added via
+    /// [`RouterBuilder::push_with_priority`], the match with the highest priority wins. Only
+    /// among matches with the same priority, the usual precedence rules (exact host before
+    /// wildcard host before fallback, longer path before shorter path) apply.
     pub fn lookup(
         &self,
         host: &(impl AsRef<[u8]> + ?Sized),
         path: &(impl AsRef<[u8]> + ?Sized),
     ) -> Option<LookupResult<'_, Value>> {
-        if !host.as_ref().is_empty() {
-            self.trie.lookup(make_key(host, path))
-        } else {
-            None
-        }
-        .or_else(|| self.fallback.lookup(make_key("", path)))
+        let host = LowercaseHost::new(host.as_ref());
+        let host = &*host;
+        let path = path.as_ref();
+        self.find_prioritized(|router| router.lookup_unprioritized(host, path))
     }
 
-    /// Retrieves the value from a previous lookup by its index
-    pub fn retrieve(&self, index: usize) -> Option<&Value> {
-        self.trie.retrieve(index)
+    /// Splits the routers for rules with explicit priorities into the ones taking precedence over
+    /// the rules with default priority 0 and the ones only considered after these.
+    fn priority_groups(&self) -> (&PriorityRoutes<Value>, &PriorityRoutes<Value>) {
+        // Routers are sorted by priority in descending order, default priority 0 goes in between
+        self.priority_routes.split_at(
+            self.priority_routes
+                .partition_point(|(priority, _)| *priority > 0),
+        )
     }
-}
-
-fn make_key<'a>(
-    host: &'a (impl AsRef<[u8]> + ?Sized),
-    path: &'a (impl AsRef<[u8]> + ?Sized),
-) -> Box<dyn Iterator<Item = &'a [u8]> + 'a> {
-    let path_iter = path
-        .as_ref()
-        .split(|c| *c == SEPARATOR)
-        .filter(|s| !s.is_empty());
 
-    let host = host.as_ref();
-    if host.is_empty() {
-        Box::new(path_iter)
-    } else {
-        Box::new(std::iter::once(host).chain(path_iter))
+    /// Runs a lookup ignoring rules with explicit priorities on the routers for all priorities,
+    /// starting with the highest priority. The first result found is returned.
+    fn find_prioritized<'a, T>(&'a self, lookup: impl Fn(&'a Self) -> Option<T>) -> Option<T> {
+        let (higher, lower) = self.priority_groups();
+        higher
+            .iter()
+            .find_map(|(_, router)| lookup(router))
+            .or_else(|| lookup(self))
+            .or_else(|| lower.iter().find_map(|(_, router)| lookup(router)))
     }
-}
 
-/// Intermediate entry stored in the router prior to merging
-#[derive(Debug)]
-struct RouterEntry<Value> {
-    path: Path,
-    value_exact: Value,
-    value_prefix: Option<Value>,
-}
+    /// Determines the router with the highest priority where a lookup ignoring rules with
+    /// explicit priorities succeeds, see [`Router::find_prioritized`].
+    fn find_prioritized_mut(&mut self, matches: impl Fn(&Self) -> bool) -> Option<&mut Self> {
+        if self.priority_routes.is_empty() {
+            return Some(self);
+        }
 
-/// The router builder used to set up a [`Router`] instance
-#[derive(Debug)]
-pub struct RouterBuilder<Value> {
-    entries: HashMap<Vec<u8>, Vec<RouterEntry<Value>>>,
-    fallbacks: Vec<RouterEntry<Value>>,
-}
+        let (higher, lower) = self.priority_groups();
+        let index = if let Some(index) = higher.iter().position(|(_, router)| matches(router)) {
+            Some(index)
+        } else if matches(self) {
+            None
+        } else {
+            Some(higher.len() + lower.iter().position(|(_, router)| matches(router))?)
+        };
+        Some(match index {
+            Some(index) => &mut self.priority_routes[index].1,
+            None => self,
+        })
+    }
 
-impl<Value: Clone + Eq> RouterBuilder<Value> {
-    fn merge_value(
-        existing: &mut Vec<RouterEntry<Value>>,
-        path: Path,
-        value_exact: Value,
-        mut value_prefix: Option<Value>,
-    ) -> bool {
-        match existing.binary_search_by_key(&path.as_slice(), |entry| entry.path.as_slice()) {
-            Ok(index) => {
-                existing[index].value_exact = value_exact;
-                if value_prefix.is_some() {
-                    existing[index].value_prefix = value_prefix;
-                }
-                true
-            }
-            Err(index) => {
-                // Adding a new entry.
-                if value_prefix.is_none() {
-                    // Copy `value_prefix` from closest parent.
-                    for parent in existing[0..index].iter_mut().rev() {
-                        if parent.path.is_prefix_of(&path) && parent.value_prefix.is_some() {
-                            value_prefix.clone_from(&parent.value_prefix);
-                            break;
+    /// Looks up a host/path combination like [`Router::lookup`] but percent-decodes the path
+    /// first, so that a request for `/sub%64ir/file` matches a rule for `/subdir`.
+    ///
+    /// `None` is returned if the path cannot be decoded safely, see [`decode_path`].
+    pub fn lookup_decoded(
+        &self,
+        host: &(impl AsRef<[u8]> + ?Sized),
+        path: &(impl AsRef<[u8]> + ?Sized),
+    ) -> Option<LookupResult<'_, Value>> {
+        self.lookup(host, &*decode_path(path.as_ref())?)
+    }
+
+    /// Looks up a path for a list of candidate host names like [`Router::lookup_hosts`] but
+    /// percent-decodes the path first, see [`Router::lookup_decoded`].
+    pub fn lookup_hosts_decoded(
+        &self,
+        hosts: &[impl AsRef<[u8]>],
+        path: &(impl AsRef<[u8]> + ?Sized),
+    ) -> Option<LookupResult<'_, Value>> {
+        self.lookup_hosts(hosts, &*decode_path(path.as_ref())?)
+    }
+
+    /// Looks up a path for a list of candidate host names, e.g. the literal host name followed by
+    /// an alias and the empty host name for fallback rules.
+    ///
+    /// Host names are tried in the order given, the first host name with any matching rule wins.
+    /// So a rule for the root path under the first host name is preferred over a rule for a
+    /// longer path under a later host name. For each host name, wildcard hosts are considered
+    /// right after the exact host name. The fallback rules are only considered where an empty
+    /// host name is listed, so [`Router::lookup`] is equivalent to passing `[host, ""]` here.
+    ///
+    /// Rules with explicit priorities are handled like with [`Router::lookup`]: a match with a
+    /// higher priority wins regardless of the position of its host name in the list.
+    pub fn lookup_hosts(
+        &self,
+        hosts: &[impl AsRef<[u8]>],
+        path: &(impl AsRef<[u8]> + ?Sized),
+    ) -> Option<LookupResult<'_, Value>> {
+        let path = path.as_ref();
+        self.find_prioritized(|router| router.lookup_hosts_unprioritized(hosts, path))
+    }
+
+    /// Looks up a path for a list of candidate host names like [`Router::lookup_hosts`] but also
+    /// considers rules added via [`RouterBuilder::push_with_scheme`] for the given request scheme.
+    ///
+    /// For each host name, a scheme-specific rule is preferred over a scheme-agnostic rule
+    /// unless the latter is a closer match, e.g. due to its longer path.
+    pub fn lookup_hosts_with_scheme(
+        &self,
+        hosts: &[impl AsRef<[u8]>],
+        path: &(impl AsRef<[u8]> + ?Sized),
+        scheme: RequestScheme,
+    ) -> Option<LookupResult<'_, Value>> {
+        let Some((_, scheme_router)) = self
+            .scheme_routes
+            .iter()
+            .find(|(existing, _)| *existing == scheme)
+        else {
+            return self.lookup_hosts(hosts, path);
+        };
+
+        let path = path.as_ref();
+        let (higher, lower) = self.priority_groups();
+        higher
+            .iter()
+            .find_map(|(_, router)| router.lookup_hosts_unprioritized(hosts, path))
+            .or_else(|| {
+                hosts.iter().find_map(|host| {
+                    let host = LowercaseHost::new(host.as_ref());
+                    let generic = self.lookup_host_ranked(&host, path);
+                    let specific = scheme_router.lookup_host_ranked(&host, path);
+                    match (generic, specific) {
+                        (Some((generic, generic_rank)), Some((specific, specific_rank))) => {
+                            if specific_rank >= generic_rank {
+                                Some(specific)
+                            } else {
+                                Some(generic)
+                            }
                         }
+                        (generic, specific) => generic.or(specific).map(|(result, _)| result),
+                    }
+                })
+            })
+            .or_else(|| {
+                lower
+                    .iter()
+                    .find_map(|(_, router)| router.lookup_hosts_unprioritized(hosts, path))
+            })
+    }
+
+    /// Looks up a host/path combination like [`Router::lookup`] but also considers rules added
+    /// via [`RouterBuilder::push_with_scheme`] for the given request scheme, see
+    /// [`Router::lookup_hosts_with_scheme`].
+    pub fn lookup_with_scheme(
+        &self,
+        host: &(impl AsRef<[u8]> + ?Sized),
+        path: &(impl AsRef<[u8]> + ?Sized),
+        scheme: RequestScheme,
+    ) -> Option<LookupResult<'_, Value>> {
+        self.lookup_hosts_with_scheme(&[host.as_ref(), b""], path, scheme)
+    }
+
+    /// Looks up a single host name (fallback rules for an empty host name) ignoring rules with
+    /// explicit priorities. The rank of the match is returned as well, see
+    /// [`Router::lookup_ranked`]. The host name has to be lowercase already.
+    fn lookup_host_ranked(
+        &self,
+        host: &[u8],
+        path: &[u8],
+    ) -> Option<(LookupResult<'_, Value>, (u8, usize))> {
+        if host.is_empty() {
+            return self
+                .fallback
+                .lookup_with_segments(make_key("", path))
+                .map(|(result, segments)| (result, (0, segments)));
+        }
+
+        let (mut result, rank) = if let Some((result, segments)) =
+            self.trie.lookup_with_segments(make_key(host, path))
+        {
+            (result, (2, segments))
+        } else {
+            let wildcard = self.wildcard_host(host)?;
+            let (result, segments) = self.trie.lookup_with_segments(make_key(wildcard, path))?;
+            (result, (1, segments))
+        };
+        result.split_host(SEPARATOR);
+        Some((result, rank))
+    }
+
+    /// Looks up a path for a list of candidate host names ignoring rules with explicit priorities.
+    fn lookup_hosts_unprioritized(
+        &self,
+        hosts: &[impl AsRef<[u8]>],
+        path: &[u8],
+    ) -> Option<LookupResult<'_, Value>> {
+        hosts.iter().find_map(|host| {
+            let host = LowercaseHost::new(host.as_ref());
+            if host.is_empty() {
+                self.fallback.lookup(make_key("", path))
+            } else {
+                self.lookup_host(&host, path)
+            }
+        })
+    }
+
+    /// Looks up a host/path combination in the routing table ignoring rules with explicit
+    /// priorities.
+    fn lookup_unprioritized(&self, host: &[u8], path: &[u8]) -> Option<LookupResult<'_, Value>> {
+        if !host.is_empty() {
+            self.lookup_host(host, path)
+        } else {
+            None
+        }
+        .or_else(|| self.fallback.lookup(make_key("", path)))
+    }
+
+    /// Looks up a host/path combination considering only rules for the exact host name and
+    /// wildcard hosts, not the fallback rules. The host name has to be lowercase already.
+    fn lookup_host(&self, host: &[u8], path: &[u8]) -> Option<LookupResult<'_, Value>> {
+        self.trie
+            .lookup(make_key(host, path))
+            .or_else(|| {
+                let wildcard = self.wildcard_host(host)?;
+                self.trie.lookup(make_key(wildcard, path))
+            })
+            .map(|mut result| {
+                result.split_host(SEPARATOR);
+                result
+            })
+    }
+
+    /// Looks up a host/path combination in the routing table like [`Router::lookup`], but also
+    /// considers rules added via [`RouterBuilder::push_with_methods`] for the given request method.
+    ///
+    /// The matching location is determined across all applicable rules. If both a rule for the
+    /// method and a method-agnostic rule apply at the same location, the former is preferred. If
+    /// multiple method-specific rules apply at the same location, the method set passed to
+    /// [`RouterBuilder::push_with_methods`] first wins. Rules with explicit priorities apply to
+    /// all methods and are handled like with [`Router::lookup`].
+    pub fn lookup_with_method(
+        &self,
+        host: &(impl AsRef<[u8]> + ?Sized),
+        path: &(impl AsRef<[u8]> + ?Sized),
+        method: &Method,
+    ) -> Option<&Value> {
+        let host = LowercaseHost::new(host.as_ref());
+        let host = &*host;
+        let path = path.as_ref();
+        self.find_prioritized(|router| router.lookup_with_method_unprioritized(host, path, method))
+    }
+
+    /// Looks up a host/path combination for the given request method ignoring rules with explicit
+    /// priorities, see [`Router::lookup_with_method`].
+    fn lookup_with_method_unprioritized(
+        &self,
+        host: &[u8],
+        path: &[u8],
+        method: &Method,
+    ) -> Option<&Value> {
+        let mut best = self.lookup_ranked(host, path);
+        let mut best_is_generic = true;
+        for (methods, router) in &self.method_routes {
+            if !methods.contains(method) {
+                continue;
+            }
+
+            if let Some((value, rank)) = router.lookup_ranked(host, path) {
+                let is_better = match &best {
+                    Some((_, best_rank)) => {
+                        rank > *best_rank || (rank == *best_rank && best_is_generic)
                     }
+                    None => true,
+                };
+                if is_better {
+                    best = Some((value, rank));
+                    best_is_generic = false;
+                }
+            }
+        }
+        best.map(|(value, _)| value)
+    }
+
+    /// Looks up a host/path combination like [`Router::lookup`], ranking the result by the kind
+    /// of host matched (exact, wildcard, fallback) and the number of segments matched.
+    fn lookup_ranked(&self, host: &[u8], path: &[u8]) -> Option<(&Value, (u8, usize))> {
+        if !host.is_empty() {
+            if let Some((result, segments)) = self.trie.lookup_with_segments(make_key(host, path)) {
+                return Some((result.as_value(), (2, segments)));
+            }
+
+            if let Some(wildcard) = self.wildcard_host(host) {
+                if let Some((result, segments)) =
+                    self.trie.lookup_with_segments(make_key(wildcard, path))
+                {
+                    return Some((result.as_value(), (1, segments)));
+                }
+            }
+        }
+
+        self.fallback
+            .lookup_with_segments(make_key("", path))
+            .map(|(result, segments)| (result.as_value(), (0, segments)))
+    }
+
+    /// Looks up a host/path combination in the routing table like [`Router::lookup`], but also
+    /// considers path patterns added via [`RouterBuilder::push_pattern`].
+    ///
+    /// Path patterns only match complete paths. A pattern match is preferred over a rule matching
+    /// fewer path segments, a rule matching the same number of segments takes precedence over a
+    /// pattern. So a rule for `/users/admin` wins over a pattern `/users/{id}`, yet the latter
+    /// wins over a rule for `/users` matching `/users/123` as a prefix. Host name precedence is
+    /// the same as with [`Router::lookup`]: patterns for a wildcard host are only considered if
+    /// neither rules nor patterns for the host itself match.
+    ///
+    /// Rules with explicit priorities are handled like with [`Router::lookup`]. Patterns always
+    /// have the default priority 0.
+    pub fn lookup_pattern<'a, 'p>(
+        &'a self,
+        host: &(impl AsRef<[u8]> + ?Sized),
+        path: &'p (impl AsRef<[u8]> + ?Sized),
+    ) -> Option<PatternMatch<'a, 'p, Value>> {
+        let host = LowercaseHost::new(host.as_ref());
+        let host = &*host;
+        let path = path.as_ref();
+        let segments = path
+            .split(|c| *c == SEPARATOR)
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+        self.find_prioritized(|router| router.lookup_pattern_unprioritized(host, path, &segments))
+    }
+
+    /// Looks up a host/path combination considering path patterns but ignoring rules with
+    /// explicit priorities, see [`Router::lookup_pattern`].
+    fn lookup_pattern_unprioritized<'a, 'p>(
+        &'a self,
+        host: &[u8],
+        path: &[u8],
+        segments: &[&'p [u8]],
+    ) -> Option<PatternMatch<'a, 'p, Value>> {
+        let literal = self.lookup_ranked(host, path);
+        let pattern = self.lookup_pattern_ranked(host, segments);
+        match (literal, pattern) {
+            (Some((_, rank)), Some((result, pattern_rank))) if pattern_rank > rank => Some(result),
+            (Some((value, _)), _) => Some(PatternMatch {
+                value,
+                names: &[],
+                captures: Vec::new(),
+            }),
+            (None, pattern) => pattern.map(|(result, _)| result),
+        }
+    }
+
+    /// Looks up the path segments in the path patterns, ranking the result the same way as
+    /// [`Router::lookup_ranked`] does.
+    fn lookup_pattern_ranked<'a, 'p>(
+        &'a self,
+        host: &[u8],
+        segments: &[&'p [u8]],
+    ) -> Option<(PatternMatch<'a, 'p, Value>, (u8, usize))> {
+        let mut candidates = Vec::with_capacity(3);
+        if !host.is_empty() {
+            candidates.push((host, 2));
+            if let Some(wildcard) = self.wildcard_host(host) {
+                candidates.push((wildcard, 1));
+            }
+        }
+        candidates.push((b"".as_slice(), 0));
+
+        candidates.into_iter().find_map(|(host, rank)| {
+            let index = self
+                .patterns
+                .binary_search_by(|(existing, _)| existing.as_slice().cmp(host))
+                .ok()?;
+            let mut captures = Vec::new();
+            let (names, value) = self.patterns[index].1.lookup(segments, &mut captures)?;
+
+            // Host name counts as a segment in the trie
+            let matched = segments.len() + usize::from(!host.is_empty());
+            Some((
+                PatternMatch {
+                    value,
+                    names,
+                    captures,
+                },
+                (rank, matched),
+            ))
+        })
+    }
+
+    /// Looks up a host/path combination in the routing table like [`Router::lookup`], returns a
+    /// mutable reference to the matching value if any.
+    ///
+    /// This allows patching individual values without rebuilding the router. Changing the value
+    /// only affects the matching rule, even if other rules have been added with an identical value.
+    pub fn lookup_mut(
+        &mut self,
+        host: &(impl AsRef<[u8]> + ?Sized),
+        path: &(impl AsRef<[u8]> + ?Sized),
+    ) -> Option<&mut Value>
+    where
+        Value: Clone,
+    {
+        let host = LowercaseHost::new(host.as_ref());
+        let host = &*host;
+        let path = path.as_ref();
+        self.find_prioritized_mut(|router| router.lookup_unprioritized(host, path).is_some())?
+            .lookup_mut_unprioritized(host, path)
+    }
+
+    /// Looks up a host/path combination like [`Router::lookup_mut`] ignoring rules with explicit
+    /// priorities. The host name has to be lowercase already.
+    fn lookup_mut_unprioritized(&mut self, host: &[u8], path: &[u8]) -> Option<&mut Value>
+    where
+        Value: Clone,
+    {
+        if !host.is_empty() {
+            if self.trie.lookup(make_key(host, path)).is_some() {
+                return self.trie.lookup_mut(make_key(host, path));
+            }
+            if let Some(wildcard) = self.wildcard_host(host).map(<[u8]>::to_vec) {
+                if self.trie.lookup(make_key(&wildcard, path)).is_some() {
+                    return self.trie.lookup_mut(make_key(&wildcard, path));
+                }
+            }
+        }
+        self.fallback.lookup_mut(make_key("", path))
+    }
+
+    /// Retrieves the exact match value of the rule for this host/path combination, e.g.
+    /// `*.example.com` and `/dir`. Unlike [`Router::lookup`], no prefix matching or wildcard
+    /// resolution takes place. If rules for this host/path combination have been added with
+    /// multiple priorities, the value with the highest priority is returned.
+    pub fn lookup_exact(
+        &self,
+        host: &(impl AsRef<[u8]> + ?Sized),
+        path: &(impl AsRef<[u8]> + ?Sized),
+    ) -> Option<&Value> {
+        let host = LowercaseHost::new(host.as_ref());
+        let host = &*host;
+        let path = path.as_ref();
+        self.find_prioritized(|router| router.lookup_exact_unprioritized(host, path))
+    }
+
+    /// Retrieves the exact match value of a rule ignoring rules with explicit priorities. The
+    /// host name has to be lowercase already.
+    fn lookup_exact_unprioritized(&self, host: &[u8], path: &[u8]) -> Option<&Value> {
+        if !host.is_empty() {
+            self.trie.lookup_exact(make_key(host, path))
+        } else {
+            self.fallback.lookup_exact(make_key("", path))
+        }
+    }
+
+    /// Retrieves a mutable reference to the exact match value of the rule for this host/path
+    /// combination, e.g. `*.example.com` and `/dir`. Unlike [`Router::lookup_mut`], no prefix
+    /// matching or wildcard resolution takes place. Priorities are handled like with
+    /// [`Router::lookup_exact`].
+    pub fn get_mut(
+        &mut self,
+        host: &(impl AsRef<[u8]> + ?Sized),
+        path: &(impl AsRef<[u8]> + ?Sized),
+    ) -> Option<&mut Value>
+    where
+        Value: Clone,
+    {
+        let host = LowercaseHost::new(host.as_ref());
+        let host = &*host;
+        let path = path.as_ref();
+        let router = self.find_prioritized_mut(|router| {
+            router.lookup_exact_unprioritized(host, path).is_some()
+        })?;
+        if !host.is_empty() {
+            router.trie.get_mut_by_label(make_key(host, path))
+        } else {
+            router.fallback.get_mut_by_label(make_key("", path))
+        }
+    }
+
+    /// Looks up a host/path combination in the routing table, returns all values applying to it.
+    ///
+    /// Values are ordered from the shortest to the longest matching path, each accompanied by the
+    /// number of segments matched (the host name counts as a segment). The last value is always
+    /// identical to the one returned by [`Router::lookup`]. Values for wildcard hosts are only
+    /// considered if there are no values for the exact host name, values for the fallback host
+    /// only if there are no host-specific values at all.
+    ///
+    /// If rules with explicit priorities have been added, only the values with the priority
+    /// selected by [`Router::lookup`] are returned.
+    pub fn lookup_all(
+        &self,
+        host: &(impl AsRef<[u8]> + ?Sized),
+        path: &(impl AsRef<[u8]> + ?Sized),
+    ) -> Vec<(LookupResult<'_, Value>, usize)> {
+        let host = LowercaseHost::new(host.as_ref());
+        let host = &*host;
+        let path = path.as_ref();
+        self.find_prioritized(|router| {
+            Some(router.lookup_all_unprioritized(host, path)).filter(|results| !results.is_empty())
+        })
+        .unwrap_or_default()
+    }
+
+    /// Looks up all values applying to a host/path combination ignoring rules with explicit
+    /// priorities, see [`Router::lookup_all`]. The host name has to be lowercase already.
+    fn lookup_all_unprioritized(
+        &self,
+        host: &[u8],
+        path: &[u8],
+    ) -> Vec<(LookupResult<'_, Value>, usize)> {
+        let mut results = if !host.is_empty() {
+            self.trie.lookup_all(make_key(host, path))
+        } else {
+            Vec::new()
+        };
+        if results.is_empty() && !host.is_empty() {
+            if let Some(wildcard) = self.wildcard_host(host) {
+                results = self.trie.lookup_all(make_key(wildcard, path));
+            }
+        }
+        if results.is_empty() {
+            results = self.fallback.lookup_all(make_key("", path));
+        } else {
+            for (result, _) in &mut results {
+                result.split_host(SEPARATOR);
+            }
+        }
+        results
+    }
+
+    /// Retrieves the value from a previous lookup by its index
+    pub fn retrieve(&self, index: usize) -> Option<&Value> {
+        self.trie.retrieve(index)
+    }
+
+    /// Returns the number of bytes saved by storing identical labels in the routing table only
+    /// once. This includes routers for rules added with methods, schemes or priorities.
+    pub fn labels_saved(&self) -> usize {
+        let nested: usize = self
+            .method_routes
+            .iter()
+            .map(|(_, router)| router)
+            .chain(self.scheme_routes.iter().map(|(_, router)| router))
+            .chain(self.priority_routes.iter().map(|(_, router)| router))
+            .map(Router::labels_saved)
+            .sum();
+        self.trie.labels_saved()
+            + self.fallback.labels_saved()
+            + self.wildcards.labels_saved()
+            + nested
+    }
+
+    /// Lists all rules in the routing table as `(host, path, value)` tuples.
+    ///
+    /// Host names are lowercase, empty for fallback rules. Paths are normalized like
+    /// [`LookupResult::path`]. Each host/path combination is listed once along with its
+    /// `value_exact` value, rules added via [`RouterBuilder::push_with_methods`] and
+    /// [`RouterBuilder::push_with_priority`] are listed after the regular rules. Path patterns
+    /// aren’t listed.
+    pub fn routes(&self) -> Box<dyn Iterator<Item = (&[u8], &[u8], &Value)> + '_> {
+        let hosts = self.trie.entries().map(|(key, value)| {
+            let (host, path) = split_host_key(key);
+            (host, path, value)
+        });
+        let fallbacks = self
+            .fallback
+            .entries()
+            .map(|(path, value)| (&[][..], path, value));
+        Box::new(
+            hosts
+                .chain(fallbacks)
+                .chain(
+                    self.method_routes
+                        .iter()
+                        .flat_map(|(_, router)| router.routes()),
+                )
+                .chain(
+                    self.scheme_routes
+                        .iter()
+                        .flat_map(|(_, router)| router.routes()),
+                )
+                .chain(
+                    self.priority_routes
+                        .iter()
+                        .flat_map(|(_, router)| router.routes()),
+                ),
+        )
+    }
+
+    /// Lists all rules in the routing table along with both of their values, in an order that
+    /// only depends on the rules themselves. This is meant for deterministic configuration dumps,
+    /// e.g. printing the effective routing configuration at startup.
+    ///
+    /// Regular rules are sorted by host name with fallback rules first, then by path, comparing
+    /// both segment by segment. Rules added via [`RouterBuilder::push_with_methods`],
+    /// [`RouterBuilder::push_with_scheme`] and [`RouterBuilder::push_with_priority`] follow,
+    /// sorted the same way within each group. Path patterns aren’t listed.
+    pub fn routes_sorted(&self) -> Vec<RouteEntry<'_, Value>> {
+        let mut result = Vec::new();
+        for (path, value_exact, value_prefix) in self.fallback.entries_sorted() {
+            result.push(RouteEntry {
+                host: &[],
+                path,
+                value_exact,
+                value_prefix,
+            });
+        }
+        for (key, value_exact, value_prefix) in self.trie.entries_sorted() {
+            let (host, path) = split_host_key(key);
+            result.push(RouteEntry {
+                host,
+                path,
+                value_exact,
+                value_prefix,
+            });
+        }
+        for (_, router) in &self.method_routes {
+            result.extend(router.routes_sorted());
+        }
+        for (_, router) in &self.scheme_routes {
+            result.extend(router.routes_sorted());
+        }
+        for (_, router) in &self.priority_routes {
+            result.extend(router.routes_sorted());
+        }
+        result
+    }
+
+    /// Looks up a host/path combination like [`Router::lookup`] but records the decision process.
+    ///
+    /// The result lists all matching rules that were considered, including the ones for wildcard
+    /// and fallback hosts, and indicates which one was selected. This is meant for troubleshooting
+    /// and is considerably less efficient than a regular lookup.
+    pub fn describe_lookup(
+        &self,
+        host: &(impl AsRef<[u8]> + ?Sized),
+        path: &(impl AsRef<[u8]> + ?Sized),
+    ) -> LookupTrace<'_, Value> {
+        let host = LowercaseHost::new(host.as_ref());
+        let host = &*host;
+        let path = path.as_ref();
+        let (higher, lower) = self.priority_groups();
+
+        let mut trace = LookupTrace {
+            candidates: Vec::new(),
+            winner: None,
+        };
+        let routers = higher
+            .iter()
+            .map(|(priority, router)| (*priority, router))
+            .chain(std::iter::once((0, self)))
+            .chain(lower.iter().map(|(priority, router)| (*priority, router)));
+        for (priority, router) in routers {
+            let winner = router.describe_unprioritized(host, path, priority, &mut trace.candidates);
+            trace.winner = trace.winner.or(winner);
+        }
+        trace
+    }
+
+    /// Adds the rules matching a host/path combination to the list of candidates, ignoring rules
+    /// with explicit priorities. Returns the index of the candidate selected by
+    /// [`Router::lookup`] if any.
+    fn describe_unprioritized<'a>(
+        &'a self,
+        host: &[u8],
+        path: &[u8],
+        priority: i32,
+        candidates: &mut Vec<LookupCandidate<'a, Value>>,
+    ) -> Option<usize> {
+        let mut add = |results: Vec<(LookupResult<'a, Value>, usize)>, host_match| {
+            let empty = results.is_empty();
+            for (mut result, segments) in results {
+                if host_match != HostMatch::Fallback {
+                    result.split_host(SEPARATOR);
+                }
+                candidates.push(LookupCandidate {
+                    priority,
+                    host_match,
+                    result,
+                    segments,
+                });
+            }
+            (!empty).then(|| candidates.len() - 1)
+        };
+
+        let mut winner = None;
+        if !host.is_empty() {
+            winner = add(self.trie.lookup_all(make_key(host, path)), HostMatch::Exact);
+            if let Some(wildcard) = self.wildcard_host(host) {
+                let result = add(
+                    self.trie.lookup_all(make_key(wildcard, path)),
+                    HostMatch::Wildcard,
+                );
+                winner = winner.or(result);
+            }
+        }
+        let result = add(
+            self.fallback.lookup_all(make_key("", path)),
+            HostMatch::Fallback,
+        );
+        winner.or(result)
+    }
+
+    /// Finds the longest wildcard host name like `*.example.com` matching the given host name.
+    ///
+    /// The wildcard trie is a suffix trie, so this is a longest suffix lookup for the labels of the
+    /// host name’s parent domain.
+    fn wildcard_host(&self, host: &[u8]) -> Option<&[u8]> {
+        let mut labels = host
+            .split(|c| *c == HOST_SEPARATOR)
+            .filter(|s| !s.is_empty());
+
+        // The first label is always matched by the wildcard
+        labels.next()?;
+
+        self.wildcards
+            .lookup_suffix(labels)
+            .map(|result| result.as_value().as_slice())
+    }
+}
+
+impl<Value> Router<Vec<Value>> {
+    /// Looks up a host/path combination in a routing table storing multiple values per location,
+    /// see [`RouterBuilder::push_multi`].
+    ///
+    /// The location is selected like with [`Router::lookup`], all of its values are returned in
+    /// the order they were added. An empty slice is returned if there is no match.
+    pub fn lookup_multi(
+        &self,
+        host: &(impl AsRef<[u8]> + ?Sized),
+        path: &(impl AsRef<[u8]> + ?Sized),
+    ) -> &[Value] {
+        let host = LowercaseHost::new(host.as_ref());
+        let host = &*host;
+        let path = path.as_ref();
+        self.find_prioritized(|router| {
+            Some(router.lookup_multi_unprioritized(host, path)).filter(|result| !result.is_empty())
+        })
+        .unwrap_or_default()
+    }
+
+    /// Looks up all values stored for a location ignoring rules with explicit priorities, see
+    /// [`Router::lookup_multi`]. The host name has to be lowercase already.
+    fn lookup_multi_unprioritized(&self, host: &[u8], path: &[u8]) -> &[Value] {
+        let mut result = &[][..];
+        if !host.is_empty() {
+            result = self.trie.lookup_multi(make_key(host, path));
+            if result.is_empty() {
+                if let Some(wildcard) = self.wildcard_host(host) {
+                    result = self.trie.lookup_multi(make_key(wildcard, path));
                 }
+            }
+        }
+        if result.is_empty() {
+            result = self.fallback.lookup_multi(make_key("", path));
+        }
+        result
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<Value> Router<Value> {
+    /// Serializes the routing table, any serde data format can be used.
+    ///
+    /// The result can be passed to [`Router::load`] to restore the router without going through
+    /// the builder again.
+    pub fn save<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        Value: Serialize,
+    {
+        self.serialize(serializer)
+    }
+
+    /// Restores a routing table previously serialized via [`Router::save`].
+    ///
+    /// The data is validated, an error is returned if it was produced by an incompatible version
+    /// or is inconsistent.
+    pub fn load<'de, D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+        Value: Deserialize<'de>,
+    {
+        Self::deserialize(deserializer)
+    }
+}
+
+/// A router that can be replaced atomically while lookups are in progress
+///
+/// [`SharedRouter::load`] returns a snapshot of the current routing table. Replacing the router
+/// via [`SharedRouter::replace`] doesn’t affect existing snapshots, so requests in flight can
+/// finish with the routing table they started with while new requests use the new one.
+///
+/// ```rust
+/// use pandora_module_utils::router::{Router, SharedRouter};
+///
+/// let mut builder = Router::builder();
+/// builder.push("localhost", "/", "Old", None).unwrap();
+/// let router = SharedRouter::new(builder.build().unwrap());
+///
+/// let snapshot = router.load();
+///
+/// let mut builder = Router::builder();
+/// builder.push("localhost", "/", "New", None).unwrap();
+/// router.replace(builder.build().unwrap());
+///
+/// assert_eq!(*snapshot.lookup("localhost", "/").unwrap(), "Old");
+/// assert_eq!(*router.load().lookup("localhost", "/").unwrap(), "New");
+/// ```
+#[derive(Debug)]
+pub struct SharedRouter<Value> {
+    router: RwLock<Arc<Router<Value>>>,
+}
+
+impl<Value> SharedRouter<Value> {
+    /// Creates a new shared router from the given routing table.
+    pub fn new(router: Router<Value>) -> Self {
+        Self {
+            router: RwLock::new(Arc::new(router)),
+        }
+    }
+
+    /// Returns the current routing table.
+    ///
+    /// The lock is only held while cloning the reference, lookups happen on the snapshot and
+    /// never block [`SharedRouter::replace`].
+    pub fn load(&self) -> Arc<Router<Value>> {
+        // The lock only protects a reference swap, so it cannot be left inconsistent by a panic.
+        self.router
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Replaces the routing table, returns the previous one.
+    pub fn replace(&self, router: Router<Value>) -> Arc<Router<Value>> {
+        let router = Arc::new(router);
+        std::mem::replace(
+            &mut *self.router.write().unwrap_or_else(PoisonError::into_inner),
+            router,
+        )
+    }
+}
+
+impl<Value> From<Router<Value>> for SharedRouter<Value> {
+    fn from(router: Router<Value>) -> Self {
+        Self::new(router)
+    }
+}
+
+impl<Value> Clone for SharedRouter<Value> {
+    /// Creates an independent shared router starting out with the current routing table.
+    fn clone(&self) -> Self {
+        Self {
+            router: RwLock::new(self.load()),
+        }
+    }
+}
+
+impl<Value: PartialEq> PartialEq for SharedRouter<Value> {
+    fn eq(&self, other: &Self) -> bool {
+        *self.load() == *other.load()
+    }
+}
+
+impl<Value: Eq> Eq for SharedRouter<Value> {}
+
+fn make_key<'a>(
+    host: &'a (impl AsRef<[u8]> + ?Sized),
+    path: &'a (impl AsRef<[u8]> + ?Sized),
+) -> Box<dyn Iterator<Item = &'a [u8]> + 'a> {
+    let path_iter = path
+        .as_ref()
+        .split(|c| *c == SEPARATOR)
+        .filter(|s| !s.is_empty());
+
+    let host = host.as_ref();
+    if host.is_empty() {
+        Box::new(path_iter)
+    } else {
+        Box::new(std::iter::once(host).chain(path_iter))
+    }
+}
+
+/// Intermediate entry stored in the router prior to merging
+#[derive(Debug)]
+struct RouterEntry<Value> {
+    path: Path,
+    value_exact: Value,
+    value_prefix: Option<Value>,
+}
+
+/// Value replaced by [`RouterBuilder::push`] along with the location it was stored under
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replaced<Value> {
+    /// Host name of the replaced entry, empty for fallback entries
+    pub host: String,
+    /// Normalized path of the replaced entry, always starting with a slash
+    pub path: String,
+    /// The previous `value_exact` value
+    pub value: Value,
+}
+
+impl<Value> Replaced<Value> {
+    fn new(host: &[u8], path: &Path, value: Value) -> Self {
+        Self {
+            host: String::from_utf8_lossy(host).to_ascii_lowercase(),
+            path: format!("/{}", String::from_utf8_lossy(path)),
+            value,
+        }
+    }
+}
+
+impl<Value> std::fmt::Display for Replaced<Value> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", self.host, self.path)
+    }
+}
+
+/// The router builder used to set up a [`Router`] instance
+#[derive(Debug)]
+pub struct RouterBuilder<Value> {
+    entries: HashMap<Vec<u8>, Vec<RouterEntry<Value>>>,
+    fallbacks: Vec<RouterEntry<Value>>,
+    method_builders: Vec<(Methods, RouterBuilder<Value>)>,
+    scheme_builders: Vec<(RequestScheme, RouterBuilder<Value>)>,
+    priority_builders: Vec<(i32, RouterBuilder<Value>)>,
+    patterns: HashMap<Vec<u8>, PatternNode<Value>>,
+}
+
+/// Checks that the host name contains neither slashes nor misplaced wildcards.
+fn validate_host(host: &[u8]) -> Result<(), Box<Error>> {
+    if host.iter().any(u8::is_ascii_control) {
+        return Err(Error::explain(
+            ErrorType::InternalError,
+            format!(
+                "invalid host name {:?}: host names cannot contain control characters",
+                String::from_utf8_lossy(host)
+            ),
+        ));
+    }
+
+    if host.contains(&SEPARATOR) {
+        return Err(Error::explain(
+            ErrorType::InternalError,
+            format!(
+                "invalid host name {}: host names cannot contain slashes",
+                String::from_utf8_lossy(host)
+            ),
+        ));
+    }
+
+    if host
+        .strip_prefix(WILDCARD_PREFIX)
+        .unwrap_or(host)
+        .contains(&b'*')
+    {
+        return Err(Error::explain(
+            ErrorType::InternalError,
+            format!(
+                "invalid host name {}: wildcard is only allowed as first label",
+                String::from_utf8_lossy(host)
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks that the path doesn’t contain any control characters like null bytes.
+fn validate_path(path: &[u8]) -> Result<(), Box<Error>> {
+    if path.iter().any(u8::is_ascii_control) {
+        return Err(Error::explain(
+            ErrorType::InternalError,
+            format!(
+                "invalid path {:?}: paths cannot contain control characters",
+                String::from_utf8_lossy(path)
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+impl<Value: Clone + Eq> RouterBuilder<Value> {
+    fn merge_value(
+        existing: &mut Vec<RouterEntry<Value>>,
+        path: Path,
+        value_exact: Value,
+        mut value_prefix: Option<Value>,
+    ) -> Option<Value> {
+        match existing.binary_search_by_key(&path.as_slice(), |entry| entry.path.as_slice()) {
+            Ok(index) => {
+                if value_prefix.is_some() {
+                    existing[index].value_prefix = value_prefix;
+                }
+                Some(std::mem::replace(
+                    &mut existing[index].value_exact,
+                    value_exact,
+                ))
+            }
+            Err(index) => {
+                // Adding a new entry.
+                if value_prefix.is_none() {
+                    // Copy `value_prefix` from closest parent.
+                    for parent in existing[0..index].iter_mut().rev() {
+                        if parent.path.is_prefix_of(&path) && parent.value_prefix.is_some() {
+                            value_prefix.clone_from(&parent.value_prefix);
+                            break;
+                        }
+                    }
+                }
+
+                existing.insert(
+                    index,
+                    RouterEntry {
+                        path,
+                        value_exact,
+                        value_prefix,
+                    },
+                );
+                None
+            }
+        }
+    }
+
+    /// Adds a host/path combination with the respective values to the routing table.
+    ///
+    /// The `value_exact` value is only used for exact path matches. For prefix matches where only
+    /// part of the lookup path matched the `value_prefix` value will be used if present.
+    ///
+    /// The path will be normalized, so that `//dir//subdir/` is equivalent to `/dir/subdir`. The
+    /// host name on the other hand cannot contain slashes, an error is returned for such host
+    /// names. Host names starting with `*.` are wildcard hosts, an asterisk anywhere else in the
+    /// host name results in an error. Host names are case-insensitive and stored in lowercase.
+    /// Neither host names nor paths can contain control characters such as null bytes. An empty
+    /// host name adds a fallback rule applying to all hosts.
+    ///
+    /// If an existing entry is overwritten, its previous `value_exact` value is returned along
+    /// with the host name and normalized path of the entry.
+    pub fn push(
+        &mut self,
+        host: impl AsRef<[u8]>,
+        path: impl AsRef<[u8]>,
+        value_exact: Value,
+        value_prefix: Option<Value>,
+    ) -> Result<Option<Replaced<Value>>, Box<Error>> {
+        let host = host.as_ref();
+        validate_path(path.as_ref())?;
+        let path = Path::new(path);
+        let existing = self.entries_for_host(host)?;
+        Ok(
+            Self::merge_value(existing, path.clone(), value_exact, value_prefix)
+                .map(|value| Replaced::new(host, &path, value)),
+        )
+    }
+
+    /// Adds a host/path combination like [`RouterBuilder::push`], but returns an error instead of
+    /// overwriting an existing entry. Paths are compared after normalization, so `/a/` and `/a`
+    /// are considered duplicates.
+    pub fn push_unique(
+        &mut self,
+        host: impl AsRef<[u8]>,
+        path: impl AsRef<[u8]>,
+        value_exact: Value,
+        value_prefix: Option<Value>,
+    ) -> Result<(), Box<Error>> {
+        let host = host.as_ref();
+        validate_path(path.as_ref())?;
+        let path = Path::new(path);
+        let existing = self.entries_for_host(host)?;
+        if existing
+            .binary_search_by_key(&path.as_slice(), |entry| entry.path.as_slice())
+            .is_ok()
+        {
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                format!(
+                    "duplicate route {}/{}",
+                    String::from_utf8_lossy(host).to_ascii_lowercase(),
+                    String::from_utf8_lossy(&path)
+                ),
+            ));
+        }
+
+        Self::merge_value(existing, path, value_exact, value_prefix);
+        Ok(())
+    }
+
+    /// Adds a host/path combination like [`RouterBuilder::push`], but restricts it to requests
+    /// using one of the given methods.
+    ///
+    /// Such rules are only considered by [`Router::lookup_with_method`]. Passing [`Methods::ANY`]
+    /// is equivalent to calling [`RouterBuilder::push`].
+    ///
+    /// If an existing entry for the same method set is overwritten, its previous `value_exact`
+    /// value is returned.
+    pub fn push_with_methods(
+        &mut self,
+        host: impl AsRef<[u8]>,
+        path: impl AsRef<[u8]>,
+        methods: Methods,
+        value_exact: Value,
+        value_prefix: Option<Value>,
+    ) -> Result<Option<Replaced<Value>>, Box<Error>> {
+        if methods == Methods::ANY {
+            return self.push(host, path, value_exact, value_prefix);
+        }
+
+        self.builder_for_methods(methods)
+            .push(host, path, value_exact, value_prefix)
+    }
+
+    /// Adds a path pattern like `/users/{id}/avatar` for the given host to the routing table.
+    ///
+    /// A parameter like `{id}` has to span an entire path segment and matches any single segment.
+    /// Parameter names have to be unique within a pattern. Patterns only match complete paths and
+    /// are only considered by [`Router::lookup_pattern`]. Host names are handled the same as with
+    /// [`RouterBuilder::push`].
+    ///
+    /// If an existing pattern is overwritten, its previous value is returned. Patterns differing
+    /// only in parameter names are considered identical.
+    pub fn push_pattern(
+        &mut self,
+        host: impl AsRef<[u8]>,
+        pattern: impl AsRef<[u8]>,
+        value: Value,
+    ) -> Result<Option<Value>, Box<Error>> {
+        let host = host.as_ref();
+        validate_host(host)?;
+        validate_path(pattern.as_ref())?;
+        let segments = parse_pattern(pattern.as_ref())?;
+        Ok(self
+            .patterns
+            .entry(host.to_ascii_lowercase())
+            .or_default()
+            .insert(segments, value))
+    }
+
+    /// Adds a host/path combination like [`RouterBuilder::push`], but with an explicit priority.
+    ///
+    /// [`Router::lookup`] will prefer matching rules with a higher priority, even if a rule with
+    /// lower priority matches a longer part of the path. Rules added via [`RouterBuilder::push`]
+    /// have priority 0, so passing 0 here is equivalent to calling [`RouterBuilder::push`]. The
+    /// other lookup functions handle priorities the same way, rules with an explicit priority
+    /// apply regardless of request method or scheme.
+    ///
+    /// If an existing entry with the same priority is overwritten, its previous `value_exact`
+    /// value is returned.
+    pub fn push_with_priority(
+        &mut self,
+        host: impl AsRef<[u8]>,
+        path: impl AsRef<[u8]>,
+        priority: i32,
+        value_exact: Value,
+        value_prefix: Option<Value>,
+    ) -> Result<Option<Replaced<Value>>, Box<Error>> {
+        if priority == 0 {
+            return self.push(host, path, value_exact, value_prefix);
+        }
+
+        self.builder_for_priority(priority)
+            .push(host, path, value_exact, value_prefix)
+    }
+
+    /// Returns the builder collecting the rules for a particular priority.
+    fn builder_for_priority(&mut self, priority: i32) -> &mut Self {
+        // Keep builders sorted by priority in descending order
+        match self
+            .priority_builders
+            .binary_search_by(|(existing, _)| priority.cmp(existing))
+        {
+            Ok(index) => &mut self.priority_builders[index].1,
+            Err(index) => {
+                self.priority_builders
+                    .insert(index, (priority, Router::builder()));
+                &mut self.priority_builders[index].1
+            }
+        }
+    }
+
+    /// Adds a host/path combination like [`RouterBuilder::push`], but restricts it to requests
+    /// using the given scheme.
+    ///
+    /// Such rules are only considered by [`Router::lookup_with_scheme`] and
+    /// [`Router::lookup_hosts_with_scheme`]. Passing `None` as scheme is equivalent to calling
+    /// [`RouterBuilder::push`].
+    ///
+    /// If an existing entry for the same scheme is overwritten, its previous `value_exact` value
+    /// is returned.
+    pub fn push_with_scheme(
+        &mut self,
+        host: impl AsRef<[u8]>,
+        path: impl AsRef<[u8]>,
+        scheme: Option<RequestScheme>,
+        value_exact: Value,
+        value_prefix: Option<Value>,
+    ) -> Result<Option<Replaced<Value>>, Box<Error>> {
+        let Some(scheme) = scheme else {
+            return self.push(host, path, value_exact, value_prefix);
+        };
+
+        self.builder_for_scheme(scheme)
+            .push(host, path, value_exact, value_prefix)
+    }
+
+    /// Returns the builder collecting the rules for a particular scheme.
+    fn builder_for_scheme(&mut self, scheme: RequestScheme) -> &mut Self {
+        let index = if let Some(index) = self
+            .scheme_builders
+            .iter()
+            .position(|(existing, _)| *existing == scheme)
+        {
+            index
+        } else {
+            self.scheme_builders.push((scheme, Router::builder()));
+            self.scheme_builders.len() - 1
+        };
+        &mut self.scheme_builders[index].1
+    }
+
+    /// Returns the builder collecting the rules for a particular method set.
+    fn builder_for_methods(&mut self, methods: Methods) -> &mut Self {
+        let index = if let Some(index) = self
+            .method_builders
+            .iter()
+            .position(|(existing, _)| *existing == methods)
+        {
+            index
+        } else {
+            self.method_builders.push((methods, Router::builder()));
+            self.method_builders.len() - 1
+        };
+        &mut self.method_builders[index].1
+    }
+
+    /// Validates the host name and returns the entries list for it.
+    fn entries_for_host(
+        &mut self,
+        host: &[u8],
+    ) -> Result<&mut Vec<RouterEntry<Value>>, Box<Error>> {
+        validate_host(host)?;
+
+        Ok(if host.is_empty() {
+            &mut self.fallbacks
+        } else {
+            self.entries.entry(host.to_ascii_lowercase()).or_default()
+        })
+    }
+
+    /// Translates all rules into a router instance while also merging values if multiple apply to
+    /// the same location.
+    ///
+    /// An error is returned if the rules cannot be translated into a routing table, e.g. if
+    /// multiple rules end up with the same key.
+    pub fn build(self) -> Result<Router<Value>, Box<Error>> {
+        let mut trie_entries = Vec::new();
+        let mut wildcard_builder = Trie::suffix_builder_with_separator(HOST_SEPARATOR);
+        let pattern_wildcards = self
+            .patterns
+            .keys()
+            .filter(|host| !self.entries.contains_key(*host));
+        for host in self.entries.keys().chain(pattern_wildcards) {
+            if let Some(suffix) = host.strip_prefix(WILDCARD_PREFIX) {
+                wildcard_builder.push(suffix.to_vec(), host.clone(), Some(host.clone()));
+            }
+        }
+
+        for (host, entries) in self.entries {
+            for entry in entries {
+                let mut key = host.clone();
+                if !entry.path.is_empty() {
+                    key.push(SEPARATOR);
+                    key.extend_from_slice(&entry.path);
+                }
+                trie_entries.push((key, entry.value_exact, entry.value_prefix));
+            }
+        }
+
+        let fallback_entries = self
+            .fallbacks
+            .into_iter()
+            .map(|entry| (entry.path.path, entry.value_exact, entry.value_prefix))
+            .collect();
+
+        let mut patterns = self.patterns.into_iter().collect::<Vec<_>>();
+        patterns.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        Ok(Router {
+            trie: Self::build_trie(trie_entries)?,
+            fallback: Self::build_trie(fallback_entries)?,
+            wildcards: wildcard_builder.build(),
+            method_routes: self
+                .method_builders
+                .into_iter()
+                .map(|(methods, builder)| Ok((methods, builder.build()?)))
+                .collect::<Result<_, Box<Error>>>()?,
+            scheme_routes: self
+                .scheme_builders
+                .into_iter()
+                .map(|(scheme, builder)| Ok((scheme, builder.build()?)))
+                .collect::<Result<_, Box<Error>>>()?,
+            priority_routes: self
+                .priority_builders
+                .into_iter()
+                .map(|(priority, builder)| Ok((priority, builder.build()?)))
+                .collect::<Result<_, Box<Error>>>()?,
+            patterns,
+        })
+    }
+
+    /// Sets up a trie from a list of entries.
+    ///
+    /// Routing tables can be large, so this sorts the entries and avoids the memory overhead of
+    /// going through a trie builder.
+    fn build_trie(
+        mut entries: Vec<(Vec<u8>, Value, Option<Value>)>,
+    ) -> Result<Trie<Value>, Box<Error>> {
+        entries.sort_by(|(a, ..), (b, ..)| compare_labels(a, b, SEPARATOR));
+        TrieBuilder::from_sorted_iter(entries.into_iter())
+    }
+}
+
+/// Merges the rules of other builders into this one, same as if these were added via
+/// [`RouterBuilder::push`].
+impl<Value: Clone + Eq> Extend<RouterBuilder<Value>> for RouterBuilder<Value> {
+    fn extend<T: IntoIterator<Item = RouterBuilder<Value>>>(&mut self, iter: T) {
+        for builder in iter {
+            for (host, entries) in builder.entries {
+                let existing = self.entries.entry(host).or_default();
+                for entry in entries {
+                    Self::merge_value(existing, entry.path, entry.value_exact, entry.value_prefix);
+                }
+            }
+
+            for entry in builder.fallbacks {
+                Self::merge_value(
+                    &mut self.fallbacks,
+                    entry.path,
+                    entry.value_exact,
+                    entry.value_prefix,
+                );
+            }
+
+            for (methods, method_builder) in builder.method_builders {
+                self.builder_for_methods(methods)
+                    .extend(std::iter::once(method_builder));
+            }
+
+            for (scheme, scheme_builder) in builder.scheme_builders {
+                self.builder_for_scheme(scheme)
+                    .extend(std::iter::once(scheme_builder));
+            }
+
+            for (priority, priority_builder) in builder.priority_builders {
+                self.builder_for_priority(priority)
+                    .extend(std::iter::once(priority_builder));
+            }
+
+            for (host, patterns) in builder.patterns {
+                self.patterns.entry(host).or_default().merge(patterns);
+            }
+        }
+    }
+}
+
+impl<Value: Clone + Eq> RouterBuilder<Vec<Value>> {
+    /// Adds a host/path combination to a routing table storing multiple values per location.
+    ///
+    /// Unlike [`RouterBuilder::push`], this doesn’t overwrite existing values: `value_exact` and
+    /// `value_prefix` are appended to the values already stored for this host/path combination.
+    /// [`Router::lookup_multi`] will return these values in insertion order. Host and path are
+    /// handled the same as with [`RouterBuilder::push`].
+    pub fn push_multi(
+        &mut self,
+        host: impl AsRef<[u8]>,
+        path: impl AsRef<[u8]>,
+        value_exact: Value,
+        value_prefix: Option<Value>,
+    ) -> Result<(), Box<Error>> {
+        validate_path(path.as_ref())?;
+        let path = Path::new(path);
+        let existing = self.entries_for_host(host.as_ref())?;
+        match existing.binary_search_by_key(&path.as_slice(), |entry| entry.path.as_slice()) {
+            Ok(index) => {
+                let entry = &mut existing[index];
+                entry.value_exact.push(value_exact);
+                if let Some(value_prefix) = value_prefix {
+                    entry
+                        .value_prefix
+                        .get_or_insert_with(Vec::new)
+                        .push(value_prefix);
+                }
+            }
+            Err(index) => existing.insert(
+                index,
+                RouterEntry {
+                    path,
+                    value_exact: vec![value_exact],
+                    value_prefix: value_prefix.map(|value| vec![value]),
+                },
+            ),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::merger::HostPathMatcher;
+
+    use super::*;
+
+    #[test]
+    fn path_normalization() {
+        assert_eq!(&Path::new("").path, b"");
+        assert_eq!(&Path::new("/").path, b"");
+        assert_eq!(&Path::new("///").path, b"");
+        assert_eq!(&Path::new("abc").path, b"abc");
+        assert_eq!(&Path::new("//abc//").path, b"abc");
+        assert_eq!(&Path::new("abc/def").path, b"abc/def");
+        assert_eq!(&Path::new("//abc//def//").path, b"abc/def");
+    }
+
+    #[test]
+    fn path_helpers() {
+        let path = Path::from_bytes(b"//abc//def/");
+        assert_eq!(path, Path::new("abc/def"));
+        assert_eq!(path.as_bytes(), b"abc/def");
+        assert_eq!(path.segments().collect::<Vec<_>>(), [b"abc", b"def"]);
+        assert!(!path.is_root());
+        assert!(Path::from_bytes(b"///").is_root());
+        assert_eq!(&Path::from_bytes(b""), EMPTY_PATH);
+
+        assert!(path.matches(b"abc/def"));
+        assert!(path.matches(b"/abc/def/"));
+        assert!(path.matches(b"///abc///def"));
+        assert!(!path.matches(b"/abc"));
+        assert!(!path.matches(b"/abc/def/ghi"));
+        assert!(!path.matches(b"/abc/defg"));
+        assert!(EMPTY_PATH.matches(b"/"));
+        assert!(EMPTY_PATH.matches(b""));
+
+        assert!(EMPTY_PATH.is_prefix_of(&path));
+        assert!(Path::from_bytes(b"/abc/").is_prefix_of(&path));
+        assert!(path.is_prefix_of(&path));
+        assert!(!Path::from_bytes(b"/ab").is_prefix_of(&path));
+        assert!(!path.is_prefix_of(&Path::from_bytes(b"/abc")));
+    }
+
+    #[test]
+    fn path_normalization_matches_lookup() {
+        let paths = [
+            "",
+            "/",
+            "///",
+            "/abc",
+            "abc/",
+            "//abc//def//",
+            "/abc/./def",
+            "/a\\b/c",
+            "/%61bc/def",
+        ];
+
+        let mut builder = Router::builder();
+        for path in paths {
+            builder.push("localhost", path, path, None).unwrap();
+        }
+        let router = builder.build().unwrap();
+
+        for path in paths {
+            let normalized = Path::from_bytes(path.as_bytes());
+            let result = router.lookup("localhost", path).unwrap();
+            assert_eq!(result.path(), normalized.as_bytes(), "{path}");
+            assert!(normalized.matches(path.as_bytes()), "{path}");
+            assert_eq!(
+                normalized.segments().collect::<Vec<_>>(),
+                make_key("", path).collect::<Vec<_>>(),
+                "{path}"
+            );
+
+            // Any path normalizing the same way is routed to the same rule
+            let result = router
+                .lookup(
+                    "localhost",
+                    &format!("//{}//", String::from_utf8_lossy(normalized.as_bytes())),
+                )
+                .unwrap();
+            assert_eq!(
+                Path::from_bytes(result.as_value().as_bytes()),
+                normalized,
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn path_remove_prefix() {
+        assert_eq!(Path::new("").remove_prefix_from(b"/"), None);
+        assert_eq!(Path::new("").remove_prefix_from(b"/abc"), None);
+        assert_eq!(Path::new("///").remove_prefix_from(b"/"), None);
+        assert_eq!(Path::new("///").remove_prefix_from(b"/abc"), None);
+        assert_eq!(
+            Path::new("abc").remove_prefix_from(b"/abc"),
+            Some("/".as_bytes())
+        );
+        assert_eq!(Path::new("abc").remove_prefix_from(b"/def"), None);
+        assert_eq!(Path::new("abc").remove_prefix_from(b"/abcd"), None);
+        assert_eq!(
+            Path::new("abc").remove_prefix_from(b"/abc//d"),
+            Some("//d".as_bytes())
+        );
+        assert_eq!(
+            Path::new("/abc/def/").remove_prefix_from(b"/abc//def"),
+            Some("/".as_bytes())
+        );
+        assert_eq!(
+            Path::new("/abc/def/").remove_prefix_from(b"/abc//def\\xyz"),
+            None
+        );
+        assert_eq!(
+            Path::new("/abc/def/").remove_prefix_from(b"/abc//def/xyz"),
+            Some("/xyz".as_bytes())
+        );
+    }
+
+    #[test]
+    fn host_path_matcher_parsing() {
+        assert_eq!(
+            HostPathMatcher::from(""),
+            HostPathMatcher {
+                host: b"".to_vec(),
+                path: Path::new(""),
+                exact: false,
+                suffix: None,
+                negated: false,
+                priority: 0,
+            }
+        );
+
+        assert_eq!(
+            HostPathMatcher::from("/*"),
+            HostPathMatcher {
+                host: b"".to_vec(),
+                path: Path::new(""),
+                exact: false,
+                suffix: None,
+                negated: false,
+                priority: 0,
+            }
+        );
+
+        assert_eq!(
+            HostPathMatcher::from("abc/*"),
+            HostPathMatcher {
+                host: b"abc".to_vec(),
+                path: Path::new(""),
+                exact: false,
+                suffix: None,
+                negated: false,
+                priority: 0,
+            }
+        );
+
+        assert_eq!(
+            HostPathMatcher::from("/abc/*"),
+            HostPathMatcher {
+                host: b"".to_vec(),
+                path: Path::new("abc"),
+                exact: false,
+                suffix: None,
+                negated: false,
+                priority: 0,
+            }
+        );
+
+        assert_eq!(
+            HostPathMatcher::from("abc"),
+            HostPathMatcher {
+                host: b"abc".to_vec(),
+                path: Path::new(""),
+                exact: false,
+                suffix: None,
+                negated: false,
+                priority: 0,
+            }
+        );
+
+        assert_eq!(
+            HostPathMatcher::from("/abc"),
+            HostPathMatcher {
+                host: b"".to_vec(),
+                path: Path::new("abc"),
+                exact: true,
+                suffix: None,
+                negated: false,
+                priority: 0,
+            }
+        );
+
+        // Wildcards are only allowed as the final path segment
+        assert!("/abc*".parse::<HostPathMatcher>().is_err());
+
+        assert_eq!(
+            HostPathMatcher::from("localhost/"),
+            HostPathMatcher {
+                host: b"localhost".to_vec(),
+                path: Path::new(""),
+                exact: true,
+                suffix: None,
+                negated: false,
+                priority: 0,
+            }
+        );
+
+        assert_eq!(
+            HostPathMatcher::from("localhost/abc/*"),
+            HostPathMatcher {
+                host: b"localhost".to_vec(),
+                path: Path::new("abc"),
+                exact: false,
+                suffix: None,
+                negated: false,
+                priority: 0,
+            }
+        );
+
+        assert_eq!(
+            HostPathMatcher::from("localhost///abc///"),
+            HostPathMatcher {
+                host: b"localhost".to_vec(),
+                path: Path::new("abc"),
+                exact: true,
+                suffix: None,
+                negated: false,
+                priority: 0,
+            }
+        );
+
+        assert_eq!(
+            HostPathMatcher::from("!localhost/abc/*"),
+            HostPathMatcher {
+                host: b"localhost".to_vec(),
+                path: Path::new("abc"),
+                exact: false,
+                suffix: None,
+                negated: true,
+                priority: 0,
+            }
+        );
+
+        assert_eq!(
+            HostPathMatcher::from("!/abc"),
+            HostPathMatcher {
+                host: b"".to_vec(),
+                path: Path::new("abc"),
+                exact: true,
+                suffix: None,
+                negated: true,
+                priority: 0,
+            }
+        );
+
+        assert_eq!(
+            HostPathMatcher::from("\\!abc"),
+            HostPathMatcher {
+                host: b"!abc".to_vec(),
+                path: Path::new(""),
+                exact: false,
+                suffix: None,
+                negated: false,
+                priority: 0,
+            }
+        );
+        assert_eq!(format!("{:?}", HostPathMatcher::from("\\!abc")), "\\!abc/*");
+        assert_eq!(
+            format!("{:?}", HostPathMatcher::from("!abc/def")),
+            "!abc/def"
+        );
+
+        assert!("!".parse::<HostPathMatcher>().is_err());
+        assert!("!/".parse::<HostPathMatcher>().is_ok());
+    }
+
+    #[test]
+    fn routing() {
+        fn lookup(router: &Router<u8>, host: &str, path: &str) -> Option<u8> {
+            router.lookup(host, path).as_deref().copied()
+        }
+
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", 1u8, Some(1)).unwrap();
+        builder.push("localhost", "/abc", 2, Some(2)).unwrap();
+        builder.push("localhost", "/xyz/abc/", 3, Some(3)).unwrap();
+        builder.push("example.com", "", 4, Some(4)).unwrap();
+        builder
+            .push("example.com", "/abc/def/", 5, Some(5))
+            .unwrap();
+        builder.push("example.com", "/x", 6, Some(6)).unwrap();
+        builder.push("", "/abc", 7, Some(7)).unwrap();
+        let router = builder.build().unwrap();
+
+        assert_eq!(lookup(&router, "localhost", "/"), Some(1));
+        assert_eq!(lookup(&router, "localhost", "/ab"), Some(1));
+        assert_eq!(lookup(&router, "localhost", "/abc"), Some(2));
+        assert_eq!(lookup(&router, "localhost", "/abc/"), Some(2));
+        assert_eq!(lookup(&router, "localhost", "/abc/d"), Some(2));
+        assert_eq!(lookup(&router, "localhost", "/abc/d/"), Some(2));
+        assert_eq!(lookup(&router, "localhost", "/xyz"), Some(1));
+        assert_eq!(lookup(&router, "localhost", "/xyz/"), Some(1));
+        assert_eq!(lookup(&router, "localhost", "/xyz/abc"), Some(3));
+        assert_eq!(lookup(&router, "example.com", "/"), Some(4));
+        assert_eq!(lookup(&router, "example.com", "/abc"), Some(4));
+        assert_eq!(lookup(&router, "example.com", "/abc/def"), Some(5));
+        assert_eq!(lookup(&router, "example.com", "/x/"), Some(6));
+        assert_eq!(lookup(&router, "example.com", "/xyz"), Some(4));
+        assert_eq!(lookup(&router, "example.net", "/"), None);
+        assert_eq!(lookup(&router, "example.net", "/abc"), Some(7));
+        assert_eq!(lookup(&router, "", "/"), None);
+        assert_eq!(lookup(&router, "", "/abc"), Some(7));
+        assert_eq!(lookup(&router, "", "/abc/def"), Some(7));
+
+        // A special case to keep in mind: slashes in host name will cause incorrect segmentation
+        // of the path, essentially causing everything after the slash to be ignored. As such, this
+        // is not an issue but it might become one as the implementation changes.
+        assert_eq!(lookup(&router, "localhost/def", "/abc"), Some(2));
+    }
+
+    #[test]
+    fn wildcard_hosts() {
+        fn lookup(router: &Router<u8>, host: &str, path: &str) -> Option<u8> {
+            router.lookup(host, path).as_deref().copied()
+        }
+
+        let mut builder = Router::builder();
+        builder.push("*.example.com", "/", 1u8, Some(1)).unwrap();
+        builder.push("*.b.example.com", "/", 2, Some(2)).unwrap();
+        builder.push("*.b.example.com", "/abc", 3, Some(3)).unwrap();
+        builder.push("c.b.example.com", "/abc", 4, Some(4)).unwrap();
+        builder.push("*.com", "/", 5, Some(5)).unwrap();
+        builder.push("", "/xyz", 6, Some(6)).unwrap();
+        assert!(builder.push("a.*.example.com", "/", 7, Some(7)).is_err());
+        assert!(builder.push("*example.com", "/", 7, Some(7)).is_err());
+        let router = builder.build().unwrap();
+
+        assert_eq!(lookup(&router, "a.example.com", "/"), Some(1));
+        assert_eq!(lookup(&router, "a.example.com", "/abc"), Some(1));
+        assert_eq!(lookup(&router, "b.example.com", "/"), Some(1));
+        assert_eq!(lookup(&router, "a.b.example.com", "/"), Some(2));
+        assert_eq!(lookup(&router, "a.b.example.com", "/abc/def"), Some(3));
+        assert_eq!(lookup(&router, "x.a.b.example.com", "/abc"), Some(3));
+        assert_eq!(lookup(&router, "c.b.example.com", "/abc"), Some(4));
+        assert_eq!(lookup(&router, "c.b.example.com", "/"), Some(2));
+        assert_eq!(lookup(&router, "example.com", "/"), Some(5));
+        assert_eq!(lookup(&router, "example.net", "/"), None);
+        assert_eq!(lookup(&router, "example.net", "/xyz"), Some(6));
+        assert_eq!(lookup(&router, "com", "/"), None);
+
+        assert_eq!(
+            router
+                .lookup_all("a.b.example.com", "/abc")
+                .into_iter()
+                .map(|(result, segments)| (*result, segments))
+                .collect::<Vec<_>>(),
+            vec![(2, 1), (3, 2)]
+        );
+    }
+
+    #[test]
+    fn wildcard_host_precedence() {
+        fn lookup(router: &Router<u8>, host: &str, path: &str) -> Option<u8> {
+            router.lookup(host, path).as_deref().copied()
+        }
+
+        let mut builder = Router::builder();
+        builder.push("*.example.com", "/", 1u8, Some(1)).unwrap();
+        builder.push("sub.example.com", "/", 2, Some(2)).unwrap();
+        builder.push("example.com", "/", 3, Some(3)).unwrap();
+        builder.push("*.sub.example.com", "/", 4, Some(4)).unwrap();
+        let router = builder.build().unwrap();
+
+        assert_eq!(lookup(&router, "example.com", "/"), Some(3));
+        assert_eq!(lookup(&router, "sub.example.com", "/"), Some(2));
+        assert_eq!(lookup(&router, "sub.example.com", "/abc"), Some(2));
+        assert_eq!(lookup(&router, "other.example.com", "/"), Some(1));
+        assert_eq!(lookup(&router, "a.other.example.com", "/"), Some(1));
+        assert_eq!(lookup(&router, "a.sub.example.com", "/"), Some(4));
+        assert_eq!(lookup(&router, "a.b.sub.example.com", "/"), Some(4));
+        assert_eq!(lookup(&router, "subexample.com", "/"), None);
+        assert_eq!(lookup(&router, "example.net", "/"), None);
+    }
+
+    #[test]
+    fn overlapping_wildcard_hosts() {
+        fn lookup(router: &Router<u8>, host: &str, path: &str) -> Option<u8> {
+            router.lookup(host, path).as_deref().copied()
+        }
+
+        let mut builder = Router::builder();
+        builder.push("*.example.com", "/", 1u8, Some(1)).unwrap();
+        builder
+            .push("*.api.example.com", "/v1", 2, Some(2))
+            .unwrap();
+        builder.push("v2.api.example.com", "/", 3, Some(3)).unwrap();
+        builder.push("", "/", 4, Some(4)).unwrap();
+        let router = builder.build().unwrap();
+
+        assert_eq!(lookup(&router, "www.example.com", "/v1"), Some(1));
+        assert_eq!(lookup(&router, "api.example.com", "/v1"), Some(1));
+        assert_eq!(lookup(&router, "v1.api.example.com", "/v1/users"), Some(2));
+        assert_eq!(lookup(&router, "v2.api.example.com", "/v1"), Some(3));
+        assert_eq!(lookup(&router, "example.org", "/v1"), Some(4));
+
+        // The longest wildcard host is selected even if none of its paths match
+        assert_eq!(lookup(&router, "v1.api.example.com", "/"), Some(4));
+
+        let result = router.lookup("a.b.api.example.com", "/v1").unwrap();
+        assert_eq!(result.host(), b"*.api.example.com");
+        assert_eq!(result.path(), b"v1");
+    }
+
+    #[test]
+    fn matched_rule() {
+        fn lookup(router: &Router<u8>, host: &str, path: &str) -> Option<(u8, String, String)> {
+            router.lookup(host, path).map(|result| {
+                (
+                    *result,
+                    String::from_utf8_lossy(result.host()).into_owned(),
+                    String::from_utf8_lossy(result.path()).into_owned(),
+                )
+            })
+        }
+
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", 1u8, Some(1)).unwrap();
+        builder.push("localhost", "/subdir", 2, Some(2)).unwrap();
+        builder
+            .push("localhost", "/subdir/subsub/", 3, Some(3))
+            .unwrap();
+        builder
+            .push("*.example.com", "/subdir", 4, Some(4))
+            .unwrap();
+        builder.push("", "/subdir", 5, Some(5)).unwrap();
+        let router = builder.build().unwrap();
+
+        let result =
+            |value, host: &str, path: &str| Some((value, host.to_owned(), path.to_owned()));
+        assert_eq!(
+            lookup(&router, "localhost", "/"),
+            result(1, "localhost", "")
+        );
+        assert_eq!(
+            lookup(&router, "localhost", "/sub"),
+            result(1, "localhost", "")
+        );
+        assert_eq!(
+            lookup(&router, "localhost", "/subdir"),
+            result(2, "localhost", "subdir")
+        );
+        assert_eq!(
+            lookup(&router, "localhost", "/subdir/sub"),
+            result(2, "localhost", "subdir")
+        );
+        assert_eq!(
+            lookup(&router, "localhost", "/subdir/subsub"),
+            result(3, "localhost", "subdir/subsub")
+        );
+        assert_eq!(
+            lookup(&router, "localhost", "/subdir/subsub/file.txt"),
+            result(3, "localhost", "subdir/subsub")
+        );
+        assert_eq!(
+            lookup(&router, "www.example.com", "/subdir/subsub"),
+            result(4, "*.example.com", "subdir")
+        );
+        assert_eq!(
+            lookup(&router, "example.net", "/subdir/subsub"),
+            result(5, "", "subdir")
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialization() {
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", 1u8, Some(1)).unwrap();
+        builder.push("localhost", "/abc", 2, Some(2)).unwrap();
+        builder.push("*.example.com", "/abc/def/", 3, None).unwrap();
+        builder.push("", "/xyz", 4, Some(4)).unwrap();
+        builder.push_pattern("localhost", "/users/{id}", 5).unwrap();
+        let router = builder.build().unwrap();
+
+        let mut serialized = Vec::new();
+        router
+            .save(&mut serde_yaml::Serializer::new(&mut serialized))
+            .unwrap();
+        let restored =
+            Router::<u8>::load(serde_yaml::Deserializer::from_slice(&serialized)).unwrap();
+        assert_eq!(restored, router);
+
+        for (host, path) in [
+            ("localhost", "/"),
+            ("localhost", "/abc/def"),
+            ("www.example.com", "/abc/def"),
+            ("www.example.com", "/abc/def/ghi"),
+            ("example.net", "/xyz"),
+        ] {
+            assert_eq!(
+                restored.lookup(host, path).as_deref(),
+                router.lookup(host, path).as_deref()
+            );
+        }
+        assert_eq!(
+            restored
+                .lookup_pattern("localhost", "/users/1")
+                .and_then(|result| result.get("id")),
+            Some(b"1".as_slice())
+        );
+    }
+
+    #[test]
+    fn unnormalized_rules() {
+        fn lookup(router: &Router<u8>, host: &str, path: &str) -> Option<u8> {
+            router.lookup(host, path).as_deref().copied()
+        }
+
+        let mut builder = Router::builder();
+        builder.push("localhost", "//", 1u8, Some(1)).unwrap();
+        builder.push("localhost", "//abc//def/", 2, None).unwrap();
+        builder.push("", "xyz//", 3, Some(3)).unwrap();
+        assert!(builder.push("localhost/abc", "/", 4, Some(4)).is_err());
+        assert!(builder.push("/", "/xyz", 5, Some(5)).is_err());
+        let router = builder.build().unwrap();
+
+        let mut builder = Router::<u8>::builder();
+        assert!(builder.push("local\0host", "/", 1, None).is_err());
+        assert!(builder.push("localhost\n", "/", 1, None).is_err());
+        assert!(builder.push("localhost", "/abc\0def", 1, None).is_err());
+        assert!(builder.push("", "/abc\0", 1, None).is_err());
+        assert!(builder.push_unique("localhost", "/\0", 1, None).is_err());
+        assert!(builder.push_pattern("localhost", "/{id}/\0", 1).is_err());
+
+        assert_eq!(lookup(&router, "localhost", "/"), Some(1));
+        assert_eq!(lookup(&router, "localhost", "/abc"), Some(1));
+        assert_eq!(lookup(&router, "localhost", "/abc/def"), Some(2));
+        assert_eq!(lookup(&router, "localhost", "/abc/def/ghi"), Some(1));
+        assert_eq!(lookup(&router, "example.com", "/xyz/abc"), Some(3));
+    }
+
+    #[test]
+    fn routing_all() {
+        fn lookup_all(router: &Router<u8>, host: &str, path: &str) -> Vec<(u8, usize)> {
+            router
+                .lookup_all(host, path)
+                .into_iter()
+                .map(|(result, segments)| (*result, segments))
+                .collect()
+        }
+
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", 1u8, Some(1)).unwrap();
+        builder.push("localhost", "/abc", 2, None).unwrap();
+        builder.push("localhost", "/abc/def/", 3, Some(3)).unwrap();
+        builder.push("", "/", 4, Some(4)).unwrap();
+        builder.push("", "/xyz", 5, Some(5)).unwrap();
+        let router = builder.build().unwrap();
+
+        assert_eq!(lookup_all(&router, "localhost", "/"), vec![(1, 1)]);
+        assert_eq!(
+            lookup_all(&router, "localhost", "/abc"),
+            vec![(1, 1), (2, 2)]
+        );
+        assert_eq!(
+            lookup_all(&router, "localhost", "/abc/def/ghi"),
+            vec![(1, 1), (1, 2), (3, 3)]
+        );
+        assert_eq!(lookup_all(&router, "example.com", "/"), vec![(4, 0)]);
+        assert_eq!(
+            lookup_all(&router, "example.com", "/xyz/abc"),
+            vec![(4, 0), (5, 1)]
+        );
+        assert_eq!(lookup_all(&router, "", "/xyz"), vec![(4, 0), (5, 1)]);
+    }
+
+    #[test]
+    fn prefix_stripping_partial_match() {
+        fn strip(router: &Router<Path>, path: &str) -> Option<(String, usize)> {
+            let results = router.lookup_all("localhost", path);
+            let (prefix, segments) = results.last()?;
+            let tail = prefix.remove_prefix_from(&path)?;
+            Some((String::from_utf8_lossy(tail).into_owned(), *segments))
+        }
+
+        let mut builder = Router::builder();
+        for path in ["/a", "/a/bc/de/f"] {
+            builder
+                .push("localhost", path, Path::new(path), Some(Path::new(path)))
+                .unwrap();
+        }
+        let router = builder.build().unwrap();
+
+        assert_eq!(
+            strip(&router, "/a/bc/de/x/f"),
+            Some(("/bc/de/x/f".to_owned(), 2))
+        );
+        assert_eq!(strip(&router, "/a/bc/de"), Some(("/bc/de".to_owned(), 2)));
+        assert_eq!(strip(&router, "/a/bc/de/f"), Some(("/".to_owned(), 5)));
+        assert_eq!(strip(&router, "/a/bc/de/f/x"), Some(("/x".to_owned(), 5)));
+    }
+
+    #[test]
+    fn mutable_access() {
+        fn lookup(router: &Router<u8>, host: &str, path: &str) -> Option<u8> {
+            router.lookup(host, path).as_deref().copied()
+        }
+
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", 1u8, Some(1)).unwrap();
+        builder.push("localhost", "/abc", 2, Some(2)).unwrap();
+        builder.push("*.example.com", "/", 3, Some(3)).unwrap();
+        builder.push("", "/xyz", 4, Some(4)).unwrap();
+        let mut router = builder.build().unwrap();
+
+        // Only the prefix value is changed, the exact match value is separate
+        *router.lookup_mut("localhost", "/abc/def").unwrap() = 12;
+        assert_eq!(lookup(&router, "localhost", "/abc/xyz"), Some(12));
+        assert_eq!(lookup(&router, "localhost", "/abc"), Some(2));
+        assert_eq!(lookup(&router, "localhost", "/"), Some(1));
+
+        *router.lookup_mut("www.example.com", "/abc").unwrap() = 13;
+        assert_eq!(lookup(&router, "a.example.com", "/xyz"), Some(13));
+        assert_eq!(lookup(&router, "a.example.com", "/"), Some(3));
+
+        *router.lookup_mut("example.net", "/xyz").unwrap() = 14;
+        assert_eq!(lookup(&router, "", "/xyz"), Some(14));
+
+        assert!(router.lookup_mut("example.net", "/").is_none());
+
+        *router.get_mut("*.example.com", "/").unwrap() = 23;
+        assert_eq!(lookup(&router, "a.example.com", "/"), Some(23));
+        *router.get_mut("", "/xyz/").unwrap() = 24;
+        assert_eq!(lookup(&router, "localhost", "/xyz"), Some(1));
+        assert_eq!(lookup(&router, "", "/xyz"), Some(24));
+
+        assert_eq!(router.lookup_exact("localhost", "/abc"), Some(&2));
+        assert_eq!(router.lookup_exact("*.example.com", ""), Some(&23));
+        assert_eq!(router.lookup_exact("localhost", "/abc/def"), None);
+        assert_eq!(router.lookup_exact("www.example.com", "/"), None);
+        assert!(router.get_mut("localhost", "/abc/def").is_none());
+        assert!(router.get_mut("www.example.com", "/").is_none());
+    }
+
+    #[test]
+    fn mutable_access_shared_values() {
+        fn lookup(router: &Router<u8>, host: &str, path: &str) -> Option<u8> {
+            router.lookup(host, path).as_deref().copied()
+        }
+
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", 1u8, Some(1)).unwrap();
+        builder.push("example.com", "/", 1, Some(1)).unwrap();
+        builder.push("example.com", "/abc", 1, None).unwrap();
+        builder.push("", "/", 1, Some(1)).unwrap();
+        let mut router = builder.build().unwrap();
+
+        *router.lookup_mut("localhost", "/xyz").unwrap() = 2;
+        assert_eq!(lookup(&router, "localhost", "/"), Some(1));
+        assert_eq!(lookup(&router, "localhost", "/xyz"), Some(2));
+        assert_eq!(lookup(&router, "example.com", "/"), Some(1));
+        assert_eq!(lookup(&router, "example.com", "/abc"), Some(1));
+        assert_eq!(lookup(&router, "example.net", "/"), Some(1));
+
+        *router.get_mut("example.com", "/abc").unwrap() = 3;
+        assert_eq!(lookup(&router, "example.com", "/abc"), Some(3));
+        assert_eq!(lookup(&router, "example.com", "/abc/def"), Some(1));
+        assert_eq!(lookup(&router, "example.com", "/"), Some(1));
+        assert_eq!(lookup(&router, "example.net", "/"), Some(1));
+    }
+
+    #[test]
+    fn labels_saved() {
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", 1u8, None).unwrap();
+        builder.push("localhost", "/static/css", 2, None).unwrap();
+        builder.push("example.com", "/", 3, None).unwrap();
+        builder.push("example.com", "/static/css", 4, None).unwrap();
+        let router = builder.build().unwrap();
+        assert_eq!(router.labels_saved(), "static/css".len());
+
+        // Routers for rules with priorities are considered as well
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", 1u8, None).unwrap();
+        builder.push("localhost", "/static/css", 2, None).unwrap();
+        builder
+            .push_with_priority("localhost", "/", 1, 3, None)
+            .unwrap();
+        builder
+            .push_with_priority("localhost", "/static/js", 1, 4, None)
+            .unwrap();
+        builder
+            .push_with_priority("example.com", "/", 1, 5, None)
+            .unwrap();
+        builder
+            .push_with_priority("example.com", "/static/js", 1, 6, None)
+            .unwrap();
+        let router = builder.build().unwrap();
+        assert_eq!(router.labels_saved(), "static/js".len());
+    }
+
+    #[test]
+    fn multiple_values() {
+        let mut builder = Router::builder();
+        builder.push_multi("localhost", "/", 1u8, Some(11)).unwrap();
+        builder.push_multi("localhost", "/abc", 2, None).unwrap();
+        builder.push_multi("localhost", "//", 3, Some(13)).unwrap();
+        builder.push_multi("*.example.com", "/", 4, None).unwrap();
+        builder.push_multi("*.example.com", "/", 5, None).unwrap();
+        builder.push_multi("", "/", 6, Some(16)).unwrap();
+        builder
+            .push_multi("localhost", "/abc/", 7, Some(17))
+            .unwrap();
+        assert!(builder.push_multi("local/host", "/", 8, None).is_err());
+        let router = builder.build().unwrap();
+
+        assert_eq!(router.lookup_multi("localhost", "/"), &[1, 3]);
+        assert_eq!(router.lookup_multi("localhost", "/abc"), &[2, 7]);
+        assert_eq!(router.lookup_multi("localhost", "/abc/def"), &[17]);
+        assert_eq!(router.lookup_multi("localhost", "/xyz"), &[11, 13]);
+        assert_eq!(router.lookup_multi("www.example.com", "/"), &[4, 5]);
+        assert_eq!(router.lookup_multi("www.example.com", "/abc"), &[16]);
+        assert_eq!(router.lookup_multi("example.net", "/"), &[6]);
+        assert!(Router::<Vec<u8>>::builder()
+            .build()
+            .unwrap()
+            .lookup_multi("localhost", "/")
+            .is_empty());
+    }
+
+    #[test]
+    fn extend() {
+        let mut builder1 = Router::builder();
+        builder1.push("localhost", "/", 1u8, Some(11)).unwrap();
+        builder1.push("localhost", "/abc", 2, Some(12)).unwrap();
+        builder1.push("", "/xyz", 3, None).unwrap();
+
+        let mut builder2 = Router::builder();
+        builder2.push("localhost", "/abc", 4, None).unwrap();
+        builder2.push("*.example.com", "/", 5, Some(15)).unwrap();
+        builder2.push("", "/xyz", 6, Some(16)).unwrap();
+
+        builder1.extend([builder2]);
+        let router = builder1.build().unwrap();
+
+        assert_eq!(router.lookup("localhost", "/").as_deref(), Some(&1));
+        assert_eq!(router.lookup("localhost", "/abc").as_deref(), Some(&4));
+        assert_eq!(router.lookup("localhost", "/abc/def").as_deref(), Some(&12));
+        assert_eq!(
+            router.lookup("www.example.com", "/abc").as_deref(),
+            Some(&15)
+        );
+        assert_eq!(router.lookup("", "/xyz").as_deref(), Some(&6));
+        assert_eq!(router.lookup("", "/xyz/abc").as_deref(), Some(&16));
+    }
+
+    #[test]
+    fn overwriting() {
+        let mut builder = Router::builder();
+        assert_eq!(builder.push("localhost", "/", 1u8, Some(11)).unwrap(), None);
+        assert_eq!(
+            builder.push("localhost", "/abc", 2, Some(12)).unwrap(),
+            None
+        );
+        assert_eq!(builder.push("", "/abc", 3, None).unwrap(), None);
+
+        let replaced = builder
+            .push("localhost", "/abc/", 4, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(replaced.value, 2);
+        assert_eq!(replaced.to_string(), "localhost/abc");
+
+        let replaced = builder.push("", "abc", 5, Some(15)).unwrap().unwrap();
+        assert_eq!(replaced.value, 3);
+        assert_eq!(replaced.to_string(), "/abc");
+        let router = builder.build().unwrap();
+
+        assert_eq!(router.lookup("localhost", "/abc").as_deref(), Some(&4));
+        assert_eq!(router.lookup("localhost", "/abc/def").as_deref(), Some(&12));
+        assert_eq!(router.lookup("", "/abc").as_deref(), Some(&5));
+        assert_eq!(router.lookup("", "/abc/def").as_deref(), Some(&15));
+    }
+
+    #[test]
+    fn host_case() {
+        let mut builder = Router::builder();
+        builder.push("example.com", "/", 1u8, Some(1)).unwrap();
+        builder.push("*.Example.NET", "/", 2, Some(2)).unwrap();
+        builder.push_pattern("API.example.com", "/{id}", 3).unwrap();
+        let replaced = builder.push("EXAMPLE.com", "/", 4, Some(4)).unwrap();
+        assert_eq!(replaced.unwrap().to_string(), "example.com/");
+        let router = builder.build().unwrap();
+
+        assert_eq!(router.lookup("Example.COM", "/").as_deref(), Some(&4));
+        assert_eq!(router.lookup("example.com", "/x").as_deref(), Some(&4));
+        let result = router.lookup("WWW.example.Net", "/").unwrap();
+        assert_eq!(*result, 2);
+        assert_eq!(result.host(), b"*.example.net");
+        assert_eq!(router.lookup_exact("EXAMPLE.COM", "/"), Some(&4));
+        assert_eq!(
+            router.lookup_pattern("Api.Example.Com", "/1").as_deref(),
+            Some(&3)
+        );
+
+        // Long host names are lowercased as well
+        let long = format!("{}.example.net", "X".repeat(HOST_BUFFER_SIZE));
+        assert_eq!(router.lookup(&long, "/").as_deref(), Some(&2));
+    }
+
+    #[test]
+    fn trailing_slash() {
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", 1u8, Some(11)).unwrap();
+        builder.push("localhost", "/docs", 2, Some(12)).unwrap();
+
+        // Explicitly registering both variants replaces the value, the later rule wins
+        let replaced = builder.push("localhost", "/api", 3, Some(13)).unwrap();
+        assert!(replaced.is_none());
+        let replaced = builder.push("localhost", "/api/", 4, Some(14)).unwrap();
+        assert_eq!(replaced.unwrap().to_string(), "localhost/api");
+        let router = builder.build().unwrap();
+
+        let lookup = |path: &str| {
+            let result = router.lookup("localhost", path).unwrap();
+            (*result, String::from_utf8_lossy(result.path()).into_owned())
+        };
+
+        // Trailing slash doesn’t matter, the matched path is reported in normalized form
+        assert_eq!(lookup("/docs"), (2, "docs".to_owned()));
+        assert_eq!(lookup("/docs/"), (2, "docs".to_owned()));
+        assert_eq!(lookup("/docs//"), (2, "docs".to_owned()));
+        assert_eq!(lookup("/docs/x/"), (12, "docs".to_owned()));
+        assert_eq!(lookup("/api"), (4, "api".to_owned()));
+        assert_eq!(lookup("/api/"), (4, "api".to_owned()));
+
+        // Root path and paths consisting of slashes only
+        assert_eq!(lookup(""), (1, String::new()));
+        assert_eq!(lookup("/"), (1, String::new()));
+        assert_eq!(lookup("///"), (1, String::new()));
+
+        // Prefix stripping consumes the trailing slash as well
+        let docs = Path::new("/docs/");
+        assert_eq!(docs.remove_prefix_from(b"/docs"), Some("/".as_bytes()));
+        assert_eq!(docs.remove_prefix_from(b"/docs/"), Some("/".as_bytes()));
+        assert_eq!(docs.remove_prefix_from(b"/docs/x/"), Some("/x/".as_bytes()));
+    }
+
+    #[test]
+    fn unique() {
+        let mut builder = Router::builder();
+        builder
+            .push_unique("localhost", "/", 1u8, Some(11))
+            .unwrap();
+        builder.push_unique("localhost", "/a", 2, Some(12)).unwrap();
+        builder.push_unique("", "/a", 3, None).unwrap();
+        builder.push_unique("example.com", "/a", 4, None).unwrap();
+
+        // Exact duplicates
+        assert!(builder.push_unique("localhost", "/", 5, None).is_err());
+        assert!(builder.push_unique("localhost", "/a", 5, None).is_err());
+
+        // Duplicates after normalization
+        assert!(builder.push_unique("localhost", "", 5, None).is_err());
+        assert!(builder.push_unique("localhost", "/a/", 5, None).is_err());
+        assert!(builder.push_unique("localhost", "//a//", 5, None).is_err());
+        assert!(builder.push_unique("", "a", 5, None).is_err());
+
+        let router = builder.build().unwrap();
+        assert_eq!(router.lookup("localhost", "/").as_deref(), Some(&1));
+        assert_eq!(router.lookup("localhost", "/a").as_deref(), Some(&2));
+        assert_eq!(router.lookup("localhost", "/a/b").as_deref(), Some(&12));
+        assert_eq!(router.lookup("", "/a").as_deref(), Some(&3));
+    }
+
+    #[test]
+    fn path_patterns() {
+        fn lookup(router: &Router<u8>, host: &str, path: &str) -> Option<(u8, Vec<String>)> {
+            router.lookup_pattern(host, path).map(|result| {
+                (
+                    *result,
+                    result
+                        .params()
+                        .map(|(name, value)| format!("{name}={}", String::from_utf8_lossy(value)))
+                        .collect(),
+                )
+            })
+        }
+
+        let mut builder = Router::builder();
+        builder.push("", "/users", 1u8, Some(1)).unwrap();
+        builder.push("", "/users/admin", 2, None).unwrap();
+        builder.push_pattern("", "/users/{id}", 3).unwrap();
+        builder.push_pattern("", "/users/{id}/avatar", 4).unwrap();
+        builder
+            .push_pattern("", "/users/{id}/posts/{post}", 5)
+            .unwrap();
+        builder.push_pattern("", "/users/admin/{page}", 6).unwrap();
+        builder
+            .push_pattern("localhost", "/users/{name}", 7)
+            .unwrap();
+        assert_eq!(
+            builder
+                .push_pattern("localhost", "//users/{user}/", 8)
+                .unwrap(),
+            Some(7)
+        );
+        assert!(builder.push_pattern("", "/users/{}", 9).is_err());
+        assert!(builder.push_pattern("", "/users/{id", 9).is_err());
+        assert!(builder.push_pattern("", "/users/x{id}", 9).is_err());
+        assert!(builder.push_pattern("", "/{id}/{id}", 9).is_err());
+        assert!(builder.push_pattern("a/b", "/{id}", 9).is_err());
+        let router = builder.build().unwrap();
+
+        assert_eq!(lookup(&router, "", "/users"), Some((1, vec![])));
+        assert_eq!(
+            lookup(&router, "", "/users/123"),
+            Some((3, vec!["id=123".to_owned()]))
+        );
+        assert_eq!(
+            lookup(&router, "", "//users//123/avatar/"),
+            Some((4, vec!["id=123".to_owned()]))
+        );
+        assert_eq!(
+            lookup(&router, "", "/users/123/posts/x%20y"),
+            Some((5, vec!["id=123".to_owned(), "post=x%20y".to_owned()]))
+        );
+
+        // Literal rules win over parameters at the same depth
+        assert_eq!(lookup(&router, "", "/users/admin"), Some((2, vec![])));
+        assert_eq!(
+            lookup(&router, "", "/users/admin/settings"),
+            Some((6, vec!["page=settings".to_owned()]))
+        );
+        assert_eq!(
+            lookup(&router, "", "/users/admin/avatar"),
+            Some((6, vec!["page=avatar".to_owned()]))
+        );
+        assert_eq!(
+            lookup(&router, "", "/users/admin/posts/1"),
+            Some((5, vec!["id=admin".to_owned(), "post=1".to_owned()]))
+        );
+
+        // Patterns only match complete paths, a prefix rule applies otherwise
+        assert_eq!(lookup(&router, "", "/users/123/other"), Some((1, vec![])));
+        assert_eq!(lookup(&router, "", "/other"), None);
+
+        // Host precedence
+        assert_eq!(
+            lookup(&router, "localhost", "/users/123"),
+            Some((8, vec!["user=123".to_owned()]))
+        );
+        assert_eq!(
+            lookup(&router, "localhost", "/users/123/avatar"),
+            Some((4, vec!["id=123".to_owned()]))
+        );
+
+        let result = router.lookup_pattern("", "/users/1/posts/2").unwrap();
+        assert_eq!(result.get("id"), Some(b"1".as_slice()));
+        assert_eq!(result.get("post"), Some(b"2".as_slice()));
+        assert_eq!(result.get("other"), None);
+
+        // Patterns are ignored by other lookup functions
+        assert_eq!(router.lookup("", "/users/123").as_deref(), Some(&1));
+    }
+
+    #[test]
+    fn priorities() {
+        fn lookup(router: &Router<u8>, host: &str, path: &str) -> Option<u8> {
+            router.lookup(host, path).as_deref().copied()
+        }
+
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", 1u8, Some(1)).unwrap();
+        builder.push("localhost", "/dir", 2, Some(2)).unwrap();
+        builder
+            .push("localhost", "/dir/subdir", 3, Some(3))
+            .unwrap();
+        builder.push("", "/other", 4, Some(4)).unwrap();
+        let router = builder.build().unwrap();
+
+        // Default priorities, longest match wins
+        assert_eq!(lookup(&router, "localhost", "/"), Some(1));
+        assert_eq!(lookup(&router, "localhost", "/dir/file"), Some(2));
+        assert_eq!(lookup(&router, "localhost", "/dir/subdir/file"), Some(3));
+        assert_eq!(lookup(&router, "example.com", "/other"), Some(4));
+
+        // Maintenance rule overriding everything but the health check
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", 1u8, Some(1)).unwrap();
+        builder.push("localhost", "/dir", 2, Some(2)).unwrap();
+        builder
+            .push("localhost", "/dir/subdir", 3, Some(3))
+            .unwrap();
+        builder.push("", "/other", 4, Some(4)).unwrap();
+        builder.push_with_priority("", "/", 10, 5, Some(5)).unwrap();
+        builder
+            .push_with_priority("", "/healthz", 10, 6, Some(6))
+            .unwrap();
+        assert_eq!(
+            builder
+                .push_with_priority("localhost", "/dir", 0, 8, Some(8))
+                .unwrap()
+                .map(|replaced| replaced.value),
+            Some(2)
+        );
+        let router = builder.build().unwrap();
+
+        assert_eq!(lookup(&router, "localhost", "/"), Some(5));
+        assert_eq!(lookup(&router, "localhost", "/dir/subdir/file"), Some(5));
+        assert_eq!(lookup(&router, "example.com", "/other"), Some(5));
+        assert_eq!(lookup(&router, "localhost", "/healthz"), Some(6));
+        assert_eq!(lookup(&router, "example.com", "/healthz/x"), Some(6));
+
+        // Lower priority only applies if nothing else matches
+        let mut builder = Router::builder();
+        builder.push("localhost", "/dir", 1u8, None).unwrap();
+        builder
+            .push_with_priority("localhost", "/dir/subdir", -1, 2, Some(2))
+            .unwrap();
+        builder.push_with_priority("", "/", -2, 3, Some(3)).unwrap();
+        let router = builder.build().unwrap();
+
+        assert_eq!(lookup(&router, "localhost", "/dir"), Some(1));
+        assert_eq!(lookup(&router, "localhost", "/dir/subdir"), Some(2));
+        assert_eq!(lookup(&router, "localhost", "/dir/file"), Some(3));
+        assert_eq!(lookup(&router, "localhost", "/"), Some(3));
+
+        let result = router.lookup("localhost", "/dir/subdir/file").unwrap();
+        assert_eq!(result.host(), b"localhost");
+        assert_eq!(result.path(), b"dir/subdir");
+    }
+
+    #[test]
+    fn priorities_lookup_variants() {
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", 1u8, Some(1)).unwrap();
+        builder.push("localhost", "/dir", 2, Some(2)).unwrap();
+        builder
+            .push_with_methods("localhost", "/dir", Methods::POST, 3, Some(3))
+            .unwrap();
+        builder.push_pattern("localhost", "/dir/{name}", 4).unwrap();
+        builder.push_with_priority("", "/", 10, 5, Some(5)).unwrap();
+        builder
+            .push_with_priority("localhost", "/dir", 10, 6, None)
+            .unwrap();
+        builder
+            .push_with_priority("", "/healthz", 10, 7, Some(7))
+            .unwrap();
+        builder
+            .push_with_priority("localhost", "/other", -1, 8, Some(8))
+            .unwrap();
+        builder
+            .push_with_priority("localhost", "/dir", -1, 9, Some(9))
+            .unwrap();
+        let mut router = builder.build().unwrap();
+
+        // Method-specific rules don’t override rules with higher priority
+        assert_eq!(
+            router.lookup_with_method("localhost", "/dir", &Method::POST),
+            Some(&6)
+        );
+        assert_eq!(
+            router.lookup_with_method("localhost", "/dir/file", &Method::POST),
+            Some(&5)
+        );
+        assert_eq!(
+            router.lookup_with_method("example.com", "/healthz", &Method::GET),
+            Some(&7)
+        );
+
+        // Neither do path patterns
+        assert_eq!(
+            router
+                .lookup_pattern("localhost", "/dir/file")
+                .map(|result| (*result, result.params().count())),
+            Some((5, 0))
+        );
 
-                existing.insert(
-                    index,
-                    RouterEntry {
-                        path,
-                        value_exact,
-                        value_prefix,
-                    },
-                );
-                false
-            }
-        }
-    }
+        // Exact lookups prefer the rule with the highest priority
+        assert_eq!(router.lookup_exact("localhost", "/dir"), Some(&6));
+        assert_eq!(router.lookup_exact("localhost", "/"), Some(&1));
+        assert_eq!(router.lookup_exact("localhost", "/other"), Some(&8));
+        assert_eq!(router.lookup_exact("localhost", "/missing"), None);
 
-    /// Adds a host/path combination with the respective values to the routing table.
-    ///
-    /// The `value_exact` value is only used for exact path matches. For prefix matches where only
-    /// part of the lookup path matched the `value_prefix` value will be used if present.
-    ///
-    /// This method returns `true` if an existing entry is overwritten, `false` otherwise.
-    pub fn push(
-        &mut self,
-        host: impl AsRef<[u8]>,
-        path: impl AsRef<[u8]>,
-        value_exact: Value,
-        value_prefix: Option<Value>,
-    ) -> bool {
-        let path = Path::new(path);
+        // Only values of the selected priority are listed
+        let all = router
+            .lookup_all("localhost", "/dir/file")
+            .into_iter()
+            .map(|(result, segments)| (*result, segments))
+            .collect::<Vec<_>>();
+        assert_eq!(all, vec![(5, 0)]);
+        let all = router
+            .lookup_all("example.com", "/other")
+            .into_iter()
+            .map(|(result, segments)| (*result, segments))
+            .collect::<Vec<_>>();
+        assert_eq!(all, vec![(5, 0)]);
 
-        let existing = if host.as_ref().is_empty() {
-            &mut self.fallbacks
-        } else {
-            self.entries.entry(host.as_ref().to_vec()).or_default()
-        };
+        // Mutable access goes to the value selected by the lookup
+        *router.lookup_mut("localhost", "/dir/file").unwrap() = 15;
+        assert_eq!(
+            router.lookup("localhost", "/dir/file").as_deref(),
+            Some(&15)
+        );
+        *router.get_mut("localhost", "/dir").unwrap() = 16;
+        assert_eq!(router.lookup("localhost", "/dir").as_deref(), Some(&16));
+        assert_eq!(router.lookup_exact("localhost", "/dir"), Some(&16));
+        *router.get_mut("localhost", "/other").unwrap() = 18;
+        assert_eq!(router.lookup_exact("localhost", "/other"), Some(&18));
+        assert!(router.get_mut("localhost", "/missing").is_none());
+
+        // Lower priorities only apply if nothing else matches
+        let mut builder = Router::builder();
+        builder
+            .push_with_methods("localhost", "/", Methods::GET, 1u8, Some(1))
+            .unwrap();
+        builder
+            .push_with_priority("localhost", "/dir", -1, 2, Some(2))
+            .unwrap();
+        let mut router = builder.build().unwrap();
+        assert_eq!(
+            router.lookup_with_method("localhost", "/dir", &Method::GET),
+            Some(&1)
+        );
+        assert_eq!(
+            router.lookup_with_method("localhost", "/dir", &Method::POST),
+            Some(&2)
+        );
+        assert_eq!(
+            router.lookup_pattern("localhost", "/dir/file").as_deref(),
+            Some(&2)
+        );
+        *router.lookup_mut("localhost", "/dir").unwrap() = 12;
+        assert_eq!(router.lookup_exact("localhost", "/dir"), Some(&12));
+        assert!(router.lookup_mut("example.com", "/").is_none());
 
-        Self::merge_value(existing, path, value_exact, value_prefix)
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", vec![1u8], None).unwrap();
+        builder.push("localhost", "/dir", vec![2, 3], None).unwrap();
+        builder
+            .push_with_priority("", "/dir", 1, vec![4, 5], None)
+            .unwrap();
+        builder
+            .push_with_priority("", "/", -1, vec![6], Some(vec![7]))
+            .unwrap();
+        let router = builder.build().unwrap();
+        assert_eq!(router.lookup_multi("localhost", "/dir"), &[4, 5]);
+        assert_eq!(router.lookup_multi("localhost", "/"), &[1]);
+        assert_eq!(router.lookup_multi("localhost", "/file"), &[7]);
+        assert_eq!(router.lookup_multi("example.com", "/"), &[6]);
     }
 
-    /// Translates all rules into a router instance while also merging values if multiple apply to
-    /// the same location.
-    pub fn build(self) -> Router<Value> {
-        let mut builder = Trie::builder();
-        for (host, entries) in self.entries {
-            for entry in entries {
-                let mut key = host.clone();
-                if !entry.path.is_empty() {
-                    key.push(SEPARATOR);
-                    key.extend_from_slice(&entry.path);
-                }
-                builder.push(key, entry.value_exact, entry.value_prefix);
-            }
+    #[test]
+    fn method_routing() {
+        fn lookup(router: &Router<u8>, host: &str, path: &str, method: Method) -> Option<u8> {
+            router.lookup_with_method(host, path, &method).copied()
         }
 
-        let mut fallback_builder = Trie::builder();
-        for entry in self.fallbacks {
-            fallback_builder.push(entry.path.path, entry.value_exact, entry.value_prefix);
-        }
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", 1u8, Some(1)).unwrap();
+        builder
+            .push_with_methods("localhost", "/", Methods::POST, 2, Some(2))
+            .unwrap();
+        builder
+            .push_with_methods(
+                "localhost",
+                "/api",
+                Methods::GET | Methods::HEAD,
+                3,
+                Some(3),
+            )
+            .unwrap();
+        builder
+            .push_with_methods("localhost", "/api", Methods::GET, 4, Some(4))
+            .unwrap();
+        builder
+            .push_with_methods("localhost", "/api", Methods::ANY, 5, Some(5))
+            .unwrap();
+        builder
+            .push_with_methods("*.example.com", "/", Methods::PUT, 6, Some(6))
+            .unwrap();
+        builder.push("", "/", 7, Some(7)).unwrap();
+        builder
+            .push_with_methods("", "/upload", Methods::POST | Methods::PUT, 8, Some(8))
+            .unwrap();
+        let router = builder.build().unwrap();
 
-        Router {
-            trie: builder.build(),
-            fallback: fallback_builder.build(),
-        }
-    }
-}
+        // Method-specific entry preferred, method-agnostic entry at the same location as fallback
+        assert_eq!(lookup(&router, "localhost", "/", Method::POST), Some(2));
+        assert_eq!(lookup(&router, "localhost", "/", Method::GET), Some(1));
+        assert_eq!(lookup(&router, "localhost", "/file", Method::POST), Some(2));
+        assert_eq!(
+            lookup(&router, "localhost", "/file", Method::DELETE),
+            Some(1)
+        );
 
-#[cfg(test)]
-mod tests {
-    use crate::merger::HostPathMatcher;
+        // First method set pushed wins
+        assert_eq!(lookup(&router, "localhost", "/api", Method::GET), Some(3));
+        assert_eq!(
+            lookup(&router, "localhost", "/api/x", Method::HEAD),
+            Some(3)
+        );
 
-    use super::*;
+        // ANY is the same as a method-agnostic entry, it wins over a method-specific entry for a
+        // shorter path
+        assert_eq!(lookup(&router, "localhost", "/api", Method::POST), Some(5));
+        let custom = Method::from_bytes(b"CUSTOM").unwrap();
+        assert_eq!(
+            lookup(&router, "localhost", "/api", custom.clone()),
+            Some(5)
+        );
 
-    #[test]
-    fn path_normalization() {
-        assert_eq!(&Path::new("").path, b"");
-        assert_eq!(&Path::new("/").path, b"");
-        assert_eq!(&Path::new("///").path, b"");
-        assert_eq!(&Path::new("abc").path, b"abc");
-        assert_eq!(&Path::new("//abc//").path, b"abc");
-        assert_eq!(&Path::new("abc/def").path, b"abc/def");
-        assert_eq!(&Path::new("//abc//def//").path, b"abc/def");
-    }
+        // Method-specific entries are ignored by regular lookups
+        assert_eq!(router.lookup("localhost", "/api").as_deref(), Some(&5));
+        assert_eq!(router.lookup("localhost", "/").as_deref(), Some(&1));
 
-    #[test]
-    fn path_remove_prefix() {
-        assert_eq!(Path::new("").remove_prefix_from(b"/"), None);
-        assert_eq!(Path::new("").remove_prefix_from(b"/abc"), None);
-        assert_eq!(Path::new("///").remove_prefix_from(b"/"), None);
-        assert_eq!(Path::new("///").remove_prefix_from(b"/abc"), None);
+        // Host precedence is preserved
         assert_eq!(
-            Path::new("abc").remove_prefix_from(b"/abc"),
-            Some("/".as_bytes())
+            lookup(&router, "www.example.com", "/", Method::PUT),
+            Some(6)
         );
-        assert_eq!(Path::new("abc").remove_prefix_from(b"/def"), None);
-        assert_eq!(Path::new("abc").remove_prefix_from(b"/abcd"), None);
         assert_eq!(
-            Path::new("abc").remove_prefix_from(b"/abc//d"),
-            Some("//d".as_bytes())
+            lookup(&router, "www.example.com", "/", Method::GET),
+            Some(7)
         );
         assert_eq!(
-            Path::new("/abc/def/").remove_prefix_from(b"/abc//def"),
-            Some("/".as_bytes())
+            lookup(&router, "www.example.com", "/upload", Method::PUT),
+            Some(6)
         );
         assert_eq!(
-            Path::new("/abc/def/").remove_prefix_from(b"/abc//def\\xyz"),
-            None
+            lookup(&router, "example.net", "/upload", Method::PUT),
+            Some(8)
         );
         assert_eq!(
-            Path::new("/abc/def/").remove_prefix_from(b"/abc//def/xyz"),
-            Some("/xyz".as_bytes())
+            lookup(&router, "example.net", "/upload", Method::GET),
+            Some(7)
         );
+        assert_eq!(lookup(&router, "example.net", "/upload", custom), Some(7));
+
+        assert!(Methods::ANY.contains(&Method::PATCH));
+        assert!(!(Methods::GET | Methods::HEAD).contains(&Method::POST));
     }
 
     #[test]
-    fn host_path_matcher_parsing() {
+    fn scheme_routing() {
+        fn lookup(
+            router: &Router<u8>,
+            host: &str,
+            path: &str,
+            scheme: RequestScheme,
+        ) -> Option<u8> {
+            router.lookup_with_scheme(host, path, scheme).map(|r| *r)
+        }
+
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", 1u8, Some(1)).unwrap();
+        builder
+            .push_with_scheme("localhost", "/", Some(RequestScheme::Https), 2, Some(2))
+            .unwrap();
+        builder.push("localhost", "/api", 3, Some(3)).unwrap();
+        builder
+            .push_with_scheme("localhost", "/api", None, 4, Some(4))
+            .unwrap();
+        builder
+            .push_with_scheme("", "/", Some(RequestScheme::Http), 5, Some(5))
+            .unwrap();
+        builder.push("", "/", 6, Some(6)).unwrap();
+        let router = builder.build().unwrap();
+
+        // Scheme-specific entry preferred at the same location
         assert_eq!(
-            HostPathMatcher::from(""),
-            HostPathMatcher {
-                host: b"".to_vec(),
-                path: Path::new(""),
-                exact: false,
-            }
+            lookup(&router, "localhost", "/file", RequestScheme::Https),
+            Some(2)
         );
-
         assert_eq!(
-            HostPathMatcher::from("/*"),
-            HostPathMatcher {
-                host: b"".to_vec(),
-                path: Path::new(""),
-                exact: false,
-            }
+            lookup(&router, "localhost", "/file", RequestScheme::Http),
+            Some(1)
         );
 
+        // Scheme-agnostic entry wins for a longer path, `None` is the same as a regular entry
         assert_eq!(
-            HostPathMatcher::from("abc/*"),
-            HostPathMatcher {
-                host: b"abc".to_vec(),
-                path: Path::new(""),
-                exact: false,
-            }
+            lookup(&router, "localhost", "/api", RequestScheme::Https),
+            Some(4)
+        );
+        assert_eq!(
+            lookup(&router, "localhost", "/api", RequestScheme::Http),
+            Some(4)
         );
 
+        // Fallback rules
         assert_eq!(
-            HostPathMatcher::from("/abc/*"),
-            HostPathMatcher {
-                host: b"".to_vec(),
-                path: Path::new("abc"),
-                exact: false,
-            }
+            lookup(&router, "example.com", "/", RequestScheme::Http),
+            Some(5)
+        );
+        assert_eq!(
+            lookup(&router, "example.com", "/", RequestScheme::Https),
+            Some(6)
         );
 
+        // Host precedence is preserved
         assert_eq!(
-            HostPathMatcher::from("abc"),
-            HostPathMatcher {
-                host: b"abc".to_vec(),
-                path: Path::new(""),
-                exact: false,
-            }
+            router
+                .lookup_hosts_with_scheme(&["localhost"], "/", RequestScheme::Http)
+                .map(|r| *r),
+            Some(1)
         );
 
+        // Scheme-specific entries are ignored by regular lookups
+        assert_eq!(router.lookup("localhost", "/").map(|r| *r), Some(1));
+        assert_eq!(router.lookup("example.com", "/").map(|r| *r), Some(6));
+    }
+
+    #[test]
+    fn routes() {
+        let mut builder = Router::builder();
+        builder
+            .push("LocalHost", "//dir//subdir/", 1, Some(2))
+            .unwrap();
+        builder.push("localhost", "/", 3, None).unwrap();
+        builder.push("*.example.com", "/", 4, None).unwrap();
+        builder.push("", "/fallback/", 5, Some(6)).unwrap();
+        builder.push("", "/", 7, None).unwrap();
+        builder
+            .push_with_methods("localhost", "/api", Methods::POST, 8, None)
+            .unwrap();
+        builder
+            .push_with_priority("example.com", "/high", 1, 9, None)
+            .unwrap();
+        let router = builder.build().unwrap();
+
+        let mut routes = router
+            .routes()
+            .map(|(host, path, value)| {
+                (
+                    String::from_utf8_lossy(host).to_string(),
+                    String::from_utf8_lossy(path).to_string(),
+                    *value,
+                )
+            })
+            .collect::<Vec<_>>();
+        routes.sort();
         assert_eq!(
-            HostPathMatcher::from("/abc"),
-            HostPathMatcher {
-                host: b"".to_vec(),
-                path: Path::new("abc"),
-                exact: true,
+            routes,
+            vec![
+                ("".to_owned(), "".to_owned(), 7),
+                ("".to_owned(), "fallback".to_owned(), 5),
+                ("*.example.com".to_owned(), "".to_owned(), 4),
+                ("example.com".to_owned(), "high".to_owned(), 9),
+                ("localhost".to_owned(), "".to_owned(), 3),
+                ("localhost".to_owned(), "api".to_owned(), 8),
+                ("localhost".to_owned(), "dir/subdir".to_owned(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn routes_sorted() {
+        let rules = [
+            ("localhost", "/dir/subdir", 1, Some(2)),
+            ("example.com", "/", 3, None),
+            ("", "/fallback", 4, Some(5)),
+            ("LocalHost", "/", 6, Some(7)),
+            ("*.example.com", "/a/b", 8, None),
+            ("localhost", "/dir-x", 9, Some(12)),
+            ("", "/", 10, None),
+        ];
+        let build = |rules: &[(&str, &str, i32, Option<i32>)]| {
+            let mut builder = Router::builder();
+            for (host, path, value_exact, value_prefix) in rules {
+                builder
+                    .push(host, path, *value_exact, *value_prefix)
+                    .unwrap();
             }
+            builder
+                .push_with_priority("example.com", "/high", 1, 11, None)
+                .unwrap();
+            builder.build().unwrap()
+        };
+
+        let dump = |router: &Router<i32>| {
+            router
+                .routes_sorted()
+                .into_iter()
+                .map(|entry| entry.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let router = build(&rules);
+        assert_eq!(
+            dump(&router),
+            vec![
+                "/: exact Some(10), prefix None",
+                "/fallback: exact Some(4), prefix Some(5)",
+                "*.example.com/a/b: exact Some(8), prefix None",
+                "example.com/: exact Some(3), prefix None",
+                "localhost/: exact Some(6), prefix Some(7)",
+                "localhost/dir/subdir: exact Some(1), prefix Some(2)",
+                "localhost/dir-x: exact Some(9), prefix Some(12)",
+                "example.com/high: exact Some(11), prefix None",
+            ]
         );
 
+        // Same rules added in a different order produce the same output
+        let mut reversed = rules;
+        reversed.reverse();
+        assert_eq!(dump(&build(&reversed)), dump(&router));
+    }
+
+    #[test]
+    fn describe_lookup() {
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", 1, Some(1)).unwrap();
+        builder.push("localhost", "/dir", 2, Some(2)).unwrap();
+        builder.push("*.localhost", "/", 3, Some(3)).unwrap();
+        builder.push("", "/", 4, Some(4)).unwrap();
+        builder
+            .push_with_priority("", "/dir/subdir", -1, 5, Some(5))
+            .unwrap();
+        builder
+            .push_with_priority("", "/other", 1, 6, Some(6))
+            .unwrap();
+        let router = builder.build().unwrap();
+
+        let summarize = |trace: &LookupTrace<'_, u8>| {
+            trace
+                .candidates
+                .iter()
+                .map(|candidate| {
+                    (
+                        candidate.priority,
+                        candidate.host_match,
+                        *candidate.result,
+                        candidate.segments,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let trace = router.describe_lookup("LOCALHOST", "/dir/subdir/file");
         assert_eq!(
-            HostPathMatcher::from("/abc*"),
-            HostPathMatcher {
-                host: b"".to_vec(),
-                path: Path::new("abc*"),
-                exact: true,
-            }
+            summarize(&trace),
+            vec![
+                (0, HostMatch::Exact, 1, 1),
+                (0, HostMatch::Exact, 2, 2),
+                (0, HostMatch::Fallback, 4, 0),
+                (-1, HostMatch::Fallback, 5, 2),
+            ]
+        );
+        assert_eq!(trace.winner, Some(1));
+        assert_eq!(trace.candidates[1].result.host(), b"localhost");
+        assert_eq!(trace.candidates[1].result.path(), b"dir");
+        assert_eq!(
+            trace.reason(),
+            "longest matching path for the exact host name, priority 0 is the highest priority with matching rules"
+        );
+        assert_eq!(
+            router.lookup("LOCALHOST", "/dir/subdir/file").as_deref(),
+            Some(&2)
         );
 
+        let trace = router.describe_lookup("www.localhost", "/other");
         assert_eq!(
-            HostPathMatcher::from("localhost/"),
-            HostPathMatcher {
-                host: b"localhost".to_vec(),
-                path: Path::new(""),
-                exact: true,
-            }
+            summarize(&trace),
+            vec![
+                (1, HostMatch::Fallback, 6, 1),
+                (0, HostMatch::Wildcard, 3, 1),
+                (0, HostMatch::Fallback, 4, 0),
+            ]
         );
+        assert_eq!(trace.winner, Some(0));
 
+        let trace = router.describe_lookup("www.localhost", "/");
+        assert_eq!(trace.winner, Some(0));
+        assert_eq!(trace.candidates[0].result.host(), b"*.localhost");
         assert_eq!(
-            HostPathMatcher::from("localhost/abc/*"),
-            HostPathMatcher {
-                host: b"localhost".to_vec(),
-                path: Path::new("abc"),
-                exact: false,
-            }
+            trace.reason(),
+            "longest matching path for the wildcard host name, no rules for the exact host name"
         );
 
+        let router = Router::<u8>::builder().build().unwrap();
+        let trace = router.describe_lookup("localhost", "/");
+        assert!(trace.candidates.is_empty());
+        assert_eq!(trace.winner, None);
+        assert_eq!(trace.reason(), "no matching rule");
+        assert_eq!(trace.to_string(), "result: no matching rule");
+    }
+
+    #[test]
+    fn host_chain() {
+        let mut builder = Router::builder();
+        builder.push("example.com", "/", 1, Some(1)).unwrap();
+        builder.push("example.com", "/dir", 2, Some(2)).unwrap();
+        builder.push("alias", "/dir/subdir", 3, Some(3)).unwrap();
+        builder.push("*.wildcard", "/", 4, Some(4)).unwrap();
+        builder.push("", "/dir/subdir/deep", 5, Some(5)).unwrap();
+        builder
+            .push_with_priority("", "/important", 1, 6, Some(6))
+            .unwrap();
+        let router = builder.build().unwrap();
+
+        let lookup = |hosts: &[&str], path: &str| router.lookup_hosts(hosts, path).map(|r| *r);
+
+        // The first host name with a match wins, even with a longer match for later host names
         assert_eq!(
-            HostPathMatcher::from("localhost///abc///"),
-            HostPathMatcher {
-                host: b"localhost".to_vec(),
-                path: Path::new("abc"),
-                exact: true,
+            lookup(&["example.com", "alias", ""], "/dir/subdir/deep/file"),
+            Some(2)
+        );
+        assert_eq!(lookup(&["EXAMPLE.COM", "alias", ""], "/file"), Some(1));
+        assert_eq!(
+            lookup(&["alias", "example.com", ""], "/dir/subdir/deep/file"),
+            Some(3)
+        );
+        assert_eq!(lookup(&["alias", "example.com", ""], "/dir/file"), Some(2));
+        assert_eq!(
+            lookup(&["unknown", "alias", ""], "/dir/subdir/deep/file"),
+            Some(3)
+        );
+
+        // Wildcard hosts are considered right after the exact host name
+        assert_eq!(lookup(&["www.wildcard", "example.com"], "/dir"), Some(4));
+
+        // Fallback rules are only considered if the empty host name is listed
+        assert_eq!(lookup(&["unknown", ""], "/dir/subdir/deep"), Some(5));
+        assert_eq!(lookup(&["unknown"], "/dir/subdir/deep"), None);
+        assert_eq!(lookup(&["", "example.com"], "/dir/subdir/deep"), Some(5));
+        assert_eq!(lookup(&["", "example.com"], "/dir"), Some(2));
+        assert_eq!(lookup(&[], "/dir"), None);
+
+        // Higher priority wins regardless of the host order
+        assert_eq!(lookup(&["example.com", ""], "/important"), Some(6));
+        assert_eq!(lookup(&["example.com"], "/important"), Some(1));
+
+        // Same result as a regular lookup
+        for (host, path) in [
+            ("example.com", "/dir/subdir/deep"),
+            ("alias", "/dir/subdir/deep"),
+            ("www.wildcard", "/important"),
+            ("unknown", "/dir/subdir/deep"),
+            ("unknown", "/"),
+        ] {
+            assert_eq!(
+                router.lookup_hosts(&[host, ""], path).map(|r| *r),
+                router.lookup(host, path).map(|r| *r)
+            );
+        }
+    }
+
+    #[test]
+    fn shared_router() {
+        fn make_router(generation: usize) -> Router<usize> {
+            let mut builder = Router::builder();
+            builder.push("localhost", "/", generation, None).unwrap();
+            for i in 0..generation {
+                builder
+                    .push("localhost", format!("/{i}"), generation, None)
+                    .unwrap();
+            }
+            builder.build().unwrap()
+        }
+
+        const GENERATIONS: usize = 50;
+        let router = SharedRouter::new(make_router(0));
+
+        std::thread::scope(|scope| {
+            let readers = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut last = 0;
+                        while last < GENERATIONS {
+                            let snapshot = router.load();
+                            let generation = *snapshot.lookup("localhost", "/").unwrap();
+
+                            // Generations never go backwards and each snapshot is consistent
+                            assert!(generation >= last);
+                            for i in 0..generation {
+                                assert_eq!(
+                                    snapshot.lookup("localhost", &format!("/{i}")).map(|r| *r),
+                                    Some(generation)
+                                );
+                            }
+                            last = generation;
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for generation in 1..=GENERATIONS {
+                let previous = router.replace(make_router(generation));
+                assert_eq!(*previous.lookup("localhost", "/").unwrap(), generation - 1);
+            }
+
+            for reader in readers {
+                reader.join().unwrap();
             }
+        });
+
+        assert_eq!(router, SharedRouter::from(make_router(GENERATIONS)));
+        assert_eq!(
+            router.clone().load().lookup("localhost", "/1").map(|r| *r),
+            Some(GENERATIONS)
         );
     }
 
     #[test]
-    fn routing() {
-        fn lookup(router: &Router<u8>, host: &str, path: &str) -> Option<u8> {
-            router.lookup(host, path).as_deref().copied()
+    fn percent_decoding() {
+        fn decode(path: &str) -> Option<String> {
+            decode_path(path.as_bytes()).map(|path| String::from_utf8(path.into_owned()).unwrap())
         }
 
+        // Plain paths are left alone
+        assert!(matches!(
+            decode_path(b"/dir/file.txt"),
+            Some(Cow::Borrowed(b"/dir/file.txt"))
+        ));
+        assert_eq!(decode("//dir//../file"), Some("//dir//../file".into()));
+
+        assert_eq!(decode("/sub%64ir/file"), Some("/subdir/file".into()));
+        assert_eq!(decode("/sub%64ir/%66ile"), Some("/subdir/file".into()));
+        assert_eq!(decode("/%C3%A4%20x"), Some("/ä x".into()));
+        assert_eq!(decode("/100%25"), Some("/100%".into()));
+        assert_eq!(decode("/%2e%2ex/.%2e.x"), Some("/..x/...x".into()));
+
+        // Escaped separators
+        assert_eq!(decode("/dir%2Ffile"), None);
+        assert_eq!(decode("/dir%2ffile"), None);
+
+        // Escaped dot segments
+        assert_eq!(decode("/dir/%2e%2e/file"), None);
+        assert_eq!(decode("/dir/.%2E/file"), None);
+        assert_eq!(decode("/dir/%2e/file"), None);
+
+        // Invalid escape sequences
+        assert_eq!(decode("/dir/%zzfile"), None);
+        assert_eq!(decode("/dir/%2"), None);
+        assert_eq!(decode("/dir/%"), None);
+        assert_eq!(decode("/dir/%00file"), None);
+
+        // Overlong and otherwise invalid UTF-8
+        assert_eq!(decode("/%c0%ae%c0%ae/file"), None);
+        assert_eq!(decode("/%C0%AF"), None);
+        assert_eq!(decode("/%ff"), None);
+        assert_eq!(decode("/%C3"), None);
+    }
+
+    #[test]
+    fn lookup_decoded() {
         let mut builder = Router::builder();
-        builder.push("localhost", "/", 1u8, Some(1));
-        builder.push("localhost", "/abc", 2, Some(2));
-        builder.push("localhost", "/xyz/abc/", 3, Some(3));
-        builder.push("example.com", "", 4, Some(4));
-        builder.push("example.com", "/abc/def/", 5, Some(5));
-        builder.push("example.com", "/x", 6, Some(6));
-        builder.push("", "/abc", 7, Some(7));
-        let router = builder.build();
+        builder.push("", "/", 1, Some(1)).unwrap();
+        builder.push("", "/subdir", 2, Some(2)).unwrap();
+        builder.push("localhost", "/subdir/", 3, Some(3)).unwrap();
+        let router = builder.build().unwrap();
 
-        assert_eq!(lookup(&router, "localhost", "/"), Some(1));
-        assert_eq!(lookup(&router, "localhost", "/ab"), Some(1));
-        assert_eq!(lookup(&router, "localhost", "/abc"), Some(2));
-        assert_eq!(lookup(&router, "localhost", "/abc/"), Some(2));
-        assert_eq!(lookup(&router, "localhost", "/abc/d"), Some(2));
-        assert_eq!(lookup(&router, "localhost", "/abc/d/"), Some(2));
-        assert_eq!(lookup(&router, "localhost", "/xyz"), Some(1));
-        assert_eq!(lookup(&router, "localhost", "/xyz/"), Some(1));
-        assert_eq!(lookup(&router, "localhost", "/xyz/abc"), Some(3));
-        assert_eq!(lookup(&router, "example.com", "/"), Some(4));
-        assert_eq!(lookup(&router, "example.com", "/abc"), Some(4));
-        assert_eq!(lookup(&router, "example.com", "/abc/def"), Some(5));
-        assert_eq!(lookup(&router, "example.com", "/x/"), Some(6));
-        assert_eq!(lookup(&router, "example.com", "/xyz"), Some(4));
-        assert_eq!(lookup(&router, "example.net", "/"), None);
-        assert_eq!(lookup(&router, "example.net", "/abc"), Some(7));
-        assert_eq!(lookup(&router, "", "/"), None);
-        assert_eq!(lookup(&router, "", "/abc"), Some(7));
-        assert_eq!(lookup(&router, "", "/abc/def"), Some(7));
+        let lookup = |host: &str, path: &str| router.lookup_decoded(host, path).map(|r| *r);
+        assert_eq!(lookup("", "/subdir/file"), Some(2));
+        assert_eq!(lookup("", "/sub%64ir/file"), Some(2));
+        assert_eq!(lookup("", "/sub%64ir%2Ffile"), None);
+        assert_eq!(lookup("", "/sub%64irx/file"), Some(1));
+        assert_eq!(lookup("", "/subdir/%2e%2e/secret"), None);
+        assert_eq!(lookup("", "/subdir/%zz"), None);
+        assert_eq!(lookup("localhost", "/%73ubdir"), Some(3));
+        assert_eq!(router.lookup("", "/sub%64ir/file").map(|r| *r), Some(1));
 
-        // A special case to keep in mind: slashes in host name will cause incorrect segmentation
-        // of the path, essentially causing everything after the slash to be ignored. As such, this
-        // is not an issue but it might become one as the implementation changes.
-        assert_eq!(lookup(&router, "localhost/def", "/abc"), Some(2));
+        assert_eq!(
+            router
+                .lookup_hosts_decoded(&["unknown", ""], "/sub%64ir")
+                .map(|r| *r),
+            Some(2)
+        );
+
+        let path = Path::new("/subdir/subsub");
+        assert_eq!(
+            path.remove_prefix_from_encoded(&"/sub%64ir//%73ubsub/%66ile"),
+            Some(&b"/%66ile"[..])
+        );
+        assert_eq!(
+            path.remove_prefix_from_encoded(&"/subdir/subsub"),
+            Some(&b"/"[..])
+        );
+        assert_eq!(path.remove_prefix_from_encoded(&"/subdir%2Fsubsub"), None);
+        assert_eq!(path.remove_prefix_from_encoded(&"/subdir/subsubx"), None);
+        assert_eq!(path.remove_prefix_from_encoded(&"/subdir"), None);
     }
 }
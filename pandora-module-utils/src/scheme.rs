@@ -0,0 +1,207 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running handlers conditionally, depending on the scheme of the request
+//!
+//! A handler wrapped in [`SchemeFilter`] can be restricted to HTTP or HTTPS requests via the
+//! `only_scheme` setting, e.g. to send a `Strict-Transport-Security` header for HTTPS requests
+//! only. For requests using a different scheme the wrapped handler won’t run at all, its
+//! `request_filter` phase will produce [`RequestFilterResult::Unhandled`].
+//!
+//! The scheme is determined by the listener the request was received on. If the server is
+//! running behind a reverse proxy, the `trust_forwarded_proto` setting can be enabled to use
+//! the scheme indicated by the `X-Forwarded-Proto` header instead.
+
+use async_trait::async_trait;
+use log::trace;
+use serde::Deserialize;
+use std::ops::{Deref, DerefMut};
+
+use crate::pingora::{Error, HttpModules, HttpPeer, ResponseHeader, SessionWrapper};
+use crate::{DeserializeMap, RequestFilter, RequestFilterResult};
+
+/// Scheme of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "lowercase")]
+pub enum RequestScheme {
+    /// Plain HTTP, `http` in configuration files
+    Http,
+    /// HTTP over TLS, `https` in configuration files
+    Https,
+}
+
+/// Configuration of a handler restricted to a request scheme
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+#[pandora(crate = "crate")]
+pub struct SchemeFilterConf<C: Default> {
+    /// If set, the handler will only run for requests using this scheme.
+    pub only_scheme: Option<RequestScheme>,
+
+    /// If `true`, the scheme indicated by the `X-Forwarded-Proto` header will be used. This
+    /// should only be enabled if the server is running behind a reverse proxy which sets this
+    /// header.
+    pub trust_forwarded_proto: bool,
+
+    /// Generic handler settings
+    ///
+    /// These settings are flattened and appear at the same level as `only_scheme` in the
+    /// configuration file.
+    #[pandora(flatten)]
+    pub config: C,
+}
+
+/// Context of the scheme filter
+#[derive(Debug)]
+pub struct SchemeFilterCtx<Ctx> {
+    active: bool,
+    handler: Ctx,
+}
+
+impl<Ctx> Deref for SchemeFilterCtx<Ctx> {
+    type Target = Ctx;
+
+    fn deref(&self) -> &Self::Target {
+        &self.handler
+    }
+}
+
+impl<Ctx> DerefMut for SchemeFilterCtx<Ctx> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.handler
+    }
+}
+
+/// Wraps a handler, only running it for requests using a particular scheme
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemeFilter<H> {
+    only_scheme: Option<RequestScheme>,
+    trust_forwarded_proto: bool,
+    handler: H,
+}
+
+impl<H> SchemeFilter<H> {
+    /// Retrieves the wrapped handler
+    pub fn as_inner(&self) -> &H {
+        &self.handler
+    }
+}
+
+impl<C, H> TryFrom<SchemeFilterConf<C>> for SchemeFilter<H>
+where
+    C: TryInto<H, Error = Box<Error>> + Default,
+{
+    type Error = Box<Error>;
+
+    fn try_from(conf: SchemeFilterConf<C>) -> Result<Self, Self::Error> {
+        Ok(Self {
+            only_scheme: conf.only_scheme,
+            trust_forwarded_proto: conf.trust_forwarded_proto,
+            handler: conf.config.try_into()?,
+        })
+    }
+}
+
+#[async_trait]
+impl<H> RequestFilter for SchemeFilter<H>
+where
+    H: RequestFilter + Sync,
+    H::Conf: Default,
+    H::CTX: Send,
+{
+    type Conf = SchemeFilterConf<H::Conf>;
+
+    type CTX = SchemeFilterCtx<H::CTX>;
+
+    fn new_ctx() -> Self::CTX {
+        Self::CTX {
+            active: false,
+            handler: H::new_ctx(),
+        }
+    }
+
+    fn init_downstream_modules(modules: &mut HttpModules) {
+        H::init_downstream_modules(modules);
+    }
+
+    async fn early_request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        let scheme = if session.is_https(self.trust_forwarded_proto) {
+            RequestScheme::Https
+        } else {
+            RequestScheme::Http
+        };
+        ctx.active = self.only_scheme.map_or(true, |only| only == scheme);
+
+        if ctx.active {
+            self.handler.early_request_filter(session, ctx).await
+        } else {
+            trace!("Skipping handler for {scheme:?} request");
+            Ok(())
+        }
+    }
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        if ctx.active {
+            self.handler.request_filter(session, ctx).await
+        } else {
+            Ok(RequestFilterResult::Unhandled)
+        }
+    }
+
+    async fn upstream_peer(
+        &self,
+        session: &mut impl SessionWrapper,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Box<HttpPeer>>, Box<Error>> {
+        if ctx.active {
+            self.handler.upstream_peer(session, ctx).await
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        if ctx.active {
+            self.handler
+                .response_filter(session, upstream_response, ctx)
+                .await
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn logging(
+        &self,
+        session: &mut impl SessionWrapper,
+        e: Option<&Error>,
+        ctx: &mut Self::CTX,
+    ) {
+        if ctx.active {
+            self.handler.logging(session, e, ctx).await;
+        }
+    }
+}
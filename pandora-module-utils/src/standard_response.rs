@@ -14,6 +14,7 @@
 
 //! Standard responses for various conditions
 
+use bytes::Bytes;
 use http::{header, method::Method, status::StatusCode};
 use maud::{html, DOCTYPE};
 
@@ -44,6 +45,42 @@ pub fn response_text(status: StatusCode) -> String {
     .into()
 }
 
+/// Sends a response with a body of known length.
+///
+/// `Content-Length` is set to the length of the body, so that the response never needs chunked
+/// encoding, and any `Transfer-Encoding` header is removed. Responses with a status code that
+/// doesn’t allow a body (1xx, 204 and 304) are sent without `Content-Length` header and body. For
+/// `HEAD` requests only the response header is sent.
+pub async fn send_response(
+    session: &mut impl SessionWrapper,
+    mut header: ResponseHeader,
+    body: impl Into<Bytes>,
+) -> Result<(), Box<Error>> {
+    let body = body.into();
+    let status = header.status;
+    let body_allowed = !status.is_informational()
+        && status != StatusCode::NO_CONTENT
+        && status != StatusCode::NOT_MODIFIED;
+
+    header.remove_header(&header::TRANSFER_ENCODING);
+    if body_allowed {
+        header.insert_header(header::CONTENT_LENGTH, body.len())?;
+    } else {
+        header.remove_header(&header::CONTENT_LENGTH);
+    }
+
+    let send_body = body_allowed && !body.is_empty() && session.req_header().method != Method::HEAD;
+    session
+        .write_response_header(Box::new(header), !send_body)
+        .await?;
+
+    if send_body {
+        session.write_response_body(Some(body), true).await?;
+    }
+
+    Ok(())
+}
+
 async fn response(
     session: &mut impl SessionWrapper,
     status: StatusCode,
@@ -53,7 +90,6 @@ async fn response(
     let text = response_text(status);
 
     let mut header = ResponseHeader::build(status, Some(4))?;
-    header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
     header.append_header(header::CONTENT_TYPE, "text/html;charset=utf-8")?;
     if let Some(location) = location {
         header.append_header(header::LOCATION, location)?;
@@ -62,16 +98,7 @@ async fn response(
         header.append_header(header::SET_COOKIE, cookie)?;
     }
 
-    let send_body = session.req_header().method != Method::HEAD;
-    session
-        .write_response_header(Box::new(header), !send_body)
-        .await?;
-
-    if send_body {
-        session.write_response_body(Some(text.into()), true).await?;
-    }
-
-    Ok(())
+    send_response(session, header, text).await
 }
 
 /// Responds with a standard error page for the given status code.
@@ -100,3 +127,109 @@ pub async fn redirect_response_with_cookie(
 ) -> Result<(), Box<Error>> {
     response(session, status, Some(location), Some(cookie)).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http::Extensions;
+    use std::ops::{Deref, DerefMut};
+    use test_log::test;
+
+    use crate::pingora::{create_test_session, RequestHeader, Session};
+
+    struct TestSession {
+        session: Session,
+        extensions: Extensions,
+    }
+
+    impl Deref for TestSession {
+        type Target = Session;
+
+        fn deref(&self) -> &Self::Target {
+            &self.session
+        }
+    }
+
+    impl DerefMut for TestSession {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.session
+        }
+    }
+
+    impl SessionWrapper for TestSession {
+        fn extensions(&self) -> &Extensions {
+            &self.extensions
+        }
+
+        fn extensions_mut(&mut self) -> &mut Extensions {
+            &mut self.extensions
+        }
+    }
+
+    async fn make_session(method: &str) -> TestSession {
+        let header = RequestHeader::build(method, b"/", None).unwrap();
+        TestSession {
+            session: create_test_session(header).await,
+            extensions: Extensions::new(),
+        }
+    }
+
+    fn header_value<'a>(session: &'a TestSession, name: &str) -> Option<&'a str> {
+        session
+            .response_written()?
+            .headers
+            .get(name)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[test(tokio::test)]
+    async fn error_page_length() {
+        let expected_length = response_text(StatusCode::NOT_FOUND).len().to_string();
+
+        let mut session = make_session("GET").await;
+        error_response(&mut session, StatusCode::NOT_FOUND)
+            .await
+            .unwrap();
+        assert_eq!(
+            header_value(&session, "Content-Length"),
+            Some(expected_length.as_str())
+        );
+        assert_eq!(header_value(&session, "Transfer-Encoding"), None);
+
+        let mut session = make_session("HEAD").await;
+        error_response(&mut session, StatusCode::NOT_FOUND)
+            .await
+            .unwrap();
+        assert_eq!(
+            header_value(&session, "Content-Length"),
+            Some(expected_length.as_str())
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn body_length() {
+        let mut session = make_session("GET").await;
+        let mut header = ResponseHeader::build(StatusCode::OK, None).unwrap();
+        header
+            .insert_header(header::TRANSFER_ENCODING, "chunked")
+            .unwrap();
+        header
+            .insert_header(header::CONTENT_LENGTH, "1000")
+            .unwrap();
+        send_response(&mut session, header, "Hello!").await.unwrap();
+        assert_eq!(header_value(&session, "Content-Length"), Some("6"));
+        assert_eq!(header_value(&session, "Transfer-Encoding"), None);
+
+        let mut session = make_session("GET").await;
+        let header = ResponseHeader::build(StatusCode::OK, None).unwrap();
+        send_response(&mut session, header, "").await.unwrap();
+        assert_eq!(header_value(&session, "Content-Length"), Some("0"));
+
+        let mut session = make_session("GET").await;
+        let mut header = ResponseHeader::build(StatusCode::NO_CONTENT, None).unwrap();
+        header.insert_header(header::CONTENT_LENGTH, "0").unwrap();
+        send_response(&mut session, header, "").await.unwrap();
+        assert_eq!(header_value(&session, "Content-Length"), None);
+    }
+}
@@ -17,46 +17,105 @@
 //! * Memory-efficient data storage after the setup phase
 //! * Zero allocation and copying during lookup
 //! * Efficient lookup
-//! * The labels are segmented with a separator character (forward slash) and only full segment
-//!   matches are accepted.
+//! * The labels are segmented with a separator character (forward slash by default) and only full
+//!   segment matches are accepted.
 //! * Different value returned for exact and prefix matches
+//! * Optional suffix matching mode, where label segments are matched starting with the last one
 //! * When the same value is used multiple times, only one copy is stored
-
+//! * When the same label is used by multiple nodes, only one copy is stored
+//! * Lookup results report the full label of the matched node, borrowed from the trie data
+//! * With the `serde` feature, a trie can be serialized and later restored without going through
+//!   the builder again
+
+#[cfg(feature = "serde")]
+use serde::{
+    de::Error as _, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer,
+};
 use std::{
+    cmp::Ordering,
+    collections::HashMap,
     fmt::Debug,
     ops::{Deref, Range},
 };
 
-/// Character to separate labels
+use crate::pingora::{Error, ErrorType};
+
+/// Default character to separate labels
 pub(crate) const SEPARATOR: u8 = b'/';
 
+/// Version of the serialized trie format, has to be increased whenever the data structure changes
+#[cfg(feature = "serde")]
+const FORMAT_VERSION: u32 = 2;
+
 /// Calculates the length of the longest common prefix of two labels. A common prefix is identical
 /// and ends at a boundary in both labels (either end of the label or a separator character).
-pub(crate) fn common_prefix_length(a: &[u8], b: &[u8]) -> usize {
+pub(crate) fn common_prefix_length(a: &[u8], b: &[u8], separator: u8) -> usize {
     let mut length = 0;
     for i in 0..std::cmp::min(a.len(), b.len()) {
         if a[i] != b[i] {
             return length;
         }
 
-        if a[i] == SEPARATOR {
+        if a[i] == separator {
             length = i;
         }
     }
 
-    if a.len() == b.len() || (a.len() < b.len() && b[a.len()] == SEPARATOR) {
+    if a.len() == b.len() || (a.len() < b.len() && b[a.len()] == separator) {
         // exact match or A is a prefix of B
         length = a.len();
-    } else if a.len() > b.len() && a[b.len()] == SEPARATOR {
+    } else if a.len() > b.len() && a[b.len()] == separator {
         // B is a prefix of A
         length = b.len();
     }
     length
 }
 
+/// Normalizes a label by removing separator characters at the beginning and the end of the label
+/// and collapsing sequences of separator characters into one.
+pub(crate) fn normalize(label: &[u8], separator: u8) -> Vec<u8> {
+    let mut had_separator = true;
+    let mut label: Vec<u8> = label
+        .iter()
+        .copied()
+        .filter(|b| {
+            if *b == separator {
+                if had_separator {
+                    false
+                } else {
+                    had_separator = true;
+                    true
+                }
+            } else {
+                had_separator = false;
+                true
+            }
+        })
+        .collect();
+
+    if label.ends_with(&[separator]) {
+        label.pop();
+    }
+
+    label
+}
+
+/// Splits a normalized label into its segments.
+fn segments(label: &[u8], separator: u8) -> impl Iterator<Item = &[u8]> + '_ {
+    label
+        .split(move |b| *b == separator)
+        .filter(|segment| !segment.is_empty())
+}
+
+/// Compares two labels segment by segment. This is the order expected by
+/// [`TrieBuilder::from_sorted_iter`].
+pub(crate) fn compare_labels(a: &[u8], b: &[u8], separator: u8) -> Ordering {
+    segments(a, separator).cmp(segments(b, separator))
+}
+
 /// A trie data structure
 ///
-/// To use memory more efficiently and to improve locality, this stores all data in three vectors.
+/// To use memory more efficiently and to improve locality, this stores all data in four vectors.
 /// One lists all nodes, ordered in such a way that children of one node are always stored
 /// consecutively and sorted by their label. A node stores an index range referring to its
 /// children.
@@ -64,14 +123,21 @@ pub(crate) fn common_prefix_length(a: &[u8], b: &[u8]) -> usize {
 /// Since values are optional and potentially rather large, existing values are stored in a
 /// separate vector. The node stores an optional index of its value, not the value itself.
 ///
-/// Finally, the third vector stores the labels of the nodes, so that nodes don’t need separate
+/// The third vector stores the labels of the nodes, so that nodes don’t need separate
 /// allocations for their labels. Each nodes refers to its label within this vector via an index
-/// range.
+/// range. Nodes with identical labels share the same range.
+///
+/// Finally, the fourth vector stores the full keys of nodes with values: all labels on the path
+/// from the root node, joined. These are only needed to report the matched key in lookup results.
+///
+/// Label segments are separated by the separator character chosen when the builder was created.
 #[derive(Clone, PartialEq, Eq)]
 pub(crate) struct Trie<Value> {
     nodes: Vec<Node>,
     values: Vec<Value>,
     labels: Vec<u8>,
+    keys: Vec<u8>,
+    separator: u8,
 }
 
 /// Trie lookup result, will dereference into the value
@@ -79,11 +145,41 @@ pub(crate) struct Trie<Value> {
 pub struct LookupResult<'a, Value> {
     value: &'a Value,
     index: usize,
+    host: &'a [u8],
+    path: &'a [u8],
 }
 
 impl<'a, Value> LookupResult<'a, Value> {
-    fn new(value: &'a Value, index: usize) -> Self {
-        Self { value, index }
+    fn new(value: &'a Value, index: usize, label: &'a [u8]) -> Self {
+        Self {
+            value,
+            index,
+            host: &[],
+            path: label,
+        }
+    }
+
+    /// Splits off the first segment of the matched label as host name.
+    pub(crate) fn split_host(&mut self, separator: u8) {
+        if let Some(pos) = self.path.iter().position(|c| *c == separator) {
+            self.host = &self.path[..pos];
+            self.path = &self.path[pos + 1..];
+        } else {
+            self.host = self.path;
+            self.path = &[];
+        }
+    }
+
+    /// The host name of the rule that matched, e.g. `*.example.com` for wildcard host matches.
+    /// This is empty if a fallback rule matched.
+    pub fn host(&self) -> &'a [u8] {
+        self.host
+    }
+
+    /// The path of the rule that matched in normalized form, e.g. `dir/subdir` for a rule defined
+    /// for `/dir/subdir/`. This is empty if a rule for the root path matched.
+    pub fn path(&self) -> &'a [u8] {
+        self.path
     }
 
     /// The index of the referenced value, allows retrieving it again without going through another
@@ -110,8 +206,8 @@ impl<Value> Deref for LookupResult<'_, Value> {
 
 /// A trie node
 ///
-/// A node label can consist of one or multiple segments (separated by `SEPARATOR`). These segments
-/// represent the route to the node from its parent node.
+/// A node label can consist of one or multiple segments (separated by the separator character).
+/// These segments represent the route to the node from its parent node.
 ///
 /// The value is optional. Nodes without a value serve merely as a routing point for multiple child
 /// nodes.
@@ -120,8 +216,10 @@ impl<Value> Deref for LookupResult<'_, Value> {
 /// never start with the same segment: in such scenarios the builder inserts an intermediate node
 /// that serves as the common parent for all nodes reachable via that segment.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct Node {
     label: Range<usize>,
+    key: Range<usize>,
     value_exact: Option<usize>,
     value_prefix: Option<usize>,
     children: Range<usize>,
@@ -131,96 +229,377 @@ impl<Value> Trie<Value> {
     /// Index of the root node in the `nodes` vector, this is where lookup always starts.
     const ROOT: usize = 0;
 
-    /// Returns a builder instance that can be used to set up the trie.
+    /// Returns a builder instance that can be used to set up the trie. Label segments will be
+    /// separated by forward slashes.
     pub(crate) fn builder() -> TrieBuilder<Value>
     where
         Value: Eq,
     {
-        TrieBuilder::<Value>::new()
+        Self::builder_with_separator(SEPARATOR)
     }
 
-    /// Converts a value index into a lookup result
-    fn to_lookup_result(&self, result: Option<usize>) -> Option<LookupResult<'_, Value>> {
-        result
-            .and_then(|index| Some((self.values.get(index)?, index)))
-            .map(|(value, index)| (LookupResult::new(value, index)))
+    /// Returns a builder instance that can be used to set up the trie, using the given character
+    /// to separate label segments.
+    pub(crate) fn builder_with_separator(separator: u8) -> TrieBuilder<Value>
+    where
+        Value: Eq,
+    {
+        TrieBuilder::<Value>::new(separator, false)
     }
 
-    /// Looks up a particular label in the trie.
+    /// Returns a builder instance setting up a trie for suffix matching, using the given character
+    /// to separate label segments.
     ///
-    /// The label is identified by an iterator producing segments. The segments are expected to be
-    /// normalized: no empty segments exist and no segments contain the separator character.
+    /// The segments of labels added to this builder are stored in reverse order, so that
+    /// `*.example.com` with `.` as separator becomes `com.example.*`. Lookups have to go through
+    /// [`Trie::lookup_suffix`] then, so that longest prefix matching produces the longest suffix
+    /// match.
+    pub(crate) fn suffix_builder_with_separator(separator: u8) -> TrieBuilder<Value>
+    where
+        Value: Eq,
+    {
+        TrieBuilder::<Value>::new(separator, true)
+    }
+
+    /// Converts a value index into a lookup result, `key` is the full label of the node the value
+    /// belongs to.
+    fn to_lookup_result(&self, key: Range<usize>, index: usize) -> Option<LookupResult<'_, Value>> {
+        Some(LookupResult::new(
+            self.values.get(index)?,
+            index,
+            self.keys.get(key)?,
+        ))
+    }
+
+    /// Walks the trie along a label, calling `visit` for each node reached.
     ///
-    /// This will return the value corresponding to the longest matching path if any.
-    pub(crate) fn lookup<'a, L>(&self, mut label: L) -> Option<LookupResult<'_, Value>>
+    /// The callback receives the node along with its index, the number of segments consumed to
+    /// reach it and a flag indicating whether the label has been consumed completely (exact match
+    /// for this node). The
+    /// walk stops as soon as no child node matches the remaining label. Segments consumed while
+    /// partially matching a multi-segment child label are not counted, so the count passed to
+    /// `visit` always corresponds to the node actually reached.
+    fn walk<'a, L>(&self, mut label: L, mut visit: impl FnMut(usize, &Node, usize, bool))
     where
         L: Iterator<Item = &'a [u8]>,
     {
-        let mut result_exact;
-        let mut result_prefix = None;
-        let mut current = self.nodes.get(Self::ROOT)?;
+        let mut segments = 0;
+        let mut current_index = Self::ROOT;
+        let mut current = if let Some(current) = self.nodes.get(current_index) {
+            current
+        } else {
+            return;
+        };
         loop {
-            result_exact = current.value_exact;
-            if current.value_prefix.is_some() {
-                result_prefix = current.value_prefix;
-            }
+            let segment = label.next();
+            visit(current_index, current, segments, segment.is_none());
 
-            let segment = if let Some(segment) = label.next() {
+            let segment = if let Some(segment) = segment {
                 segment
             } else {
-                // End of label, return either exact or prefix result
-                return self.to_lookup_result(result_exact.or(result_prefix));
+                // End of label
+                return;
             };
 
             // TODO: Binary search might be more efficient here
             let mut found_match = false;
-            for child in current.children.start..current.children.end {
-                let child = self.nodes.get(child)?;
+            for child_index in current.children.start..current.children.end {
+                let child = if let Some(child) = self.nodes.get(child_index) {
+                    child
+                } else {
+                    return;
+                };
                 let mut label_start = child.label.start;
                 let label_end = child.label.end;
-                let length = common_prefix_length(segment, &self.labels[label_start..label_end]);
+                let length = common_prefix_length(
+                    segment,
+                    &self.labels[label_start..label_end],
+                    self.separator,
+                );
                 if length > 0 {
                     label_start += length;
 
+                    // Segments only count once the child node has been matched completely
+                    let mut consumed = 1;
+
                     // Keep matching more segments until there is no more label left
                     while label_end > label_start {
                         // Skip separator character
                         label_start += 1;
 
                         let segment = if let Some(segment) = label.next() {
+                            consumed += 1;
                             segment
                         } else {
-                            // End of label, return whatever we’ve got
-                            return self.to_lookup_result(result_prefix);
+                            // End of label, the child node isn’t reached
+                            return;
                         };
 
-                        let length =
-                            common_prefix_length(segment, &self.labels[label_start..label_end]);
+                        let length = common_prefix_length(
+                            segment,
+                            &self.labels[label_start..label_end],
+                            self.separator,
+                        );
                         if length > 0 {
                             label_start += length;
                         } else {
                             // Got only a partial match
-                            return self.to_lookup_result(result_prefix);
+                            return;
                         }
                     }
 
+                    segments += consumed;
                     found_match = true;
+                    current_index = child_index;
                     current = child;
                     break;
                 }
             }
 
             if !found_match {
-                return self.to_lookup_result(result_prefix);
+                return;
             }
         }
     }
 
+    /// Looks up a particular label in the trie.
+    ///
+    /// The label is identified by an iterator producing segments. The segments are expected to be
+    /// normalized: no empty segments exist and no segments contain the separator character.
+    ///
+    /// This will return the value corresponding to the longest matching path if any.
+    pub(crate) fn lookup<'a, L>(&self, label: L) -> Option<LookupResult<'_, Value>>
+    where
+        L: Iterator<Item = &'a [u8]>,
+    {
+        self.lookup_with_segments(label).map(|(result, _)| result)
+    }
+
+    /// Looks up a particular label in the trie like [`Trie::lookup`] but also returns the number of
+    /// segments consumed to reach the matching node.
+    pub(crate) fn lookup_with_segments<'a, L>(
+        &self,
+        label: L,
+    ) -> Option<(LookupResult<'_, Value>, usize)>
+    where
+        L: Iterator<Item = &'a [u8]>,
+    {
+        let (node, exact, segments) = self.lookup_node(label)?;
+        let node = self.nodes.get(node)?;
+        let index = if exact {
+            node.value_exact
+        } else {
+            node.value_prefix
+        }?;
+        Some((self.to_lookup_result(node.key.clone(), index)?, segments))
+    }
+
+    /// Looks up a particular label in a trie set up via [`Trie::suffix_builder_with_separator`].
+    ///
+    /// The segments are expected to be normalized, same as with [`Trie::lookup`]. They are fed into
+    /// the trie in reverse order, so this will return the value corresponding to the longest
+    /// matching suffix if any.
+    pub(crate) fn lookup_suffix<'a, L>(&self, label: L) -> Option<LookupResult<'_, Value>>
+    where
+        L: DoubleEndedIterator<Item = &'a [u8]>,
+    {
+        self.lookup(label.rev())
+    }
+
+    /// Looks up a particular label in the trie like [`Trie::lookup`] but returns a mutable
+    /// reference to the value.
+    ///
+    /// Changing the value only affects the exact match or prefix value found. Identical values are
+    /// stored only once, so a value shared with other nodes is copied first.
+    pub(crate) fn lookup_mut<'a, L>(&mut self, label: L) -> Option<&mut Value>
+    where
+        L: Iterator<Item = &'a [u8]>,
+        Value: Clone,
+    {
+        let (node, exact, _) = self.lookup_node(label)?;
+        self.node_value_mut(node, exact)
+    }
+
+    /// Retrieves a mutable reference to the exact match value stored for a label if any.
+    ///
+    /// Unlike [`Trie::lookup_mut`], this only considers the node identified by the label itself,
+    /// prefix values and values of parent nodes are ignored.
+    pub(crate) fn get_mut_by_label<'a, L>(&mut self, label: L) -> Option<&mut Value>
+    where
+        L: Iterator<Item = &'a [u8]>,
+        Value: Clone,
+    {
+        let (node, _) = self.exact_node(label)?;
+        self.node_value_mut(node, true)
+    }
+
+    /// Retrieves a mutable reference to the exact match or prefix value of the node with the given
+    /// index.
+    ///
+    /// If the value is shared with other nodes or the node’s other value, it is copied and the node
+    /// is changed to refer to the copy. This way the change doesn’t affect any other values.
+    fn node_value_mut(&mut self, node: usize, exact: bool) -> Option<&mut Value>
+    where
+        Value: Clone,
+    {
+        let current = self.nodes.get(node)?;
+        let index = if exact {
+            current.value_exact
+        } else {
+            current.value_prefix
+        }?;
+
+        let references = self
+            .nodes
+            .iter()
+            .flat_map(|node| [node.value_exact, node.value_prefix])
+            .filter(|value| *value == Some(index))
+            .count();
+        let index = if references > 1 {
+            let value = self.values.get(index)?.clone();
+            let new_index = self.values.len();
+            self.values.push(value);
+
+            let node = self.nodes.get_mut(node)?;
+            if exact {
+                node.value_exact = Some(new_index);
+            } else {
+                node.value_prefix = Some(new_index);
+            }
+            new_index
+        } else {
+            index
+        };
+        self.values.get_mut(index)
+    }
+
+    /// Looks up a particular label in the trie without falling back to prefix matches.
+    ///
+    /// A value is only returned if the label is consumed completely and ends precisely on a node
+    /// with an exact match value.
+    pub(crate) fn lookup_exact<'a, L>(&self, label: L) -> Option<&Value>
+    where
+        L: Iterator<Item = &'a [u8]>,
+    {
+        let (_, index) = self.exact_node(label)?;
+        self.retrieve(index)
+    }
+
+    /// Determines the node identified by the label along with the index of its exact match value
+    /// if there is one.
+    fn exact_node<'a, L>(&self, label: L) -> Option<(usize, usize)>
+    where
+        L: Iterator<Item = &'a [u8]>,
+    {
+        let mut result = None;
+        self.walk(label, |node_index, node, _, end| {
+            if end {
+                result = node.value_exact.map(|index| (node_index, index));
+            }
+        });
+        result
+    }
+
+    /// Determines the node with a value corresponding to the longest matching path if any. Along
+    /// with the node index, a flag is returned indicating whether the exact match value applies
+    /// (as opposed to the prefix value), and the number of segments consumed to reach the node.
+    fn lookup_node<'a, L>(&self, label: L) -> Option<(usize, bool, usize)>
+    where
+        L: Iterator<Item = &'a [u8]>,
+    {
+        let mut result = None;
+        self.walk(label, |node_index, node, segments, end| {
+            if end && node.value_exact.is_some() {
+                result = Some((node_index, true, segments));
+            } else if node.value_prefix.is_some() {
+                result = Some((node_index, false, segments));
+            }
+        });
+        result
+    }
+
+    /// Looks up a particular label in the trie, returning all values encountered along the way.
+    ///
+    /// The label is expected to be normalized, same as with [`Trie::lookup`]. The results are
+    /// ordered from the shortest to the longest match, each accompanied by the number of segments
+    /// consumed to reach it. Nodes without an applicable value are skipped. The last result is
+    /// always identical to the one returned by [`Trie::lookup`].
+    pub(crate) fn lookup_all<'a, L>(&self, label: L) -> Vec<(LookupResult<'_, Value>, usize)>
+    where
+        L: Iterator<Item = &'a [u8]>,
+    {
+        let mut results = Vec::new();
+        self.walk(label, |_, node, segments, end| {
+            let value = if end {
+                node.value_exact.or(node.value_prefix)
+            } else {
+                node.value_prefix
+            };
+            if let Some(result) =
+                value.and_then(|index| self.to_lookup_result(node.key.clone(), index))
+            {
+                results.push((result, segments));
+            }
+        });
+        results
+    }
+
     /// Retrieves the value from a previous lookup by its index
     pub(crate) fn retrieve(&self, index: usize) -> Option<&Value> {
         self.values.get(index)
     }
 
+    /// Returns the number of label bytes saved by storing identical node labels only once.
+    pub(crate) fn labels_saved(&self) -> usize {
+        let total: usize = self.nodes.iter().map(|node| node.label.len()).sum();
+        total.saturating_sub(self.labels.len())
+    }
+
+    /// Lists all nodes with a value for exact matches, returning the full key of each node along
+    /// with that value.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (&[u8], &Value)> + '_ {
+        self.nodes.iter().filter_map(|node| {
+            let value = self.values.get(node.value_exact?)?;
+            Some((self.keys.get(node.key.clone())?, value))
+        })
+    }
+
+    /// Lists all nodes with values in the order of their full keys, comparing keys segment by
+    /// segment like [`compare_labels`]. Each entry consists of the full key, the value for exact
+    /// matches and the value for prefix matches. Unlike with [`Trie::entries`], the order doesn’t
+    /// depend on how the trie was built.
+    pub(crate) fn entries_sorted(&self) -> Vec<(&[u8], Option<&Value>, Option<&Value>)> {
+        let label = |index: usize| {
+            self.nodes
+                .get(index)
+                .and_then(|node| self.labels.get(node.label.clone()))
+                .unwrap_or_default()
+        };
+
+        let mut result = Vec::new();
+        let mut stack = vec![Self::ROOT];
+        while let Some(index) = stack.pop() {
+            let Some(node) = self.nodes.get(index) else {
+                continue;
+            };
+
+            if node.value_exact.is_some() || node.value_prefix.is_some() {
+                result.push((
+                    self.keys.get(node.key.clone()).unwrap_or_default(),
+                    node.value_exact.and_then(|index| self.values.get(index)),
+                    node.value_prefix.and_then(|index| self.values.get(index)),
+                ));
+            }
+
+            // Child labels never start with the same segment, comparing them is sufficient. Push
+            // in reverse order so that the first child is processed next.
+            let mut children = node.children.clone().collect::<Vec<_>>();
+            children.sort_by(|a, b| compare_labels(label(*b), label(*a), self.separator));
+            stack.extend(children);
+        }
+        result
+    }
+
     fn fmt_field(
         &self,
         f: &mut std::fmt::DebugStruct<'_, '_>,
@@ -251,7 +630,7 @@ impl<Value> Trie<Value> {
             f.field(&String::from_utf8_lossy(&label), &value);
         }
 
-        label.push(SEPARATOR);
+        label.push(self.separator);
         for child in node.children.start..node.children.end {
             self.fmt_field(f, child, &label)?;
         }
@@ -260,6 +639,114 @@ impl<Value> Trie<Value> {
     }
 }
 
+impl<Value> Trie<Vec<Value>> {
+    /// Looks up a particular label in a trie storing multiple values per label, e.g. built from
+    /// labels with `Vec` values via [`TrieBuilder::from_sorted_iter`].
+    ///
+    /// This selects the matching node like [`Trie::lookup`] and returns all of its values in the
+    /// order they were added. An empty slice is returned if there is no match.
+    pub(crate) fn lookup_multi<'a, L>(&self, label: L) -> &[Value]
+    where
+        L: Iterator<Item = &'a [u8]>,
+    {
+        self.lookup(label)
+            .map(|result| result.as_value().as_slice())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<Value> Trie<Value> {
+    /// Makes sure that a deserialized trie is consistent, so that lookups cannot panic or loop.
+    ///
+    /// Labels, keys, values and children of all nodes have to be within bounds. Children always
+    /// have to be stored after their parent node.
+    fn validate(&self) -> Result<(), String> {
+        if self.nodes.get(Self::ROOT).is_none() {
+            return Err("trie has no root node".to_owned());
+        }
+
+        for (index, node) in self.nodes.iter().enumerate() {
+            if node.label.start > node.label.end || node.label.end > self.labels.len() {
+                return Err(format!("label of node {index} is out of bounds"));
+            }
+
+            if node.key.start > node.key.end || node.key.end > self.keys.len() {
+                return Err(format!("key of node {index} is out of bounds"));
+            }
+
+            if node.children.start > node.children.end
+                || node.children.end > self.nodes.len()
+                || (!node.children.is_empty() && node.children.start <= index)
+            {
+                return Err(format!("children of node {index} are out of bounds"));
+            }
+
+            for value in [node.value_exact, node.value_prefix].into_iter().flatten() {
+                if value >= self.values.len() {
+                    return Err(format!("value of node {index} is out of bounds"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<Value: Serialize> Serialize for Trie<Value> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("Trie", 6)?;
+        state.serialize_field("version", &FORMAT_VERSION)?;
+        state.serialize_field("nodes", &self.nodes)?;
+        state.serialize_field("values", &self.values)?;
+        state.serialize_field("labels", &self.labels)?;
+        state.serialize_field("keys", &self.keys)?;
+        state.serialize_field("separator", &self.separator)?;
+        state.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, Value: Deserialize<'de>> Deserialize<'de> for Trie<Value> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(rename = "Trie")]
+        struct SerializedTrie<V> {
+            version: u32,
+            nodes: Vec<Node>,
+            values: Vec<V>,
+            labels: Vec<u8>,
+            keys: Vec<u8>,
+            separator: u8,
+        }
+
+        let data = SerializedTrie::<Value>::deserialize(deserializer)?;
+        if data.version != FORMAT_VERSION {
+            return Err(D::Error::custom(format!(
+                "unsupported trie format version {}, expected {FORMAT_VERSION}",
+                data.version
+            )));
+        }
+
+        let trie = Self {
+            nodes: data.nodes,
+            values: data.values,
+            labels: data.labels,
+            keys: data.keys,
+            separator: data.separator,
+        };
+        trie.validate().map_err(D::Error::custom)?;
+        Ok(trie)
+    }
+}
+
 impl<Value> Debug for Trie<Value>
 where
     Value: Debug,
@@ -273,11 +760,14 @@ where
 /// A trie builder used to set up a `Trie` instance
 ///
 /// In addition to setting up the trie structure, this will keep track of the requires allocation
-/// size for the trie vectors.
+/// size for the trie vectors. For labels this is an upper bound, identical labels are only stored
+/// once.
 #[derive(Debug)]
 pub(crate) struct TrieBuilder<Value> {
     nodes: usize,
     labels: usize,
+    separator: u8,
+    suffix: bool,
     root: BuilderNode<Value>,
 }
 
@@ -294,10 +784,12 @@ struct BuilderNode<Value> {
 
 impl<Value: Eq> TrieBuilder<Value> {
     /// Creates a new builder.
-    fn new() -> Self {
+    fn new(separator: u8, suffix: bool) -> Self {
         Self {
             nodes: 1,
             labels: 0,
+            separator,
+            suffix,
             root: BuilderNode::<Value> {
                 label: Vec::new(),
                 children: Vec::new(),
@@ -321,10 +813,11 @@ impl<Value: Eq> TrieBuilder<Value> {
         nodes: &mut usize,
         labels: &mut usize,
         label: &mut Vec<u8>,
+        separator: u8,
     ) -> &'a mut BuilderNode<Value> {
         let mut match_ = None;
         for (i, node) in current.children.iter_mut().enumerate() {
-            let length = common_prefix_length(&node.label, label);
+            let length = common_prefix_length(&node.label, label, separator);
             if length > 0 {
                 label.drain(..std::cmp::min(length + 1, label.len()));
                 if length < node.label.len() {
@@ -356,37 +849,97 @@ impl<Value: Eq> TrieBuilder<Value> {
         }
 
         return match match_ {
-            Some(i) => Self::find_insertion_point(&mut current.children[i], nodes, labels, label),
+            Some(i) => Self::find_insertion_point(
+                &mut current.children[i],
+                nodes,
+                labels,
+                label,
+                separator,
+            ),
             None => current,
         };
     }
 
-    /// Adds a value for the given label. Will return `true` if an existing value was overwritten.
+    /// Adds a value for the given label. If an existing value was overwritten, the previous exact
+    /// match value is returned.
     ///
     /// `value_exact` will only be returned for exact matches. If present, `value_prefix` will be
     /// returned for any paths starting with the given label.
     ///
-    /// The label is expected to be normalized: no separator characters at the beginning or end, and
-    /// always only one separator character used to separate segments.
+    /// The label will be normalized first: separator characters at the beginning or end are
+    /// removed, and sequences of separator characters are collapsed into one. This makes sure that
+    /// labels like `//dir//subdir/` match the same paths as `dir/subdir`.
     pub(crate) fn push(
         &mut self,
-        mut label: Vec<u8>,
+        label: Vec<u8>,
+        value_exact: Value,
+        value_prefix: Option<Value>,
+    ) -> Option<Value> {
+        let key = self.key_for_label(&label);
+        self.push_key(key, value_exact, value_prefix)
+    }
+
+    /// Adds a value for a key produced by [`TrieBuilder::key_for_label`], see
+    /// [`TrieBuilder::push`].
+    fn push_key(
+        &mut self,
+        key: Vec<u8>,
         value_exact: Value,
         value_prefix: Option<Value>,
-    ) -> bool {
+    ) -> Option<Value> {
+        let node = self.node_for_key(key);
+        node.value_prefix = value_prefix;
+        node.value_exact.replace(value_exact)
+    }
+
+    /// Normalizes a label and reverses the order of its segments if this builder is set up for
+    /// suffix matching.
+    fn key_for_label(&self, label: &[u8]) -> Vec<u8> {
+        let label = normalize(label, self.separator);
+        if self.suffix {
+            label
+                .split(|c| *c == self.separator)
+                .rev()
+                .collect::<Vec<_>>()
+                .join(&self.separator)
+        } else {
+            label
+        }
+    }
+
+    /// Recursively adds the values of a node from another builder and its children.
+    ///
+    /// `prefix` is the full key of the parent node, the node’s own label is appended to it. Keys
+    /// are already normalized and reversed where necessary, so these are added as is.
+    fn push_builder_node(&mut self, mut node: BuilderNode<Value>, prefix: &[u8]) {
+        let mut label = prefix.to_vec();
+        if !label.is_empty() && !node.label.is_empty() {
+            label.push(self.separator);
+        }
+        label.append(&mut node.label);
+
+        if let Some(value_exact) = node.value_exact {
+            self.push_key(label.clone(), value_exact, node.value_prefix);
+        }
+
+        for child in node.children {
+            self.push_builder_node(child, &label);
+        }
+    }
+
+    /// Finds the node for the given key, inserting a new node without values if necessary.
+    fn node_for_key(&mut self, mut label: Vec<u8>) -> &mut BuilderNode<Value> {
         let node = Self::find_insertion_point(
             &mut self.root,
             &mut self.nodes,
             &mut self.labels,
             &mut label,
+            self.separator,
         );
 
         if label.is_empty() {
-            // Exact match, replace the value for this node
-            let had_value = node.value_exact.is_some();
-            node.value_exact = Some(value_exact);
-            node.value_prefix = value_prefix;
-            had_value
+            // Exact match, the node already exists
+            node
         } else {
             // Insert new node as child of the current one
             self.nodes += 1;
@@ -394,20 +947,21 @@ impl<Value: Eq> TrieBuilder<Value> {
             node.children.push(BuilderNode {
                 label,
                 children: Vec::new(),
-                value_exact: Some(value_exact),
-                value_prefix,
+                value_exact: None,
+                value_prefix: None,
             });
-            false
+            node.children.last_mut().unwrap()
         }
     }
 
     /// Pushes an empty entry into the nodes vector.
     ///
     /// This is used to allocate space for the node, so that child nodes are always stored
-    /// consecutively. The values are adjusted by `into_trie_node` later.
+    /// consecutively. The entry is replaced by the result of `into_trie_node` later.
     fn push_trie_node(nodes: &mut Vec<Node>) {
         nodes.push(Node {
             label: 0..0,
+            key: 0..0,
             value_exact: None,
             value_prefix: None,
             children: 0..0,
@@ -426,59 +980,360 @@ impl<Value: Eq> TrieBuilder<Value> {
         }
     }
 
-    /// Sets up an entry in the nodes vector.
+    /// Returns the range of an already existing label or adds it to the labels vector and returns
+    /// the new range.
+    fn add_label(
+        label: Vec<u8>,
+        labels: &mut Vec<u8>,
+        label_ranges: &mut HashMap<Vec<u8>, Range<usize>>,
+    ) -> Range<usize> {
+        label_ranges
+            .entry(label)
+            .or_insert_with_key(|label| {
+                let range = labels.len()..labels.len() + label.len();
+                labels.extend_from_slice(label);
+                range
+            })
+            .clone()
+    }
+
+    /// Adds the full key of a node to the keys vector and returns its range.
+    fn add_key(key: &[u8], keys: &mut Vec<u8>) -> Range<usize> {
+        let range = keys.len()..keys.len() + key.len();
+        keys.extend_from_slice(key);
+        range
+    }
+
+    /// Produces a trie node from a builder node.
     ///
-    /// This will transfer data from a builder node to the trie node identified via index. It will
-    /// also recurse to make sure child nodes of the current node are transferred as well.
+    /// This will transfer data from a builder node to a trie node. It will also recurse to make
+    /// sure child nodes of the current node are added to the nodes vector as well. `prefix` is the
+    /// full key of the parent node.
     fn into_trie_node(
         mut current: BuilderNode<Value>,
-        index: usize,
-        nodes: &mut Vec<Node>,
-        labels: &mut Vec<u8>,
-        values: &mut Vec<Value>,
-    ) {
-        nodes[index].label = labels.len()..labels.len() + current.label.len();
-        labels.append(&mut current.label);
-
-        if let Some(value) = current.value_exact {
-            nodes[index].value_exact = Some(Self::add_value(value, values));
-        }
-        if let Some(value) = current.value_prefix {
-            nodes[index].value_prefix = Some(Self::add_value(value, values));
+        prefix: &[u8],
+        writer: &mut TrieWriter<Value>,
+        separator: u8,
+    ) -> Node {
+        let mut key = prefix.to_vec();
+        if !key.is_empty() && !current.label.is_empty() {
+            key.push(separator);
         }
+        key.extend_from_slice(&current.label);
+
+        let label = Self::add_label(
+            std::mem::take(&mut current.label),
+            &mut writer.labels,
+            &mut writer.label_ranges,
+        );
+
+        // Full key is only needed for lookup results, so it is only stored for nodes with values
+        let key_range = if current.value_exact.is_some() || current.value_prefix.is_some() {
+            Self::add_key(&key, &mut writer.keys)
+        } else {
+            0..0
+        };
+
+        let value_exact = current
+            .value_exact
+            .map(|value| Self::add_value(value, &mut writer.values));
+        let value_prefix = current
+            .value_prefix
+            .map(|value| Self::add_value(value, &mut writer.values));
 
         current.children.sort_by(|a, b| a.label.cmp(&b.label));
 
-        let mut child_index = nodes.len();
-        nodes[index].children = child_index..child_index + current.children.len();
+        let children = writer.nodes.len()..writer.nodes.len() + current.children.len();
         for _ in &current.children {
-            Self::push_trie_node(nodes);
+            Self::push_trie_node(&mut writer.nodes);
+        }
+
+        for (child_index, child) in children.clone().zip(current.children) {
+            writer.nodes[child_index] = Self::into_trie_node(child, &key, writer, separator);
         }
 
-        for child in current.children {
-            Self::into_trie_node(child, child_index, nodes, labels, values);
-            child_index += 1;
+        Node {
+            label,
+            key: key_range,
+            value_exact,
+            value_prefix,
+            children,
         }
     }
 
     /// Translates the builder data into a `Trie` instance.
     pub(crate) fn build(self) -> Trie<Value> {
-        let mut nodes = Vec::with_capacity(self.nodes);
-        let mut labels = Vec::with_capacity(self.labels);
-        let mut values = Vec::new();
+        let mut writer = TrieWriter {
+            nodes: Vec::with_capacity(self.nodes),
+            labels: Vec::with_capacity(self.labels),
+            label_ranges: HashMap::new(),
+            keys: Vec::new(),
+            values: Vec::new(),
+        };
+
+        Self::push_trie_node(&mut writer.nodes);
+        writer.nodes[Trie::<Value>::ROOT] =
+            Self::into_trie_node(self.root, &[], &mut writer, self.separator);
 
-        let index = nodes.len();
-        Self::push_trie_node(&mut nodes);
-        Self::into_trie_node(self.root, index, &mut nodes, &mut labels, &mut values);
+        assert_eq!(writer.nodes.len(), self.nodes);
+        assert!(writer.labels.len() <= self.labels);
+        writer.into_trie(self.separator)
+    }
+
+    /// Sets up a trie from labels sorted via [`compare_labels`], without going through the
+    /// builder. Label segments will be separated by forward slashes.
+    ///
+    /// The items are the same as the parameters of [`TrieBuilder::push`] and labels are
+    /// normalized the same way. Unlike with the builder, the final trie data is produced in a
+    /// single pass, without keeping an intermediate tree structure in memory. An error is
+    /// returned if the labels aren’t sorted or if the same label is encountered twice.
+    pub(crate) fn from_sorted_iter(
+        iter: impl Iterator<Item = (Vec<u8>, Value, Option<Value>)>,
+    ) -> Result<Trie<Value>, Box<Error>> {
+        Self::from_sorted_iter_with_separator(iter, SEPARATOR)
+    }
+
+    /// Sets up a trie from sorted labels like [`TrieBuilder::from_sorted_iter`], using the given
+    /// character to separate label segments.
+    pub(crate) fn from_sorted_iter_with_separator(
+        iter: impl Iterator<Item = (Vec<u8>, Value, Option<Value>)>,
+        separator: u8,
+    ) -> Result<Trie<Value>, Box<Error>> {
+        let mut writer = TrieWriter {
+            nodes: Vec::new(),
+            labels: Vec::new(),
+            label_ranges: HashMap::new(),
+            keys: Vec::new(),
+            values: Vec::new(),
+        };
+
+        // The nodes on the path to the most recent label, starting with the root node
+        let mut stack = vec![OpenNode::new(Vec::new())];
+        let mut previous: Option<Vec<u8>> = None;
+        for (label, value_exact, value_prefix) in iter {
+            let label = normalize(&label, separator);
+            if let Some(previous) = &previous {
+                if compare_labels(previous, &label, separator) != Ordering::Less {
+                    return Err(Error::explain(
+                        ErrorType::InternalError,
+                        format!(
+                            "trie labels aren’t sorted or not unique: {} follows {}",
+                            String::from_utf8_lossy(&label),
+                            String::from_utf8_lossy(previous)
+                        ),
+                    ));
+                }
+            }
+
+            let mut depth = 1;
+            let mut new_segments = segments(&label, separator).peekable();
+            while depth < stack.len() && new_segments.peek() == Some(&stack[depth].label.as_slice())
+            {
+                depth += 1;
+                new_segments.next();
+            }
+
+            // Nodes that aren’t on the path to the new label won’t get any more children.
+            while stack.len() > depth {
+                writer.close(&mut stack, separator);
+            }
+
+            for segment in new_segments {
+                stack.push(OpenNode::new(segment.to_vec()));
+            }
 
-        assert_eq!(nodes.len(), self.nodes);
-        assert_eq!(labels.len(), self.labels);
-        values.shrink_to_fit();
+            // Stack always contains the root node
+            let node = stack.last_mut().unwrap();
+            node.value_exact = Some(Self::add_value(value_exact, &mut writer.values));
+            node.value_prefix =
+                value_prefix.map(|value_prefix| Self::add_value(value_prefix, &mut writer.values));
+
+            previous = Some(label);
+        }
+
+        while stack.len() > 1 {
+            writer.close(&mut stack, separator);
+        }
+        Ok(writer.finish(stack.pop().unwrap(), separator))
+    }
+}
+
+/// A node on the path currently processed by [`TrieBuilder::from_sorted_iter`]
+///
+/// This node can still receive more children, its label always consists of a single segment.
+#[derive(Debug)]
+struct OpenNode {
+    label: Vec<u8>,
+    value_exact: Option<usize>,
+    value_prefix: Option<usize>,
+    children: Vec<ClosedNode>,
+}
+
+impl OpenNode {
+    fn new(label: Vec<u8>) -> Self {
+        Self {
+            label,
+            value_exact: None,
+            value_prefix: None,
+            children: Vec::new(),
+        }
+    }
+}
+
+/// A node processed by [`TrieBuilder::from_sorted_iter`] that won’t receive more children
+///
+/// The children of this node have been written already. Its own label might still be extended
+/// however, if its parent node turns out to have no other purpose than routing to it.
+#[derive(Debug)]
+struct ClosedNode {
+    label: Vec<u8>,
+    key: Option<Vec<u8>>,
+    value_exact: Option<usize>,
+    value_prefix: Option<usize>,
+    children: Range<usize>,
+}
+
+/// Collects the trie data produced by [`TrieBuilder::build`] or [`TrieBuilder::from_sorted_iter`]
+///
+/// With `from_sorted_iter` the nodes are written in reverse order: children always before their
+/// parent, the root node last. `finish` reverses the nodes vector then, so that the resulting
+/// layout is the one expected by `Trie`.
+#[derive(Debug)]
+struct TrieWriter<Value> {
+    nodes: Vec<Node>,
+    labels: Vec<u8>,
+    label_ranges: HashMap<Vec<u8>, Range<usize>>,
+    keys: Vec<u8>,
+    values: Vec<Value>,
+}
+
+impl<Value: Eq> TrieWriter<Value> {
+    /// Removes the last node from the stack and adds it to the children of its parent node.
+    fn close(&mut self, stack: &mut Vec<OpenNode>, separator: u8) {
+        let mut node = stack.pop().unwrap();
+
+        // Full key is only needed for nodes with values, it consists of all labels on the stack
+        // (root node label is always empty).
+        let key = (node.value_exact.is_some() || node.value_prefix.is_some()).then(|| {
+            let mut key = Vec::new();
+            for label in stack[1..].iter().map(|node| &node.label) {
+                key.extend_from_slice(label);
+                key.push(separator);
+            }
+            key.extend_from_slice(&node.label);
+            key
+        });
+
+        let closed = if node.value_exact.is_none()
+            && node.value_prefix.is_none()
+            && node.children.len() == 1
+        {
+            // A node without values and only one child is merged into the child, same as the
+            // builder would never create it.
+            let mut child = node.children.pop().unwrap();
+            node.label.push(separator);
+            node.label.append(&mut child.label);
+            child.label = node.label;
+            child
+        } else {
+            ClosedNode {
+                children: self.write_children(node.children),
+                label: node.label,
+                key,
+                value_exact: node.value_exact,
+                value_prefix: node.value_prefix,
+            }
+        };
+        stack.last_mut().unwrap().children.push(closed);
+    }
+
+    /// Writes out the children of a node, returns their index range.
+    fn write_children(&mut self, mut children: Vec<ClosedNode>) -> Range<usize> {
+        children.sort_by(|a, b| a.label.cmp(&b.label));
+
+        let start = self.nodes.len();
+        for child in children.into_iter().rev() {
+            self.write_node(
+                child.label,
+                child.key,
+                child.value_exact,
+                child.value_prefix,
+                child.children,
+            );
+        }
+        start..self.nodes.len()
+    }
+
+    fn write_node(
+        &mut self,
+        label: Vec<u8>,
+        key: Option<Vec<u8>>,
+        value_exact: Option<usize>,
+        value_prefix: Option<usize>,
+        children: Range<usize>,
+    ) {
+        self.nodes.push(Node {
+            label: TrieBuilder::<Value>::add_label(label, &mut self.labels, &mut self.label_ranges),
+            key: key.map_or(0..0, |key| {
+                TrieBuilder::<Value>::add_key(&key, &mut self.keys)
+            }),
+            value_exact,
+            value_prefix,
+            children,
+        });
+    }
+
+    /// Writes out the root node and produces the trie.
+    fn finish(mut self, root: OpenNode, separator: u8) -> Trie<Value> {
+        let children = self.write_children(root.children);
+        self.write_node(
+            root.label,
+            None,
+            root.value_exact,
+            root.value_prefix,
+            children,
+        );
+
+        let count = self.nodes.len();
+        self.nodes.reverse();
+        for node in &mut self.nodes {
+            node.children = count - node.children.end..count - node.children.start;
+        }
+
+        self.into_trie(separator)
+    }
+
+    /// Produces the trie from the data collected.
+    fn into_trie(mut self, separator: u8) -> Trie<Value> {
+        self.nodes.shrink_to_fit();
+        self.labels.shrink_to_fit();
+        self.keys.shrink_to_fit();
+        self.values.shrink_to_fit();
 
         Trie {
-            nodes,
-            labels,
-            values,
+            nodes: self.nodes,
+            values: self.values,
+            labels: self.labels,
+            keys: self.keys,
+            separator,
+        }
+    }
+}
+
+/// Creates a builder using forward slashes as separator, same as [`Trie::builder`].
+impl<Value: Eq> Default for TrieBuilder<Value> {
+    fn default() -> Self {
+        Trie::builder()
+    }
+}
+
+/// Merges the entries of other builders into this one, same as if these were added via
+/// [`TrieBuilder::push`]. All builders are expected to use the same separator character and
+/// matching mode.
+impl<Value: Eq> Extend<TrieBuilder<Value>> for TrieBuilder<Value> {
+    fn extend<T: IntoIterator<Item = TrieBuilder<Value>>>(&mut self, iter: T) {
+        for builder in iter {
+            self.push_builder_node(builder.root, &[]);
         }
     }
 }
@@ -488,29 +1343,58 @@ mod tests {
     use super::*;
 
     fn make_key<'a>(s: &'a str) -> Box<dyn Iterator<Item = &[u8]> + 'a> {
+        make_key_with_separator(s, SEPARATOR)
+    }
+
+    fn make_key_with_separator<'a>(
+        s: &'a str,
+        separator: u8,
+    ) -> Box<dyn Iterator<Item = &'a [u8]> + 'a> {
         Box::new(
             s.as_bytes()
-                .split(|c| *c == SEPARATOR)
+                .split(move |c| *c == separator)
                 .filter(|s| !s.is_empty()),
         )
     }
 
     #[test]
     fn common_prefix() {
-        assert_eq!(common_prefix_length(b"", b""), 0);
-        assert_eq!(common_prefix_length(b"abc", b""), 0);
-        assert_eq!(common_prefix_length(b"", b"abc"), 0);
-        assert_eq!(common_prefix_length(b"abc", b"abc"), 3);
-        assert_eq!(common_prefix_length(b"a", b"abc"), 0);
-        assert_eq!(common_prefix_length(b"abc", b"a"), 0);
-        assert_eq!(common_prefix_length(b"a", b"a/bc"), 1);
-        assert_eq!(common_prefix_length(b"a/bc", b"a"), 1);
-        assert_eq!(common_prefix_length(b"a/b", b"a/bc"), 1);
-        assert_eq!(common_prefix_length(b"a/bc", b"a/b"), 1);
-        assert_eq!(common_prefix_length(b"a/bc", b"a/bc"), 4);
-        assert_eq!(common_prefix_length(b"a/bc", b"a/bc/d"), 4);
-        assert_eq!(common_prefix_length(b"a/bc/d", b"a/bc"), 4);
-        assert_eq!(common_prefix_length(b"a/bc/d", b"x/bc/d"), 0);
+        assert_eq!(common_prefix_length(b"", b"", SEPARATOR), 0);
+        assert_eq!(common_prefix_length(b"abc", b"", SEPARATOR), 0);
+        assert_eq!(common_prefix_length(b"", b"abc", SEPARATOR), 0);
+        assert_eq!(common_prefix_length(b"abc", b"abc", SEPARATOR), 3);
+        assert_eq!(common_prefix_length(b"a", b"abc", SEPARATOR), 0);
+        assert_eq!(common_prefix_length(b"abc", b"a", SEPARATOR), 0);
+        assert_eq!(common_prefix_length(b"a", b"a/bc", SEPARATOR), 1);
+        assert_eq!(common_prefix_length(b"a/bc", b"a", SEPARATOR), 1);
+        assert_eq!(common_prefix_length(b"a/b", b"a/bc", SEPARATOR), 1);
+        assert_eq!(common_prefix_length(b"a/bc", b"a/b", SEPARATOR), 1);
+        assert_eq!(common_prefix_length(b"a/bc", b"a/bc", SEPARATOR), 4);
+        assert_eq!(common_prefix_length(b"a/bc", b"a/bc/d", SEPARATOR), 4);
+        assert_eq!(common_prefix_length(b"a/bc/d", b"a/bc", SEPARATOR), 4);
+        assert_eq!(common_prefix_length(b"a/bc/d", b"x/bc/d", SEPARATOR), 0);
+        assert_eq!(common_prefix_length(b"a.bc", b"a.bc.d", b'.'), 4);
+        assert_eq!(common_prefix_length(b"a.bc", b"a.bcd", b'.'), 1);
+        assert_eq!(common_prefix_length(b"a/bc", b"a/bc/d", b'.'), 0);
+    }
+
+    #[test]
+    fn label_normalization() {
+        assert_eq!(normalize(b"", SEPARATOR), b"");
+        assert_eq!(normalize(b"///", SEPARATOR), b"");
+        assert_eq!(normalize(b"abc", SEPARATOR), b"abc");
+        assert_eq!(normalize(b"//abc//def//", SEPARATOR), b"abc/def");
+        assert_eq!(normalize(b"..abc..def", b'.'), b"abc.def");
+
+        let mut builder = Trie::builder();
+        builder.push(b"//abc//def/".to_vec(), 1, Some(2));
+        builder.push(b"/".to_vec(), 3, None);
+        let trie = builder.build();
+
+        assert_eq!(trie.lookup(make_key("")).as_deref(), Some(&3));
+        assert_eq!(trie.lookup(make_key("abc")).as_deref(), None);
+        assert_eq!(trie.lookup(make_key("abc/def")).as_deref(), Some(&1));
+        assert_eq!(trie.lookup(make_key("abc/def/ghi")).as_deref(), Some(&2));
     }
 
     #[test]
@@ -524,9 +1408,14 @@ mod tests {
             ("a/bc", 4, 14),
             ("a/bc/de/g", 5, 15),
         ] {
-            assert!(!builder.push(label.as_bytes().to_vec(), value_exact, Some(value_prefix)));
+            assert!(builder
+                .push(label.as_bytes().to_vec(), value_exact, Some(value_prefix))
+                .is_none());
         }
-        assert!(builder.push("a/bc".as_bytes().to_vec(), 6, Some(16)));
+        assert_eq!(
+            builder.push("a/bc".as_bytes().to_vec(), 6, Some(16)),
+            Some(4)
+        );
         let trie = builder.build();
 
         assert_eq!(trie.lookup(make_key("")).as_deref(), Some(&1));
@@ -554,9 +1443,14 @@ mod tests {
             ("a/bc", 4, 14),
             ("a/bc/de/g", 5, 15),
         ] {
-            assert!(!builder.push(label.as_bytes().to_vec(), value_exact, Some(value_prefix)));
+            assert!(builder
+                .push(label.as_bytes().to_vec(), value_exact, Some(value_prefix))
+                .is_none());
         }
-        assert!(builder.push("a/bc".as_bytes().to_vec(), 6, Some(16)));
+        assert_eq!(
+            builder.push("a/bc".as_bytes().to_vec(), 6, Some(16)),
+            Some(4)
+        );
         let trie = builder.build();
 
         assert_eq!(trie.lookup(make_key("")).as_deref(), None);
@@ -576,6 +1470,88 @@ mod tests {
         assert_eq!(trie.lookup(make_key("a/bc/de/h")).as_deref(), Some(&16));
     }
 
+    #[test]
+    fn lookup_with_custom_separator() {
+        let mut builder = Trie::builder_with_separator(b'.');
+        for (label, value_exact, value_prefix) in [
+            ("com", 1, 11),
+            ("com.example", 2, 12),
+            ("com.example.www", 3, 13),
+            ("org.example.www", 4, 14),
+            ("com.example/www", 5, 15),
+        ] {
+            assert!(builder
+                .push(label.as_bytes().to_vec(), value_exact, Some(value_prefix))
+                .is_none());
+        }
+        let trie = builder.build();
+
+        let lookup = |key| {
+            trie.lookup(make_key_with_separator(key, b'.'))
+                .as_deref()
+                .copied()
+        };
+        assert_eq!(lookup(""), None);
+        assert_eq!(lookup("com"), Some(1));
+        assert_eq!(lookup("com.example"), Some(2));
+        assert_eq!(lookup("com..example."), Some(2));
+        assert_eq!(lookup("com.example.www"), Some(3));
+        assert_eq!(lookup("com.example.www.sub"), Some(13));
+        assert_eq!(lookup("com.example.mail"), Some(12));
+        assert_eq!(lookup("com.examples"), Some(11));
+        assert_eq!(lookup("com.example/www"), Some(5));
+        assert_eq!(lookup("org.example"), None);
+        assert_eq!(lookup("org.example.www"), Some(4));
+        assert_eq!(lookup("org.example.www.sub"), Some(14));
+    }
+
+    #[test]
+    fn suffix_lookup() {
+        let mut builder = Trie::suffix_builder_with_separator(b'.');
+        for (label, value_exact, value_prefix) in [
+            ("example.com", 1, 11),
+            ("sub.example.com", 2, 12),
+            ("..www.sub.example.com.", 3, 13),
+            ("example.net", 4, 14),
+        ] {
+            assert!(builder
+                .push(label.as_bytes().to_vec(), value_exact, Some(value_prefix))
+                .is_none());
+        }
+        assert_eq!(builder.push(b"example.net".to_vec(), 5, Some(15)), Some(4));
+
+        let mut other = Trie::suffix_builder_with_separator(b'.');
+        other.push(b"com".to_vec(), 6, Some(16));
+        builder.extend([other]);
+        let trie = builder.build();
+
+        let lookup = |key: &str| {
+            let segments = key
+                .as_bytes()
+                .split(|c| *c == b'.')
+                .filter(|s| !s.is_empty());
+            trie.lookup_suffix(segments).as_deref().copied()
+        };
+        assert_eq!(lookup(""), None);
+        assert_eq!(lookup("com"), Some(6));
+        assert_eq!(lookup("example.com"), Some(1));
+        assert_eq!(lookup("sub.example.com"), Some(2));
+        assert_eq!(lookup("other.example.com"), Some(11));
+        assert_eq!(lookup("a.sub.example.com"), Some(12));
+        assert_eq!(lookup("www.sub.example.com"), Some(3));
+        assert_eq!(lookup("a.www.sub.example.com"), Some(13));
+        assert_eq!(lookup("examples.com"), Some(16));
+        assert_eq!(lookup("www.example.net"), Some(15));
+        assert_eq!(lookup("example.org"), None);
+
+        // Prefix lookups see the reversed segments
+        assert_eq!(
+            trie.lookup(make_key_with_separator("com.example.sub", b'.'))
+                .as_deref(),
+            Some(&2)
+        );
+    }
+
     #[test]
     fn value_compacting() {
         let mut builder = Trie::builder();
@@ -586,9 +1562,550 @@ mod tests {
             ("a/bc", 123, 123),
             ("a/bc/de/g", 123, 123),
         ] {
-            assert!(!builder.push(label.as_bytes().to_vec(), value_exact, Some(value_prefix)));
+            assert!(builder
+                .push(label.as_bytes().to_vec(), value_exact, Some(value_prefix))
+                .is_none());
         }
         let trie = builder.build();
         assert_eq!(trie.values.len(), 2);
     }
+
+    #[test]
+    fn mutable_access() {
+        let mut builder = Trie::builder();
+        for (label, value_exact, value_prefix) in
+            [("", 1, None), ("a", 2, Some(12)), ("a/bc/de", 3, None)]
+        {
+            assert!(builder
+                .push(label.as_bytes().to_vec(), value_exact, value_prefix)
+                .is_none());
+        }
+        let mut trie = builder.build();
+
+        *trie.lookup_mut(make_key("a/x")).unwrap() = 22;
+        assert_eq!(trie.lookup(make_key("a/x")).as_deref(), Some(&22));
+        assert_eq!(trie.lookup(make_key("a/bc")).as_deref(), Some(&22));
+        assert_eq!(trie.lookup(make_key("a")).as_deref(), Some(&2));
+
+        *trie.get_mut_by_label(make_key("a/bc/de")).unwrap() = 33;
+        assert_eq!(trie.lookup(make_key("a/bc/de")).as_deref(), Some(&33));
+
+        assert!(trie.lookup_mut(make_key("x")).is_none());
+        assert!(trie.get_mut_by_label(make_key("a/bc")).is_none());
+        assert!(trie.get_mut_by_label(make_key("a/x")).is_none());
+    }
+
+    #[test]
+    fn mutable_access_shared_values() {
+        let mut builder = Trie::builder();
+        for (label, value_exact, value_prefix) in
+            [("a", 1, Some(1)), ("a/bc", 1, None), ("x", 1, Some(2))]
+        {
+            assert!(builder
+                .push(label.as_bytes().to_vec(), value_exact, value_prefix)
+                .is_none());
+        }
+        let mut trie = builder.build();
+        assert_eq!(trie.values.len(), 2);
+
+        *trie.lookup_mut(make_key("a/x")).unwrap() = 11;
+        assert_eq!(trie.lookup(make_key("a")).as_deref(), Some(&1));
+        assert_eq!(trie.lookup(make_key("a/x")).as_deref(), Some(&11));
+        assert_eq!(trie.lookup(make_key("a/bc")).as_deref(), Some(&1));
+        assert_eq!(trie.lookup(make_key("x")).as_deref(), Some(&1));
+        assert_eq!(trie.lookup(make_key("x/y")).as_deref(), Some(&2));
+
+        *trie.get_mut_by_label(make_key("x")).unwrap() = 12;
+        assert_eq!(trie.lookup(make_key("x")).as_deref(), Some(&12));
+        assert_eq!(trie.lookup(make_key("x/y")).as_deref(), Some(&2));
+        assert_eq!(trie.lookup(make_key("a/bc")).as_deref(), Some(&1));
+
+        *trie.get_mut_by_label(make_key("a/bc")).unwrap() = 13;
+        assert_eq!(trie.lookup(make_key("a/bc")).as_deref(), Some(&13));
+        assert_eq!(trie.lookup(make_key("a")).as_deref(), Some(&1));
+        assert_eq!(trie.values.len(), 5);
+
+        // No copy is necessary once a value is no longer shared
+        *trie.get_mut_by_label(make_key("a/bc")).unwrap() = 14;
+        assert_eq!(trie.lookup(make_key("a/bc")).as_deref(), Some(&14));
+        assert_eq!(trie.values.len(), 5);
+    }
+
+    #[test]
+    fn lookup_exact() {
+        let mut builder = Trie::builder();
+        for (label, value_exact, value_prefix) in [
+            ("a", 1, Some(11)),
+            ("a/bc", 2, Some(12)),
+            ("a/bc/de/f", 3, None),
+        ] {
+            assert!(builder
+                .push(label.as_bytes().to_vec(), value_exact, value_prefix)
+                .is_none());
+        }
+        let trie = builder.build();
+
+        assert_eq!(trie.lookup_exact(make_key("a")), Some(&1));
+        assert_eq!(trie.lookup_exact(make_key("a/bc")), Some(&2));
+        assert_eq!(trie.lookup_exact(make_key("a/bc/de/f")), Some(&3));
+
+        // Prefix matches only
+        assert_eq!(trie.lookup(make_key("a/bc/extra")).as_deref(), Some(&12));
+        assert_eq!(trie.lookup_exact(make_key("a/bc/extra")), None);
+        assert_eq!(trie.lookup_exact(make_key("a/bc/de")), None);
+
+        // No node
+        assert_eq!(trie.lookup(make_key("a/b")).as_deref(), Some(&11));
+        assert_eq!(trie.lookup_exact(make_key("a/b")), None);
+        assert_eq!(trie.lookup_exact(make_key("")), None);
+    }
+
+    #[test]
+    fn extend() {
+        let entries1 = [("", 1, Some(11)), ("a", 2, Some(12)), ("a/bc/de", 3, None)];
+        let entries2 = [
+            ("a/bc", 4, Some(14)),
+            ("a/bc/de", 5, Some(15)),
+            ("x/y", 6, None),
+        ];
+
+        let mut builder1 = Trie::builder();
+        let mut builder2 = Trie::builder();
+        let mut expected = Trie::builder();
+        for (label, value_exact, value_prefix) in entries1 {
+            builder1.push(label.as_bytes().to_vec(), value_exact, value_prefix);
+            expected.push(label.as_bytes().to_vec(), value_exact, value_prefix);
+        }
+        for (label, value_exact, value_prefix) in entries2 {
+            builder2.push(label.as_bytes().to_vec(), value_exact, value_prefix);
+            expected.push(label.as_bytes().to_vec(), value_exact, value_prefix);
+        }
+
+        builder1.extend([builder2]);
+        assert_eq!(builder1.nodes, expected.nodes);
+        assert_eq!(builder1.labels, expected.labels);
+
+        let trie = builder1.build();
+        assert_eq!(trie, expected.build());
+        assert_eq!(trie.lookup(make_key("")).as_deref(), Some(&1));
+        assert_eq!(trie.lookup(make_key("a/x")).as_deref(), Some(&12));
+        assert_eq!(trie.lookup(make_key("a/bc")).as_deref(), Some(&4));
+        assert_eq!(trie.lookup(make_key("a/bc/x")).as_deref(), Some(&14));
+        assert_eq!(trie.lookup(make_key("a/bc/de")).as_deref(), Some(&5));
+        assert_eq!(trie.lookup(make_key("a/bc/de/f")).as_deref(), Some(&15));
+        assert_eq!(trie.lookup(make_key("x/y")).as_deref(), Some(&6));
+        assert_eq!(trie.lookup(make_key("x")).as_deref(), Some(&11));
+    }
+
+    #[test]
+    fn label_compacting() {
+        let mut builder = Trie::builder();
+        for (label, value_exact, value_prefix) in [
+            ("a", 1, 11),
+            ("a/static/css", 2, 12),
+            ("a/static/js", 3, 13),
+            ("b", 4, 14),
+            ("b/static/css", 5, 15),
+            ("b/static/js", 6, 16),
+            ("c/static/css", 7, 17),
+        ] {
+            assert!(builder
+                .push(label.as_bytes().to_vec(), value_exact, Some(value_prefix))
+                .is_none());
+        }
+        let trie = builder.build();
+
+        let ranges = |label: &[u8]| {
+            trie.nodes
+                .iter()
+                .filter(|node| &trie.labels[node.label.clone()] == label)
+                .map(|node| node.label.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let static_ranges = ranges(b"static");
+        assert_eq!(static_ranges.len(), 2);
+        assert_eq!(static_ranges[0], static_ranges[1]);
+
+        let css_ranges = ranges(b"css");
+        assert_eq!(css_ranges.len(), 2);
+        assert_eq!(css_ranges[0], css_ranges[1]);
+
+        assert_eq!(trie.labels, b"astaticcssjsbc/static/css".to_vec());
+        assert_eq!(trie.labels_saved(), "staticcssjs".len());
+
+        assert_eq!(trie.lookup(make_key("a/static/css")).as_deref(), Some(&2));
+        assert_eq!(trie.lookup(make_key("b/static/js")).as_deref(), Some(&6));
+        assert_eq!(
+            trie.lookup(make_key("c/static/css/x")).as_deref(),
+            Some(&17)
+        );
+    }
+
+    #[test]
+    fn lookup_keys() {
+        let entries = [
+            ("", 1, Some(11)),
+            ("a", 2, Some(12)),
+            ("a/bc/de", 3, Some(13)),
+            ("a/bc/de/f", 4, None),
+            ("x/y", 5, Some(15)),
+        ];
+
+        let mut builder = Trie::builder();
+        for (label, value_exact, value_prefix) in entries {
+            builder.push(label.as_bytes().to_vec(), value_exact, value_prefix);
+        }
+        let built = builder.build();
+
+        let sorted = TrieBuilder::from_sorted_iter(entries.into_iter().map(
+            |(label, value_exact, value_prefix)| {
+                (label.as_bytes().to_vec(), value_exact, value_prefix)
+            },
+        ))
+        .unwrap();
+
+        for trie in [built, sorted] {
+            let lookup = |label| {
+                trie.lookup(make_key(label))
+                    .map(|result| (*result, String::from_utf8_lossy(result.path()).into_owned()))
+            };
+
+            assert_eq!(lookup(""), Some((1, "".to_owned())));
+            assert_eq!(lookup("b"), Some((11, "".to_owned())));
+            assert_eq!(lookup("a"), Some((2, "a".to_owned())));
+            assert_eq!(lookup("a/bc"), Some((12, "a".to_owned())));
+            assert_eq!(lookup("a/bc/de"), Some((3, "a/bc/de".to_owned())));
+            assert_eq!(lookup("a/bc/de/f"), Some((4, "a/bc/de/f".to_owned())));
+            assert_eq!(lookup("a/bc/de/f/g"), Some((13, "a/bc/de".to_owned())));
+            assert_eq!(lookup("x/y/z"), Some((15, "x/y".to_owned())));
+            assert_eq!(lookup("x"), Some((11, "".to_owned())));
+        }
+    }
+
+    #[test]
+    fn sorted_construction() {
+        let mut entries = Vec::new();
+        for (label, value_exact, value_prefix) in [
+            ("", 1, Some(11)),
+            ("a", 2, Some(12)),
+            ("a-b", 3, None),
+            ("a/bc/de/f", 4, Some(14)),
+            ("//a//bc/", 5, Some(15)),
+            ("a/bc/de/g", 6, Some(16)),
+            ("a/bc/x/y/z", 7, None),
+            ("b/c/d", 8, Some(18)),
+            ("b/c/e", 8, Some(18)),
+            ("c/d/e/f", 9, Some(19)),
+            ("c/d/e", 10, None),
+        ] {
+            entries.push((label.as_bytes().to_vec(), value_exact, value_prefix));
+        }
+
+        let mut builder = Trie::builder();
+        for (label, value_exact, value_prefix) in entries.iter().cloned() {
+            builder.push(label, value_exact, value_prefix);
+        }
+        let expected = builder.build();
+
+        entries.sort_by(|(a, ..), (b, ..)| compare_labels(a, b, SEPARATOR));
+        let trie = TrieBuilder::from_sorted_iter(entries.into_iter()).unwrap();
+
+        #[cfg(feature = "serde")]
+        assert_eq!(trie.validate(), Ok(()));
+        assert_eq!(trie.nodes.len(), expected.nodes.len());
+        assert_eq!(trie.labels.len(), expected.labels.len());
+        assert_eq!(trie.values.len(), expected.values.len());
+        for key in [
+            "",
+            "x",
+            "a",
+            "a/x",
+            "a-b",
+            "a-b/x",
+            "a/bc",
+            "a/bcd",
+            "a/bc/de",
+            "a/bc/de/f",
+            "a/bc/de/f/g",
+            "a/bc/de/g",
+            "a/bc/de/h",
+            "a/bc/x",
+            "a/bc/x/y",
+            "a/bc/x/y/z",
+            "a/bc/x/y/z/w",
+            "b",
+            "b/c",
+            "b/c/d",
+            "b/c/e/f",
+            "c/d",
+            "c/d/e",
+            "c/d/e/f",
+            "c/d/e/g",
+        ] {
+            assert_eq!(
+                trie.lookup(make_key(key)).as_deref(),
+                expected.lookup(make_key(key)).as_deref(),
+                "lookup for {key}"
+            );
+            assert_eq!(
+                trie.lookup_exact(make_key(key)),
+                expected.lookup_exact(make_key(key)),
+                "exact lookup for {key}"
+            );
+        }
+
+        // Unsorted and duplicate labels are rejected
+        assert!(TrieBuilder::from_sorted_iter(
+            [(b"b".to_vec(), 1, None), (b"a".to_vec(), 2, None)].into_iter()
+        )
+        .is_err());
+        assert!(TrieBuilder::from_sorted_iter(
+            [(b"a".to_vec(), 1, None), (b"/a/".to_vec(), 2, None)].into_iter()
+        )
+        .is_err());
+
+        // Empty input produces an empty trie
+        let trie = TrieBuilder::<u8>::from_sorted_iter(std::iter::empty()).unwrap();
+        assert_eq!(trie.lookup(make_key("")).as_deref(), None);
+        assert_eq!(trie.lookup(make_key("a")).as_deref(), None);
+    }
+
+    #[test]
+    fn sorted_construction_with_custom_separator() {
+        let mut entries = Vec::new();
+        for (label, value_exact, value_prefix) in [
+            ("com", 1, Some(11)),
+            ("com.example", 2, Some(12)),
+            ("com.example.www", 3, Some(13)),
+            ("com.example.www.a.b", 4, None),
+            ("org.example.www", 5, Some(15)),
+            ("com.example/www", 6, Some(16)),
+        ] {
+            entries.push((label.as_bytes().to_vec(), value_exact, value_prefix));
+        }
+
+        let mut builder = Trie::builder_with_separator(b'.');
+        for (label, value_exact, value_prefix) in entries.iter().cloned() {
+            builder.push(label, value_exact, value_prefix);
+        }
+        let expected = builder.build();
+
+        entries.sort_by(|(a, ..), (b, ..)| compare_labels(a, b, b'.'));
+        let trie = TrieBuilder::from_sorted_iter_with_separator(entries.into_iter(), b'.').unwrap();
+
+        #[cfg(feature = "serde")]
+        assert_eq!(trie.validate(), Ok(()));
+        assert_eq!(trie.nodes.len(), expected.nodes.len());
+        assert_eq!(trie.labels.len(), expected.labels.len());
+        for key in [
+            "",
+            "com",
+            "com.example",
+            "com..example.",
+            "com.example.www",
+            "com.example.www.a",
+            "com.example.www.a.b",
+            "com.example.mail",
+            "com.examples",
+            "com.example/www",
+            "org.example",
+            "org.example.www.sub",
+        ] {
+            let result = trie.lookup(make_key_with_separator(key, b'.'));
+            let expected_result = expected.lookup(make_key_with_separator(key, b'.'));
+            assert_eq!(
+                result.as_deref(),
+                expected_result.as_deref(),
+                "lookup for {key}"
+            );
+            assert_eq!(
+                result.map(|result| result.path().to_vec()),
+                expected_result.map(|result| result.path().to_vec()),
+                "matched key for {key}"
+            );
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialization() {
+        let mut builder = Trie::builder();
+        for (label, value_exact, value_prefix) in [
+            ("", 1u8, 11),
+            ("a", 2, 12),
+            ("bc", 7, 17),
+            ("a/bc/de/f", 3, 13),
+            ("a/bc", 4, 14),
+            ("a/bc/de/g", 5, 15),
+        ] {
+            builder.push(label.as_bytes().to_vec(), value_exact, Some(value_prefix));
+        }
+        let trie = builder.build();
+
+        let serialized = serde_yaml::to_string(&trie).unwrap();
+        let restored: Trie<u8> = serde_yaml::from_str(&serialized).unwrap();
+        assert_eq!(restored, trie);
+
+        for key in [
+            "",
+            "a",
+            "x",
+            "bc",
+            "x/y",
+            "a/bc",
+            "a/b",
+            "a/bc/de",
+            "a/bc/de/f",
+            "a/bc/de/g",
+        ] {
+            assert_eq!(
+                restored.lookup(make_key(key)).as_deref(),
+                trie.lookup(make_key(key)).as_deref()
+            );
+        }
+
+        let mut value: serde_yaml::Value = serde_yaml::from_str(&serialized).unwrap();
+        value["version"] = 0.into();
+        assert!(serde_yaml::from_value::<Trie<u8>>(value).is_err());
+
+        let mut value: serde_yaml::Value = serde_yaml::from_str(&serialized).unwrap();
+        value["values"] = serde_yaml::Value::Sequence(Vec::new());
+        assert!(serde_yaml::from_value::<Trie<u8>>(value).is_err());
+
+        let mut value: serde_yaml::Value = serde_yaml::from_str(&serialized).unwrap();
+        value["labels"] = serde_yaml::Value::Sequence(Vec::new());
+        assert!(serde_yaml::from_value::<Trie<u8>>(value).is_err());
+
+        let mut value: serde_yaml::Value = serde_yaml::from_str(&serialized).unwrap();
+        value["nodes"][1]["children"]["start"] = 0.into();
+        value["nodes"][1]["children"]["end"] = 1.into();
+        assert!(serde_yaml::from_value::<Trie<u8>>(value).is_err());
+    }
+
+    #[test]
+    fn lookup_all() {
+        fn lookup_all(trie: &Trie<u8>, key: &str) -> Vec<(u8, usize)> {
+            trie.lookup_all(make_key(key))
+                .into_iter()
+                .map(|(result, segments)| (*result, segments))
+                .collect()
+        }
+
+        let mut builder = Trie::builder();
+        for (label, value_exact, value_prefix) in [
+            ("", 1, Some(11)),
+            ("a", 2, Some(12)),
+            ("a/bc", 4, None),
+            ("a/bc/de/f", 3, Some(13)),
+            ("a/bc/de/g", 5, Some(15)),
+        ] {
+            assert!(builder
+                .push(label.as_bytes().to_vec(), value_exact, value_prefix)
+                .is_none());
+        }
+        let trie = builder.build();
+
+        assert_eq!(lookup_all(&trie, ""), vec![(1, 0)]);
+        assert_eq!(lookup_all(&trie, "x"), vec![(11, 0)]);
+        assert_eq!(lookup_all(&trie, "a"), vec![(11, 0), (2, 1)]);
+        assert_eq!(lookup_all(&trie, "a/bc"), vec![(11, 0), (12, 1), (4, 2)]);
+        assert_eq!(lookup_all(&trie, "a/bc/x"), vec![(11, 0), (12, 1)]);
+
+        // Intermediate node `a/bc/de` has no value and is skipped
+        assert_eq!(lookup_all(&trie, "a/bc/de"), vec![(11, 0), (12, 1)]);
+        assert_eq!(
+            lookup_all(&trie, "a/bc/de/f"),
+            vec![(11, 0), (12, 1), (3, 4)]
+        );
+        assert_eq!(
+            lookup_all(&trie, "a/bc/de/f/x"),
+            vec![(11, 0), (12, 1), (13, 4)]
+        );
+
+        for key in [
+            "",
+            "x",
+            "a",
+            "a/bc",
+            "a/bc/x",
+            "a/bc/de",
+            "a/bc/de/f",
+            "a/bc/de/f/x",
+        ] {
+            assert_eq!(
+                trie.lookup_all(make_key(key))
+                    .last()
+                    .map(|(result, _)| **result),
+                trie.lookup(make_key(key)).as_deref().copied()
+            );
+        }
+    }
+
+    #[test]
+    fn partial_multi_segment_match() {
+        let mut builder = Trie::builder();
+        assert!(builder.push(b"a".to_vec(), 1, Some(11)).is_none());
+        assert!(builder.push(b"a/bc/de/f".to_vec(), 2, Some(12)).is_none());
+        let trie = builder.build();
+
+        for key in ["a/bc", "a/bc/de", "a/bc/de/x/f", "a/bc/x/de/f"] {
+            assert_eq!(trie.lookup(make_key(key)).as_deref(), Some(&11));
+            assert_eq!(
+                trie.lookup_all(make_key(key))
+                    .into_iter()
+                    .map(|(result, segments)| (*result, segments))
+                    .collect::<Vec<_>>(),
+                vec![(11, 1)]
+            );
+        }
+
+        assert_eq!(
+            trie.lookup_all(make_key("a/bc/de/f/x"))
+                .into_iter()
+                .map(|(result, segments)| (*result, segments))
+                .collect::<Vec<_>>(),
+            vec![(11, 1), (12, 4)]
+        );
+    }
+
+    #[test]
+    fn entries_sorted() {
+        let labels = ["b", "a-c", "a/b/c/d", "", "a/b", "a/x/y"];
+        let build = |labels: &[&str]| {
+            let mut builder = Trie::builder();
+            for label in labels {
+                builder.push(label.as_bytes().to_vec(), label.len(), None);
+            }
+            builder.build()
+        };
+
+        let trie = build(&labels);
+        let entries = trie
+            .entries_sorted()
+            .into_iter()
+            .map(|(key, value_exact, value_prefix)| {
+                (
+                    String::from_utf8_lossy(key).to_string(),
+                    value_exact.copied(),
+                    value_prefix.copied(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            vec![
+                ("".to_owned(), Some(0), None),
+                ("a/b".to_owned(), Some(3), None),
+                ("a/b/c/d".to_owned(), Some(7), None),
+                ("a/x/y".to_owned(), Some(5), None),
+                ("a-c".to_owned(), Some(3), None),
+                ("b".to_owned(), Some(1), None),
+            ]
+        );
+
+        let mut reversed = labels;
+        reversed.reverse();
+        assert_eq!(build(&reversed).entries_sorted(), trie.entries_sorted());
+    }
 }
@@ -25,12 +25,18 @@ struct Handler {
     anonymization: ip_anonymization_module::IPAnonymizationHandler,
     #[cfg(feature = "common-log-top-level")]
     log: common_log_module::CommonLogHandler,
+    #[cfg(feature = "metrics-top-level")]
+    metrics: metrics_module::MetricsHandler,
     #[cfg(feature = "compression-top-level")]
     compression: compression_module::CompressionHandler,
     #[cfg(feature = "headers-top-level")]
     headers: headers_module::HeadersHandler,
+    #[cfg(feature = "lowercase-top-level")]
+    lowercase: lowercase_module::LowercaseHandler,
     #[cfg(feature = "auth-top-level")]
     auth: auth_module::AuthHandler,
+    #[cfg(feature = "redirect-map-top-level")]
+    redirect_map: redirect_map_module::RedirectMapHandler,
     #[cfg(feature = "rewrite-top-level")]
     rewrite: rewrite_module::RewriteHandler,
     #[cfg(feature = "upstream-top-level")]
@@ -45,6 +51,8 @@ struct Handler {
         feature = "compression-per-host",
         feature = "headers-per-host",
         feature = "ip-anonymization-per-host",
+        feature = "lowercase-per-host",
+        feature = "redirect-map-per-host",
         feature = "rewrite-per-host",
         feature = "response-per-host",
         feature = "static-files-per-host",
@@ -63,8 +71,12 @@ struct HostHandler {
     compression: compression_module::CompressionHandler,
     #[cfg(feature = "headers-per-host")]
     headers: headers_module::HeadersHandler,
+    #[cfg(feature = "lowercase-per-host")]
+    lowercase: lowercase_module::LowercaseHandler,
     #[cfg(feature = "auth-per-host")]
     auth: auth_module::AuthHandler,
+    #[cfg(feature = "redirect-map-per-host")]
+    redirect_map: redirect_map_module::RedirectMapHandler,
     #[cfg(feature = "rewrite-per-host")]
     rewrite: rewrite_module::RewriteHandler,
     #[cfg(feature = "upstream-per-host")]
@@ -103,8 +115,9 @@ fn main() {
 
     let opt = Opt::parse();
 
+    let files = opt.startup.conf.as_deref().unwrap_or(&[]);
     #[allow(unused_mut)]
-    let mut conf = match Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[])) {
+    let mut conf = match Conf::load_from_files_with_limit(files, opt.startup.max_config_size) {
         Ok(conf) => conf,
         Err(err) => {
             error!("{err}");
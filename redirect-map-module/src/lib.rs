@@ -0,0 +1,374 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![doc = include_str!("../README.md")]
+
+use async_trait::async_trait;
+use http::status::StatusCode;
+use log::{debug, trace};
+use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
+use pandora_module_utils::router::{Path, Router};
+use pandora_module_utils::standard_response::redirect_response;
+use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+/// Configuration file settings of the redirect map module
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct RedirectMapConf {
+    /// Maps old paths to redirect targets. A path like `/dir/*` matches the directory and
+    /// anything within it, the remainder of the path is appended to the target then.
+    pub redirect_map: HashMap<String, String>,
+    /// File to load additional redirects from, one `from to` pair per line. Entries in
+    /// `redirect_map` take precedence over the ones listed in this file.
+    pub redirect_map_file: Option<PathBuf>,
+    /// Status code of the redirect responses, one of 301, 302, 303, 307 or 308. The default is
+    /// 301 (Moved Permanently).
+    pub redirect_map_status: Option<u16>,
+}
+
+/// A single redirect from the map
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Redirect {
+    from: Path,
+    to: String,
+    prefix: bool,
+}
+
+impl Redirect {
+    /// Determines the redirect target for the given request path.
+    fn target(&self, path: &str) -> String {
+        if !self.prefix {
+            return self.to.clone();
+        }
+
+        let mut target = self.to.trim_end_matches('/').to_owned();
+        let tail = self
+            .from
+            .remove_prefix_from(&path)
+            .unwrap_or(path.as_bytes());
+        if tail == b"/" && !path.ends_with('/') && !target.is_empty() {
+            // The directory itself was requested, don’t add a trailing slash
+            return target;
+        }
+
+        // The path was a valid string, so everything after a slash is a valid string as well
+        target.push_str(&String::from_utf8_lossy(tail));
+        target
+    }
+}
+
+fn redirect_status(status: Option<u16>) -> Result<StatusCode, Box<Error>> {
+    match status {
+        Some(301) | None => Ok(StatusCode::MOVED_PERMANENTLY),
+        Some(302) => Ok(StatusCode::FOUND),
+        Some(303) => Ok(StatusCode::SEE_OTHER),
+        Some(307) => Ok(StatusCode::TEMPORARY_REDIRECT),
+        Some(308) => Ok(StatusCode::PERMANENT_REDIRECT),
+        Some(status) => Err(Error::explain(
+            ErrorType::InternalError,
+            format!("status {status} is not a redirect status, expected 301, 302, 303, 307 or 308"),
+        )),
+    }
+}
+
+/// Reads a redirect map file. Each line contains the old path and the redirect target separated
+/// by whitespace, empty lines and lines starting with `#` are ignored.
+fn read_map_file(path: &std::path::Path) -> Result<Vec<(String, String)>, Box<Error>> {
+    let contents = std::fs::read_to_string(path).map_err(|err| {
+        Error::because(
+            ErrorType::FileOpenError,
+            format!("failed opening redirect map file `{}`", path.display()),
+            err,
+        )
+    })?;
+
+    let mut entries = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(from), Some(to), None) => entries.push((from.to_owned(), to.to_owned())),
+            _ => {
+                return Err(Error::explain(
+                    ErrorType::FileReadError,
+                    format!(
+                        "line {} of redirect map file `{}` should contain exactly two values",
+                        index + 1,
+                        path.display()
+                    ),
+                ))
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Redirect map module handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedirectMapHandler {
+    router: Router<Redirect>,
+    status: StatusCode,
+}
+
+impl TryFrom<RedirectMapConf> for RedirectMapHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: RedirectMapConf) -> Result<Self, Self::Error> {
+        let status = redirect_status(conf.redirect_map_status)?;
+
+        let mut entries = if let Some(path) = &conf.redirect_map_file {
+            read_map_file(path)?
+        } else {
+            Vec::new()
+        };
+        entries.extend(conf.redirect_map);
+
+        // Exact and prefix redirects for the same path have to be added in one go.
+        let mut redirects: BTreeMap<Path, (Option<Redirect>, Option<Redirect>)> = BTreeMap::new();
+        for (from, to) in entries {
+            if !from.starts_with('/') {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    format!("redirect map path `{from}` should start with a slash"),
+                ));
+            }
+
+            let (path, prefix) = match from.strip_suffix("/*") {
+                Some(path) => (Path::new(path), true),
+                None => (Path::new(&from), false),
+            };
+            let redirect = Redirect {
+                from: path.clone(),
+                to,
+                prefix,
+            };
+
+            let entry = redirects.entry(path).or_default();
+            if prefix {
+                entry.1 = Some(redirect);
+            } else {
+                entry.0 = Some(redirect);
+            }
+        }
+
+        let mut builder = Router::builder();
+        for (path, (exact, prefix)) in redirects {
+            let Some(exact) = exact.or_else(|| prefix.clone()) else {
+                continue;
+            };
+            builder.push("", path.as_bytes(), exact, prefix)?;
+        }
+
+        Ok(Self {
+            router: builder.build()?,
+            status,
+        })
+    }
+}
+
+#[async_trait]
+impl RequestFilter for RedirectMapHandler {
+    type Conf = RedirectMapConf;
+
+    type CTX = ();
+
+    fn new_ctx() -> Self::CTX {}
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        let uri = session.uri();
+        let path = uri.path();
+        let redirect = if let Some(redirect) = self.router.lookup("", path) {
+            redirect
+        } else {
+            trace!("No redirect for path {path}");
+            return Ok(RequestFilterResult::Unhandled);
+        };
+
+        let mut location = redirect.target(path);
+        if let Some(query) = uri.query() {
+            if !location.contains('?') {
+                location.push('?');
+                location.push_str(query);
+            }
+        }
+
+        debug!("redirecting {path} to {location}");
+        redirect_response(session, self.status, &location).await?;
+        Ok(RequestFilterResult::ResponseSent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http::header;
+    use pandora_module_utils::pingora::{create_test_session, RequestHeader, Session};
+    use pandora_module_utils::FromYaml;
+    use startup_module::DefaultApp;
+    use test_log::test;
+
+    fn make_app(conf: &str) -> DefaultApp<RedirectMapHandler> {
+        DefaultApp::new(
+            <RedirectMapHandler as RequestFilter>::Conf::from_yaml(conf)
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    async fn make_session(path: &str) -> Session {
+        let header = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+        create_test_session(header).await
+    }
+
+    async fn assert_redirect(
+        app: &mut DefaultApp<RedirectMapHandler>,
+        path: &str,
+        status: u16,
+        location: &str,
+    ) {
+        let session = make_session(path).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        let session = result.session();
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, status);
+        assert_eq!(response.headers.get(header::LOCATION).unwrap(), location);
+    }
+
+    async fn assert_unhandled(app: &mut DefaultApp<RedirectMapHandler>, path: &str) {
+        let session = make_session(path).await;
+        let result = app.handle_request(session).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(404))
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn exact_redirects() {
+        let mut app = make_app(
+            r#"
+                redirect_map:
+                    /old.html: /new.html
+                    /dir/about: https://example.com/about
+            "#,
+        );
+
+        assert_redirect(&mut app, "/old.html", 301, "/new.html").await;
+        assert_redirect(&mut app, "/old.html?a=b", 301, "/new.html?a=b").await;
+        assert_redirect(&mut app, "/dir/about/", 301, "https://example.com/about").await;
+        assert_unhandled(&mut app, "/dir/about/team").await;
+        assert_unhandled(&mut app, "/dir").await;
+    }
+
+    #[test(tokio::test)]
+    async fn prefix_redirects() {
+        let mut app = make_app(
+            r#"
+                redirect_map:
+                    /blog/*: /news/
+                    /blog/archive/*: https://archive.example.com
+                    /blog/archive: /news/archive.html
+                redirect_map_status: 308
+            "#,
+        );
+
+        assert_redirect(&mut app, "/blog", 308, "/news").await;
+        assert_redirect(&mut app, "/blog/", 308, "/news/").await;
+        assert_redirect(&mut app, "/blog/post.html", 308, "/news/post.html").await;
+        assert_redirect(&mut app, "/blog/archive", 308, "/news/archive.html").await;
+        assert_redirect(
+            &mut app,
+            "/blog/archive/2020/post.html?a=b",
+            308,
+            "https://archive.example.com/2020/post.html?a=b",
+        )
+        .await;
+        assert_unhandled(&mut app, "/blogs").await;
+    }
+
+    #[test(tokio::test)]
+    async fn pass_through() {
+        let mut app = make_app("{}");
+        assert_unhandled(&mut app, "/").await;
+        assert_unhandled(&mut app, "/file.txt").await;
+
+        let mut app = make_app(
+            r#"
+                redirect_map:
+                    /old: /new
+            "#,
+        );
+        assert_unhandled(&mut app, "/").await;
+        assert_unhandled(&mut app, "/new").await;
+        assert_unhandled(&mut app, "/older").await;
+    }
+
+    #[test(tokio::test)]
+    async fn map_file() {
+        let path = std::env::temp_dir().join("redirect-map-module-test.txt");
+        std::fs::write(
+            &path,
+            "# Moved pages\n/old.html /new.html\n\n/docs/* /manual/\n/both /from-file\n",
+        )
+        .unwrap();
+
+        let mut app = make_app(&format!(
+            r#"
+                redirect_map_file: {}
+                redirect_map:
+                    /both: /from-config
+            "#,
+            path.display()
+        ));
+        assert_redirect(&mut app, "/old.html", 301, "/new.html").await;
+        assert_redirect(&mut app, "/docs/intro.html", 301, "/manual/intro.html").await;
+        assert_redirect(&mut app, "/both", 301, "/from-config").await;
+        assert_unhandled(&mut app, "/new.html").await;
+
+        std::fs::write(&path, "/old.html /new.html\n/invalid\n").unwrap();
+        let conf = RedirectMapConf {
+            redirect_map_file: Some(path.clone()),
+            ..Default::default()
+        };
+        assert!(RedirectMapHandler::try_from(conf).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_config() {
+        for conf in [
+            "{redirect_map: {/old: /new}, redirect_map_status: 200}",
+            "{redirect_map: {old: /new}}",
+            "redirect_map_file: /nonexistent/redirect-map-module.txt",
+        ] {
+            assert!(RedirectMapHandler::try_from(
+                <RedirectMapHandler as RequestFilter>::Conf::from_yaml(conf).unwrap()
+            )
+            .is_err());
+        }
+    }
+}
@@ -16,8 +16,9 @@
 
 use async_trait::async_trait;
 use headers_module::configuration::CustomHeadersConf;
-use http::{header, HeaderName, HeaderValue, StatusCode};
+use http::{HeaderName, HeaderValue, StatusCode};
 use pandora_module_utils::pingora::{ResponseHeader, SessionWrapper};
+use pandora_module_utils::standard_response::send_response;
 use pandora_module_utils::{pingora::Error, RequestFilterResult};
 use pandora_module_utils::{DeserializeMap, RequestFilter};
 use serde::de::{Deserialize, Deserializer, Unexpected};
@@ -85,13 +86,7 @@ impl RequestFilter for ResponseHandler {
             for (name, value) in &self.response_headers {
                 response_header.insert_header(name, value)?;
             }
-            response_header.insert_header(header::CONTENT_LENGTH, response.len())?;
-            session
-                .write_response_header(Box::new(response_header), false)
-                .await?;
-            session
-                .write_response_body(Some(response.clone().into()), true)
-                .await?;
+            send_response(session, response_header, response.clone()).await?;
             Ok(RequestFilterResult::ResponseSent)
         } else {
             Ok(RequestFilterResult::Unhandled)
@@ -103,6 +98,7 @@ impl RequestFilter for ResponseHandler {
 mod tests {
     use super::*;
 
+    use http::header;
     use pandora_module_utils::{
         pingora::{create_test_session, ErrorType, RequestHeader, Session},
         FromYaml,
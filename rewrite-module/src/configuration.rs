@@ -19,6 +19,7 @@ use pandora_module_utils::merger::PathMatcher;
 use pandora_module_utils::{DeserializeMap, OneOrMany};
 use regex::Regex;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::default::Default;
 use std::fmt::Debug;
 
@@ -27,6 +28,7 @@ pub(crate) enum Variable {
     Tail,
     Query,
     Header(HeaderName),
+    Capture(usize),
 }
 
 #[derive(Clone, PartialEq, Eq)]
@@ -82,6 +84,14 @@ impl From<&str> for VariableInterpolation {
                         Variable::Tail
                     } else if name == "query" {
                         Variable::Query
+                    } else if !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit()) {
+                        if let Ok(index) = name.parse() {
+                            Variable::Capture(index)
+                        } else {
+                            // Index too large, look for another variable start further ahead
+                            search_start = start + Self::VARIABLE_PREFIX.len();
+                            continue;
+                        }
                     } else if let Some(name) = name.strip_prefix("http_") {
                         if let Ok(header) = HeaderName::try_from(name.replace('_', "-")) {
                             Variable::Header(header)
@@ -210,6 +220,27 @@ impl TryFrom<String> for RegexMatch {
     }
 }
 
+/// A condition on a request header that has to be satisfied for a rewrite rule to apply
+///
+/// If multiple checks are specified, all of them have to pass. If no checks are specified, the
+/// header merely has to be present. A condition is satisfied if at least one of the header’s
+/// values passes the checks, it is never satisfied for a missing header.
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct HeaderCondition {
+    /// Name of the request header to check, e.g. `User-Agent`
+    pub header: String,
+
+    /// Value the header has to be equal to
+    pub exact: Option<String>,
+
+    /// String the header value has to contain, e.g. `Mobile`
+    pub contains: Option<String>,
+
+    /// Regular expression the header value has to match. Prefixing the regular expression with
+    /// `!` will negate its effect.
+    pub regex: Option<RegexMatch>,
+}
+
 /// A rewrite rule resulting in either request URI change or redirect
 #[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct RewriteRule {
@@ -237,6 +268,11 @@ pub struct RewriteRule {
     /// but those containing a `file` parameter.
     pub query_regex: Option<RegexMatch>,
 
+    /// Request header conditions, all of these have to be satisfied for the rule to apply. For
+    /// example `{header: User-Agent, contains: Mobile}` will only match requests with a
+    /// `User-Agent` header containing `Mobile`.
+    pub conditions: OneOrMany<HeaderCondition>,
+
     /// New URI to be set on match
     ///
     /// The following variables will be resolved:
@@ -250,10 +286,42 @@ pub struct RewriteRule {
     ///   `/file.txt?a=b` will be rewritten into `/file.html?a=b`.
     /// * `${http_<header>}`: This allows inserting arbitrary HTTP headers into the redirect
     ///   target.
+    /// * `${1}`, `${2}` and so on: Only valid with a non-negated `from_regex`. This will be
+    ///   replaced by the respective capture group of the regular expression match, `${0}` being
+    ///   the entire match. For example, if `from_regex` is `^/old/(.*)$` and `to` is `/new/${1}`,
+    ///   a request to `/old/file.txt` will be rewritten into `/new/file.txt`.
     pub to: VariableInterpolation,
 
     /// Rewriting type, one of `internal` (default), `redirect` or `permanent`
     pub r#type: RewriteType,
+
+    /// Status code of the redirect response, one of 301, 302, 303, 307 or 308. If not set, 307
+    /// is used for `redirect` and 308 for `permanent` rules. Not allowed for `internal` rules.
+    pub status: Option<u16>,
+
+    /// If `true`, the query string of the original request is appended to the new URI. If the new
+    /// URI already has a query string, the original parameters are added after its parameters,
+    /// with parameters already present in the new URI taking precedence over original parameters
+    /// of the same name.
+    ///
+    /// The query string manipulations below are applied to the combined query string.
+    pub append_query: bool,
+
+    /// If `true`, the query string of the new URI is removed entirely. Parameters from
+    /// `set_query_param` will still be added.
+    pub clear_query: bool,
+
+    /// Query parameters to be removed from the new URI. A trailing `*` makes the name match all
+    /// parameters starting with the given prefix, e.g. `utm_*` will remove both `utm_source` and
+    /// `utm_campaign`.
+    pub remove_query_param: OneOrMany<String>,
+
+    /// Query parameters to be set on the new URI, e.g. `{lang: en}`. Existing parameters with the
+    /// same name are replaced, other parameters are appended to the query string.
+    ///
+    /// Parameter names and values are used as is and compared without percent decoding, these
+    /// should be percent encoded where necessary.
+    pub set_query_param: HashMap<String, String>,
 }
 
 impl Default for RewriteRule {
@@ -262,8 +330,14 @@ impl Default for RewriteRule {
             from: "/*".into(),
             from_regex: None,
             query_regex: None,
+            conditions: Default::default(),
             to: "/".into(),
             r#type: RewriteType::Internal,
+            status: None,
+            append_query: false,
+            clear_query: false,
+            remove_query_param: Default::default(),
+            set_query_param: Default::default(),
         }
     }
 }
@@ -333,6 +407,19 @@ mod tests {
             }),
             b"${aresolved".to_vec()
         );
+
+        assert_eq!(
+            VariableInterpolation::from("/${1}/${0}${-1}${99999999999999999999999}").interpolate(
+                |variable, result| {
+                    if let Variable::Capture(index) = variable {
+                        result.extend_from_slice(index.to_string().as_bytes())
+                    } else {
+                        panic!("Unexpected variable in lookup")
+                    }
+                }
+            ),
+            b"/1/0${-1}${99999999999999999999999}".to_vec()
+        );
     }
 
     #[test]
@@ -15,22 +15,206 @@
 //! Handler for the `request_filter` phase.
 
 use async_trait::async_trait;
-use http::StatusCode;
+use http::{HeaderMap, HeaderName, StatusCode};
 use log::{error, trace};
 use pandora_module_utils::merger::Merger;
-use pandora_module_utils::pingora::{Error, SessionWrapper};
+use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
 use pandora_module_utils::router::{Path, Router};
 use pandora_module_utils::standard_response::redirect_response;
 use pandora_module_utils::{RequestFilter, RequestFilterResult};
+use std::borrow::Cow;
 
-use crate::configuration::{RegexMatch, RewriteConf, RewriteType, Variable, VariableInterpolation};
+use crate::configuration::{
+    HeaderCondition, RegexMatch, RewriteConf, RewriteType, Variable, VariableInterpolation,
+};
+
+/// A request header condition with the header name parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Condition {
+    header: HeaderName,
+    exact: Option<String>,
+    contains: Option<String>,
+    regex: Option<RegexMatch>,
+}
+
+impl TryFrom<HeaderCondition> for Condition {
+    type Error = Box<Error>;
+
+    fn try_from(condition: HeaderCondition) -> Result<Self, Self::Error> {
+        let header = HeaderName::try_from(condition.header.as_str()).map_err(|err| {
+            Error::because(
+                ErrorType::InternalError,
+                format!(
+                    "invalid header name in rewrite condition: {}",
+                    condition.header
+                ),
+                err,
+            )
+        })?;
+        Ok(Self {
+            header,
+            exact: condition.exact,
+            contains: condition.contains,
+            regex: condition.regex,
+        })
+    }
+}
+
+impl Condition {
+    /// Checks whether any value of the header passes all checks.
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        headers.get_all(&self.header).iter().any(|value| {
+            let Ok(value) = value.to_str() else {
+                return false;
+            };
+            self.exact.as_ref().map_or(true, |exact| value == exact)
+                && self
+                    .contains
+                    .as_ref()
+                    .map_or(true, |contains| value.contains(contains.as_str()))
+                && self
+                    .regex
+                    .as_ref()
+                    .map_or(true, |regex| regex.matches(value))
+        })
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct Rule {
     from_regex: Option<RegexMatch>,
     query_regex: Option<RegexMatch>,
+    conditions: Vec<Condition>,
     to: VariableInterpolation,
     r#type: RewriteType,
+    status: Option<StatusCode>,
+    query: QueryRewrite,
+}
+
+/// Query string changes applied to the rewrite target
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct QueryRewrite {
+    append: bool,
+    clear: bool,
+    remove: Vec<String>,
+    set: Vec<(String, String)>,
+}
+
+impl QueryRewrite {
+    fn is_empty(&self) -> bool {
+        !self.append && !self.clear && self.remove.is_empty() && self.set.is_empty()
+    }
+
+    /// Extracts the name from a query parameter like `name=value`.
+    fn param_name(param: &[u8]) -> &[u8] {
+        param.split(|c| *c == b'=').next().unwrap_or_default()
+    }
+
+    /// Checks whether a query parameter should be removed, either due to being listed explicitly
+    /// or due to matching a prefix like `utm_*`.
+    fn is_removed(&self, name: &[u8]) -> bool {
+        self.remove.iter().any(|removed| {
+            if let Some(prefix) = removed.strip_suffix('*') {
+                name.starts_with(prefix.as_bytes())
+            } else {
+                name == removed.as_bytes()
+            }
+        })
+    }
+
+    /// Applies the changes to the query string of the target, keeping the order of parameters
+    /// which aren’t removed. `original` is the query string of the request, only used if it
+    /// should be appended.
+    fn apply(&self, target: Vec<u8>, original: Option<&str>) -> Vec<u8> {
+        if self.is_empty() {
+            return target;
+        }
+
+        let (target, fragment) = match target.iter().position(|c| *c == b'#') {
+            Some(pos) => target.split_at(pos),
+            None => (target.as_slice(), &[][..]),
+        };
+        let (path, query) = match target.iter().position(|c| *c == b'?') {
+            Some(pos) => (&target[..pos], &target[pos + 1..]),
+            None => (target, &[][..]),
+        };
+
+        let mut params = query
+            .split(|c| *c == b'&')
+            .filter(|param| !param.is_empty())
+            .map(Cow::Borrowed)
+            .collect::<Vec<_>>();
+
+        if self.append {
+            // Parameters of the target take precedence over original parameters
+            let target_params = params.len();
+            for param in original
+                .unwrap_or_default()
+                .as_bytes()
+                .split(|c| *c == b'&')
+            {
+                let name = Self::param_name(param);
+                if !param.is_empty()
+                    && !params[..target_params]
+                        .iter()
+                        .any(|existing| Self::param_name(existing) == name)
+                {
+                    params.push(Cow::Borrowed(param));
+                }
+            }
+        }
+
+        if self.clear {
+            params.clear();
+        } else {
+            params.retain(|param| !self.is_removed(Self::param_name(param)));
+        }
+
+        for (name, value) in &self.set {
+            let mut new_param = name.as_bytes().to_vec();
+            new_param.push(b'=');
+            new_param.extend_from_slice(value.as_bytes());
+
+            // Replace the first occurrence and remove any others
+            let mut new_param = Some(new_param);
+            params.retain_mut(|param| {
+                if Self::param_name(param) != name.as_bytes() {
+                    true
+                } else if let Some(new_param) = new_param.take() {
+                    *param = Cow::Owned(new_param);
+                    true
+                } else {
+                    false
+                }
+            });
+            if let Some(new_param) = new_param {
+                params.push(Cow::Owned(new_param));
+            }
+        }
+
+        let mut result = path.to_vec();
+        if !params.is_empty() {
+            result.push(b'?');
+            result.extend_from_slice(&params.join(&b'&'));
+        }
+        result.extend_from_slice(fragment);
+        result
+    }
+}
+
+fn redirect_status(status: Option<u16>, default: StatusCode) -> Result<StatusCode, Box<Error>> {
+    match status {
+        Some(301) => Ok(StatusCode::MOVED_PERMANENTLY),
+        Some(302) => Ok(StatusCode::FOUND),
+        Some(303) => Ok(StatusCode::SEE_OTHER),
+        Some(307) => Ok(StatusCode::TEMPORARY_REDIRECT),
+        Some(308) => Ok(StatusCode::PERMANENT_REDIRECT),
+        Some(status) => Err(Error::explain(
+            ErrorType::InternalError,
+            format!("status {status} is not a redirect status, expected 301, 302, 303, 307 or 308"),
+        )),
+        None => Ok(default),
+    }
 }
 
 /// Rewrite module handler
@@ -53,19 +237,56 @@ impl TryFrom<RewriteConf> for RewriteHandler {
 
         for rule in conf.rewrite_rules {
             let path = rule.from.path.clone();
+            let status = match rule.r#type {
+                RewriteType::Internal => {
+                    if let Some(status) = rule.status {
+                        return Err(Error::explain(
+                            ErrorType::InternalError,
+                            format!("status {status} cannot be used with internal rewrite rules"),
+                        ));
+                    }
+                    None
+                }
+                RewriteType::Redirect => Some(redirect_status(
+                    rule.status,
+                    StatusCode::TEMPORARY_REDIRECT,
+                )?),
+                RewriteType::Permanent => Some(redirect_status(
+                    rule.status,
+                    StatusCode::PERMANENT_REDIRECT,
+                )?),
+            };
+
+            let mut set_query_param = rule.set_query_param.into_iter().collect::<Vec<_>>();
+            set_query_param.sort();
+            let query = QueryRewrite {
+                append: rule.append_query,
+                clear: rule.clear_query,
+                remove: rule.remove_query_param.into(),
+                set: set_query_param,
+            };
+
+            let conditions = Vec::from(rule.conditions)
+                .into_iter()
+                .map(Condition::try_from)
+                .collect::<Result<Vec<_>, _>>()?;
+
             let from = rule.from;
             let rule = Rule {
                 from_regex: rule.from_regex,
                 query_regex: rule.query_regex,
+                conditions,
                 to: rule.to,
                 r#type: rule.r#type,
+                status,
+                query,
             };
 
             merger.push(from, (path, rule));
         }
 
         Ok(Self {
-            router: merger.merge(|rules| rules.cloned().collect::<Vec<_>>()),
+            router: merger.merge(|rules| rules.cloned().collect::<Vec<_>>())?,
         })
     }
 }
@@ -97,8 +318,15 @@ impl RequestFilter for RewriteHandler {
 
         // Iterate in reverse order, merging puts rules in reverse order of precedence.
         for (rule_path, rule) in list.iter().rev() {
+            let mut captures = None;
             if let Some(from_regex) = &rule.from_regex {
-                if !from_regex.matches(session.uri().path()) {
+                if from_regex.negate {
+                    if !from_regex.matches(path) {
+                        continue;
+                    }
+                } else if let Some(result) = from_regex.regex.captures(path) {
+                    captures = Some(result);
+                } else {
                     continue;
                 }
             }
@@ -109,6 +337,15 @@ impl RequestFilter for RewriteHandler {
                 }
             }
 
+            let headers = &session.req_header().headers;
+            if !rule
+                .conditions
+                .iter()
+                .all(|condition| condition.matches(headers))
+            {
+                continue;
+            }
+
             trace!(
                 "Matched rule for path `{}`",
                 String::from_utf8_lossy(rule_path)
@@ -133,7 +370,13 @@ impl RequestFilter for RewriteHandler {
                         result.extend_from_slice(value.as_bytes())
                     }
                 }
+                Variable::Capture(index) => {
+                    if let Some(capture) = captures.as_ref().and_then(|c| c.get(*index)) {
+                        result.extend_from_slice(capture.as_str().as_bytes());
+                    }
+                }
             });
+            let target = rule.query.apply(target, session.uri().query());
 
             match rule.r#type {
                 RewriteType::Internal => {
@@ -148,6 +391,7 @@ impl RequestFilter for RewriteHandler {
                     break;
                 }
                 RewriteType::Redirect | RewriteType::Permanent => {
+                    let status = rule.status.unwrap_or(StatusCode::TEMPORARY_REDIRECT);
                     let location = match String::from_utf8(target) {
                         Ok(location) => location,
                         Err(err) => {
@@ -155,11 +399,6 @@ impl RequestFilter for RewriteHandler {
                             return Ok(RequestFilterResult::Unhandled);
                         }
                     };
-                    let status = if rule.r#type == RewriteType::Redirect {
-                        StatusCode::TEMPORARY_REDIRECT
-                    } else {
-                        StatusCode::PERMANENT_REDIRECT
-                    };
                     redirect_response(session, status, &location).await?;
                     return Ok(RequestFilterResult::ResponseSent);
                 }
@@ -174,9 +413,11 @@ impl RequestFilter for RewriteHandler {
 mod tests {
     use super::*;
 
-    use pandora_module_utils::pingora::{create_test_session, ErrorType, RequestHeader, Session};
+    use http::Extensions;
+    use pandora_module_utils::pingora::{create_test_session, RequestHeader, Session};
     use pandora_module_utils::FromYaml;
     use startup_module::DefaultApp;
+    use std::ops::{Deref, DerefMut};
     use test_log::test;
 
     fn make_app(conf: &str) -> DefaultApp<RewriteHandler> {
@@ -416,6 +657,407 @@ mod tests {
         );
     }
 
+    struct TestSession {
+        session: Session,
+        extensions: Extensions,
+    }
+
+    impl Deref for TestSession {
+        type Target = Session;
+
+        fn deref(&self) -> &Self::Target {
+            &self.session
+        }
+    }
+
+    impl DerefMut for TestSession {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.session
+        }
+    }
+
+    impl SessionWrapper for TestSession {
+        fn extensions(&self) -> &Extensions {
+            &self.extensions
+        }
+
+        fn extensions_mut(&mut self) -> &mut Extensions {
+            &mut self.extensions
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn request_filter_result() {
+        let handler: RewriteHandler = <RewriteHandler as RequestFilter>::Conf::from_yaml(
+            r#"
+                rewrite_rules:
+                -
+                    from: /internal
+                    to: /rewritten
+                    type: internal
+                -
+                    from: /redirect
+                    to: https://example.com/
+                    type: redirect
+            "#,
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+
+        // Internal rewrites change the URI and let other handlers process the request
+        let mut session = TestSession {
+            session: make_session("/internal").await,
+            extensions: Extensions::new(),
+        };
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await.unwrap(),
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/rewritten");
+        assert_eq!(session.original_uri(), "/internal");
+        assert!(session.response_written().is_none());
+
+        // Redirects produce a response
+        let mut session = TestSession {
+            session: make_session("/redirect").await,
+            extensions: Extensions::new(),
+        };
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await.unwrap(),
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(session.uri(), "/redirect");
+        assert_eq!(
+            session.response_written().map(|r| r.status),
+            Some(StatusCode::TEMPORARY_REDIRECT)
+        );
+        assert_eq!(
+            session
+                .response_written()
+                .and_then(|r| r.headers.get("Location"))
+                .map(|h| h.to_str().unwrap()),
+            Some("https://example.com/")
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn regex_captures() {
+        let mut app = make_app(
+            r#"
+                rewrite_rules:
+                -
+                    from: /*
+                    from_regex: ^/old/([^/]+)/(.*)$
+                    to: /new/${2}/${1}${query}
+                    type: permanent
+                -
+                    from: /*
+                    from_regex: "!^/old/"
+                    to: /other${1}
+                    type: redirect
+            "#,
+        );
+
+        let session = make_session("/old/dir/file.txt?a=b").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(
+            result.session().response_written().map(|r| r.status),
+            Some(StatusCode::PERMANENT_REDIRECT)
+        );
+        assert_eq!(
+            result
+                .session()
+                .response_written()
+                .and_then(|r| r.headers.get("Location"))
+                .map(|h| h.to_str().unwrap()),
+            Some("/new/file.txt/dir?a=b")
+        );
+
+        // Negated regular expressions have no capture groups
+        let session = make_session("/file.txt").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(
+            result
+                .session()
+                .response_written()
+                .and_then(|r| r.headers.get("Location"))
+                .map(|h| h.to_str().unwrap()),
+            Some("/other")
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn redirect_status() {
+        let mut app = make_app(
+            r#"
+                rewrite_rules:
+                -
+                    from: /moved/*
+                    from_regex: ^/moved/(.*)$
+                    to: https://example.com/${1}
+                    type: permanent
+                    status: 301
+                -
+                    from: /found
+                    to: /elsewhere
+                    type: redirect
+                    status: 302
+            "#,
+        );
+
+        let session = make_session("/moved/file.txt").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(
+            result.session().response_written().map(|r| r.status),
+            Some(StatusCode::MOVED_PERMANENTLY)
+        );
+        assert_eq!(
+            result
+                .session()
+                .response_written()
+                .and_then(|r| r.headers.get("Location"))
+                .map(|h| h.to_str().unwrap()),
+            Some("https://example.com/file.txt")
+        );
+
+        let session = make_session("/found").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(
+            result.session().response_written().map(|r| r.status),
+            Some(StatusCode::FOUND)
+        );
+
+        for conf in [
+            "rewrite_rules: {from: /, to: /a, type: redirect, status: 200}",
+            "rewrite_rules: {from: /, to: /a, type: permanent, status: 404}",
+            "rewrite_rules: {from: /, to: /a, status: 301}",
+        ] {
+            assert!(RewriteHandler::try_from(
+                <RewriteHandler as RequestFilter>::Conf::from_yaml(conf).unwrap()
+            )
+            .is_err());
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn header_conditions() {
+        let mut app = make_app(
+            r#"
+                rewrite_rules:
+                -
+                    from: /*
+                    conditions:
+                        header: User-Agent
+                        contains: Mobile
+                    to: /mobile${tail}
+                -
+                    from: /api/*
+                    conditions:
+                    -
+                        header: X-Api-Version
+                        exact: "2"
+                    -
+                        header: Accept
+                        regex: "!xml"
+                    to: /api/v2${tail}
+            "#,
+        );
+
+        async fn make_session_with_headers(path: &str, headers: &[(&str, &str)]) -> Session {
+            let mut session = make_session(path).await;
+            for (name, value) in headers {
+                session
+                    .req_header_mut()
+                    .append_header(name.to_string(), *value)
+                    .unwrap();
+            }
+            session
+        }
+
+        let cases: &[(&str, &[(&str, &str)], &str)] = &[
+            ("/file.txt", &[], "/file.txt"),
+            ("/file.txt", &[("User-Agent", "Desktop")], "/file.txt"),
+            (
+                "/file.txt",
+                &[("User-Agent", "Some Mobile Browser")],
+                "/mobile/file.txt",
+            ),
+            ("/api/", &[("X-Api-Version", "2")], "/api/"),
+            ("/api/", &[("Accept", "text/html")], "/api/"),
+            (
+                "/api/",
+                &[("X-Api-Version", "2"), ("Accept", "text/xml")],
+                "/api/",
+            ),
+            (
+                "/api/",
+                &[("X-Api-Version", "20"), ("Accept", "text/html")],
+                "/api/",
+            ),
+            (
+                "/api/",
+                &[("X-Api-Version", "2"), ("Accept", "text/html")],
+                "/api/v2/",
+            ),
+            (
+                "/api/",
+                &[
+                    ("X-Api-Version", "1"),
+                    ("X-Api-Version", "2"),
+                    ("Accept", "text/html"),
+                ],
+                "/api/v2/",
+            ),
+        ];
+        for (path, headers, expected) in cases {
+            let session = make_session_with_headers(path, headers).await;
+            let mut result = app.handle_request(session).await;
+            assert_eq!(
+                result.err().as_ref().map(|err| &err.etype),
+                Some(&ErrorType::HTTPStatus(404))
+            );
+            assert_eq!(result.session().uri(), *expected, "{path} {headers:?}");
+        }
+
+        assert!(RewriteHandler::try_from(
+            <RewriteHandler as RequestFilter>::Conf::from_yaml(
+                "rewrite_rules: {from: /, to: /a, conditions: {header: \"invalid header\"}}"
+            )
+            .unwrap()
+        )
+        .is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn query_manipulation() {
+        let mut app = make_app(
+            r#"
+                rewrite_rules:
+                -
+                    from: /file.txt
+                    to: /another.txt
+                    set_query_param:
+                        lang: en
+                -
+                    from: /tracking/*
+                    to: /target${tail}${query}
+                    remove_query_param: [utm_*, ref]
+                    set_query_param:
+                        b: replaced
+                -
+                    from: /redirect
+                    to: https://example.com/${query}
+                    type: redirect
+                    clear_query: true
+                    set_query_param:
+                        from: redirect
+            "#,
+        );
+
+        let session = make_session("/file.txt").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(404))
+        );
+        assert_eq!(result.session().uri(), "/another.txt?lang=en");
+
+        let session =
+            make_session("/tracking/file.txt?a=1&utm_source=x&b=2&ref=y&utm_campaign=z&c=3").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(404))
+        );
+        assert_eq!(
+            result.session().uri(),
+            "/target/file.txt?a=1&b=replaced&c=3"
+        );
+
+        let session = make_session("/tracking/file.txt?utm_source=x").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(404))
+        );
+        assert_eq!(result.session().uri(), "/target/file.txt?b=replaced");
+
+        let session = make_session("/redirect?a=b").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(
+            result
+                .session()
+                .response_written()
+                .and_then(|r| r.headers.get("Location"))
+                .map(|h| h.to_str().unwrap()),
+            Some("https://example.com/?from=redirect")
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn append_query() {
+        let mut app = make_app(
+            r#"
+                rewrite_rules:
+                -
+                    from: /append/*
+                    to: /target${tail}
+                    append_query: true
+                -
+                    from: /no-append/*
+                    to: /target${tail}
+                -
+                    from: /merge
+                    to: https://example.com/?a=target&b=target#top
+                    type: redirect
+                    append_query: true
+                    remove_query_param: c
+            "#,
+        );
+
+        let session = make_session("/append/file.txt?a=1&b=2").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(404))
+        );
+        assert_eq!(result.session().uri(), "/target/file.txt?a=1&b=2");
+
+        let session = make_session("/append/file.txt").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(404))
+        );
+        assert_eq!(result.session().uri(), "/target/file.txt");
+
+        let session = make_session("/no-append/file.txt?a=1&b=2").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(404))
+        );
+        assert_eq!(result.session().uri(), "/target/file.txt");
+
+        let session = make_session("/merge?b=1&c=2&d=3&d=4").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(
+            result
+                .session()
+                .response_written()
+                .and_then(|r| r.headers.get("Location"))
+                .map(|h| h.to_str().unwrap()),
+            Some("https://example.com/?a=target&b=target&d=3&d=4#top")
+        );
+    }
+
     #[test(tokio::test)]
     async fn rule_order() {
         let mut app = make_app(
@@ -14,13 +14,13 @@
 
 use async_trait::async_trait;
 use clap::Parser;
-use pandora_module_utils::pingora::{
-    http_proxy_service, Error, ErrorType, ProxyHttp, Server, ServerConf, ServerOpt,
-};
+use log::warn;
+use pandora_module_utils::pingora::{Error, ErrorType, ProxyHttp, Server, ServerConf, ServerOpt};
 use pandora_module_utils::{DeserializeMap, OneOrMany};
 use pingora::listeners::{TcpSocketOptions, TlsAccept};
+use pingora::proxy::http_proxy;
+use pingora::services::{listening::Service as ListeningService, Service};
 use pingora_core::listeners::tls::TlsSettings;
-use pingora::services::Service;
 use pingora::tls::ext::ssl_add_chain_cert;
 use pingora::tls::{
     ext::{ssl_use_certificate, ssl_use_private_key},
@@ -34,11 +34,46 @@ use std::collections::HashMap;
 use std::fs::read;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::redirector::create_redirector;
+use crate::throttle::{ThrottledApp, TokenBucket};
 
 pub(crate) const TLS_CONF_ERR: ErrorType = ErrorType::Custom("TLSConfigError");
 
+/// Maximum time new connections are deferred by default if `max_connection_rate` is exceeded
+const DEFAULT_MAX_CONNECTION_DELAY_MS: u64 = 10_000;
+
+/// Environment variable with addresses to listen on, used if neither command line flags nor
+/// configuration file specify any. Multiple addresses can be separated by commas or whitespace.
+pub const LISTEN_ADDR_ENV: &str = "LISTEN_ADDR";
+
+/// Determines the addresses to listen on. Command line flags take precedence over configuration
+/// file settings, these over addresses from the environment variable. Built-in defaults are used
+/// if none of these produce any addresses.
+fn resolve_listen(
+    opt: Option<Vec<ListenAddr>>,
+    conf: OneOrMany<ListenAddr>,
+    env: Option<String>,
+) -> OneOrMany<ListenAddr> {
+    let mut listen = opt.map(|l| l.into()).unwrap_or(conf);
+    if listen.is_empty() {
+        if let Some(env) = env {
+            for addr in env.split(|c: char| c == ',' || c.is_whitespace()) {
+                if !addr.is_empty() {
+                    listen.push(addr.into());
+                }
+            }
+        }
+    }
+    if listen.is_empty() {
+        // Make certain we have a listening address
+        listen.push("127.0.0.1:8080".into());
+        listen.push("[::1]:8080".into());
+    }
+    listen
+}
+
 /// Run a web server
 #[derive(Debug, Default, Parser)]
 pub struct StartupOpt {
@@ -56,6 +91,15 @@ pub struct StartupOpt {
     /// The path to the configuration file. This command line flag can be specified multiple times.
     #[clap(short, long)]
     pub conf: Option<Vec<String>>,
+    /// Maximum size of configuration files in bytes, larger files are rejected.
+    #[clap(long)]
+    pub max_config_size: Option<u64>,
+    /// Maximum number of new connections to handle per second.
+    #[clap(long)]
+    pub max_connection_rate: Option<u32>,
+    /// Number of worker threads per service.
+    #[clap(long)]
+    pub threads: Option<usize>,
 }
 
 /// Address for the server to listen on
@@ -363,6 +407,32 @@ impl TlsAccept for TlsAcceptCallbacks {
     }
 }
 
+/// Applies the command line override for the number of worker threads, warning if the number of
+/// threads exceeds the number of CPU cores available.
+fn apply_threads(
+    mut conf: ServerConf,
+    threads: Option<usize>,
+    available: Option<usize>,
+) -> ServerConf {
+    if let Some(threads) = threads {
+        conf.threads = threads;
+    }
+
+    if conf.threads == 0 {
+        warn!("number of worker threads cannot be zero, using one thread");
+        conf.threads = 1;
+    }
+
+    if let Some(available) = available.filter(|available| conf.threads > *available) {
+        warn!(
+            "{} worker threads configured but only {available} CPU cores available, this is likely to degrade performance",
+            conf.threads
+        );
+    }
+
+    conf
+}
+
 /// Configuration settings of the startup module
 #[derive(Debug, Default, PartialEq, Eq, DeserializeMap)]
 pub struct StartupConf {
@@ -372,6 +442,18 @@ pub struct StartupConf {
     /// TLS configuration for the server
     pub tls: TlsConf,
 
+    /// Maximum number of new connections to handle per second, handling of any connections
+    /// exceeding this rate is deferred
+    pub max_connection_rate: Option<u32>,
+
+    /// Number of new connections that can be handled at once before `max_connection_rate`
+    /// applies, defaults to `max_connection_rate`
+    pub connection_burst: Option<u32>,
+
+    /// Maximum time in milliseconds that handling of a new connection can be deferred due to
+    /// `max_connection_rate`, connections exceeding it are dropped. Defaults to 10 seconds.
+    pub max_connection_delay_ms: Option<u64>,
+
     /// Pingora’s default server configuration options
     #[pandora(flatten)]
     pub server: ServerConf,
@@ -386,13 +468,17 @@ impl StartupConf {
     {
         let opt = opt.unwrap_or_default();
 
-        let mut listen = opt.listen.map(|l| l.into()).unwrap_or(self.listen);
-        if listen.is_empty() {
-            // Make certain we have a listening address
-            listen.push("127.0.0.1:8080".into());
-            listen.push("[::1]:8080".into());
-        }
+        let listen = resolve_listen(
+            opt.listen,
+            self.listen,
+            std::env::var(LISTEN_ADDR_ENV).ok(),
+        );
 
+        let server_conf = apply_threads(
+            self.server,
+            opt.threads,
+            std::thread::available_parallelism().ok().map(usize::from),
+        );
         let mut server = Server::new_with_opt_and_conf(
             ServerOpt {
                 daemon: opt.daemon,
@@ -401,11 +487,26 @@ impl StartupConf {
                 nocapture: false,
                 conf: None,
             },
-            self.server,
+            server_conf,
         );
         server.bootstrap();
 
-        let mut service = http_proxy_service(&server.configuration, app);
+        let bucket = opt
+            .max_connection_rate
+            .or(self.max_connection_rate)
+            .filter(|rate| *rate > 0)
+            .map(|rate| {
+                let burst = self.connection_burst.unwrap_or(rate).max(1);
+                let max_delay = Duration::from_millis(
+                    self.max_connection_delay_ms
+                        .unwrap_or(DEFAULT_MAX_CONNECTION_DELAY_MS),
+                );
+                TokenBucket::new(rate, burst, max_delay, Instant::now())
+            });
+        let mut service = ListeningService::new(
+            "Pingora HTTP Proxy Service".to_owned(),
+            ThrottledApp::new(http_proxy(&server.configuration, app), bucket),
+        );
         for addr in &listen {
             if addr.tls {
                 continue;
@@ -441,3 +542,74 @@ impl StartupConf {
         Ok(server)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::FromYaml;
+
+    fn addrs(listen: OneOrMany<ListenAddr>) -> Vec<String> {
+        listen.into_iter().map(|addr| addr.addr).collect()
+    }
+
+    #[test]
+    fn listen_precedence() {
+        let opt = || Some(vec!["127.0.0.1:1".into()]);
+        let conf = || OneOrMany::from(vec![ListenAddr::from("127.0.0.1:2")]);
+        let env = || Some("127.0.0.1:3, [::1]:3".to_owned());
+
+        assert_eq!(
+            addrs(resolve_listen(opt(), conf(), env())),
+            vec!["127.0.0.1:1"]
+        );
+        assert_eq!(
+            addrs(resolve_listen(None, conf(), env())),
+            vec!["127.0.0.1:2"]
+        );
+        assert_eq!(
+            addrs(resolve_listen(None, Default::default(), env())),
+            vec!["127.0.0.1:3", "[::1]:3"]
+        );
+        assert_eq!(
+            addrs(resolve_listen(None, Default::default(), None)),
+            vec!["127.0.0.1:8080", "[::1]:8080"]
+        );
+        assert_eq!(
+            addrs(resolve_listen(
+                None,
+                Default::default(),
+                Some(" 127.0.0.1:3\n\t0.0.0.0:4,,".to_owned())
+            )),
+            vec!["127.0.0.1:3", "0.0.0.0:4"]
+        );
+        assert_eq!(
+            addrs(resolve_listen(None, Default::default(), Some(" ".to_owned()))),
+            vec!["127.0.0.1:8080", "[::1]:8080"]
+        );
+    }
+
+    #[test]
+    fn threads() {
+        let conf = StartupConf::from_yaml(
+            r#"
+                threads: 4
+                work_stealing: false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(conf.server.threads, 4);
+        assert!(!conf.server.work_stealing);
+
+        let server = apply_threads(conf.server, None, Some(8));
+        assert_eq!(server.threads, 4);
+        assert!(!server.work_stealing);
+
+        // Command line takes precedence, oversubscription is only a warning
+        let server = apply_threads(server, Some(16), Some(8));
+        assert_eq!(server.threads, 16);
+
+        let server = apply_threads(server, Some(0), None);
+        assert_eq!(server.threads, 1);
+    }
+}
@@ -16,15 +16,16 @@
 
 mod configuration;
 mod redirector;
+mod throttle;
 
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 pub use configuration::{
-    CertKeyConf, ListenAddr, StartupConf, StartupOpt, TlsConf, TlsRedirectorConf,
+    CertKeyConf, ListenAddr, StartupConf, StartupOpt, TlsConf, TlsRedirectorConf, LISTEN_ADDR_ENV,
 };
 use http::Extensions;
 use pandora_module_utils::pingora::{
-    Error, HttpPeer, ProxyHttp, ResponseHeader, Session, SessionWrapper,
+    parse_absolute_form, Error, HttpPeer, ProxyHttp, ResponseHeader, Session, SessionWrapper,
 };
 use pandora_module_utils::{RequestFilter, RequestFilterResult};
 use pingora::modules::http::HttpModules;
@@ -110,8 +111,9 @@ impl AppResult {
 
 /// A basic Pingora app implementation, to be passed to [`StartupConf::into_server`]
 ///
-/// This app will only handle the `request_filter`, `upstream_peer`, `upstream_response_filter` and
-/// `logging` phases. All processing will be delegated to the respective `RequestFilter` methods.
+/// This app will only handle the `early_request_filter`, `request_filter`, `upstream_peer`,
+/// `response_filter` and `logging` phases. All processing will be delegated to the respective
+/// `RequestFilter` methods.
 #[derive(Debug)]
 pub struct DefaultApp<H> {
     handler: H,
@@ -193,6 +195,8 @@ impl<H> DefaultApp<H> {
                     let upstream_peer = self.upstream_peer(&mut session, &mut ctx).await?;
                     let mut response_header = upstream_response(&mut session, upstream_peer)?;
                     self.upstream_response_filter(&mut session, &mut response_header, &mut ctx);
+                    self.response_filter(&mut session, &mut response_header, &mut ctx)
+                        .await?;
                     session
                         .downstream_modules_ctx
                         .response_header_filter(&mut response_header, false)
@@ -258,6 +262,10 @@ where
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<(), Box<Error>> {
+        if let Some(uri) = parse_absolute_form(&session.req_header().uri) {
+            session.req_header_mut().set_uri(uri);
+        }
+
         let mut session = SessionWrapperImpl::new(session, &mut ctx.extensions, self.capture_body);
         self.handler
             .early_request_filter(&mut session, &mut ctx.handler)
@@ -294,6 +302,18 @@ where
         }
     }
 
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        let mut session = SessionWrapperImpl::new(session, &mut ctx.extensions, self.capture_body);
+        self.handler
+            .response_filter(&mut session, upstream_response, &mut ctx.handler)
+            .await
+    }
+
     async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX) {
         let mut session = SessionWrapperImpl::new(session, &mut ctx.extensions, self.capture_body);
         self.handler
@@ -0,0 +1,205 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Limiting the rate at which new connections are handled
+
+use async_trait::async_trait;
+use log::debug;
+use pingora::apps::ServerApp;
+use pingora::protocols::Stream;
+use pingora::server::ShutdownWatch;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Token bucket determining how long a new connection has to wait before being handled
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    /// Number of tokens added per second
+    rate: f64,
+    /// Maximum number of tokens in the bucket
+    capacity: f64,
+    /// Maximum time a connection can be deferred, limits the number of tokens reserved in advance
+    max_delay: Duration,
+    /// Number of tokens available (negative if reserved in advance) and time of the last update
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// Creates a new full bucket. `rate` and `capacity` are expected to be larger than zero.
+    pub(crate) fn new(rate: u32, capacity: u32, max_delay: Duration, now: Instant) -> Self {
+        Self {
+            rate: rate.into(),
+            capacity: capacity.into(),
+            max_delay,
+            state: Mutex::new((capacity.into(), now)),
+        }
+    }
+
+    /// Takes a token from the bucket. If the bucket is empty, the token is reserved in advance and
+    /// the time until it becomes available is returned.
+    ///
+    /// If the token would only become available after more than `max_delay`, nothing is reserved
+    /// and `None` is returned.
+    pub(crate) fn reserve(&self, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+
+        let elapsed = now.saturating_duration_since(*last).as_secs_f64();
+        *tokens = (*tokens + elapsed * self.rate).min(self.capacity);
+        *last = now.max(*last);
+
+        let remaining = *tokens - 1.0;
+        let delay = if remaining >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-remaining / self.rate)
+        };
+        if delay > self.max_delay {
+            return None;
+        }
+
+        *tokens = remaining;
+        Some(delay)
+    }
+}
+
+/// Server app wrapper deferring new connections if the configured connection rate is exceeded
+pub(crate) struct ThrottledApp<A> {
+    inner: Arc<A>,
+    bucket: Option<TokenBucket>,
+}
+
+impl<A> ThrottledApp<A> {
+    /// Wraps a server app, no throttling takes place if `bucket` is `None`.
+    pub(crate) fn new(inner: A, bucket: Option<TokenBucket>) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            bucket,
+        }
+    }
+}
+
+#[async_trait]
+impl<A> ServerApp for ThrottledApp<A>
+where
+    A: ServerApp + Send + Sync + 'static,
+{
+    async fn process_new(
+        self: &Arc<Self>,
+        session: Stream,
+        shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        if let Some(bucket) = &self.bucket {
+            let Some(delay) = bucket.reserve(Instant::now()) else {
+                debug!("connection rate exceeded, dropping new connection");
+                return None;
+            };
+
+            if !delay.is_zero() {
+                debug!("connection rate exceeded, deferring new connection by {delay:?}");
+                let mut shutdown = shutdown.clone();
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = shutdown.changed() => {
+                        debug!("server shutting down, dropping deferred connection");
+                        return None;
+                    }
+                }
+            }
+        }
+
+        self.inner.process_new(session, shutdown).await
+    }
+
+    fn cleanup(&self) {
+        self.inner.cleanup()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_delay(delay: Duration, expected_ms: u64) {
+        let diff = delay.as_secs_f64() * 1000.0 - expected_ms as f64;
+        assert!(diff.abs() < 0.001, "{delay:?}");
+    }
+
+    #[test]
+    fn burst() {
+        let start = Instant::now();
+        let bucket = TokenBucket::new(10, 5, Duration::from_secs(10), start);
+
+        // Burst capacity is available immediately, further connections are spaced out
+        for expected in [0, 0, 0, 0, 0, 100, 200, 300] {
+            assert_delay(bucket.reserve(start).unwrap(), expected);
+        }
+
+        // Reserved tokens have to be replenished first
+        assert_delay(
+            bucket.reserve(start + Duration::from_millis(350)).unwrap(),
+            50,
+        );
+
+        // Bucket doesn’t fill up beyond its capacity
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..5 {
+            assert_eq!(bucket.reserve(much_later), Some(Duration::ZERO));
+        }
+        assert_delay(bucket.reserve(much_later).unwrap(), 100);
+    }
+
+    #[test]
+    fn max_delay() {
+        let start = Instant::now();
+        let bucket = TokenBucket::new(10, 2, Duration::from_millis(300), start);
+
+        for expected in [0, 0, 100, 200, 300] {
+            assert_delay(bucket.reserve(start).unwrap(), expected);
+        }
+
+        // Connections exceeding the maximum delay don’t reserve any tokens
+        for _ in 0..100 {
+            assert_eq!(bucket.reserve(start), None);
+        }
+        assert_eq!(bucket.reserve(start + Duration::from_millis(50)), None);
+        assert_delay(
+            bucket.reserve(start + Duration::from_millis(100)).unwrap(),
+            300,
+        );
+    }
+
+    #[test]
+    fn rate_under_load() {
+        let start = Instant::now();
+        let bucket = TokenBucket::new(100, 10, Duration::from_secs(10), start);
+
+        // 1000 connections arriving within one second, count those handled within that second
+        let handled = (0..1000)
+            .map(|i| {
+                let now = start + Duration::from_millis(i);
+                now + bucket.reserve(now).unwrap()
+            })
+            .filter(|time| *time <= start + Duration::from_secs(1))
+            .count();
+        assert!((100..=110).contains(&handled), "{handled}");
+
+        // With a lower maximum delay, excess connections are dropped instead
+        let bucket = TokenBucket::new(100, 10, Duration::from_millis(100), start);
+        let handled = (0..1000)
+            .filter_map(|i| bucket.reserve(start + Duration::from_millis(i)))
+            .count();
+        assert!((100..=130).contains(&handled), "{handled}");
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! In-memory cache of preloaded files.
+
+use bytes::Bytes;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+use crate::metadata::{content_etag, Metadata};
+
+/// Contents of a preloaded file along with the metadata used to validate it
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CacheEntry {
+    data: Bytes,
+    modified: Option<SystemTime>,
+    /// ETag derived from the file contents, calculated on first use
+    etag: OnceLock<String>,
+}
+
+/// Keeps contents of preloaded files in memory
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct FileCache {
+    files: HashMap<PathBuf, CacheEntry>,
+}
+
+impl FileCache {
+    /// Loads all files matching a glob pattern into the cache. The pattern is resolved relative to
+    /// the root directory, files outside the root directory are ignored.
+    ///
+    /// Errors are logged but otherwise ignored, a pattern without any matching files results in a
+    /// warning.
+    pub(crate) fn preload(&mut self, root: &Path, pattern: &str) {
+        let root_pattern = if let Some(root) = root.to_str() {
+            glob::Pattern::escape(root)
+        } else {
+            warn!("cannot preload files, root directory {root:?} isn't valid Unicode");
+            return;
+        };
+        let full_pattern = format!("{root_pattern}/{}", pattern.trim_start_matches('/'));
+
+        let paths = match glob::glob(&full_pattern) {
+            Ok(paths) => paths,
+            Err(err) => {
+                warn!("ignoring invalid preload pattern `{pattern}`: {err}");
+                return;
+            }
+        };
+
+        let mut found = false;
+        for path in paths {
+            let path = match path {
+                Ok(path) => path,
+                Err(err) => {
+                    warn!("failed resolving preload pattern `{pattern}`: {err}");
+                    continue;
+                }
+            };
+
+            let path = match path.canonicalize() {
+                Ok(path) => path,
+                Err(err) => {
+                    warn!("failed resolving preload path {path:?}: {err}");
+                    continue;
+                }
+            };
+
+            if !path.starts_with(root) {
+                warn!("not preloading {path:?}, it is outside the root directory");
+                continue;
+            }
+
+            let modified = match path.metadata() {
+                Ok(meta) if meta.is_file() => meta.modified().ok(),
+                Ok(_) => continue,
+                Err(err) => {
+                    warn!("failed retrieving metadata for preload path {path:?}: {err}");
+                    continue;
+                }
+            };
+
+            match std::fs::read(&path) {
+                Ok(data) => {
+                    debug!("preloaded file {path:?}");
+                    let data = data.into();
+                    self.files.insert(
+                        path,
+                        CacheEntry {
+                            data,
+                            modified,
+                            etag: OnceLock::new(),
+                        },
+                    );
+                    found = true;
+                }
+                Err(err) => warn!("failed preloading file {path:?}: {err}"),
+            }
+        }
+
+        if !found {
+            warn!("preload pattern `{pattern}` didn't match any files");
+        }
+    }
+
+    /// Retrieves the contents of a file if it is cached. The size and last modified time of the
+    /// file are verified to match the cached contents, so that stale cache entries are ignored.
+    pub(crate) fn get(&self, path: &Path, meta: &Metadata) -> Option<&Bytes> {
+        self.entry(path, meta).map(|entry| &entry.data)
+    }
+
+    /// Retrieves an ETag derived from the SHA-256 hash of the contents if the file is cached. The
+    /// hash is only calculated once per file.
+    pub(crate) fn content_etag(&self, path: &Path, meta: &Metadata) -> Option<&str> {
+        self.entry(path, meta).map(|entry| {
+            entry
+                .etag
+                .get_or_init(|| content_etag(&entry.data))
+                .as_str()
+        })
+    }
+
+    fn entry(&self, path: &Path, meta: &Metadata) -> Option<&CacheEntry> {
+        self.files.get(path).filter(|entry| {
+            entry.data.len() as u64 == meta.size && entry.modified == meta.modified_time
+        })
+    }
+}
@@ -14,7 +14,7 @@
 
 //! Handles compression for a Pingora session, both static (precompressed files) and dynamic.
 
-use http::{header, status::StatusCode};
+use http::{header, status::StatusCode, Method};
 use pandora_module_utils::pingora::{Error, ResponseCompression, ResponseHeader, SessionWrapper};
 use std::path::{Path, PathBuf};
 
@@ -79,7 +79,7 @@ impl<'a> Compression<'a> {
     /// add `Content-Encoding` HTTP header among other thins.
     pub(crate) fn transform_header(
         &mut self,
-        _session: &mut impl SessionWrapper,
+        session: &mut impl SessionWrapper,
         mut header: Box<ResponseHeader>,
     ) -> Result<Box<ResponseHeader>, Box<Error>> {
         let mut header =
@@ -87,11 +87,22 @@ impl<'a> Compression<'a> {
                 // No actual content here, so no compression
                 header
             } else if let Some(algorithm) = self.precompressed_active {
-                // File is pre-compressed, only need to adjust header
+                // File is pre-compressed, only need to adjust header and make sure it isn’t
+                // compressed again
                 header.insert_header(header::CONTENT_ENCODING, algorithm.name())?;
+                session.disable_compression();
                 header
             } else {
                 // Pingora’s dynamic compression will take care of this if necessary
+                if self.dynamic
+                    && session.req_header().method == Method::HEAD
+                    && !session.compression_disabled()
+                {
+                    // Without a body nothing gets compressed, yet a GET request would produce a
+                    // compressed response of unknown length. Reporting the uncompressed length
+                    // would be misleading, so leave it out like a chunked GET response would.
+                    header.remove_header(&header::CONTENT_LENGTH);
+                }
                 header
             };
 
@@ -19,6 +19,7 @@ use mime_guess::mime::FromStrError;
 use mime_guess::Mime;
 use pandora_module_utils::{DeserializeMap, OneOrMany};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::ffi::OsString;
 use std::path::PathBuf;
 
@@ -70,9 +71,9 @@ pub struct StaticFilesOpt {
     pub canonicalize_uri: Option<bool>,
 
     /// Index file to look for when displaying a directory. This command line flag can be specified
-    /// multiple times.
-    #[clap(long)]
-    pub index_file: Option<Vec<String>>,
+    /// multiple times, the first file found is used.
+    #[clap(long, alias = "index-file")]
+    pub index_files: Option<Vec<String>>,
 
     /// URI path of the page to display instead of the default Not Found page, e.g. /404.html
     #[clap(long)]
@@ -92,6 +93,36 @@ pub struct StaticFilesOpt {
     /// specified multiple times.
     #[clap(long, value_parser = clap::value_parser!(String))]
     pub declare_charset_types: Option<Vec<MimeMatch>>,
+
+    /// Files to load into memory on startup, e.g. /css/*.css. Glob patterns are resolved relative
+    /// to the root directory. This command line flag can be specified multiple times.
+    #[clap(long)]
+    pub preload: Option<Vec<String>>,
+
+    /// Display a list of directory entries if a directory has no index file.
+    #[clap(long)]
+    pub directory_listing: Option<bool>,
+
+    /// Include entries with names starting with a dot in directory listings.
+    #[clap(long)]
+    pub show_hidden: Option<bool>,
+
+    /// Produce weak ETag validators (W/ prefix).
+    #[clap(long)]
+    pub etag_weak: Option<bool>,
+
+    /// Derive ETags of preloaded files from a hash of their contents rather than modification time
+    /// and size.
+    #[clap(long)]
+    pub etag_content_hash: Option<bool>,
+
+    /// Serve files behind symbolic links pointing outside the root directory.
+    #[clap(long)]
+    pub follow_symlinks: Option<bool>,
+
+    /// Infer the MIME type of files without a known extension from their contents.
+    #[clap(long)]
+    pub content_type_sniffing: Option<bool>,
 }
 
 /// Configuration file settings of the static files module
@@ -103,22 +134,59 @@ pub struct StaticFilesConf {
     /// Redirect /file%2e.txt to /file.txt and /dir to /dir/.
     pub canonicalize_uri: bool,
 
-    /// List of index files to look for in a directory.
-    pub index_file: OneOrMany<String>,
+    /// List of index files to look for in a directory, the first file found is used. If not
+    /// set, `index.html` is used.
+    #[pandora(alias = "index_file")]
+    pub index_files: Option<OneOrMany<String>>,
 
     /// URI path of the page to display instead of the default Not Found page, e.g. /404.html
     pub page_404: Option<String>,
 
+    /// URI paths of pages to display instead of the default error pages, keyed by status code,
+    /// e.g. `403: /403.html`. An entry for status code 404 takes precedence over `page_404`.
+    pub error_pages: HashMap<u16, String>,
+
     /// List of file extensions to check when looking for pre-compressed versions of a file.
     /// Supported file extensions are gz (gzip), zz (zlib deflate), z (compress), br (Brotli),
     /// zst (Zstandard).
     pub precompressed: OneOrMany<CompressionAlgorithm>,
 
-    /// The character set to declare for text files.
+    /// The character set to declare for text files. No character set is declared if this is
+    /// empty.
+    #[pandora(alias = "default_charset")]
     pub declare_charset: String,
 
     /// List of MIME types that the `declare_charset` setting should apply to.
     pub declare_charset_types: OneOrMany<MimeMatch>,
+
+    /// List of files to load into memory on startup, e.g. /css/*.css. Glob patterns are resolved
+    /// relative to the root directory.
+    pub preload: OneOrMany<String>,
+
+    /// If `true`, a list of directory entries will be displayed for directories without an index
+    /// file.
+    pub directory_listing: bool,
+
+    /// If `true`, directory listings will include entries with names starting with a dot.
+    pub show_hidden: bool,
+
+    /// If `true`, ETags will be weak validators (`W/` prefix).
+    pub etag_weak: bool,
+
+    /// If `true`, ETags of preloaded files will be derived from a SHA-256 hash of the file contents
+    /// rather than its modification time and size.
+    pub etag_content_hash: bool,
+
+    /// If `true`, symbolic links will be followed even if they point outside the root directory.
+    pub follow_symlinks: bool,
+
+    /// If `true`, the MIME type of files without a known file extension will be inferred from the
+    /// first bytes of the file rather than defaulting to `application/octet-stream`.
+    pub content_type_sniffing: bool,
+
+    /// `Cache-Control` header values to send, keyed by glob patterns like `*.html` matched against
+    /// the file name. If multiple patterns match, the longest one is used.
+    pub cache_control: HashMap<String, String>,
 }
 
 impl StaticFilesConf {
@@ -133,8 +201,8 @@ impl StaticFilesConf {
             self.canonicalize_uri = canonicalize_uri;
         }
 
-        if let Some(index_file) = opt.index_file {
-            self.index_file = index_file.into();
+        if let Some(index_files) = opt.index_files {
+            self.index_files = Some(index_files.into());
         }
 
         if opt.page_404.is_some() {
@@ -152,6 +220,34 @@ impl StaticFilesConf {
         if let Some(declare_charset_types) = opt.declare_charset_types {
             self.declare_charset_types = declare_charset_types.into();
         }
+
+        if let Some(preload) = opt.preload {
+            self.preload = preload.into();
+        }
+
+        if let Some(directory_listing) = opt.directory_listing {
+            self.directory_listing = directory_listing;
+        }
+
+        if let Some(show_hidden) = opt.show_hidden {
+            self.show_hidden = show_hidden;
+        }
+
+        if let Some(etag_weak) = opt.etag_weak {
+            self.etag_weak = etag_weak;
+        }
+
+        if let Some(etag_content_hash) = opt.etag_content_hash {
+            self.etag_content_hash = etag_content_hash;
+        }
+
+        if let Some(follow_symlinks) = opt.follow_symlinks {
+            self.follow_symlinks = follow_symlinks;
+        }
+
+        if let Some(content_type_sniffing) = opt.content_type_sniffing {
+            self.content_type_sniffing = content_type_sniffing;
+        }
     }
 }
 
@@ -160,11 +256,20 @@ impl Default for StaticFilesConf {
         Self {
             root: None,
             canonicalize_uri: true,
-            index_file: Default::default(),
+            index_files: None,
             page_404: None,
+            error_pages: HashMap::new(),
             precompressed: Default::default(),
             declare_charset: "utf-8".to_owned(),
             declare_charset_types: Default::default(),
+            preload: Default::default(),
+            directory_listing: false,
+            show_hidden: false,
+            etag_weak: false,
+            etag_content_hash: false,
+            follow_symlinks: false,
+            content_type_sniffing: false,
+            cache_control: HashMap::new(),
         }
     }
 }
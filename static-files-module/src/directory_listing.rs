@@ -0,0 +1,169 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Generating directory listings.
+
+use http::{header, status::StatusCode};
+use httpdate::fmt_http_date;
+use log::debug;
+use maud::{html, DOCTYPE};
+use pandora_module_utils::pingora::{Error, ResponseHeader, SessionWrapper};
+use pandora_module_utils::standard_response::send_response;
+use percent_encoding::{percent_decode_str, percent_encode, AsciiSet};
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::path::URI_ESC_CHARSET;
+
+// Characters that would change the meaning of a link need to be escaped in addition.
+const LINK_ESC_CHARSET: &AsciiSet = &URI_ESC_CHARSET.add(b'#').add(b'?').add(b'%');
+
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    link: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+/// Contents of a directory to be displayed.
+#[derive(Debug)]
+pub(crate) struct DirectoryListing {
+    entries: Vec<Entry>,
+}
+
+impl DirectoryListing {
+    /// Reads the entries of a directory. Unless `show_hidden` is `true`, entries with names
+    /// starting with a dot are left out.
+    pub(crate) fn read(dir: &Path, show_hidden: bool) -> std::io::Result<Self> {
+        let mut entries = Vec::new();
+        for entry in dir.read_dir()? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if !show_hidden && name.as_encoded_bytes().starts_with(b".") {
+                continue;
+            }
+
+            // Resolve symlinks, entries that cannot be resolved aren’t worth displaying.
+            let meta = match std::fs::metadata(entry.path()) {
+                Ok(meta) => meta,
+                Err(err) => {
+                    debug!("skipping directory entry {:?}: {err}", entry.path());
+                    continue;
+                }
+            };
+
+            let mut link = percent_encode(name.as_encoded_bytes(), LINK_ESC_CHARSET).to_string();
+            if meta.is_dir() {
+                link.push('/');
+            }
+
+            entries.push(Entry {
+                name: name.to_string_lossy().into_owned(),
+                link,
+                is_dir: meta.is_dir(),
+                size: meta.len(),
+                modified: meta.modified().ok(),
+            });
+        }
+
+        // Directories first, then files, both sorted by name
+        entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+        Ok(Self { entries })
+    }
+
+    /// Produces the HTML code of the directory listing. `base_path` is the URI path of the
+    /// directory as seen by the client, it has to end with a slash.
+    pub(crate) fn to_html(&self, base_path: &str, show_parent: bool) -> String {
+        let title = percent_decode_str(base_path).decode_utf8_lossy();
+        html! {
+            (DOCTYPE)
+            html {
+                head {
+                    meta charset="utf-8";
+                    title {
+                        "Index of " (title)
+                    }
+                }
+
+                body {
+                    h1 {
+                        "Index of " (title)
+                    }
+
+                    table {
+                        thead {
+                            tr {
+                                th { "Name" }
+                                th { "Size" }
+                                th { "Last modified" }
+                            }
+                        }
+
+                        tbody {
+                            @if show_parent {
+                                tr {
+                                    td {
+                                        a href={ (base_path) "../" } { "../" }
+                                    }
+                                    td { "-" }
+                                    td {}
+                                }
+                            }
+
+                            @for entry in &self.entries {
+                                tr {
+                                    td {
+                                        a href={ (base_path) (entry.link) } {
+                                            (entry.name)
+                                            @if entry.is_dir {
+                                                "/"
+                                            }
+                                        }
+                                    }
+                                    td {
+                                        @if entry.is_dir {
+                                            "-"
+                                        } @else {
+                                            (entry.size)
+                                        }
+                                    }
+                                    td {
+                                        @if let Some(modified) = entry.modified {
+                                            (fmt_http_date(modified))
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        .into()
+    }
+}
+
+/// Responds with the given directory listing page.
+pub(crate) async fn directory_listing_response(
+    session: &mut impl SessionWrapper,
+    text: String,
+) -> Result<(), Box<Error>> {
+    let mut header = ResponseHeader::build(StatusCode::OK, Some(2))?;
+    header.append_header(header::CONTENT_TYPE, "text/html;charset=utf-8")?;
+
+    send_response(session, header, text).await
+}
@@ -14,17 +14,50 @@
 
 //! Writing files to Pingora session.
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use http::status::StatusCode;
-use log::error;
+use log::{debug, error};
 use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
 use std::cmp::min;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
 use std::path::Path;
 
 const BUFFER_SIZE: usize = 64 * 1024;
 
+/// Checks whether a write error has been caused by the client closing the connection.
+fn is_disconnect(err: &Error) -> bool {
+    matches!(err.etype(), ErrorType::ConnectionClosed)
+        || err
+            .root_cause()
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|err| {
+                matches!(
+                    err.kind(),
+                    ErrorKind::BrokenPipe
+                        | ErrorKind::ConnectionReset
+                        | ErrorKind::ConnectionAborted
+                )
+            })
+}
+
+/// Writes response data to the session. Returns `false` if the client closed the connection,
+/// this isn’t considered an error.
+async fn write_body(
+    session: &mut impl SessionWrapper,
+    data: Option<Bytes>,
+    end_of_stream: bool,
+) -> Result<bool, Box<Error>> {
+    match session.write_response_body(data, end_of_stream).await {
+        Ok(()) => Ok(true),
+        Err(err) if is_disconnect(&err) => {
+            debug!("client closed the connection: {err}");
+            Ok(false)
+        }
+        Err(err) => Err(err),
+    }
+}
+
 /// Writes a chunk of a file as a Pingora session response. The data will be passed through the
 /// compression handler first in case dynamic compression is enabled.
 pub(crate) async fn file_response(
@@ -65,11 +98,35 @@ pub(crate) async fn file_response(
         }
 
         buf.truncate(len);
-        session.write_response_body(Some(buf.into()), false).await?;
+        if !write_body(session, Some(buf.into()), false).await? {
+            // Dropping the file here, no point reading the rest of it
+            debug!("aborted writing {path:?} with {remaining} bytes left to be written");
+            return Ok(());
+        }
         remaining -= len;
     }
 
-    session.write_response_body(None, true).await?;
+    write_body(session, None, true).await?;
+
+    Ok(())
+}
+
+/// Writes a chunk of cached file data as a Pingora session response.
+pub(crate) async fn cached_response(
+    session: &mut impl SessionWrapper,
+    data: &Bytes,
+    start: u64,
+    end: u64,
+) -> Result<(), Box<Error>> {
+    if write_body(
+        session,
+        Some(data.slice(start as usize..=end as usize)),
+        false,
+    )
+    .await?
+    {
+        write_body(session, None, true).await?;
+    }
 
     Ok(())
 }
@@ -15,23 +15,29 @@
 //! Handler for the `request_filter` phase.
 
 use async_trait::async_trait;
-use http::{method::Method, status::StatusCode};
+use http::{header, method::Method, status::StatusCode, HeaderValue};
 use log::{debug, info, warn};
 use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
 use pandora_module_utils::standard_response::{error_response, redirect_response};
 use pandora_module_utils::{RequestFilter, RequestFilterResult};
+use std::collections::HashMap;
 use std::io::ErrorKind;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use crate::cache::FileCache;
 use crate::compression::Compression;
 use crate::configuration::StaticFilesConf;
-use crate::file_writer::file_response;
+use crate::directory_listing::{directory_listing_response, DirectoryListing};
+use crate::file_writer::{cached_response, file_response};
 use crate::metadata::Metadata;
 use crate::mime_matcher::MimeMatcher;
 use crate::path::{path_to_uri, resolve_uri};
 use crate::range::{extract_range, Range};
 use crate::CompressionAlgorithm;
 
+const DEFAULT_INDEX_FILE: &str = "index.html";
+
 const DEFAULT_TEXT_TYPES: &[&str] = &[
     "text/*",
     "*+xml",
@@ -46,11 +52,222 @@ const DEFAULT_TEXT_TYPES: &[&str] = &[
 pub struct StaticFilesHandler {
     root: Option<PathBuf>,
     canonicalize_uri: bool,
-    index_file: Vec<String>,
-    page_404: Option<String>,
+    index_files: Vec<String>,
+    error_pages: Arc<HashMap<StatusCode, String>>,
     precompressed: Vec<CompressionAlgorithm>,
     declare_charset: String,
     declare_charset_matcher: MimeMatcher,
+    cache: FileCache,
+    directory_listing: bool,
+    show_hidden: bool,
+    etag_weak: bool,
+    etag_content_hash: bool,
+    follow_symlinks: bool,
+    content_type_sniffing: bool,
+    cache_control: Vec<(glob::Pattern, HeaderValue)>,
+}
+
+/// Error pages of an outer static files handler, stored in `SessionWrapper::extensions`. These
+/// serve as defaults for handlers configured per virtual host.
+#[derive(Debug, Clone)]
+struct DefaultErrorPages(Arc<HashMap<StatusCode, String>>);
+
+impl StaticFilesHandler {
+    /// Resolves the path of the error page configured for the status code if any. If this handler
+    /// has no error page for the status code, the default error pages are considered.
+    fn resolve_error_page(
+        &self,
+        session: &impl SessionWrapper,
+        root: &Path,
+        status: StatusCode,
+    ) -> Option<PathBuf> {
+        let page = self.error_pages.get(&status).or_else(|| {
+            let DefaultErrorPages(defaults) = session.extensions().get()?;
+            defaults.get(&status)
+        })?;
+        debug!("error page for status {status} is {page}");
+        match resolve_uri(page, root, self.follow_symlinks) {
+            Ok(path) => Some(path),
+            Err(err) => {
+                warn!("Failed resolving error page {page}: {err}");
+                None
+            }
+        }
+    }
+
+    /// Produces an error response, using the configured error page if it exists. The standard
+    /// error page is used as fallback.
+    async fn send_error(
+        &self,
+        session: &mut impl SessionWrapper,
+        root: &Path,
+        status: StatusCode,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        if let Some(path) = self.resolve_error_page(session, root, status) {
+            if path.is_file() {
+                match self.serve_file(session, path, Some(status)).await? {
+                    None => return Ok(RequestFilterResult::ResponseSent),
+                    Some(error) => {
+                        debug!("failed serving error page, status {error}");
+                    }
+                }
+            } else {
+                warn!("Error page {path:?} is not a file, ignoring");
+            }
+        }
+
+        error_response(session, status).await?;
+        Ok(RequestFilterResult::ResponseSent)
+    }
+
+    /// Determines the configured `Cache-Control` header value for a file from its name.
+    fn cache_control(&self, path: &Path) -> Option<&HeaderValue> {
+        let name = path.file_name()?.to_str()?;
+        self.cache_control
+            .iter()
+            .find(|(pattern, _)| pattern.matches(name))
+            .map(|(_, value)| value)
+    }
+
+    /// Sends the file as response. If `error_status` is present, the file is an error page: it is
+    /// sent with the given status code and conditional or range requests aren’t considered.
+    ///
+    /// If no response could be sent, the status code of the error response to be produced
+    /// instead is returned.
+    async fn serve_file(
+        &self,
+        session: &mut impl SessionWrapper,
+        path: PathBuf,
+        error_status: Option<StatusCode>,
+    ) -> Result<Option<StatusCode>, Box<Error>> {
+        let mut compression = Compression::new(session, &self.precompressed);
+
+        let (path, orig_path) =
+            if let Some(precompressed_path) = compression.rewrite_path(session, &path) {
+                (precompressed_path, Some(path))
+            } else {
+                (path, None)
+            };
+
+        let cache_control = if error_status.is_some() {
+            None
+        } else {
+            self.cache_control(orig_path.as_ref().unwrap_or(&path))
+        };
+
+        let mut meta = match Metadata::from_path(&path, orig_path.as_ref()) {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == ErrorKind::InvalidInput => {
+                warn!("Path {path:?} is not a regular file, denying access");
+                return Ok(Some(StatusCode::FORBIDDEN));
+            }
+            Err(err) => {
+                warn!("failed retrieving metadata for path {path:?}: {err}");
+                return Ok(Some(StatusCode::INTERNAL_SERVER_ERROR));
+            }
+        };
+
+        if self.etag_content_hash {
+            // Hashing files on each request would be too expensive, only preloaded files qualify
+            if let Some(etag) = self.cache.content_etag(&path, &meta) {
+                meta.etag = etag.to_owned();
+            }
+        }
+        if self.etag_weak {
+            meta.set_weak_etag();
+        }
+        if self.content_type_sniffing {
+            // Pre-compressed variants are never sniffed, the original file is used instead
+            let (sniff_path, data) = match &orig_path {
+                Some(orig_path) => (orig_path, None),
+                None => (&path, self.cache.get(&path, &meta)),
+            };
+            if let Err(err) = meta.sniff_mime(sniff_path, data.map(|data| data.as_ref())) {
+                debug!("failed determining MIME type of {sniff_path:?} from its contents: {err}");
+            }
+        }
+
+        if error_status.is_none() && meta.has_failed_precondition(session) {
+            debug!("If-Match/If-Unmodified-Since precondition failed");
+            let header = meta.to_custom_header(StatusCode::PRECONDITION_FAILED)?;
+            let header = compression.transform_header(session, header)?;
+            session.write_response_header(header, true).await?;
+            return Ok(None);
+        }
+
+        if error_status.is_none() && meta.is_not_modified(session) {
+            debug!("If-None-Match/If-Modified-Since check resulted in Not Modified");
+            let mut header = meta.to_custom_header(StatusCode::NOT_MODIFIED)?;
+            if let Some(cache_control) = cache_control {
+                header.insert_header(header::CACHE_CONTROL, cache_control.clone())?;
+            }
+            let header = compression.transform_header(session, header)?;
+            session.write_response_header(header, true).await?;
+            return Ok(None);
+        }
+
+        let charset = if !self.declare_charset.is_empty()
+            && meta.mime.get_param(mime_guess::mime::CHARSET).is_none()
+            && self.declare_charset_matcher.matches(&meta.mime)
+        {
+            Some(self.declare_charset.as_str())
+        } else {
+            None
+        };
+
+        let range = if error_status.is_none() {
+            extract_range(session, &meta)
+        } else {
+            None
+        };
+        // Byte range to be sent, `None` for an empty file
+        let (mut header, body_range) = match range {
+            Some(Range::Valid(start, end)) => {
+                debug!("bytes range requested: {start}-{end}");
+                let header = meta.to_partial_content_header(charset, start, end)?;
+                let header = compression.transform_header(session, header)?;
+                (header, Some((start, end)))
+            }
+            Some(Range::OutOfBounds) => {
+                debug!("requested bytes range is out of bounds");
+                let header = meta.to_not_satisfiable_header(charset)?;
+                let header = compression.transform_header(session, header)?;
+                session.write_response_header(header, true).await?;
+                return Ok(None);
+            }
+            None => {
+                // Range is either missing or cannot be parsed, produce the entire file.
+                let header = meta.to_response_header(charset)?;
+                let header = compression.transform_header(session, header)?;
+                (header, meta.size.checked_sub(1).map(|end| (0, end)))
+            }
+        };
+
+        if let Some(status) = error_status {
+            header.set_status(status)?;
+        }
+
+        if let Some(cache_control) = cache_control {
+            header.insert_header(header::CACHE_CONTROL, cache_control.clone())?;
+        }
+
+        let body_range = body_range.filter(|_| session.req_header().method != Method::HEAD);
+        session
+            .write_response_header(header, body_range.is_none())
+            .await?;
+
+        if let Some((start, end)) = body_range {
+            if let Some(data) = self.cache.get(&path, &meta) {
+                debug!("serving preloaded file {path:?}");
+                cached_response(session, data, start, end).await?;
+            } else {
+                // sendfile would be nice but not currently possible within pingora-proxy (see
+                // https://github.com/cloudflare/pingora/issues/160)
+                file_response(session, &path, start, end).await?;
+            }
+        }
+        Ok(None)
+    }
 }
 
 #[async_trait]
@@ -66,6 +283,13 @@ impl RequestFilter for StaticFilesHandler {
         session: &mut impl SessionWrapper,
         _ctx: &mut Self::CTX,
     ) -> Result<RequestFilterResult, Box<Error>> {
+        if !self.error_pages.is_empty() {
+            // Outermost handler’s error pages become the defaults for virtual hosts
+            session
+                .extensions_mut()
+                .get_or_insert_with(|| DefaultErrorPages(self.error_pages.clone()));
+        }
+
         let root = if let Some(root) = self.root.as_ref() {
             root
         } else {
@@ -76,31 +300,14 @@ impl RequestFilter for StaticFilesHandler {
         let uri = session.uri();
         debug!("received URI path {}", uri.path());
 
-        let (mut path, not_found) = match resolve_uri(uri.path(), root) {
-            Ok(path) => (path, false),
-            Err(err) if err.kind() == ErrorKind::NotFound => {
-                debug!("canonicalizing resulted in NotFound error");
-
-                let path = self.page_404.as_ref().and_then(|page_404| {
-                    debug!("error page is {page_404}");
-                    match resolve_uri(page_404, root) {
-                        Ok(path) => Some(path),
-                        Err(err) => {
-                            warn!("Failed resolving error page {page_404}: {err}");
-                            None
-                        }
-                    }
-                });
-
-                if let Some(path) = path {
-                    (path, true)
-                } else {
-                    error_response(session, StatusCode::NOT_FOUND).await?;
-                    return Ok(RequestFilterResult::ResponseSent);
-                }
-            }
+        let mut path = match resolve_uri(uri.path(), root, self.follow_symlinks) {
+            Ok(path) => path,
             Err(err) => {
                 let status = match err.kind() {
+                    ErrorKind::NotFound => {
+                        debug!("canonicalizing resulted in NotFound error");
+                        StatusCode::NOT_FOUND
+                    }
                     ErrorKind::InvalidInput => {
                         warn!("rejecting invalid path {}", uri.path());
                         StatusCode::BAD_REQUEST
@@ -118,14 +325,13 @@ impl RequestFilter for StaticFilesHandler {
                         StatusCode::INTERNAL_SERVER_ERROR
                     }
                 };
-                error_response(session, status).await?;
-                return Ok(RequestFilterResult::ResponseSent);
+                return self.send_error(session, root, status).await;
             }
         };
 
         debug!("translated into file path {path:?}");
 
-        if self.canonicalize_uri && !not_found {
+        if self.canonicalize_uri {
             if let Some(mut canonical) = path_to_uri(&path, root) {
                 if canonical != uri.path() {
                     if let Some(query) = uri.query() {
@@ -151,12 +357,13 @@ impl RequestFilter for StaticFilesHandler {
         }
 
         if path.is_dir() {
-            for filename in &self.index_file {
-                let candidate = path.join(filename);
-                if candidate.is_file() {
-                    debug!("using directory index file {filename}");
-                    path = candidate;
-                }
+            if let Some(filename) = self
+                .index_files
+                .iter()
+                .find(|filename| path.join(filename).is_file())
+            {
+                debug!("using directory index file {filename}");
+                path = path.join(filename);
             }
         }
 
@@ -168,91 +375,45 @@ impl RequestFilter for StaticFilesHandler {
             }
             _ => {
                 warn!("Denying method {}", session.req_header().method);
-                error_response(session, StatusCode::METHOD_NOT_ALLOWED).await?;
-                return Ok(RequestFilterResult::ResponseSent);
+                return self
+                    .send_error(session, root, StatusCode::METHOD_NOT_ALLOWED)
+                    .await;
             }
         }
 
-        let mut compression = Compression::new(session, &self.precompressed);
-
-        let (path, orig_path) =
-            if let Some(precompressed_path) = compression.rewrite_path(session, &path) {
-                (precompressed_path, Some(path))
-            } else {
-                (path, None)
+        if self.directory_listing && path.is_dir() {
+            let listing = match DirectoryListing::read(&path, self.show_hidden) {
+                Ok(listing) => listing,
+                Err(err) => {
+                    warn!("failed reading directory {path:?}: {err}");
+                    return self
+                        .send_error(session, root, StatusCode::INTERNAL_SERVER_ERROR)
+                        .await;
+                }
             };
 
-        let meta = match Metadata::from_path(&path, orig_path.as_ref()) {
-            Ok(meta) => meta,
-            Err(err) if err.kind() == ErrorKind::InvalidInput => {
-                warn!("Path {path:?} is not a regular file, denying access");
-                error_response(session, StatusCode::FORBIDDEN).await?;
-                return Ok(RequestFilterResult::ResponseSent);
-            }
-            Err(err) => {
-                warn!("failed retrieving metadata for path {path:?}: {err}");
-                error_response(session, StatusCode::INTERNAL_SERVER_ERROR).await?;
-                return Ok(RequestFilterResult::ResponseSent);
+            // Links are built from the original URI so that they still work if a prefix has been
+            // removed from the path.
+            let mut base_path = session.original_uri().path().to_owned();
+            if !base_path.ends_with('/') {
+                base_path.push('/');
             }
-        };
-
-        if meta.has_failed_precondition(session) {
-            debug!("If-Match/If-Unmodified-Since precondition failed");
-            let header = meta.to_custom_header(StatusCode::PRECONDITION_FAILED)?;
-            let header = compression.transform_header(session, header)?;
-            session.write_response_header(header, true).await?;
-            return Ok(RequestFilterResult::ResponseSent);
-        }
 
-        if meta.is_not_modified(session) {
-            debug!("If-None-Match/If-Modified-Since check resulted in Not Modified");
-            let header = meta.to_custom_header(StatusCode::NOT_MODIFIED)?;
-            let header = compression.transform_header(session, header)?;
-            session.write_response_header(header, true).await?;
+            debug!("producing directory listing for {path:?}");
+            let text = listing.to_html(&base_path, &path != root);
+            directory_listing_response(session, text).await?;
             return Ok(RequestFilterResult::ResponseSent);
         }
 
-        let charset = if self.declare_charset_matcher.matches(&meta.mime) {
-            Some(self.declare_charset.as_str())
-        } else {
-            None
-        };
-
-        let (mut header, start, end) = match extract_range(session, &meta) {
-            Some(Range::Valid(start, end)) => {
-                debug!("bytes range requested: {start}-{end}");
-                let header = meta.to_partial_content_header(charset, start, end)?;
-                let header = compression.transform_header(session, header)?;
-                (header, start, end)
-            }
-            Some(Range::OutOfBounds) => {
-                debug!("requested bytes range is out of bounds");
-                let header = meta.to_not_satisfiable_header(charset)?;
-                let header = compression.transform_header(session, header)?;
-                session.write_response_header(header, true).await?;
-                return Ok(RequestFilterResult::ResponseSent);
-            }
-            None => {
-                // Range is either missing or cannot be parsed, produce the entire file.
-                let header = meta.to_response_header(charset)?;
-                let header = compression.transform_header(session, header)?;
-                (header, 0, meta.size - 1)
-            }
-        };
-
-        if not_found {
-            header.set_status(StatusCode::NOT_FOUND)?;
+        if path.is_dir() {
+            debug!("no index file found in directory {path:?}");
+            return self.send_error(session, root, StatusCode::NOT_FOUND).await;
         }
 
-        let send_body = session.req_header().method != Method::HEAD;
-        session.write_response_header(header, !send_body).await?;
-
-        if send_body {
-            // sendfile would be nice but not currently possible within pingora-proxy (see
-            // https://github.com/cloudflare/pingora/issues/160)
-            file_response(session, &path, start, end).await?;
+        match self.serve_file(session, path, None).await? {
+            Some(status) => self.send_error(session, root, status).await,
+            None => Ok(RequestFilterResult::ResponseSent),
         }
-        Ok(RequestFilterResult::ResponseSent)
     }
 }
 
@@ -272,6 +433,15 @@ impl TryFrom<StaticFilesConf> for StaticFilesHandler {
             None
         };
 
+        let mut cache = FileCache::default();
+        if let Some(root) = &root {
+            for pattern in conf.preload.iter() {
+                cache.preload(root, pattern);
+            }
+        } else if !conf.preload.is_empty() {
+            warn!("no root directory configured, ignoring preload setting");
+        }
+
         let mut declare_charset_matcher = MimeMatcher::new();
         if !conf.declare_charset_types.is_empty() {
             for mime in conf.declare_charset_types {
@@ -283,14 +453,69 @@ impl TryFrom<StaticFilesConf> for StaticFilesHandler {
             }
         }
 
+        let mut cache_control = conf
+            .cache_control
+            .into_iter()
+            .map(|(pattern, value)| {
+                let value = HeaderValue::try_from(value).map_err(|err| {
+                    Error::because(
+                        ErrorType::InternalError,
+                        format!("Invalid Cache-Control value for {pattern}"),
+                        err,
+                    )
+                })?;
+                let pattern = glob::Pattern::new(&pattern).map_err(|err| {
+                    Error::because(
+                        ErrorType::InternalError,
+                        format!("Invalid file name pattern {pattern}"),
+                        err,
+                    )
+                })?;
+                Ok((pattern, value))
+            })
+            .collect::<Result<Vec<_>, Box<Error>>>()?;
+
+        // Longer patterns are more specific, these should be checked first
+        cache_control.sort_by(|(a, _), (b, _)| {
+            b.as_str()
+                .len()
+                .cmp(&a.as_str().len())
+                .then_with(|| a.as_str().cmp(b.as_str()))
+        });
+
+        let mut error_pages = HashMap::with_capacity(conf.error_pages.len() + 1);
+        for (status, page) in conf.error_pages {
+            let status = StatusCode::from_u16(status).map_err(|err| {
+                Error::because(
+                    ErrorType::InternalError,
+                    format!("Invalid status code {status} for error page {page}"),
+                    err,
+                )
+            })?;
+            error_pages.insert(status, page);
+        }
+        if let Some(page_404) = conf.page_404 {
+            error_pages.entry(StatusCode::NOT_FOUND).or_insert(page_404);
+        }
+
         Ok(Self {
             root,
             canonicalize_uri: conf.canonicalize_uri,
-            index_file: conf.index_file.into(),
-            page_404: conf.page_404,
+            index_files: conf
+                .index_files
+                .map_or_else(|| vec![DEFAULT_INDEX_FILE.to_owned()], Into::into),
+            error_pages: Arc::new(error_pages),
             precompressed: conf.precompressed.into(),
             declare_charset: conf.declare_charset,
             declare_charset_matcher,
+            cache,
+            directory_listing: conf.directory_listing,
+            show_hidden: conf.show_hidden,
+            etag_weak: conf.etag_weak,
+            etag_content_hash: conf.etag_content_hash,
+            follow_symlinks: conf.follow_symlinks,
+            content_type_sniffing: conf.content_type_sniffing,
+            cache_control,
         })
     }
 }
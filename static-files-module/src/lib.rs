@@ -14,9 +14,11 @@
 
 #![doc = include_str!("../README.md")]
 
+mod cache;
 mod compression;
 mod compression_algorithm;
 mod configuration;
+mod directory_listing;
 mod file_writer;
 mod handler;
 pub mod metadata;
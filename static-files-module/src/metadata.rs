@@ -15,10 +15,12 @@
 //! File metadata handling
 
 use http::{header, status::StatusCode};
-use httpdate::fmt_http_date;
+use httpdate::{fmt_http_date, parse_http_date};
 use mime_guess::Mime;
 use pandora_module_utils::pingora::{ResponseHeader, SessionWrapper};
-use std::io::{Error, ErrorKind};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read};
 use std::path::Path;
 use std::time::SystemTime;
 
@@ -32,13 +34,95 @@ pub struct Metadata {
     /// Last modified time of the file in the format `Fri, 15 May 2015 15:34:21 GMT` if the time
     /// can be retrieved
     pub modified: Option<String>,
+    /// Last modified time of the file if it can be retrieved
+    pub modified_time: Option<SystemTime>,
     /// ETag header for the file, encoding last modified time and file size
     pub etag: String,
 }
 
+/// Produces an ETag value from the SHA-256 hash of the file contents.
+pub(crate) fn content_etag(data: &[u8]) -> String {
+    format!("\"{:x}\"", Sha256::digest(data))
+}
+
+/// Produces an ETag value from the last modified time and size of a file, with an optional suffix
+/// distinguishing representations of the same file.
+fn make_etag(meta: &std::fs::Metadata, suffix: Option<&str>) -> String {
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_secs());
+    if let Some(suffix) = suffix {
+        format!("\"{modified:x}-{:x}-{suffix}\"", meta.len())
+    } else {
+        format!("\"{modified:x}-{:x}\"", meta.len())
+    }
+}
+
+/// Number of bytes at the start of a file considered when inferring its MIME type
+const SNIFF_LENGTH: usize = 512;
+
+/// Infers the MIME type from the first bytes of a file. Returns `None` for unrecognized data.
+fn sniff_mime(data: &[u8]) -> Option<Mime> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"\x1f\x8b", "application/gzip"),
+    ];
+    const HTML_TAGS: &[&[u8]] = &[b"<!doctype html", b"<html", b"<head", b"<body"];
+
+    if let Some((_, mime)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| data.starts_with(signature))
+    {
+        return mime.parse().ok();
+    }
+
+    let text = data.strip_prefix(b"\xef\xbb\xbf").unwrap_or(data);
+    let is_text = match std::str::from_utf8(text) {
+        Ok(_) => true,
+        // Data might end in the middle of a character
+        Err(err) => err.error_len().is_none(),
+    } && !text
+        .iter()
+        .any(|b| b.is_ascii_control() && !b"\t\n\x0c\r".contains(b));
+    if !is_text {
+        return None;
+    }
+
+    let start = text
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(text.len());
+    let trimmed = &text[start..];
+    let is_html = HTML_TAGS.iter().any(|tag| {
+        trimmed.len() > tag.len()
+            && trimmed[..tag.len()].eq_ignore_ascii_case(tag)
+            && matches!(trimmed[tag.len()], b'>' | b' ' | b'\t' | b'\n' | b'\r')
+    });
+    Some(if is_html {
+        mime_guess::mime::TEXT_HTML
+    } else {
+        mime_guess::mime::TEXT_PLAIN
+    })
+}
+
+/// Parses an HTTP date from the given request header. Returns `None` if the header is missing or
+/// its value isn’t a valid date.
+fn header_date(session: &impl SessionWrapper, name: header::HeaderName) -> Option<SystemTime> {
+    let value = session.req_header().headers.get(name)?.to_str().ok()?;
+    parse_http_date(value).ok()
+}
+
 impl Metadata {
-    /// Collects the metadata for a file. If `orig_path` is present, it will be used to determine
-    /// the MIME type instead of `path`.
+    /// Collects the metadata for a file. If `orig_path` is present, `path` is considered a
+    /// pre-compressed variant of it. The MIME type and ETag will be determined by `orig_path`
+    /// then, with the ETag getting the file extension of `path` appended to keep the encodings
+    /// apart.
     ///
     /// This method will return any errors produced by [`std::fs::metadata()`]. It will also result
     /// in a [`ErrorKind::InvalidInput`] error if the path given doesn’t point to a regular file.
@@ -54,24 +138,77 @@ impl Metadata {
 
         let mime = mime_guess::from_path(orig_path.unwrap_or(path)).first_or_octet_stream();
         let size = meta.len();
-        let modified = meta.modified().ok().map(fmt_http_date);
-        let etag = format!(
-            "\"{:x}-{:x}\"",
-            meta.modified()
-                .ok()
-                .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
-                .map_or(0, |duration| duration.as_secs()),
-            meta.len()
-        );
+        let modified_time = meta.modified().ok();
+        let modified = modified_time.map(fmt_http_date);
+        let etag = match orig_path.and_then(|orig_path| orig_path.as_ref().metadata().ok()) {
+            Some(orig_meta) => make_etag(
+                &orig_meta,
+                path.as_ref()
+                    .extension()
+                    .and_then(|extension| extension.to_str()),
+            ),
+            None => make_etag(&meta, None),
+        };
 
         Ok(Self {
             mime,
             size,
             modified,
+            modified_time,
             etag,
         })
     }
 
+    /// Infers the MIME type from the file contents if it couldn’t be determined from the file
+    /// extension. If `data` is `None`, the beginning of the file is read from `path`.
+    pub fn sniff_mime<P: AsRef<Path> + ?Sized>(
+        &mut self,
+        path: &P,
+        data: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        if mime_guess::from_path(path).first().is_some() {
+            return Ok(());
+        }
+
+        let mime = if let Some(data) = data {
+            sniff_mime(&data[..data.len().min(SNIFF_LENGTH)])
+        } else {
+            let mut buffer = Vec::with_capacity(SNIFF_LENGTH);
+            File::open(path)?
+                .take(SNIFF_LENGTH as u64)
+                .read_to_end(&mut buffer)?;
+            sniff_mime(&buffer)
+        };
+
+        if let Some(mime) = mime {
+            self.mime = mime;
+        }
+        Ok(())
+    }
+
+    /// Marks the ETag as a weak validator by adding the `W/` prefix.
+    pub fn set_weak_etag(&mut self) {
+        if !self.etag.starts_with("W/") {
+            self.etag.insert_str(0, "W/");
+        }
+    }
+
+    /// Compares an entity tag from a request header to the ETag of the file. Weak comparison
+    /// ignores the `W/` prefix, with strong comparison weak entity tags never match.
+    pub(crate) fn matches_etag(&self, value: &str, weak_comparison: bool) -> bool {
+        if weak_comparison {
+            value.strip_prefix("W/").unwrap_or(value)
+                == self.etag.strip_prefix("W/").unwrap_or(&self.etag)
+        } else {
+            !value.starts_with("W/") && value == self.etag
+        }
+    }
+
+    /// Parses the last modified time of the file back, this has the precision of the header value.
+    fn modified_header_time(&self) -> Option<SystemTime> {
+        parse_http_date(self.modified.as_deref()?).ok()
+    }
+
     /// Checks `If-Match` and `If-Unmodified-Since` headers of the request to determine whether
     /// a `412 Precondition Failed` response should be produced.
     pub fn has_failed_precondition(&self, session: &impl SessionWrapper) -> bool {
@@ -84,14 +221,10 @@ impl Metadata {
                 && value
                     .split(',')
                     .map(str::trim)
-                    .all(|value| value != self.etag)
-        } else if let Some(value) = headers
-            .get(header::IF_UNMODIFIED_SINCE)
-            .and_then(|value| value.to_str().ok())
-        {
-            self.modified
-                .as_ref()
-                .is_some_and(|modified| modified != value)
+                    .all(|value| !self.matches_etag(value, false))
+        } else if let Some(since) = header_date(session, header::IF_UNMODIFIED_SINCE) {
+            self.modified_header_time()
+                .is_some_and(|modified| modified > since)
         } else {
             false
         }
@@ -109,14 +242,10 @@ impl Metadata {
                 || value
                     .split(',')
                     .map(str::trim)
-                    .any(|value| value == self.etag)
-        } else if let Some(value) = headers
-            .get(header::IF_MODIFIED_SINCE)
-            .and_then(|value| value.to_str().ok())
-        {
-            self.modified
-                .as_ref()
-                .is_some_and(|modified| modified == value)
+                    .any(|value| self.matches_etag(value, true))
+        } else if let Some(since) = header_date(session, header::IF_MODIFIED_SINCE) {
+            self.modified_header_time()
+                .is_some_and(|modified| modified <= since)
         } else {
             false
         }
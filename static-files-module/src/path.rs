@@ -16,10 +16,10 @@
 
 use percent_encoding::{percent_decode_str, percent_encode, AsciiSet, CONTROLS};
 use std::io::{Error, ErrorKind};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 // This matches pingora logic, see https://github.com/cloudflare/pingora/blob/2501d4adb038d93613c0edbd7c1e3b3de9b415b1/pingora-core/src/protocols/http/v1/server.rs#L934
-const URI_ESC_CHARSET: &AsciiSet = &CONTROLS.add(b' ').add(b'<').add(b'>').add(b'"');
+pub(crate) const URI_ESC_CHARSET: &AsciiSet = &CONTROLS.add(b' ').add(b'<').add(b'>').add(b'"');
 
 #[cfg(unix)]
 fn path_from_bytes(bytes: &[u8]) -> &std::ffi::OsStr {
@@ -38,29 +38,58 @@ fn path_from_bytes(bytes: &[u8]) -> String {
 
 /// Resolves the path from a URI against the path to a root directory.
 ///
+/// Empty path segments are ignored, so `/a//b.txt` resolves to the same file as `/a/b.txt`
+/// regardless of the operating system. `.` and `..` components are resolved before the file
+/// system is accessed. Unless `follow_symlinks` is `true`, the resulting path is then
+/// canonicalized and has to be located within the root directory, so symbolic links cannot point
+/// outside of it.
+///
 /// This will return an error under the following conditions:
 ///
 /// * Invalid path, not starting with a slash (/): results in [`ErrorKind::InvalidInput`]
-/// * Resolved path outside the root directory: results in [`ErrorKind::InvalidData`]
-/// * [`std::fs::canonicalize()`] failed: results in [`ErrorKind::NotFound`],
-///   [`ErrorKind::PermissionDenied`] and other errors
-pub fn resolve_uri(uri_path: &str, root: &Path) -> Result<PathBuf, Error> {
+/// * Path pointing outside the root directory or path segment containing a separator after
+///   percent-decoding: results in [`ErrorKind::InvalidData`]
+/// * Symbolic link pointing outside the root directory while `follow_symlinks` is `false`:
+///   results in [`ErrorKind::NotFound`]
+/// * [`std::fs::canonicalize()`] or [`std::fs::metadata()`] failed: results in
+///   [`ErrorKind::NotFound`], [`ErrorKind::PermissionDenied`] and other errors
+pub fn resolve_uri(uri_path: &str, root: &Path, follow_symlinks: bool) -> Result<PathBuf, Error> {
     let uri_path = uri_path.strip_prefix('/').ok_or(ErrorKind::InvalidInput)?;
 
-    let uri_path = uri_path.strip_suffix('/').unwrap_or(uri_path);
-
     let mut path = root.to_path_buf();
-    for component in uri_path.split('/') {
-        let decoded = percent_decode_str(component).collect::<Vec<_>>();
-        path.push(path_from_bytes(&decoded))
-    }
+    let mut depth = 0;
+    for segment in uri_path.split('/').filter(|segment| !segment.is_empty()) {
+        let decoded = percent_decode_str(segment).collect::<Vec<_>>();
 
-    let path = path.canonicalize()?;
+        // Don’t leave it to PathBuf to interpret the segment, it might contain a separator or
+        // be an absolute path on some systems.
+        let segment = Path::new(path_from_bytes(&decoded));
+        let mut components = segment.components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) if name == segment.as_os_str() => {
+                path.push(name);
+                depth += 1;
+            }
+            (Some(Component::CurDir), None) => {}
+            (Some(Component::ParentDir), None) if depth > 0 => {
+                path.pop();
+                depth -= 1;
+            }
+            _ => return Err(ErrorKind::InvalidData.into()),
+        }
+    }
 
-    if path.starts_with(root) {
+    if follow_symlinks {
+        // Only make sure that the path exists
+        path.metadata()?;
         Ok(path)
     } else {
-        Err(ErrorKind::InvalidData.into())
+        let path = path.canonicalize()?;
+        if path.starts_with(root) {
+            Ok(path)
+        } else {
+            Err(ErrorKind::NotFound.into())
+        }
     }
 }
 
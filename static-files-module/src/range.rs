@@ -40,19 +40,34 @@ impl Range {
         }
 
         let (start, end) = range.trim().split_once('-')?;
-        let (start, end) = if start.is_empty() {
-            let len = u64::from_str(end.trim()).ok()?;
-            if len > file_size {
-                return Some(Self::OutOfBounds);
-            }
-            (file_size - len, file_size - 1)
-        } else if end.is_empty() {
-            (u64::from_str(start.trim()).ok()?, file_size - 1)
+        let start = Some(start.trim())
+            .filter(|start| !start.is_empty())
+            .map(u64::from_str)
+            .transpose()
+            .ok()?;
+        let end = Some(end.trim())
+            .filter(|end| !end.is_empty())
+            .map(u64::from_str)
+            .transpose()
+            .ok()?;
+
+        let last = if let Some(last) = file_size.checked_sub(1) {
+            last
         } else {
-            (
-                u64::from_str(start.trim()).ok()?,
-                u64::from_str(end.trim()).ok()?,
-            )
+            // No range can be satisfied for an empty file
+            return (start.is_some() || end.is_some()).then_some(Self::OutOfBounds);
+        };
+
+        let (start, end) = match (start, end) {
+            (None, Some(len)) => {
+                if len > file_size {
+                    return Some(Self::OutOfBounds);
+                }
+                (file_size - len, last)
+            }
+            (Some(start), None) => (start, last),
+            (Some(start), Some(end)) => (start, end),
+            (None, None) => return None,
         };
 
         if end >= file_size || start > end {
@@ -76,7 +91,7 @@ pub fn extract_range(session: &impl SessionWrapper, meta: &Metadata) -> Option<R
         .get(header::IF_RANGE)
         .and_then(|value| value.to_str().ok())
     {
-        if value != meta.etag
+        if !meta.matches_etag(value, false)
             && !meta
                 .modified
                 .as_ref()
@@ -112,6 +127,7 @@ mod tests {
             mime: MimeGuess::from_ext("txt").first_or_octet_stream(),
             size: 1000,
             modified: Some("Fri, 15 May 2015 15:34:21 GMT".into()),
+            modified_time: None,
             etag: "\"abc\"".into(),
         }
     }
@@ -198,6 +214,16 @@ mod tests {
         );
     }
 
+    #[test]
+    fn empty_file() {
+        assert_eq!(Range::parse("bytes=0-", 0), Some(Range::OutOfBounds));
+        assert_eq!(Range::parse("bytes=0-0", 0), Some(Range::OutOfBounds));
+        assert_eq!(Range::parse("bytes=-1", 0), Some(Range::OutOfBounds));
+        assert_eq!(Range::parse("bytes=-", 0), None);
+        assert_eq!(Range::parse("bytes=x-", 0), None);
+        assert_eq!(Range::parse("bytes=-1", 1), Some(Range::Valid(0, 0)));
+    }
+
     #[test(tokio::test)]
     async fn multiple_ranges() {
         // Multiple ranges are unsupported, should be treated like no Range header.
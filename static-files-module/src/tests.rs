@@ -12,21 +12,30 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::file_writer::{cached_response, file_response};
 use crate::handler::StaticFilesHandler;
 use crate::metadata::Metadata;
+use crate::path::resolve_uri;
+use crate::StaticFilesOpt;
 
+use async_trait::async_trait;
+use bytes::Bytes;
 use compression_module::CompressionHandler;
 use const_format::{concatcp, str_repeat};
-use http::status::StatusCode;
+use http::{status::StatusCode, Extensions};
 use pandora_module_utils::pingora::{
-    create_test_session, ErrorType, RequestHeader, Session, SessionWrapper,
+    create_test_session, Error, ErrorType, RequestHeader, Session, SessionWrapper,
 };
 use pandora_module_utils::standard_response::response_text;
 use pandora_module_utils::{FromYaml, RequestFilter};
 use rewrite_module::RewriteHandler;
+use sha2::{Digest, Sha256};
 use startup_module::{AppResult, DefaultApp};
+use std::io::ErrorKind;
+use std::ops::{Deref, DerefMut};
 use std::path::PathBuf;
 use test_log::test;
+use virtual_hosts_module::VirtualHostsHandler;
 
 #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
 struct Handler {
@@ -35,6 +44,12 @@ struct Handler {
     static_files: StaticFilesHandler,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
+struct HostsHandler {
+    static_files: StaticFilesHandler,
+    virtual_hosts: VirtualHostsHandler<StaticFilesHandler>,
+}
+
 fn root_path(filename: &str) -> PathBuf {
     let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     path.push("testdata");
@@ -161,6 +176,88 @@ async fn text_file() {
     assert_body(&result, concatcp!(str_repeat!("0123456789", 10000), "\n"));
 }
 
+#[test(tokio::test)]
+async fn absolute_form() {
+    let meta = Metadata::from_path(&root_path("file.txt"), None).unwrap();
+
+    let mut app = make_app(default_conf());
+    let session = make_session("GET", "http://localhost:8080/file.txt?abc").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", &meta.modified.unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, "Hi!\n");
+}
+
+#[test(tokio::test)]
+async fn preload() {
+    let root = std::env::temp_dir().join("static-files-module-preload-test");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("cached.txt"), "Old!\n").unwrap();
+
+    let mut app = make_app(format!(
+        "root: {}\npreload: [/cached.*, /missing.txt]",
+        root.to_str().unwrap()
+    ));
+
+    let session = make_session("GET", "/cached.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "Old!\n");
+
+    // Same file size but a different modification time invalidates the cache entry, timestamp
+    // granularity of the file system might require multiple attempts.
+    let modified = |name: &str| root.join(name).metadata().unwrap().modified().unwrap();
+    let old_modified = modified("cached.txt");
+    while modified("cached.txt") == old_modified {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        std::fs::write(root.join("cached.txt"), "New!\n").unwrap();
+    }
+
+    let session = make_session("GET", "/cached.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "New!\n");
+
+    // Size change invalidates the cache entry
+    std::fs::write(root.join("cached.txt"), "Newer!\n").unwrap();
+
+    let session = make_session("GET", "/cached.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "Newer!\n");
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test(tokio::test)]
+async fn preload_empty_file() {
+    let mut app = make_app(extended_conf("preload: /subdir/*"));
+    for method in ["GET", "HEAD"] {
+        let session = make_session(method, "/subdir/empty.js").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 200);
+        assert_eq!(
+            result.session().response_written().unwrap().headers["Content-Length"],
+            "0"
+        );
+        assert_body(&result, "");
+    }
+}
+
 #[test(tokio::test)]
 async fn dir_index() {
     let meta = Metadata::from_path(&root_path("index.html"), None).unwrap();
@@ -182,14 +279,22 @@ async fn dir_index() {
     );
     assert_body(&result, "<html>Hi!</html>\n");
 
-    // Without matching directory index this should produce Forbidden response.
+    // index.html is the default index file
     let mut app = make_app(default_conf());
+    let session = make_session("GET", "/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "<html>Hi!</html>\n");
 
-    let text = response_text(StatusCode::FORBIDDEN);
+    // Without matching directory index this should produce Not Found response.
+    let mut app = make_app(extended_conf("index_files: []"));
+
+    let text = response_text(StatusCode::NOT_FOUND);
     let session = make_session("GET", "/").await;
     let mut result = app.handle_request(session).await;
     assert!(result.err().is_none());
-    assert_status(&mut result, 403);
+    assert_status(&mut result, 404);
     assert_headers(
         &mut result,
         vec![
@@ -200,6 +305,66 @@ async fn dir_index() {
     assert_body(&result, &text);
 }
 
+#[test(tokio::test)]
+async fn dir_index_order() {
+    async fn request(app: &mut DefaultApp<Handler>) -> AppResult {
+        let session = make_session("GET", "/indexes/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 200);
+        result
+    }
+
+    let meta = Metadata::from_path(&root_path("indexes/index.htm"), None).unwrap();
+    let mut app = make_app(extended_conf(
+        "index_files: [index.html, index.htm, default.html]",
+    ));
+    let mut result = request(&mut app).await;
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/html;charset=utf-8"),
+            ("last-modified", &meta.modified.unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, "<html>index.htm</html>\n");
+
+    let mut app = make_app(extended_conf("index_files: [default.html, index.htm]"));
+    let result = request(&mut app).await;
+    assert_body(&result, "<html>default.html</html>\n");
+
+    // Content type is determined by the index file
+    let meta = Metadata::from_path(&root_path("indexes/index.txt"), None).unwrap();
+    let mut app = make_app(extended_conf("index_files: [index.txt, index.htm]"));
+    let mut result = request(&mut app).await;
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", &meta.modified.unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, "index.txt\n");
+
+    // Command line flag replaces configured index files
+    let mut conf =
+        <Handler as RequestFilter>::Conf::from_yaml(extended_conf("index_files: [default.html]"))
+            .unwrap();
+    conf.static_files.merge_with_opt(StaticFilesOpt {
+        index_files: Some(vec!["index.htm".to_owned()]),
+        ..Default::default()
+    });
+    let mut app = DefaultApp::new(conf.try_into().unwrap());
+    let result = request(&mut app).await;
+    assert_body(&result, "<html>index.htm</html>\n");
+}
+
 #[test(tokio::test)]
 async fn no_trailing_slash() {
     let mut app = make_app(default_conf());
@@ -239,14 +404,14 @@ async fn no_trailing_slash() {
     assert_body(&result, &text);
 
     // Without canonicalize_uri this should just produce the response
-    // (Forbidden because no index file).
+    // (Not Found because no index file).
     let mut app = make_app(extended_conf("canonicalize_uri: false"));
 
-    let text = response_text(StatusCode::FORBIDDEN);
+    let text = response_text(StatusCode::NOT_FOUND);
     let session = make_session("GET", "/subdir").await;
     let mut result = app.handle_request(session).await;
     assert!(result.err().is_none());
-    assert_status(&mut result, 403);
+    assert_status(&mut result, 404);
     assert_headers(
         &mut result,
         vec![
@@ -384,15 +549,130 @@ async fn no_file_with_page_404() {
     assert_body(&result, "Hi!\n");
 }
 
+#[test(tokio::test)]
+async fn error_pages() {
+    let mut app = make_app(extended_conf(
+        r#"
+            page_404: /file.txt
+            error_pages:
+                404: /index.html
+                405: /file.txt
+        "#,
+    ));
+
+    let meta = Metadata::from_path(&root_path("index.html"), None).unwrap();
+    let index = std::fs::read_to_string(root_path("index.html")).unwrap();
+
+    let session = make_session("GET", "/missing.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+    assert_body(&result, &index);
+
+    // Conditional requests aren’t considered for error pages
+    let mut session = make_session("GET", "/missing.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", &meta.etag)
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+    assert_body(&result, &index);
+
+    let session = make_session("POST", "/file.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 405);
+    assert_body(&result, "Hi!\n");
+
+    // Missing error pages result in the standard error page
+    let mut app = make_app(extended_conf(
+        r#"
+            error_pages:
+                404: /missing.html
+                405: /subdir
+        "#,
+    ));
+
+    for (method, path, status) in [
+        ("GET", "/missing.txt", StatusCode::NOT_FOUND),
+        ("POST", "/file.txt", StatusCode::METHOD_NOT_ALLOWED),
+    ] {
+        let text = response_text(status);
+        let session = make_session(method, path).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, status.as_u16());
+        assert_headers(
+            &mut result,
+            vec![
+                ("Content-Length", &text.len().to_string()),
+                ("Content-Type", "text/html;charset=utf-8"),
+            ],
+        );
+        assert_body(&result, &text);
+    }
+}
+
+#[test(tokio::test)]
+async fn vhost_error_pages() {
+    let mut app = DefaultApp::<HostsHandler>::new(
+        <HostsHandler as RequestFilter>::Conf::from_yaml(format!(
+            r#"
+                error_pages:
+                    404: /index.html
+                    405: /index.html
+                vhosts:
+                    a.example.com:
+                        {root}
+                        error_pages:
+                            404: /file.txt
+                    b.example.com:
+                        {root}
+                        page_404: /app.3f2a9c.js
+                    c.example.com:
+                        {root}
+            "#,
+            root = default_conf()
+        ))
+        .unwrap()
+        .try_into()
+        .unwrap(),
+    );
+
+    let index = std::fs::read_to_string(root_path("index.html")).unwrap();
+    let script = std::fs::read_to_string(root_path("app.3f2a9c.js")).unwrap();
+
+    // Virtual host error pages take precedence, top-level error pages serve as defaults
+    for (host, method, path, status, expected) in [
+        ("a.example.com", "GET", "/missing.txt", 404, "Hi!\n"),
+        ("b.example.com", "GET", "/missing.txt", 404, &script),
+        ("c.example.com", "GET", "/missing.txt", 404, &index),
+        ("a.example.com", "POST", "/file.txt", 405, &index),
+        ("b.example.com", "POST", "/file.txt", 405, &index),
+    ] {
+        let mut session = make_session(method, path).await;
+        session
+            .req_header_mut()
+            .insert_header("Host", host)
+            .unwrap();
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, status);
+        assert_body(&result, expected);
+    }
+}
+
 #[test(tokio::test)]
 async fn no_index() {
     let mut app = make_app(default_conf());
 
-    let text = response_text(StatusCode::FORBIDDEN);
+    let text = response_text(StatusCode::NOT_FOUND);
     let session = make_session("GET", "/subdir/").await;
     let mut result = app.handle_request(session).await;
     assert!(result.err().is_none());
-    assert_status(&mut result, 403);
+    assert_status(&mut result, 404);
     assert_headers(
         &mut result,
         vec![
@@ -401,6 +681,85 @@ async fn no_index() {
         ],
     );
     assert_body(&result, &text);
+
+    // Error page is displayed if configured
+    let mut app = make_app(extended_conf("page_404: /file.txt"));
+
+    let session = make_session("GET", "/subdir/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+    assert_body(&result, "Hi!\n");
+}
+
+#[test(tokio::test)]
+async fn directory_listing() {
+    let mut app = make_app(extended_conf("directory_listing: true"));
+
+    let session = make_session("GET", "/listing/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+
+    let body = result.body_str();
+    assert!(body.contains("<title>Index of /listing/</title>"));
+    assert!(body.contains(r#"<a href="/listing/../">../</a>"#));
+    assert!(body.contains(r#"<a href="/listing/nested/">nested/</a>"#));
+    assert!(body.contains(r#"<a href="/listing/a&amp;b.txt">a&amp;b.txt</a></td><td>10</td>"#));
+    assert!(body.contains(r#"<a href="/listing/file.txt">file.txt</a></td><td>6</td>"#));
+    assert!(!body.contains(".hidden"));
+
+    // Directories are listed before files
+    let nested = body.find("nested/").unwrap();
+    let file = body.find("file.txt").unwrap();
+    assert!(nested < file);
+
+    // Hidden files are displayed if requested
+    let mut app = make_app(extended_conf("directory_listing: true\nshow_hidden: true"));
+
+    let session = make_session("GET", "/listing/").await;
+    let result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert!(result
+        .body_str()
+        .contains(r#"<a href="/listing/.hidden">.hidden</a>"#));
+
+    // Index file takes precedence
+    let session = make_session("GET", "/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "<html>Hi!</html>\n");
+
+    // No link to parent directory for the root directory
+    let mut app = make_app(extended_conf("directory_listing: true\nindex_files: []"));
+
+    let session = make_session("GET", "/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert!(result.body_str().contains("<title>Index of /</title>"));
+    assert!(!result.body_str().contains("../"));
+
+    // Scenario where prefix has been stripped from URI
+    let mut app = make_app(extended_conf(
+        "directory_listing: true\nrewrite_rules: {from: /static/*, to: '${tail}${query}'}",
+    ));
+
+    let session = make_session("GET", "/static/listing/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    let body = result.body_str();
+    assert!(body.contains("<title>Index of /static/listing/</title>"));
+    assert!(body.contains(r#"<a href="/static/listing/nested/">nested/</a>"#));
+
+    // HEAD request produces headers only
+    let session = make_session("HEAD", "/static/listing/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "");
 }
 
 #[test(tokio::test)]
@@ -492,6 +851,22 @@ async fn head_request() {
     assert_body(&result, "");
 }
 
+#[test(tokio::test)]
+async fn empty_file() {
+    let mut app = make_app(default_conf());
+    for method in ["GET", "HEAD"] {
+        let session = make_session(method, "/subdir/empty.js").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 200);
+        assert_eq!(
+            result.session().response_written().unwrap().headers["Content-Length"],
+            "0"
+        );
+        assert_body(&result, "");
+    }
+}
+
 #[test(tokio::test)]
 async fn bad_request() {
     let mut app = make_app(default_conf());
@@ -524,6 +899,97 @@ async fn bad_request() {
     assert_body(&result, &text);
 }
 
+#[test]
+fn path_resolution() {
+    let root = root_path("").canonicalize().unwrap();
+    let resolve = |path| resolve_uri(path, &root, false).map_err(|err| err.kind());
+
+    assert_eq!(
+        resolve("/subdir//empty.js"),
+        Ok(root.join("subdir/empty.js"))
+    );
+    assert_eq!(
+        resolve("//subdir///empty.js"),
+        Ok(root.join("subdir/empty.js"))
+    );
+    assert_eq!(
+        resolve("/subdir/./empty.js"),
+        Ok(root.join("subdir/empty.js"))
+    );
+    assert_eq!(resolve("/subdir/../file.txt"), Ok(root.join("file.txt")));
+    assert_eq!(resolve("/subdir//"), Ok(root.join("subdir")));
+    assert_eq!(resolve("/"), Ok(root.clone()));
+
+    assert_eq!(resolve("subdir/empty.js"), Err(ErrorKind::InvalidInput));
+    assert_eq!(resolve("/subdir/../../etc"), Err(ErrorKind::InvalidData));
+    assert_eq!(resolve("/subdir//..//..//etc"), Err(ErrorKind::InvalidData));
+    assert_eq!(
+        resolve("/subdir/%2E%2E/%2E%2E/etc"),
+        Err(ErrorKind::InvalidData)
+    );
+    assert_eq!(resolve("/subdir%2Fempty.js"), Err(ErrorKind::InvalidData));
+    assert_eq!(resolve("/subdir/%2Fetc"), Err(ErrorKind::InvalidData));
+}
+
+#[cfg(unix)]
+#[test(tokio::test)]
+async fn symlinks() {
+    // By default, links within the root directory are resolved to their canonical location
+    let mut app = make_app(default_conf());
+    let session = make_session("GET", "/link_inside.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 308);
+    assert_eq!(
+        result
+            .session()
+            .response_written()
+            .unwrap()
+            .headers
+            .get("Location")
+            .unwrap(),
+        "/file.txt"
+    );
+
+    let mut app = make_app(extended_conf("canonicalize_uri: false"));
+    let session = make_session("GET", "/link_inside.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "Hi!\n");
+
+    // Links pointing outside the root directory are rejected by default
+    let session = make_session("GET", "/link_outside.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+
+    // Traversal is rejected regardless of symlinks
+    let session = make_session("GET", "/subdir/../../outside.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 400);
+
+    // Following symbolic links allows both
+    let mut app = make_app(extended_conf("follow_symlinks: true"));
+    let session = make_session("GET", "/link_inside.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "Hi!\n");
+
+    let session = make_session("GET", "/link_outside.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "Outside!\n");
+
+    let session = make_session("GET", "/subdir/../../outside.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 400);
+}
+
 #[test(tokio::test)]
 async fn if_none_match() {
     let meta = Metadata::from_path(&root_path("file.txt"), None).unwrap();
@@ -769,6 +1235,234 @@ async fn if_match() {
     assert_body(&result, "");
 }
 
+#[test(tokio::test)]
+async fn etag_weak() {
+    let meta = Metadata::from_path(&root_path("file.txt"), None).unwrap();
+    let weak_etag = format!("W/{}", meta.etag);
+
+    let mut app = make_app(extended_conf("etag_weak: true"));
+    let session = make_session("GET", "/file.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &weak_etag),
+        ],
+    );
+
+    // If-None-Match uses weak comparison
+    for etag in [&weak_etag, &meta.etag] {
+        let mut session = make_session("GET", "/file.txt").await;
+        session
+            .req_header_mut()
+            .insert_header("If-None-Match", etag)
+            .unwrap();
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 304);
+        assert_headers(
+            &mut result,
+            vec![
+                ("last-modified", meta.modified.as_ref().unwrap()),
+                ("etag", &weak_etag),
+            ],
+        );
+        assert_body(&result, "");
+    }
+
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", "W/\"xyz\"")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "Hi!\n");
+
+    // If-Match uses strong comparison, weak ETags never match
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-Match", &weak_etag)
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 412);
+}
+
+#[test(tokio::test)]
+async fn etag_content_hash() {
+    let meta = Metadata::from_path(&root_path("file.txt"), None).unwrap();
+    let etag = format!("\"{:x}\"", Sha256::digest(b"Hi!\n"));
+
+    let mut app = make_app(extended_conf("etag_content_hash: true\npreload: /file.txt"));
+    let session = make_session("GET", "/file.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &etag),
+        ],
+    );
+    assert_body(&result, "Hi!\n");
+
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", &etag)
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 304);
+    assert_headers(
+        &mut result,
+        vec![
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &etag),
+        ],
+    );
+    assert_body(&result, "");
+
+    // The ETag based on modification time and size no longer matches
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", &meta.etag)
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "Hi!\n");
+
+    // Files that aren’t preloaded keep the ETag based on modification time and size
+    let mut app = make_app(extended_conf("etag_content_hash: true"));
+    let session = make_session("GET", "/file.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, "Hi!\n");
+}
+
+#[test(tokio::test)]
+async fn cache_control() {
+    fn cache_control(result: &mut AppResult) -> Option<String> {
+        result
+            .session()
+            .response_written()
+            .unwrap()
+            .headers
+            .get("Cache-Control")
+            .map(|value| value.to_str().unwrap().to_owned())
+    }
+
+    // No header by default
+    let mut app = make_app(default_conf());
+    let session = make_session("GET", "/app.3f2a9c.js").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(cache_control(&mut result), None);
+
+    let mut app = make_app(extended_conf(
+        r#"
+            cache_control:
+                "*.html": no-cache
+                "*.js": max-age=3600
+                "*.[0-9a-f][0-9a-f][0-9a-f][0-9a-f][0-9a-f][0-9a-f].js": max-age=31536000, immutable
+        "#,
+    ));
+    let session = make_session("GET", "/app.3f2a9c.js").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(
+        cache_control(&mut result).as_deref(),
+        Some("max-age=31536000, immutable")
+    );
+
+    let session = make_session("GET", "/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(cache_control(&mut result).as_deref(), Some("no-cache"));
+
+    // Header is sent with Not Modified responses as well
+    let meta = Metadata::from_path(&root_path("index.html"), None).unwrap();
+    let mut session = make_session("GET", "/index.html").await;
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", &meta.etag)
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 304);
+    assert_eq!(cache_control(&mut result).as_deref(), Some("no-cache"));
+}
+
+#[test(tokio::test)]
+async fn content_type_sniffing() {
+    fn content_type(result: &mut AppResult) -> String {
+        result
+            .session()
+            .response_written()
+            .unwrap()
+            .headers
+            .get("Content-Type")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    // Extensionless files are sent as binary data by default
+    let mut app = make_app(default_conf());
+    for path in ["/sniffing/image", "/sniffing/page"] {
+        let session = make_session("GET", path).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 200);
+        assert_eq!(content_type(&mut result), "application/octet-stream");
+    }
+
+    let mut app = make_app(extended_conf("content_type_sniffing: true"));
+    for (path, expected) in [
+        ("/sniffing/image", "image/png"),
+        ("/sniffing/page", "text/html;charset=utf-8"),
+        // File extension takes precedence
+        ("/file.txt", "text/plain;charset=utf-8"),
+        ("/index.html", "text/html;charset=utf-8"),
+    ] {
+        let session = make_session("GET", path).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 200);
+        assert_eq!(content_type(&mut result), expected);
+    }
+}
+
 #[test(tokio::test)]
 async fn if_modified_since() {
     let meta = Metadata::from_path(&root_path("file.txt"), None).unwrap();
@@ -835,6 +1529,27 @@ async fn if_modified_since() {
     );
     assert_body(&result, "Hi!\n");
 
+    // Any date after the last modification time counts as not modified
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-Modified-Since", "Fri, 01 Jan 2100 00:00:00 GMT")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 304);
+    assert_body(&result, "");
+
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "Hi!\n");
+
     // With compression enabled this should produce Vary header
     let mut app = make_app(extended_conf("compression_level_gzip: 3"));
     let mut session = make_session("GET", "/file.txt").await;
@@ -881,6 +1596,22 @@ async fn if_unmodified_since() {
     );
     assert_body(&result, "Hi!\n");
 
+    // Precondition is satisfied by later dates, invalid dates are ignored
+    for since in [
+        "Fri, 01 Jan 2100 00:00:00 GMT",
+        "Thu, 01 Jan 1970 00:00:00 GTM",
+    ] {
+        let mut session = make_session("GET", "/file.txt").await;
+        session
+            .req_header_mut()
+            .insert_header("If-Unmodified-Since", since)
+            .unwrap();
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 200);
+        assert_body(&result, "Hi!\n");
+    }
+
     let mut session = make_session("GET", "/file.txt").await;
     session
         .req_header_mut()
@@ -1028,6 +1759,29 @@ async fn ranged_request() {
     );
     assert_body(&result, "");
 
+    // Invalid and multiple ranges are ignored, the entire file is returned
+    for range in ["bytes=abc-", "bytes=2-5,7-9"] {
+        let mut session = make_session("GET", "/large.txt").await;
+        session
+            .req_header_mut()
+            .insert_header("Range", range)
+            .unwrap();
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 200);
+        assert_headers(
+            &mut result,
+            vec![
+                ("Content-Length", &meta.size.to_string()),
+                ("accept-ranges", "bytes"),
+                ("Content-Type", "text/plain;charset=utf-8"),
+                ("last-modified", meta.modified.as_ref().unwrap()),
+                ("etag", &meta.etag),
+            ],
+        );
+        assert_eq!(result.body().len() as u64, meta.size);
+    }
+
     // With compression enabled this should produce Vary header
     let mut app = make_app(extended_conf("compression_level_gzip: 3"));
     let mut session = make_session("GET", "/large.txt").await;
@@ -1098,6 +1852,46 @@ async fn dynamic_compression() {
         ],
     );
 
+    // HEAD request cannot know the compressed size, so no Content-Length header
+    let mut session = make_session("HEAD", "/large.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "gzip")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    let headers = &result.session().response_written().unwrap().headers;
+    assert!(headers.get("Content-Length").is_none());
+    assert_eq!(
+        headers.get("Content-Type").unwrap(),
+        "text/plain;charset=utf-8"
+    );
+    assert_eq!(headers.get("etag").unwrap(), meta.etag.as_str());
+    assert_eq!(headers.get("vary").unwrap(), "Accept-Encoding");
+    assert_body(&result, "");
+
+    // Without dynamic compression HEAD requests report the file size
+    let mut session = make_session("HEAD", "/large.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "unsupported")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("vary", "Accept-Encoding"),
+        ],
+    );
+
     // We shouldn’t get ranged requests in practice but Pingora will compress even these responses.
     let mut session = make_session("GET", "/large.txt").await;
     session
@@ -1130,6 +1924,8 @@ async fn static_compression() {
     let meta = Metadata::from_path(&root_path("large_precompressed.txt"), None).unwrap();
     let meta_compressed =
         Metadata::from_path(&root_path("large_precompressed.txt.gz"), None).unwrap();
+    // ETag is derived from the original file but has to differ for the compressed variant
+    let etag_compressed = format!("{}-gz\"", meta.etag.strip_suffix('"').unwrap());
     let mut app = make_app(extended_conf("precompressed: [gz, br]"));
 
     // Regular request should result in compressed response
@@ -1150,7 +1946,7 @@ async fn static_compression() {
             ("accept-ranges", "bytes"),
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta_compressed.modified.as_ref().unwrap()),
-            ("etag", &meta_compressed.etag),
+            ("etag", &etag_compressed),
             ("Content-Encoding", "gzip"),
             ("vary", "Accept-Encoding"),
         ],
@@ -1174,7 +1970,7 @@ async fn static_compression() {
             ("accept-ranges", "bytes"),
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta_compressed.modified.as_ref().unwrap()),
-            ("etag", &meta_compressed.etag),
+            ("etag", &etag_compressed),
             ("Content-Encoding", "gzip"),
             ("vary", "Accept-Encoding"),
         ],
@@ -1203,6 +1999,21 @@ async fn static_compression() {
         ],
     );
 
+    // Conditional request should be matched against the ETag of the compressed variant
+    let mut session = make_session("GET", "/large_precompressed.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "gzip")
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", &etag_compressed)
+        .unwrap();
+
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 304);
+
     // Ranged response should be compressed
     let mut session = make_session("GET", "/large_precompressed.txt").await;
     session
@@ -1228,7 +2039,7 @@ async fn static_compression() {
             ),
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta_compressed.modified.as_ref().unwrap()),
-            ("etag", &meta_compressed.etag),
+            ("etag", &etag_compressed),
             ("Content-Encoding", "gzip"),
             ("vary", "Accept-Encoding"),
         ],
@@ -1297,4 +2108,135 @@ async fn charset() {
             ("etag", &meta.etag),
         ],
     );
+
+    // Text types get the charset, binary types are left untouched
+    let mut app = make_app(extended_conf(
+        "default_charset: iso-8859-1\ncontent_type_sniffing: true",
+    ));
+    for (path, expected) in [
+        ("/index.html", "text/html;charset=iso-8859-1"),
+        ("/sniffing/image", "image/png"),
+    ] {
+        let session = make_session("GET", path).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 200);
+        assert_eq!(
+            result.session().response_written().unwrap().headers["Content-Type"],
+            expected
+        );
+    }
+
+    // Empty charset disables the declaration
+    let mut app = make_app(extended_conf("declare_charset: ''"));
+    let session = make_session("GET", "/index.html").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(
+        result.session().response_written().unwrap().headers["Content-Type"],
+        "text/html"
+    );
+}
+
+/// Session wrapper simulating a connection failing after a number of response body chunks
+struct DisconnectingSession {
+    session: Session,
+    extensions: Extensions,
+    chunks_left: usize,
+    error: ErrorKind,
+    received: usize,
+    end_of_stream: bool,
+}
+
+impl DisconnectingSession {
+    async fn new(chunks: usize, error: ErrorKind) -> Self {
+        Self {
+            session: make_session("GET", "/large.txt").await,
+            extensions: Extensions::new(),
+            chunks_left: chunks,
+            error,
+            received: 0,
+            end_of_stream: false,
+        }
+    }
+}
+
+impl Deref for DisconnectingSession {
+    type Target = Session;
+
+    fn deref(&self) -> &Self::Target {
+        &self.session
+    }
+}
+
+impl DerefMut for DisconnectingSession {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.session
+    }
+}
+
+#[async_trait]
+impl SessionWrapper for DisconnectingSession {
+    fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    async fn write_response_body(
+        &mut self,
+        data: Option<Bytes>,
+        end_of_stream: bool,
+    ) -> Result<(), Box<Error>> {
+        if self.chunks_left == 0 {
+            return Err(Error::because(
+                ErrorType::WriteError,
+                "writing body",
+                std::io::Error::from(self.error),
+            ));
+        }
+
+        self.chunks_left -= 1;
+        self.received += data.map_or(0, |data| data.len());
+        self.end_of_stream = end_of_stream;
+        Ok(())
+    }
+}
+
+#[test(tokio::test)]
+async fn client_disconnect() {
+    let path = root_path("large.txt");
+    let size = Metadata::from_path(&path, None).unwrap().size;
+
+    // Client goes away after the first chunk, the rest of the file isn’t sent
+    let mut session = DisconnectingSession::new(1, ErrorKind::BrokenPipe).await;
+    file_response(&mut session, &path, 0, size - 1)
+        .await
+        .unwrap();
+    assert!(session.received > 0);
+    assert!((session.received as u64) < size);
+    assert!(!session.end_of_stream);
+
+    // Client goes away before the end of stream marker
+    let mut session = DisconnectingSession::new(1, ErrorKind::ConnectionReset).await;
+    file_response(&mut session, &path, 0, 99).await.unwrap();
+    assert_eq!(session.received, 100);
+    assert!(!session.end_of_stream);
+
+    let data = Bytes::from(std::fs::read(&path).unwrap());
+    let mut session = DisconnectingSession::new(0, ErrorKind::BrokenPipe).await;
+    cached_response(&mut session, &data, 0, size - 1)
+        .await
+        .unwrap();
+    assert_eq!(session.received, 0);
+
+    // Other write errors are still reported
+    let mut session = DisconnectingSession::new(1, ErrorKind::Other).await;
+    let err = file_response(&mut session, &path, 0, size - 1)
+        .await
+        .unwrap_err();
+    assert_eq!(err.etype(), &ErrorType::WriteError);
 }
@@ -21,6 +21,9 @@ use std::collections::HashMap;
 pub struct SubPathConf<C: Default> {
     /// If `true`, matched path will be removed from the URI before passing it on to the handler.
     pub strip_prefix: bool,
+    /// If `true` and `strip_prefix` is set, a request path consisting only of the matched prefix
+    /// (e.g. `/subdir` without a trailing slash) results in an empty path rather than `/`.
+    pub keep_empty_path: bool,
     /// Generic handler settings
     ///
     /// These settings are flattened and appear at the same level as `strip_prefix` in the
@@ -45,9 +48,43 @@ pub struct VirtualHostConf<C: Default> {
     pub config: C,
 }
 
+/// Request queue configuration
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct RequestQueueConf {
+    /// Maximum number of requests processed at the same time, no limit applies if not set
+    pub max_concurrent: Option<usize>,
+    /// Maximum number of requests waiting once `max_concurrent` is reached, further requests are
+    /// rejected with status 503
+    pub max_length: usize,
+    /// Maximum time in milliseconds that a request can wait before it is rejected with status 503
+    pub timeout_ms: u64,
+}
+
+impl Default for RequestQueueConf {
+    fn default() -> Self {
+        Self {
+            max_concurrent: None,
+            max_length: 0,
+            timeout_ms: 1000,
+        }
+    }
+}
+
 /// Virtual hosts configuration
 #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct VirtualHostsConf<C: Default> {
     /// Maps virtual host names to their configuration
+    ///
+    /// Host names prefixed with `http://` or `https://` only apply to requests using that scheme.
     pub vhosts: HashMap<OneOrMany<String>, VirtualHostConf<C>>,
+    /// If `true`, the host name of an absolute-form request target (`GET http://example.com/`)
+    /// takes precedence over the `Host` header when selecting the virtual host. For HTTP/2
+    /// requests the `:authority` pseudo-header always takes precedence.
+    pub prefer_uri_host: bool,
+    /// If `true`, the scheme indicated by the `X-Forwarded-Proto` header is used when selecting
+    /// between virtual hosts restricted to `http://` or `https://`. This should only be enabled
+    /// if the server is running behind a reverse proxy which sets this header.
+    pub trust_forwarded_proto: bool,
+    /// Limits the number of requests processed at the same time, queueing excess requests
+    pub request_queue: RequestQueueConf,
 }
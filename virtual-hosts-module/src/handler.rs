@@ -14,15 +14,21 @@
 
 use async_trait::async_trait;
 use http::uri::Uri;
-use log::warn;
-use pandora_module_utils::pingora::{Error, HttpModules, HttpPeer, SessionWrapper};
-use pandora_module_utils::router::{Path, Router};
+use log::{debug, log_enabled, warn};
+use pandora_module_utils::pingora::{
+    Error, ErrorType, HttpModules, HttpPeer, ResponseHeader, SessionWrapper,
+};
+use pandora_module_utils::router::{decode_path, Path, Router, SharedRouter};
+use pandora_module_utils::scheme::RequestScheme;
 use pandora_module_utils::{RequestFilter, RequestFilterResult};
 use std::collections::BTreeSet;
 use std::fmt::Debug;
 use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use crate::configuration::VirtualHostsConf;
+use crate::queue::{QueuePermit, RequestQueue};
 
 fn set_uri_path(uri: &Uri, path: &[u8]) -> Uri {
     let mut parts = uri.clone().into_parts();
@@ -39,14 +45,77 @@ fn set_uri_path(uri: &Uri, path: &[u8]) -> Uri {
     parts.try_into().unwrap_or_else(|_| uri.clone())
 }
 
+/// Removes the port number from a host name like `example.com:8080` or `[::1]:8080`, returns
+/// `None` if there is no port number.
+fn strip_port(host: &str) -> Option<&str> {
+    let (name, port) = host.rsplit_once(':')?;
+    if !port.is_empty()
+        && port.bytes().all(|b| b.is_ascii_digit())
+        && (!name.contains(':') || name.ends_with(']'))
+    {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+/// Splits a virtual host name like `https://example.com` into the scheme and the host name.
+/// The scheme is `None` if the host name has no scheme prefix.
+fn split_scheme(host: &str) -> (Option<RequestScheme>, &str) {
+    if let Some(host) = host.strip_prefix("http://") {
+        (Some(RequestScheme::Http), host)
+    } else if let Some(host) = host.strip_prefix("https://") {
+        (Some(RequestScheme::Https), host)
+    } else {
+        (None, host)
+    }
+}
+
+/// Prefix to be removed from the request path along with the `keep_empty_path` flag
+type StripPrefix = Option<(Path, bool)>;
+
+/// The handler for a virtual host or subdirectory along with the rule it was configured for
+///
+/// Host name and path of the rule are prepared when the routing table is built, so that
+/// requests can refer to them without any allocations.
+#[derive(Debug, PartialEq, Eq)]
+struct Route<H> {
+    strip_prefix: StripPrefix,
+    handler: Arc<H>,
+    /// Lowercase host name of the rule, empty for the default virtual host
+    host: Arc<str>,
+    /// Path of the rule in normalized form, `/` for the virtual host itself
+    path: Arc<str>,
+}
+
+type HandlerRouter<H> = Router<Arc<Route<H>>>;
+
 /// Context for the virtual hosts handler
 #[derive(Debug)]
-pub struct VirtualHostsCtx<Ctx> {
-    index: Option<usize>,
+pub struct VirtualHostsCtx<Ctx, H> {
+    selected: Option<Arc<H>>,
+    matched: Option<(Arc<str>, Arc<str>)>,
+    permit: Option<QueuePermit>,
     handler: Ctx,
 }
 
-impl<Ctx> Deref for VirtualHostsCtx<Ctx> {
+impl<Ctx, H> VirtualHostsCtx<Ctx, H> {
+    /// Retrieves the host name of the virtual host handling the request, e.g. `*.example.com` if
+    /// a wildcard host matched. This is empty if the request is handled by the default virtual
+    /// host and `None` if no virtual host matched.
+    pub fn matched_host(&self) -> Option<&str> {
+        self.matched.as_ref().map(|(host, _)| &**host)
+    }
+
+    /// Retrieves the subdirectory of the rule handling the request in normalized form, e.g.
+    /// `/subdir/subsub`. This is `/` if no subdirectory rule matched and `None` if no virtual host
+    /// matched.
+    pub fn matched_path(&self) -> Option<&str> {
+        self.matched.as_ref().map(|(_, path)| &**path)
+    }
+}
+
+impl<Ctx, H> Deref for VirtualHostsCtx<Ctx, H> {
     type Target = Ctx;
 
     fn deref(&self) -> &Self::Target {
@@ -54,16 +123,19 @@ impl<Ctx> Deref for VirtualHostsCtx<Ctx> {
     }
 }
 
-impl<Ctx> DerefMut for VirtualHostsCtx<Ctx> {
+impl<Ctx, H> DerefMut for VirtualHostsCtx<Ctx, H> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.handler
     }
 }
 
 /// Virtual Hosts module handler
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug)]
 pub struct VirtualHostsHandler<H: Debug> {
-    handlers: Router<(Option<Path>, H)>,
+    handlers: SharedRouter<Arc<Route<H>>>,
+    prefer_uri_host: AtomicBool,
+    trust_forwarded_proto: AtomicBool,
+    queue: RequestQueue,
 }
 
 impl<H: Debug> VirtualHostsHandler<H> {
@@ -71,30 +143,79 @@ impl<H: Debug> VirtualHostsHandler<H> {
     ///
     /// This will return `None` if the `request_filter` handler wasn’t called for this context yet
     /// or it didn’t find a matching handler.
-    pub fn as_inner(&self, ctx: &<Self as RequestFilter>::CTX) -> Option<&H>
+    pub fn as_inner<'a>(&self, ctx: &'a <Self as RequestFilter>::CTX) -> Option<&'a H>
     where
-        H: RequestFilter + Sync,
+        H: RequestFilter + Send + Sync,
         H::Conf: Default,
         H::CTX: Send,
     {
-        self.handlers.retrieve(ctx.index?).map(|(_, h)| h)
+        ctx.selected.as_deref()
+    }
+
+    /// Replaces the virtual hosts configuration without interrupting requests in flight.
+    ///
+    /// The new routing table is built on the calling thread before being swapped in, so this
+    /// shouldn’t be called from a request handler. Requests already being processed will finish
+    /// with the previous configuration. If the new configuration is invalid, an error is returned
+    /// and the previous configuration stays in effect.
+    pub fn reload<C>(&self, conf: VirtualHostsConf<C>) -> Result<(), Box<Error>>
+    where
+        H: Clone + Eq,
+        C: TryInto<H, Error = Box<Error>> + Default,
+    {
+        let prefer_uri_host = conf.prefer_uri_host;
+        let trust_forwarded_proto = conf.trust_forwarded_proto;
+        let request_queue = conf.request_queue.clone();
+        self.handlers.replace(build_router(conf)?);
+        self.queue.configure(request_queue);
+        self.prefer_uri_host
+            .store(prefer_uri_host, Ordering::Relaxed);
+        self.trust_forwarded_proto
+            .store(trust_forwarded_proto, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl<H: Debug> Clone for VirtualHostsHandler<H> {
+    fn clone(&self) -> Self {
+        Self {
+            handlers: self.handlers.clone(),
+            prefer_uri_host: self.prefer_uri_host.load(Ordering::Relaxed).into(),
+            trust_forwarded_proto: self.trust_forwarded_proto.load(Ordering::Relaxed).into(),
+            queue: self.queue.clone(),
+        }
     }
 }
 
+impl<H: Debug + PartialEq> PartialEq for VirtualHostsHandler<H> {
+    fn eq(&self, other: &Self) -> bool {
+        self.handlers == other.handlers
+            && self.prefer_uri_host.load(Ordering::Relaxed)
+                == other.prefer_uri_host.load(Ordering::Relaxed)
+            && self.trust_forwarded_proto.load(Ordering::Relaxed)
+                == other.trust_forwarded_proto.load(Ordering::Relaxed)
+            && self.queue == other.queue
+    }
+}
+
+impl<H: Debug + Eq> Eq for VirtualHostsHandler<H> {}
+
 #[async_trait]
 impl<H> RequestFilter for VirtualHostsHandler<H>
 where
-    H: RequestFilter + Sync + Debug,
+    H: RequestFilter + Send + Sync + Debug,
     H::Conf: Default,
     H::CTX: Send,
 {
     type Conf = VirtualHostsConf<H::Conf>;
 
-    type CTX = VirtualHostsCtx<H::CTX>;
+    type CTX = VirtualHostsCtx<H::CTX, H>;
 
     fn new_ctx() -> Self::CTX {
         Self::CTX {
-            index: None,
+            selected: None,
+            matched: None,
+            permit: None,
             handler: H::new_ctx(),
         }
     }
@@ -109,21 +230,72 @@ where
         ctx: &mut Self::CTX,
     ) -> Result<(), Box<Error>> {
         let path = session.uri().path();
-        let host = session.host().unwrap_or_default();
+        let host = if self.prefer_uri_host.load(Ordering::Relaxed) {
+            session.uri_host().or_else(|| session.host())
+        } else {
+            session.host()
+        }
+        .unwrap_or_default();
+
+        let scheme = if session.is_https(self.trust_forwarded_proto.load(Ordering::Relaxed)) {
+            RequestScheme::Https
+        } else {
+            RequestScheme::Http
+        };
 
-        if let Some(result) = self.handlers.lookup(host.as_ref(), &path) {
-            let (strip_path, handler) = result.as_value();
-            let index = result.index();
-            let new_path = strip_path
+        // Keep using the same routing table for the entire request even if it is replaced.
+        let router = self.handlers.load();
+
+        // Paths are matched in decoded form. Paths that cannot be decoded safely are rejected, as
+        // these could be interpreted differently by the virtual host’s handler.
+        let decoded = decode_path(path.as_bytes()).ok_or_else(|| {
+            Error::explain(
+                ErrorType::HTTPStatus(400),
+                "request path cannot be decoded safely",
+            )
+        })?;
+
+        // Try the literal host name first, then the host name without the port, then the default
+        // host.
+        let result = if let Some(alias) = strip_port(&host) {
+            router.lookup_hosts_with_scheme(&[&*host, alias, ""], &*decoded, scheme)
+        } else {
+            router.lookup_hosts_with_scheme(&[&*host, ""], &*decoded, scheme)
+        };
+
+        if let Some(result) = result {
+            let route = result.as_value();
+            let handler = route.handler.clone();
+            let new_path = route
+                .strip_prefix
                 .as_ref()
-                .and_then(|p| p.remove_prefix_from(&path));
+                .and_then(|(p, keep_empty_path)| {
+                    let new_path = p.remove_prefix_from_encoded(&path)?;
+
+                    // The remainder is only empty if the path doesn’t end with a slash, `/` is
+                    // substituted for it by default. An empty path cannot be combined with a
+                    // query.
+                    if *keep_empty_path
+                        && new_path == b"/"
+                        && !path.ends_with('/')
+                        && session.uri().query().is_none()
+                    {
+                        Some(&b""[..])
+                    } else {
+                        Some(new_path)
+                    }
+                });
 
-            ctx.index = Some(index);
+            ctx.selected = Some(handler.clone());
+            ctx.matched = Some((route.host.clone(), route.path.clone()));
 
             if let Some(new_path) = new_path {
                 session.set_uri(set_uri_path(session.uri(), new_path));
             }
 
+            // Requests wait their turn under load, keeping the permit until the request is done.
+            ctx.permit = Some(self.queue.acquire(&route.host).await?);
+
             handler.early_request_filter(session, ctx).await?;
         }
 
@@ -135,7 +307,7 @@ where
         session: &mut impl SessionWrapper,
         ctx: &mut Self::CTX,
     ) -> Result<RequestFilterResult, Box<Error>> {
-        if let Some(handler) = self.as_inner(ctx) {
+        if let Some(handler) = ctx.selected.clone() {
             handler.request_filter(session, ctx).await
         } else {
             Ok(RequestFilterResult::Unhandled)
@@ -147,104 +319,168 @@ where
         session: &mut impl SessionWrapper,
         ctx: &mut Self::CTX,
     ) -> Result<Option<Box<HttpPeer>>, Box<Error>> {
-        if let Some(handler) = self.as_inner(ctx) {
+        if let Some(handler) = ctx.selected.clone() {
             handler.upstream_peer(session, ctx).await
         } else {
             Ok(None)
         }
     }
 
+    async fn response_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        if let Some(handler) = ctx.selected.clone() {
+            handler
+                .response_filter(session, upstream_response, ctx)
+                .await
+        } else {
+            Ok(())
+        }
+    }
+
     async fn logging(
         &self,
         session: &mut impl SessionWrapper,
         e: Option<&Error>,
         ctx: &mut Self::CTX,
     ) {
-        if let Some(handler) = self.as_inner(ctx) {
+        if let Some(handler) = ctx.selected.clone() {
             handler.logging(session, e, ctx).await;
         }
     }
 }
 
-impl<C, H> TryFrom<VirtualHostsConf<C>> for VirtualHostsHandler<H>
+/// Builds the routing table for the virtual hosts configuration.
+fn build_router<C, H>(conf: VirtualHostsConf<C>) -> Result<HandlerRouter<H>, Box<Error>>
 where
-    H: Debug + Clone + Eq,
+    H: Clone + Eq,
     C: TryInto<H, Error = Box<Error>> + Default,
 {
-    type Error = Box<Error>;
+    let mut handlers = Router::builder();
+    let mut default: Option<Vec<String>> = None;
+    for (mut hosts, host_conf) in conf.vhosts.into_iter() {
+        let handler = Arc::new(host_conf.config.try_into()?);
 
-    fn try_from(conf: VirtualHostsConf<C>) -> Result<Self, Box<Error>> {
-        let mut handlers = Router::builder();
-        let mut default: Option<Vec<String>> = None;
-        for (mut hosts, host_conf) in conf.vhosts.into_iter() {
-            let handler = host_conf.config.try_into()?;
-
-            let mut names = BTreeSet::new();
-            if host_conf.default {
-                if let Some(previous) = &default {
-                    warn!(
-                        "both [{}] and [{}] are marked as default virtual host, ignoring the latter",
-                        previous.join(", "),
-                        hosts.join(", ")
-                    );
-                } else {
-                    default = Some(hosts.clone().into());
-                    names.insert(String::new());
-                }
+        let mut names = BTreeSet::new();
+        if host_conf.default {
+            if let Some(previous) = &default {
+                warn!(
+                    "both [{}] and [{}] are marked as default virtual host, ignoring the latter",
+                    previous.join(", "),
+                    hosts.join(", ")
+                );
+            } else {
+                default = Some(hosts.clone().into());
+                names.insert(String::new());
             }
+        }
 
-            hosts.retain(|host| {
-                if host.is_empty() {
+        hosts.retain(|host| {
+                if split_scheme(host).1.is_empty() {
                     warn!("ignoring empty host name in virtual hosts configuration, please use `default` setting instead");
                     false
                 } else {
                     true
                 }
             });
-            names.extend(hosts);
+        names.extend(hosts);
 
-            for host in &names {
-                if handlers.push(
-                    host,
-                    "",
-                    (None, handler.clone()),
-                    Some((None, handler.clone())),
-                ) {
-                    warn!("overriding existing entry for virtual host {host}");
-                }
+        for host in &names {
+            let (scheme, name) = split_scheme(host);
+            let route = Arc::new(Route {
+                strip_prefix: None,
+                handler: handler.clone(),
+                host: name.to_ascii_lowercase().into(),
+                path: "/".into(),
+            });
+            if handlers
+                .push_with_scheme(name, "", scheme, route.clone(), Some(route))?
+                .is_some()
+            {
+                warn!("duplicate virtual host {host}, previous handler discarded");
             }
+        }
 
-            let mut subpaths = host_conf.subpaths.into_iter().collect::<Vec<_>>();
+        let mut subpaths = host_conf.subpaths.into_iter().collect::<Vec<_>>();
 
-            // Make sure to add exact match rules last so that these take precedence over prefix
-            // rules. This also ensures that these rules are merged with the right prefix rule
-            // because these are all added already.
-            subpaths.sort_by_key(|(rule, _)| rule.exact);
+        // Make sure to add exact match rules last so that these take precedence over prefix
+        // rules. This also ensures that these rules are merged with the right prefix rule
+        // because these are all added already.
+        subpaths.sort_by_key(|(rule, _)| rule.exact);
 
-            for (rule, conf) in subpaths {
-                let handler = conf.config.try_into()?;
-                let strip_path = if conf.strip_prefix {
-                    Some(&rule.path)
-                } else {
-                    None
-                };
-                for host in &names {
-                    handlers.push(
-                        host,
-                        &*rule.path,
-                        (strip_path.cloned(), handler.clone()),
-                        if rule.exact {
-                            None
-                        } else {
-                            Some((strip_path.cloned(), handler.clone()))
-                        },
-                    );
+        for (rule, conf) in subpaths {
+            let handler = Arc::new(conf.config.try_into()?);
+            let strip_path = if conf.strip_prefix {
+                Some((rule.path.clone(), conf.keep_empty_path))
+            } else {
+                None
+            };
+            for host in &names {
+                let (scheme, name) = split_scheme(host);
+                let route = Arc::new(Route {
+                    strip_prefix: strip_path.clone(),
+                    handler: handler.clone(),
+                    host: name.to_ascii_lowercase().into(),
+                    path: format!("/{}", String::from_utf8_lossy(rule.path.as_bytes())).into(),
+                });
+                let previous = handlers.push_with_scheme(
+                    name,
+                    &*rule.path,
+                    scheme,
+                    route.clone(),
+                    (!rule.exact).then_some(route),
+                )?;
+
+                // Exact rules are expected to replace the value of a prefix rule for the same
+                // path, only replacing a prefix rule indicates a duplicate.
+                if let Some(replaced) = previous.filter(|_| !rule.exact) {
+                    warn!("duplicate virtual host subpath {replaced}, previous handler discarded");
                 }
             }
         }
-        let handlers = handlers.build();
+    }
+    let handlers = handlers.build()?;
 
-        Ok(Self { handlers })
+    if log_enabled!(log::Level::Debug) {
+        debug!("virtual hosts route table:");
+        for (host, path, route) in handlers.routes() {
+            debug!(
+                "  {}/{}{}",
+                String::from_utf8_lossy(host),
+                String::from_utf8_lossy(path),
+                if route.strip_prefix.is_some() {
+                    " (strip prefix)"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+
+    Ok(handlers)
+}
+
+impl<C, H> TryFrom<VirtualHostsConf<C>> for VirtualHostsHandler<H>
+where
+    H: Debug + Clone + Eq,
+    C: TryInto<H, Error = Box<Error>> + Default,
+{
+    type Error = Box<Error>;
+
+    fn try_from(conf: VirtualHostsConf<C>) -> Result<Self, Box<Error>> {
+        let prefer_uri_host = conf.prefer_uri_host;
+        let trust_forwarded_proto = conf.trust_forwarded_proto;
+        let queue = RequestQueue::default();
+        queue.configure(conf.request_queue.clone());
+        Ok(Self {
+            handlers: build_router(conf)?.into(),
+            prefer_uri_host: prefer_uri_host.into(),
+            trust_forwarded_proto: trust_forwarded_proto.into(),
+            queue,
+        })
     }
 }
 
@@ -252,6 +488,7 @@ where
 mod tests {
     use super::*;
 
+    use http::{Extensions, Version};
     use pandora_module_utils::pingora::{
         create_test_session, ErrorType, RequestHeader, ResponseHeader, Session,
     };
@@ -261,9 +498,17 @@ mod tests {
     use upstream_module::UpstreamHandler;
 
     fn make_app(add_default: bool) -> DefaultApp<VirtualHostsHandler<UpstreamHandler>> {
+        make_app_with_options(add_default, false)
+    }
+
+    fn make_app_with_options(
+        add_default: bool,
+        prefer_uri_host: bool,
+    ) -> DefaultApp<VirtualHostsHandler<UpstreamHandler>> {
         DefaultApp::new(
             <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(format!(
                 r#"
+                    prefer_uri_host: {prefer_uri_host}
                     vhosts:
                         [localhost:8080, 127.0.0.1:8080, "[::1]:8080"]:
                             default: {add_default}
@@ -276,10 +521,18 @@ mod tests {
                                     upstream: http://127.0.0.3
                                 /subdir/subsub/*:
                                     upstream: http://127.0.0.4
+                                /deep/*:
+                                    strip_prefix: true
+                                    upstream: http://127.0.0.8
+                                /deep/bc/de/f/*:
+                                    strip_prefix: true
+                                    upstream: http://127.0.0.9
                         [example.com, example.com:8080]:
                             upstream: http://127.0.0.5
                         example.info:
                             upstream: http://127.0.0.6
+                        "*.example.info":
+                            upstream: http://127.0.0.7
                 "#
             ))
             .unwrap()
@@ -305,6 +558,20 @@ mod tests {
         session
     }
 
+    async fn make_absolute_form_session(uri: &str, host: Option<&str>) -> Session {
+        let header = RequestHeader::build("GET", uri.as_bytes(), None).unwrap();
+        let mut session = create_test_session(header).await;
+
+        if let Some(host) = host {
+            session
+                .req_header_mut()
+                .insert_header("Host", host)
+                .unwrap();
+        }
+
+        session
+    }
+
     fn response_header() -> ResponseHeader {
         ResponseHeader::build(200, None).unwrap()
     }
@@ -322,6 +589,25 @@ mod tests {
         assert!(result.err().is_none());
     }
 
+    #[test(tokio::test)]
+    async fn host_match_mixed_case() {
+        let mut app = make_app(false);
+        for (host, upstream) in [
+            ("Example.COM", "127.0.0.5"),
+            ("LOCALHOST:8080", "127.0.0.1"),
+            ("WWW.Example.Info", "127.0.0.7"),
+        ] {
+            let session = make_session("/", Some(host)).await;
+            let result = app
+                .handle_request_with_upstream(session, |_, peer| {
+                    assert_eq!(peer.sni, upstream);
+                    Ok(response_header())
+                })
+                .await;
+            assert!(result.err().is_none());
+        }
+    }
+
     #[test(tokio::test)]
     async fn host_alias_match() {
         let mut app = make_app(false);
@@ -335,6 +621,48 @@ mod tests {
         assert!(result.err().is_none());
     }
 
+    #[test(tokio::test)]
+    async fn host_port_alias_match() {
+        let mut app = make_app(true);
+        for (host, upstream) in [
+            // Host name without port number is tried before the default host
+            ("example.info:8080", "127.0.0.6"),
+            ("www.example.info:443", "127.0.0.7"),
+            ("example.com:8443", "127.0.0.5"),
+            // Configuration including the port number is preferred
+            ("localhost:8080", "127.0.0.1"),
+            ("[::1]:8080", "127.0.0.1"),
+            // Default host applies if neither host name matches
+            ("localhost:8081", "127.0.0.1"),
+            ("example.net:8080", "127.0.0.1"),
+        ] {
+            let session = make_session("/", Some(host)).await;
+            let result = app
+                .handle_request_with_upstream(session, |_, peer| {
+                    assert_eq!(peer.sni, upstream);
+                    Ok(response_header())
+                })
+                .await;
+            assert!(result.err().is_none());
+        }
+
+        // A root match for the host name beats a subpath match for the default host
+        let session = make_session("/subdir/xyz", Some("example.com:8080")).await;
+        let result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.5");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+
+        assert_eq!(strip_port("example.com:8080"), Some("example.com"));
+        assert_eq!(strip_port("[::1]:8080"), Some("[::1]"));
+        assert_eq!(strip_port("[::1]"), None);
+        assert_eq!(strip_port("example.com"), None);
+        assert_eq!(strip_port("example.com:"), None);
+    }
+
     #[test(tokio::test)]
     async fn uri_match() {
         let mut app = make_app(false);
@@ -374,6 +702,160 @@ mod tests {
         assert!(result.err().is_none());
     }
 
+    #[test(tokio::test)]
+    async fn absolute_form() {
+        let mut app = make_app(false);
+        let session =
+            make_absolute_form_session("http://localhost:8080/subdir/xyz?abc", None).await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.2");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().uri().path(), "/xyz");
+        assert_eq!(
+            result.session().original_uri(),
+            "http://localhost:8080/subdir/xyz?abc"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn http2_authority() {
+        // HTTP/2 requests carry the host name in the `:authority` pseudo-header
+        let mut app = make_app(false);
+        let mut session = make_absolute_form_session("https://example.com/", None).await;
+        session.req_header_mut().set_version(Version::HTTP_2);
+        let result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.5");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+
+        // `:authority` takes precedence over the `Host` header
+        let mut app = make_app(false);
+        let mut session =
+            make_absolute_form_session("https://example.info/", Some("example.com")).await;
+        session.req_header_mut().set_version(Version::HTTP_2);
+        let result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.6");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+
+        // `Host` header is used if `:authority` is missing
+        let mut app = make_app(false);
+        let mut session = make_session("/", Some("example.info")).await;
+        session.req_header_mut().set_version(Version::HTTP_2);
+        let result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.6");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+
+        // With HTTP/1 the `Host` header takes precedence
+        let mut app = make_app(false);
+        let session =
+            make_absolute_form_session("https://example.info/", Some("example.com")).await;
+        let result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.5");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+    }
+
+    #[test(tokio::test)]
+    async fn absolute_form_host_precedence() {
+        let mut app = make_app(false);
+        let session =
+            make_absolute_form_session("http://localhost:8080/subdir/xyz", Some("example.com"))
+                .await;
+        let result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.5");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+
+        let mut app = make_app_with_options(false, true);
+        let session =
+            make_absolute_form_session("http://localhost:8080/subdir/xyz", Some("example.com"))
+                .await;
+        let result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.2");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+
+        let mut app = make_app_with_options(false, true);
+        let session = make_absolute_form_session("/subdir/xyz", Some("example.com")).await;
+        let result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.5");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+    }
+
+    #[test(tokio::test)]
+    async fn wildcard_host_match() {
+        let mut app = make_app(true);
+        let session = make_session("/", Some("www.example.info")).await;
+        let result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.7");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+
+        let session = make_session("/", Some("example.info")).await;
+        let result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.6");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+    }
+
+    #[test(tokio::test)]
+    async fn partial_deep_match() {
+        let mut app = make_app(false);
+        let session = make_session("/deep/bc/de/x/f", Some("localhost:8080")).await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.8");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().uri().path(), "/bc/de/x/f");
+
+        let session = make_session("/deep/bc/de/f/x", Some("localhost:8080")).await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.9");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().uri().path(), "/x");
+    }
+
     #[test(tokio::test)]
     async fn default_fallback() {
         let mut app = make_app(true);
@@ -458,6 +940,51 @@ mod tests {
         assert_eq!(result.session().original_uri(), "//subdir///xyz//");
     }
 
+    #[test(tokio::test)]
+    async fn subdir_match_percent_encoded() {
+        let mut app = make_app(true);
+        let session = make_session("/sub%64ir/other%2Etxt", Some("localhost:8080")).await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.2");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().uri(), "/other%2Etxt");
+        assert_eq!(result.session().original_uri(), "/sub%64ir/other%2Etxt");
+
+        let mut app = make_app(true);
+        let session = make_session("/subdir/%66ile.txt", Some("localhost:8080")).await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.3");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().uri(), "/subdir/%66ile.txt");
+
+        // Paths that cannot be decoded safely are rejected, even if the default host would match
+        for path in [
+            "/subdir%2Ffile.txt",
+            "/api/a%2Fb",
+            "/subdir/%2e%2e/file.txt",
+            "/subdir/%00",
+            "/subdir/%c0%ae%c0%ae/file.txt",
+            "/subdir/%zz",
+        ] {
+            let mut app = make_app(true);
+            let session = make_session(path, Some("localhost:8080")).await;
+            let result = app.handle_request(session).await;
+            assert_eq!(
+                result.err().as_ref().map(|err| &err.etype),
+                Some(&ErrorType::HTTPStatus(400)),
+                "{path}"
+            );
+        }
+    }
+
     #[test(tokio::test)]
     async fn subdir_no_match() {
         let mut app = make_app(true);
@@ -560,4 +1087,454 @@ mod tests {
         assert_eq!(result.session().uri(), "/file.txt/xyz");
         assert_eq!(result.session().original_uri(), "/subdir/file.txt/xyz");
     }
+
+    #[test(tokio::test)]
+    async fn subdir_keep_empty_path() {
+        let mut app = DefaultApp::<VirtualHostsHandler<UpstreamHandler>>::new(
+            <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(
+                r#"
+                    vhosts:
+                        localhost:
+                            default: true
+                            upstream: http://127.0.0.1
+                            subpaths:
+                                /slash/*:
+                                    strip_prefix: true
+                                    upstream: http://127.0.0.2
+                                /empty/*:
+                                    strip_prefix: true
+                                    keep_empty_path: true
+                                    upstream: http://127.0.0.3
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        for (uri, upstream, expected) in [
+            ("/slash", "127.0.0.2", "/"),
+            ("/slash/", "127.0.0.2", "/"),
+            ("/empty", "127.0.0.3", ""),
+            ("/empty/", "127.0.0.3", "/"),
+            ("/empty/xyz", "127.0.0.3", "/xyz"),
+            ("/empty?abc", "127.0.0.3", "/?abc"),
+        ] {
+            let session = make_session(uri, Some("localhost")).await;
+            let mut result = app
+                .handle_request_with_upstream(session, |_, peer| {
+                    assert_eq!(peer.sni, upstream);
+                    Ok(response_header())
+                })
+                .await;
+            assert!(result.err().is_none(), "{uri}");
+            assert_eq!(result.session().uri(), expected, "{uri}");
+            assert_eq!(result.session().original_uri(), uri, "{uri}");
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn scheme_match() {
+        fn make_scheme_app(
+            trust_forwarded_proto: bool,
+        ) -> DefaultApp<VirtualHostsHandler<UpstreamHandler>> {
+            DefaultApp::new(
+                <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(format!(
+                    r#"
+                        trust_forwarded_proto: {trust_forwarded_proto}
+                        vhosts:
+                            example.com:
+                                upstream: http://127.0.0.1
+                                subpaths:
+                                    /api/*:
+                                        upstream: http://127.0.0.2
+                            https://example.com:
+                                upstream: http://127.0.0.3
+                            http://example.net:
+                                default: true
+                                upstream: http://127.0.0.4
+                            https://example.net:
+                                upstream: http://127.0.0.5
+                    "#
+                ))
+                .unwrap()
+                .try_into()
+                .unwrap(),
+            )
+        }
+
+        let mut app = make_scheme_app(true);
+        for (host, path, proto, upstream) in [
+            // Scheme-specific host preferred, scheme-agnostic host as fallback
+            ("example.com", "/", Some("https"), "127.0.0.3"),
+            ("example.com", "/", Some("http"), "127.0.0.1"),
+            ("example.com", "/", None, "127.0.0.1"),
+            ("example.com", "/file", Some("https"), "127.0.0.3"),
+            // Longer subpath beats scheme-specific root
+            ("example.com", "/api/x", Some("https"), "127.0.0.2"),
+            ("example.com", "/api/x", Some("http"), "127.0.0.2"),
+            // Scheme-specific hosts only
+            ("example.net", "/", Some("https"), "127.0.0.5"),
+            ("example.net", "/", Some("http"), "127.0.0.4"),
+            // Default host applies regardless of scheme
+            ("example.org", "/", Some("https"), "127.0.0.4"),
+        ] {
+            let mut session = make_session(path, Some(host)).await;
+            if let Some(proto) = proto {
+                session
+                    .req_header_mut()
+                    .insert_header("X-Forwarded-Proto", proto)
+                    .unwrap();
+            }
+            let result = app
+                .handle_request_with_upstream(session, |_, peer| {
+                    assert_eq!(peer.sni, upstream, "{host}{path} via {proto:?}");
+                    Ok(response_header())
+                })
+                .await;
+            assert!(result.err().is_none());
+        }
+
+        // X-Forwarded-Proto header is ignored unless trusted
+        let mut app = make_scheme_app(false);
+        let mut session = make_session("/", Some("example.com")).await;
+        session
+            .req_header_mut()
+            .insert_header("X-Forwarded-Proto", "https")
+            .unwrap();
+        let result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.1");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+    }
+
+    #[test]
+    fn invalid_host_name() {
+        let conf = <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(
+            r#"
+                vhosts:
+                    localhost/subdir:
+                        upstream: http://127.0.0.1
+            "#,
+        )
+        .unwrap();
+        assert!(VirtualHostsHandler::<UpstreamHandler>::try_from(conf).is_err());
+
+        let conf = <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(
+            r#"
+                vhosts:
+                    localhost:
+                        upstream: http://127.0.0.1
+                        subpaths:
+                            //subdir//subsub/*:
+                                upstream: http://127.0.0.2
+            "#,
+        )
+        .unwrap();
+        assert!(VirtualHostsHandler::<UpstreamHandler>::try_from(conf).is_ok());
+
+        let conf = <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(
+            r#"
+                vhosts:
+                    localhost:
+                        upstream: http://127.0.0.1
+                        subpaths:
+                            "/sub\0dir/*":
+                                upstream: http://127.0.0.2
+            "#,
+        )
+        .unwrap();
+        assert!(VirtualHostsHandler::<UpstreamHandler>::try_from(conf).is_err());
+    }
+
+    #[test]
+    fn duplicate_subpath() {
+        // Subpaths colliding after normalization produce a warning rather than an error
+        let conf = <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(
+            r#"
+                vhosts:
+                    localhost:
+                        upstream: http://127.0.0.1
+                        subpaths:
+                            /subdir/*:
+                                upstream: http://127.0.0.2
+                            /subdir//*:
+                                upstream: http://127.0.0.2
+            "#,
+        )
+        .unwrap();
+        assert!(VirtualHostsHandler::<UpstreamHandler>::try_from(conf).is_ok());
+    }
+
+    /// Session wrapper allowing to call handler phases directly
+    struct TestSession {
+        session: Session,
+        extensions: Extensions,
+    }
+
+    impl Deref for TestSession {
+        type Target = Session;
+
+        fn deref(&self) -> &Self::Target {
+            &self.session
+        }
+    }
+
+    impl DerefMut for TestSession {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.session
+        }
+    }
+
+    impl SessionWrapper for TestSession {
+        fn extensions(&self) -> &Extensions {
+            &self.extensions
+        }
+
+        fn extensions_mut(&mut self) -> &mut Extensions {
+            &mut self.extensions
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn matched_rule() {
+        let handler: VirtualHostsHandler<UpstreamHandler> =
+            <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(
+                r#"
+                    vhosts:
+                        localhost:
+                            default: true
+                            upstream: http://127.0.0.1
+                            subpaths:
+                                /subdir/*:
+                                    upstream: http://127.0.0.2
+                                /subdir/file.txt:
+                                    upstream: http://127.0.0.5
+                                /subdir/subsub/*:
+                                    upstream: http://127.0.0.3
+                        "*.example.com":
+                            upstream: http://127.0.0.4
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        let matched = |uri: &'static str, host: Option<&'static str>| {
+            let handler = &handler;
+            async move {
+                let mut session = TestSession {
+                    session: make_session(uri, host).await,
+                    extensions: Extensions::new(),
+                };
+                let mut ctx = VirtualHostsHandler::<UpstreamHandler>::new_ctx();
+                handler
+                    .early_request_filter(&mut session, &mut ctx)
+                    .await
+                    .unwrap();
+                (
+                    ctx.matched_host().map(|host| host.to_owned()),
+                    ctx.matched_path().map(|path| path.to_owned()),
+                )
+            }
+        };
+        let expected = |host: &str, path: &str| (Some(host.to_owned()), Some(path.to_owned()));
+
+        assert_eq!(
+            matched("/", Some("localhost")).await,
+            expected("localhost", "/")
+        );
+        assert_eq!(
+            matched("/subdir_xyz", Some("localhost")).await,
+            expected("localhost", "/")
+        );
+        assert_eq!(
+            matched("/subdir", Some("localhost")).await,
+            expected("localhost", "/subdir")
+        );
+        assert_eq!(
+            matched("/subdir/sub/xyz", Some("localhost")).await,
+            expected("localhost", "/subdir")
+        );
+        assert_eq!(
+            matched("/subdir/file.txt", Some("localhost")).await,
+            expected("localhost", "/subdir/file.txt")
+        );
+        // Exact rules don’t apply to subdirectories, the prefix rule handles these
+        assert_eq!(
+            matched("/subdir/file.txt/xyz", Some("localhost")).await,
+            expected("localhost", "/subdir")
+        );
+        assert_eq!(
+            matched("/subdir/subsub", Some("localhost")).await,
+            expected("localhost", "/subdir/subsub")
+        );
+        assert_eq!(
+            matched("//subdir//subsub/xyz", Some("localhost")).await,
+            expected("localhost", "/subdir/subsub")
+        );
+        assert_eq!(
+            matched("/subdir/subsub/xyz", Some("www.example.com")).await,
+            expected("*.example.com", "/")
+        );
+        assert_eq!(
+            matched("/subdir/subsub/xyz", Some("unknown")).await,
+            expected("", "/subdir/subsub")
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn reload() {
+        let conf = |hosts: &str| {
+            <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(format!(
+                r#"
+                    vhosts:
+                        [{hosts}]:
+                            upstream: http://127.0.0.1
+                "#
+            ))
+            .unwrap()
+        };
+
+        async fn lookup(
+            handler: &VirtualHostsHandler<UpstreamHandler>,
+            host: &str,
+        ) -> <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::CTX {
+            let mut session = TestSession {
+                session: make_session("/", Some(host)).await,
+                extensions: Extensions::new(),
+            };
+            let mut ctx = VirtualHostsHandler::<UpstreamHandler>::new_ctx();
+            handler
+                .early_request_filter(&mut session, &mut ctx)
+                .await
+                .unwrap();
+            ctx
+        }
+
+        let handler: Arc<VirtualHostsHandler<UpstreamHandler>> =
+            Arc::new(conf("localhost").try_into().unwrap());
+        let old_ctx = lookup(&handler, "localhost").await;
+        assert_eq!(old_ctx.matched_host(), Some("localhost"));
+
+        let tasks = (0..4)
+            .map(|_| {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    loop {
+                        let ctx = lookup(&handler, "example.com").await;
+                        if let Some(host) = ctx.matched_host() {
+                            assert!(handler.as_inner(&ctx).is_some());
+                            break host.to_owned();
+                        }
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for i in 0..20 {
+            handler
+                .reload(conf(&format!("localhost, host{i}.example.com")))
+                .unwrap();
+            tokio::task::yield_now().await;
+        }
+
+        // Invalid configuration is rejected, previous configuration stays in effect
+        assert!(handler.reload(conf("localhost/subdir")).is_err());
+        assert_eq!(
+            lookup(&handler, "host19.example.com").await.matched_host(),
+            Some("host19.example.com")
+        );
+
+        handler.reload(conf("example.com")).unwrap();
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), "example.com");
+        }
+
+        // Requests in flight keep their handler, new requests use the new configuration
+        assert!(handler.as_inner(&old_ctx).is_some());
+        assert_eq!(lookup(&handler, "localhost").await.matched_host(), None);
+    }
+
+    #[test(tokio::test)]
+    async fn request_queue() {
+        type Ctx = <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::CTX;
+
+        async fn start(
+            handler: &VirtualHostsHandler<UpstreamHandler>,
+            host: &str,
+        ) -> Result<Ctx, Box<Error>> {
+            let mut session = TestSession {
+                session: make_session("/", Some(host)).await,
+                extensions: Extensions::new(),
+            };
+            let mut ctx = VirtualHostsHandler::<UpstreamHandler>::new_ctx();
+            handler.early_request_filter(&mut session, &mut ctx).await?;
+            Ok(ctx)
+        }
+
+        let handler: Arc<VirtualHostsHandler<UpstreamHandler>> = Arc::new(
+            <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(
+                r#"
+                    request_queue:
+                        max_concurrent: 1
+                        max_length: 1
+                        timeout_ms: 100
+                    vhosts:
+                        localhost:
+                            upstream: http://127.0.0.1
+                        example.com:
+                            upstream: http://127.0.0.2
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        let ctx = start(&handler, "localhost").await.unwrap();
+
+        // Waiting request times out
+        assert_eq!(
+            start(&handler, "example.com")
+                .await
+                .err()
+                .map(|err| err.etype),
+            Some(ErrorType::HTTPStatus(503))
+        );
+
+        // Waiting request proceeds once the previous request is done, queue is full meanwhile
+        let waiting = tokio::spawn({
+            let handler = handler.clone();
+            async move {
+                start(&handler, "example.com")
+                    .await
+                    .map(|ctx| ctx.matched_host().map(str::to_owned))
+            }
+        });
+        while handler.queue.length() == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            start(&handler, "localhost")
+                .await
+                .err()
+                .map(|err| err.etype),
+            Some(ErrorType::HTTPStatus(503))
+        );
+        drop(ctx);
+        assert_eq!(
+            waiting.await.unwrap().unwrap(),
+            Some("example.com".to_owned())
+        );
+
+        // Requests not matching any virtual host aren’t queued
+        let ctx = start(&handler, "localhost").await.unwrap();
+        assert!(start(&handler, "example.net").await.is_ok());
+        drop(ctx);
+    }
 }
@@ -16,6 +16,7 @@
 
 mod configuration;
 mod handler;
+mod queue;
 
-pub use configuration::{SubPathConf, VirtualHostConf, VirtualHostsConf};
+pub use configuration::{RequestQueueConf, SubPathConf, VirtualHostConf, VirtualHostsConf};
 pub use handler::VirtualHostsHandler;
@@ -0,0 +1,388 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Limiting the number of requests processed concurrently, queueing excess requests
+
+use pandora_module_utils::pingora::{Error, ErrorType};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+use crate::configuration::RequestQueueConf;
+
+/// A request waiting for its turn
+#[derive(Debug)]
+struct Waiter {
+    id: u64,
+    sender: oneshot::Sender<()>,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    /// Queue settings
+    conf: RequestQueueConf,
+    /// Number of requests currently being processed
+    active: usize,
+    /// Number of requests currently waiting
+    length: usize,
+    /// Identifier of the next waiting request
+    next_id: u64,
+    /// Waiting requests by virtual host
+    waiting: HashMap<String, VecDeque<Waiter>>,
+    /// Virtual hosts with waiting requests in the order they get their next turn
+    rotation: VecDeque<String>,
+}
+
+impl QueueState {
+    fn has_capacity(&self) -> bool {
+        self.conf
+            .max_concurrent
+            .map_or(true, |max| self.active < max)
+    }
+
+    /// Lets waiting requests proceed as long as there is capacity. Virtual hosts take turns so
+    /// that a single busy virtual host cannot starve the others.
+    fn grant_waiting(&mut self) {
+        while self.has_capacity() {
+            let Some(host) = self.rotation.pop_front() else {
+                break;
+            };
+            let Some(queue) = self.waiting.get_mut(&host) else {
+                continue;
+            };
+            let waiter = queue.pop_front();
+            if queue.is_empty() {
+                self.waiting.remove(&host);
+            } else {
+                self.rotation.push_back(host);
+            }
+
+            if let Some(waiter) = waiter {
+                self.length -= 1;
+                if waiter.sender.send(()).is_ok() {
+                    self.active += 1;
+                }
+            }
+        }
+    }
+
+    /// Removes a waiting request from the queue, returns `false` if it isn’t waiting any more
+    /// because it was granted its turn.
+    fn remove_waiter(&mut self, host: &str, id: u64) -> bool {
+        let Some(queue) = self.waiting.get_mut(host) else {
+            return false;
+        };
+        let Some(index) = queue.iter().position(|waiter| waiter.id == id) else {
+            return false;
+        };
+
+        queue.remove(index);
+        self.length -= 1;
+        if queue.is_empty() {
+            self.waiting.remove(host);
+            self.rotation.retain(|entry| entry != host);
+        }
+        true
+    }
+
+    /// Marks a request as completed, letting the next waiting request proceed.
+    fn release(&mut self) {
+        self.active -= 1;
+        self.grant_waiting();
+    }
+}
+
+/// Request queue shared between all clones of a virtual hosts handler
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RequestQueue {
+    state: Arc<Mutex<QueueState>>,
+}
+
+impl RequestQueue {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Retrieves the number of waiting requests.
+    #[cfg(test)]
+    pub(crate) fn length(&self) -> usize {
+        self.lock().length
+    }
+
+    /// Applies new queue settings. Requests already processed or waiting are unaffected, but
+    /// waiting requests will proceed immediately if the new settings allow it.
+    pub(crate) fn configure(&self, conf: RequestQueueConf) {
+        let mut state = self.lock();
+        state.conf = conf;
+        state.grant_waiting();
+    }
+
+    /// Waits until a request for the given virtual host can be processed. The returned permit
+    /// should be kept until the request is completed.
+    ///
+    /// This will produce a 503 error if too many requests are already waiting or the request
+    /// doesn’t get its turn within the configured time.
+    pub(crate) async fn acquire(&self, host: &str) -> Result<QueuePermit, Box<Error>> {
+        let (id, max_wait, mut receiver) = {
+            let mut state = self.lock();
+            let state = &mut *state;
+            if state.conf.max_concurrent.is_none() {
+                return Ok(QueuePermit { queue: None });
+            }
+
+            if state.length == 0 && state.has_capacity() {
+                state.active += 1;
+                return Ok(QueuePermit {
+                    queue: Some(self.clone()),
+                });
+            }
+
+            if state.length >= state.conf.max_length {
+                return Err(Error::explain(
+                    ErrorType::HTTPStatus(503),
+                    "too many requests waiting to be processed",
+                ));
+            }
+
+            let id = state.next_id;
+            state.next_id += 1;
+            state.length += 1;
+
+            let (sender, receiver) = oneshot::channel();
+            let queue = state.waiting.entry(host.to_owned()).or_default();
+            if queue.is_empty() {
+                state.rotation.push_back(host.to_owned());
+            }
+            queue.push_back(Waiter { id, sender });
+            (id, Duration::from_millis(state.conf.timeout_ms), receiver)
+        };
+
+        let mut guard = WaitGuard {
+            queue: self,
+            host,
+            id,
+            finished: false,
+        };
+
+        let granted = matches!(timeout(max_wait, &mut receiver).await, Ok(Ok(())));
+
+        // The turn might have been granted after the timeout elapsed, the request should proceed
+        // then.
+        guard.finished = true;
+        if granted || !self.lock().remove_waiter(host, id) {
+            Ok(QueuePermit {
+                queue: Some(self.clone()),
+            })
+        } else {
+            Err(Error::explain(
+                ErrorType::HTTPStatus(503),
+                "timed out waiting for the request to be processed",
+            ))
+        }
+    }
+}
+
+impl PartialEq for RequestQueue {
+    fn eq(&self, other: &Self) -> bool {
+        if Arc::ptr_eq(&self.state, &other.state) {
+            return true;
+        }
+
+        let conf = self.lock().conf.clone();
+        conf == other.lock().conf
+    }
+}
+
+impl Eq for RequestQueue {}
+
+/// Removes a request from the queue if it stops waiting prematurely, e.g. because the client
+/// disconnected.
+struct WaitGuard<'a> {
+    queue: &'a RequestQueue,
+    host: &'a str,
+    id: u64,
+    finished: bool,
+}
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let mut state = self.queue.lock();
+            if !state.remove_waiter(self.host, self.id) {
+                state.release();
+            }
+        }
+    }
+}
+
+/// Permission to process a request, letting the next request proceed when dropped
+#[derive(Debug)]
+pub(crate) struct QueuePermit {
+    queue: Option<RequestQueue>,
+}
+
+impl Drop for QueuePermit {
+    fn drop(&mut self) {
+        if let Some(queue) = &self.queue {
+            queue.lock().release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    fn make_queue(max_concurrent: usize, max_length: usize, timeout_ms: u64) -> RequestQueue {
+        let queue = RequestQueue::default();
+        queue.configure(RequestQueueConf {
+            max_concurrent: Some(max_concurrent),
+            max_length,
+            timeout_ms,
+        });
+        queue
+    }
+
+    fn status(result: Result<QueuePermit, Box<Error>>) -> Option<ErrorType> {
+        result.err().map(|err| err.etype)
+    }
+
+    #[test(tokio::test)]
+    async fn unlimited() {
+        let queue = RequestQueue::default();
+        let permits = [
+            queue.acquire("a").await.unwrap(),
+            queue.acquire("a").await.unwrap(),
+            queue.acquire("b").await.unwrap(),
+        ];
+        assert!(permits.iter().all(|permit| permit.queue.is_none()));
+    }
+
+    #[test(tokio::test)]
+    async fn queued_in_order() {
+        let queue = make_queue(1, 10, 10000);
+        let permit = queue.acquire("a").await.unwrap();
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut tasks = Vec::new();
+        for (index, host) in ["a", "a", "a", "b", "b", "c"].into_iter().enumerate() {
+            let queue = queue.clone();
+            let sender = sender.clone();
+            tasks.push(tokio::spawn(async move {
+                let _permit = queue.acquire(host).await.unwrap();
+                sender.send((index, host)).unwrap();
+            }));
+
+            // Make sure the requests are queued in the expected order
+            while queue.length() <= index {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        drop(permit);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        drop(sender);
+
+        let mut order = Vec::new();
+        while let Some(entry) = receiver.recv().await {
+            order.push(entry);
+        }
+
+        // Virtual hosts take turns, requests for the same virtual host are processed in order
+        assert_eq!(
+            order,
+            [(0, "a"), (3, "b"), (5, "c"), (1, "a"), (4, "b"), (2, "a")]
+        );
+
+        let state = queue.lock();
+        assert_eq!(state.active, 0);
+        assert_eq!(state.length, 0);
+    }
+
+    #[test(tokio::test)]
+    async fn queue_full() {
+        let queue = make_queue(1, 1, 10000);
+        let permit = queue.acquire("a").await.unwrap();
+
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.acquire("b").await.map(|_| ()) }
+        });
+        while queue.length() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        assert_eq!(
+            status(queue.acquire("c").await),
+            Some(ErrorType::HTTPStatus(503))
+        );
+
+        drop(permit);
+        assert!(waiting.await.unwrap().is_ok());
+        assert_eq!(queue.lock().active, 0);
+    }
+
+    #[test(tokio::test)]
+    async fn timed_out() {
+        let queue = make_queue(1, 10, 50);
+        let permit = queue.acquire("a").await.unwrap();
+
+        assert_eq!(
+            status(queue.acquire("b").await),
+            Some(ErrorType::HTTPStatus(503))
+        );
+        {
+            let state = queue.lock();
+            assert_eq!(state.length, 0);
+            assert!(state.waiting.is_empty());
+            assert!(state.rotation.is_empty());
+        }
+
+        // Timed out request doesn’t affect the following ones
+        drop(permit);
+        let _permit = queue.acquire("b").await.unwrap();
+        assert_eq!(queue.lock().active, 1);
+    }
+
+    #[test(tokio::test)]
+    async fn reconfigure() {
+        let queue = make_queue(1, 10, 10000);
+        let permit = queue.acquire("a").await.unwrap();
+
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.acquire("b").await.map(|_| ()) }
+        });
+        while queue.length() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // Raising the limit lets the waiting request proceed
+        queue.configure(RequestQueueConf {
+            max_concurrent: Some(2),
+            max_length: 10,
+            timeout_ms: 10000,
+        });
+        assert!(waiting.await.unwrap().is_ok());
+
+        drop(permit);
+        assert_eq!(queue.lock().active, 0);
+    }
+}